@@ -0,0 +1,47 @@
+// Copyright 2024 Contributors to the Veraison project.
+// SPDX-License-Identifier: Apache-2.0
+
+//! A key request from a web page, with the asynchronous keybroker client compiled to WebAssembly.
+//!
+//! The evidence is the CCA example token, so the keybroker server must use mock challenges, and the
+//! wrapping key-pair is generated in WebAssembly. The stages of the key request are passed to the
+//! page as JSON progress events, for it to show the background-check flow as it unfolds.
+use keybroker_client::asynchronous::AsyncKeyBrokerClient;
+use keybroker_client::{CcaExampleToken, ProgressObserver};
+use keybroker_common::ProgressEvent;
+use rsa::rand_core::OsRng;
+use rsa::RsaPrivateKey;
+use wasm_bindgen::prelude::*;
+
+/// Passes the progress events to a JavaScript function, as JSON strings.
+struct JsProgressObserver(js_sys::Function);
+
+impl ProgressObserver for JsProgressObserver {
+    fn progress(&self, event: &ProgressEvent) {
+        if let Ok(event) = serde_json::to_string(event) {
+            // The events are only informative, a failing callback does not stop the key request.
+            let _ = self.0.call1(&JsValue::NULL, &JsValue::from_str(&event));
+        }
+    }
+}
+
+/// Request a key from the keybroker server at `endpoint`, calling `on_progress` with each
+/// progress event, and return the key as text.
+#[wasm_bindgen(js_name = getKey)]
+pub async fn get_key(
+    endpoint: String,
+    key_name: String,
+    on_progress: js_sys::Function,
+) -> Result<String, JsError> {
+    // The size of the ephemeral wrapping keys of the native client, which keeps the generation
+    // short in the browser.
+    let wrapping_key = RsaPrivateKey::new(&mut OsRng, 2048)?;
+
+    let client = AsyncKeyBrokerClient::new(&endpoint)
+        .with_progress_observer(JsProgressObserver(on_progress));
+    let key = client
+        .get_key(&key_name, &CcaExampleToken {}, &wrapping_key)
+        .await?;
+
+    Ok(String::from_utf8_lossy(key.expose_secret()).into_owned())
+}
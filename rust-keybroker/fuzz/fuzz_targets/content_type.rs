@@ -0,0 +1,11 @@
+// Copyright 2024 Contributors to the Veraison project.
+// SPDX-License-Identifier: Apache-2.0
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+// The raw bytes of the Content-Type header of an evidence submission.
+fuzz_target!(|data: &[u8]| {
+    let _ = keybroker_server::input::evidence_media_type(Some(data));
+});
@@ -0,0 +1,11 @@
+// Copyright 2024 Contributors to the Veraison project.
+// SPDX-License-Identifier: Apache-2.0
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+// The base64 body of an evidence submission.
+fuzz_target!(|data: &[u8]| {
+    let _ = keybroker_server::input::decode_evidence(data);
+});
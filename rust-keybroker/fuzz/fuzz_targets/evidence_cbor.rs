@@ -0,0 +1,11 @@
+// Copyright 2024 Contributors to the Veraison project.
+// SPDX-License-Identifier: Apache-2.0
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+// The decoded evidence of a submission, before it is sent to the verifier.
+fuzz_target!(|data: &[u8]| {
+    let _ = keybroker_server::input::check_cbor_tagged(data);
+});
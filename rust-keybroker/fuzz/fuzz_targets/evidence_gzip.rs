@@ -0,0 +1,11 @@
+// Copyright 2024 Contributors to the Veraison project.
+// SPDX-License-Identifier: Apache-2.0
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+// The gzip-compressed body of an evidence submission, capped at 64 KiB once decompressed.
+fuzz_target!(|data: &[u8]| {
+    let _ = keybroker_server::input::decode_content(Some(b"gzip"), data.to_vec(), 64 * 1024);
+});
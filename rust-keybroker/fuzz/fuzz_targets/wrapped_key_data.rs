@@ -0,0 +1,12 @@
+// Copyright 2024 Contributors to the Veraison project.
+// SPDX-License-Identifier: Apache-2.0
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+// The JSON wrapped key data (or error information) returned by the server to the client.
+fuzz_target!(|data: &[u8]| {
+    let _ = keybroker_client::protocol::parse_wrapped_key_data(data);
+    let _ = keybroker_client::protocol::parse_error_information(data);
+});
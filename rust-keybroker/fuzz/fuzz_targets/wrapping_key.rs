@@ -0,0 +1,13 @@
+// Copyright 2024 Contributors to the Veraison project.
+// SPDX-License-Identifier: Apache-2.0
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+// The JSON key request, including the client's public wrapping key.
+fuzz_target!(|data: &[u8]| {
+    if let Ok(request) = keybroker_server::input::parse_key_request(data) {
+        let _ = keybroker_server::input::wrapping_public_key(&request.pubkey);
+    }
+});
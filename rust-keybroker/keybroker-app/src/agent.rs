@@ -0,0 +1,376 @@
+// Copyright 2024 Contributors to the Veraison project.
+// SPDX-License-Identifier: Apache-2.0
+
+//! The agent mode of the app, for the "retrieve a secret at boot and serve it to local processes"
+//! pattern: the key is retrieved once, kept in memory, and served on a Unix socket to the processes
+//! of the allowed users and groups, as told by the credentials of the socket peer.
+//!
+//! The protocol is minimal: a client connects, sends `GET` followed by an end of line, and reads the
+//! key bytes until the agent closes the connection, e.g. `echo GET | socat - UNIX-CONNECT:<socket>`.
+//! Any other request, or a connection from a process that is not allowed, is closed without a
+//! response.
+//!
+//! The key is retrieved again every `--refresh-secs` seconds, if set, and whenever the agent gets a
+//! SIGHUP; a failed refresh keeps the previous key. The key is zeroized when the agent exits, on
+//! SIGINT or SIGTERM.
+use keybroker_client::SecretKeyMaterial;
+use nix::sys::signal::{SigSet, Signal};
+use nix::sys::socket::{getsockopt, sockopt::PeerCredentials};
+use std::fs::Permissions;
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use thiserror::Error;
+
+/// The request a client sends to get the key.
+const GET_REQUEST: &str = "GET";
+
+/// The maximum length of a request, in bytes.
+const MAX_REQUEST_LENGTH: u64 = 64;
+
+/// The time allowed to a client to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Errors in setting up the agent.
+#[derive(Error, Debug)]
+pub enum AgentError {
+    /// The socket is in use by another agent.
+    #[error("Another agent is already listening on {0}")]
+    AlreadyListening(PathBuf),
+
+    /// The socket path is taken by something else than a socket, which is left alone.
+    #[error("{0} exists and is not a socket")]
+    NotASocket(PathBuf),
+
+    /// Creating the socket failed.
+    #[error("I/O error on {0}: {1}")]
+    Io(PathBuf, std::io::Error),
+
+    /// The signals could not be set up.
+    #[error("Failed to set up the signal handling: {0}")]
+    Signal(nix::errno::Errno),
+}
+
+/// The users and groups whose processes may get the key.
+#[derive(Clone, Debug, Default)]
+pub struct PeerPolicy {
+    pub uids: Vec<u32>,
+    pub gids: Vec<u32>,
+}
+
+impl PeerPolicy {
+    /// Whether a process running with these user and group IDs may get the key.
+    pub fn allows(&self, uid: u32, gid: u32) -> bool {
+        self.uids.contains(&uid) || self.gids.contains(&gid)
+    }
+
+    /// The mode of the socket file, which lets the processes allowed by the policy connect, and
+    /// only them as far as the owner of the agent and its group go.
+    pub fn socket_mode(&self, owner: u32) -> u32 {
+        if self.uids.iter().any(|uid| *uid != owner) {
+            // The other allowed users can be in any group, the credentials check is what keeps
+            // everyone else out.
+            0o666
+        } else if !self.gids.is_empty() {
+            0o660
+        } else {
+            0o600
+        }
+    }
+}
+
+/// The key served by the agent, zeroized when it is replaced, cleared or dropped.
+#[derive(Default)]
+pub struct SharedKey(RwLock<Option<SecretKeyMaterial>>);
+
+impl SharedKey {
+    pub fn new(key: SecretKeyMaterial) -> SharedKey {
+        SharedKey(RwLock::new(Some(key)))
+    }
+
+    /// Replace the key, zeroizing the previous one.
+    pub fn replace(&self, key: SecretKeyMaterial) {
+        *self.0.write().expect("Poisoned key lock.") = Some(key);
+    }
+
+    /// Zeroize the key, after which none is served.
+    pub fn clear(&self) {
+        self.0.write().expect("Poisoned key lock.").take();
+    }
+
+    fn write_to(&self, mut out: impl Write) -> std::io::Result<()> {
+        match &*self.0.read().expect("Poisoned key lock.") {
+            Some(key) => out.write_all(key.expose_secret()),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Listen on a Unix socket, replacing the socket file a previous agent may have left behind.
+pub fn bind(path: &Path, mode: u32) -> Result<UnixListener, AgentError> {
+    let io_error = |error| AgentError::Io(path.to_path_buf(), error);
+
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if !metadata.file_type().is_socket() => {
+            return Err(AgentError::NotASocket(path.to_path_buf()))
+        }
+        Ok(_) => {
+            if UnixStream::connect(path).is_ok() {
+                return Err(AgentError::AlreadyListening(path.to_path_buf()));
+            }
+            std::fs::remove_file(path).map_err(io_error)?;
+        }
+        Err(error) if error.kind() == ErrorKind::NotFound => {}
+        Err(error) => return Err(io_error(error)),
+    }
+
+    let listener = UnixListener::bind(path).map_err(io_error)?;
+    std::fs::set_permissions(path, Permissions::from_mode(mode)).map_err(io_error)?;
+    Ok(listener)
+}
+
+/// Answer the request of a client, if its credentials are allowed.
+fn handle_client(stream: UnixStream, key: &SharedKey, policy: &PeerPolicy) -> std::io::Result<()> {
+    let peer = getsockopt(&stream, PeerCredentials)?;
+    if !policy.allows(peer.uid(), peer.gid()) {
+        log::warn!(
+            "Rejected a connection from process {} (uid {}, gid {})",
+            peer.pid(),
+            peer.uid(),
+            peer.gid()
+        );
+        return Ok(());
+    }
+
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut request = String::new();
+    BufReader::new((&stream).take(MAX_REQUEST_LENGTH)).read_line(&mut request)?;
+    if request.trim_end() != GET_REQUEST {
+        log::warn!("Ignored an invalid request from process {}", peer.pid());
+        return Ok(());
+    }
+
+    log::debug!(
+        "Serving the key to process {} (uid {})",
+        peer.pid(),
+        peer.uid()
+    );
+    key.write_to(&stream)
+}
+
+/// Serve the key to the clients connecting to the listener, each in its own thread.
+pub fn serve(listener: UnixListener, key: Arc<SharedKey>, policy: Arc<PeerPolicy>) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let key = key.clone();
+                let policy = policy.clone();
+                std::thread::spawn(move || {
+                    if let Err(error) = handle_client(stream, &key, &policy) {
+                        log::warn!("Failed to serve a client: {error}");
+                    }
+                });
+            }
+            Err(error) => log::warn!("Failed to accept a connection: {error}"),
+        }
+    }
+}
+
+/// What the agent is told to do by the signals it gets.
+enum Order {
+    Refresh,
+    Exit,
+}
+
+/// Block the signals the agent acts upon, leaving them to the signal thread started by [`run`].
+///
+/// This must be called before any other thread is started, including the ones of the HTTP client,
+/// as the threads inherit the signal mask of the thread starting them.
+pub fn block_signals() -> Result<SigSet, AgentError> {
+    let mut signals = SigSet::empty();
+    signals.add(Signal::SIGHUP);
+    signals.add(Signal::SIGINT);
+    signals.add(Signal::SIGTERM);
+    signals.thread_block().map_err(AgentError::Signal)?;
+    Ok(signals)
+}
+
+/// Serve the key on the socket until SIGINT or SIGTERM, retrieving it again with `retrieve` on
+/// SIGHUP and every `refresh` interval. The `signals` are the ones blocked by [`block_signals`].
+pub fn run<F>(
+    signals: SigSet,
+    socket: &Path,
+    policy: PeerPolicy,
+    key: SecretKeyMaterial,
+    refresh: Option<Duration>,
+    mut retrieve: F,
+) -> Result<(), AgentError>
+where
+    F: FnMut() -> Option<SecretKeyMaterial>,
+{
+    let (orders, ordered) = mpsc::channel();
+    std::thread::spawn(move || loop {
+        let order = match signals.wait() {
+            Ok(Signal::SIGHUP) => Order::Refresh,
+            Ok(_) => Order::Exit,
+            Err(error) => {
+                log::error!("Failed to wait for signals: {error}");
+                Order::Exit
+            }
+        };
+        let exit = matches!(order, Order::Exit);
+        if orders.send(order).is_err() || exit {
+            break;
+        }
+    });
+
+    let listener = bind(socket, policy.socket_mode(nix::unistd::geteuid().as_raw()))?;
+    let key = Arc::new(SharedKey::new(key));
+    let server_key = key.clone();
+    let policy = Arc::new(policy);
+    std::thread::spawn(move || serve(listener, server_key, policy));
+    log::info!("Serving the key on {}", socket.display());
+
+    loop {
+        let order = match refresh {
+            Some(interval) => match ordered.recv_timeout(interval) {
+                Err(RecvTimeoutError::Timeout) => Order::Refresh,
+                order => order.unwrap_or(Order::Exit),
+            },
+            None => ordered.recv().unwrap_or(Order::Exit),
+        };
+
+        match order {
+            Order::Refresh => match retrieve() {
+                Some(new_key) => {
+                    key.replace(new_key);
+                    log::info!("The key was refreshed");
+                }
+                None => log::warn!("The key refresh failed, the previous key is still served"),
+            },
+            Order::Exit => break,
+        }
+    }
+
+    // The server thread is left to die with the process, but not before the key is gone.
+    key.clear();
+    let _ = std::fs::remove_file(socket);
+    log::info!("Exiting, the key was zeroized");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn socket_path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("keybroker-app-{}-{name}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn start_agent(name: &str, policy: PeerPolicy) -> PathBuf {
+        let path = socket_path(name);
+        let listener = bind(&path, 0o600).unwrap();
+        let key = Arc::new(SharedKey::new(SecretKeyMaterial::new(
+            b"May the force be with you.",
+        )));
+        std::thread::spawn(move || serve(listener, key, Arc::new(policy)));
+        path
+    }
+
+    fn request(path: &Path, request: &[u8]) -> Vec<u8> {
+        // The agent may close a connection it rejects before the request is even sent, the
+        // response is then empty.
+        let mut stream = UnixStream::connect(path).unwrap();
+        let mut response = Vec::new();
+        let _ = stream
+            .write_all(request)
+            .and_then(|_| stream.read_to_end(&mut response));
+        response
+    }
+
+    fn own_ids() -> PeerPolicy {
+        PeerPolicy {
+            uids: vec![nix::unistd::geteuid().as_raw()],
+            gids: vec![],
+        }
+    }
+
+    #[test]
+    fn socket_permissions() {
+        let path = socket_path("permissions");
+        let _listener = bind(&path, 0o600).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let owner = 1000;
+        let policy = |uids: Vec<u32>, gids: Vec<u32>| PeerPolicy { uids, gids };
+        assert_eq!(policy(vec![owner], vec![]).socket_mode(owner), 0o600);
+        assert_eq!(policy(vec![owner], vec![100]).socket_mode(owner), 0o660);
+        assert_eq!(policy(vec![owner, 1001], vec![]).socket_mode(owner), 0o666);
+    }
+
+    #[test]
+    fn socket_in_use_or_taken() {
+        let path = socket_path("in-use");
+        let listener = bind(&path, 0o600).unwrap();
+        assert!(matches!(
+            bind(&path, 0o600),
+            Err(AgentError::AlreadyListening(_))
+        ));
+
+        // A socket left behind by an agent that is gone is replaced.
+        drop(listener);
+        assert!(bind(&path, 0o600).is_ok());
+
+        let path = socket_path("not-a-socket");
+        std::fs::write(&path, b"precious").unwrap();
+        assert!(matches!(bind(&path, 0o600), Err(AgentError::NotASocket(_))));
+        assert_eq!(std::fs::read(&path).unwrap(), b"precious");
+    }
+
+    #[test]
+    fn concurrent_readers() {
+        let path = start_agent("concurrent", own_ids());
+
+        let readers: Vec<_> = (0..8)
+            .map(|_| {
+                let path = path.clone();
+                std::thread::spawn(move || request(&path, b"GET\n"))
+            })
+            .collect();
+        for reader in readers {
+            assert_eq!(reader.join().unwrap(), b"May the force be with you.");
+        }
+    }
+
+    #[test]
+    fn rejected_peers_and_requests() {
+        let path = start_agent("rejected", PeerPolicy::default());
+        assert!(request(&path, b"GET\n").is_empty());
+
+        let path = start_agent("invalid", own_ids());
+        assert!(request(&path, b"PUT\n").is_empty());
+    }
+
+    #[test]
+    fn key_replaced() {
+        let key = SharedKey::new(SecretKeyMaterial::new(b"old"));
+        key.replace(SecretKeyMaterial::new(b"new"));
+
+        let mut served = Vec::new();
+        key.write_to(&mut served).unwrap();
+        assert_eq!(served, b"new");
+
+        key.clear();
+        served.clear();
+        key.write_to(&mut served).unwrap();
+        assert!(served.is_empty());
+    }
+}
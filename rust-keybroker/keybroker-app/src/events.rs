@@ -0,0 +1,61 @@
+// Copyright 2024 Contributors to the Veraison project.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Reporting of the progress of the key requests as newline-delimited JSON events, for the
+//! programs driving the app, which would otherwise have to scrape the log messages.
+//!
+//! Each line is a [`ProgressEvent`] from keybroker-common, which consumers can depend on to parse
+//! them.
+use keybroker_client::ProgressObserver;
+use keybroker_common::ProgressEvent;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+
+/// A ProgressObserver writing the events, one JSON document per line.
+#[derive(Debug)]
+pub struct EventWriter {
+    out: File,
+}
+
+impl EventWriter {
+    /// Write the events to a file, which is created or truncated.
+    pub fn create(path: &Path) -> std::io::Result<EventWriter> {
+        Ok(EventWriter {
+            out: File::create(path)?,
+        })
+    }
+
+    /// Write the events to a file descriptor inherited from the parent process.
+    #[cfg(unix)]
+    pub fn from_fd(fd: i32) -> std::io::Result<EventWriter> {
+        use std::os::fd::{FromRawFd, OwnedFd};
+
+        // Check that the descriptor is open before taking ownership of it.
+        // SAFETY: fcntl(F_GETFD) has no side effects, whatever the descriptor.
+        if unsafe { nix::libc::fcntl(fd, nix::libc::F_GETFD) } == -1 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        // SAFETY: the descriptor is open, and it was handed over to the app for its events only.
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        Ok(EventWriter {
+            out: File::from(fd),
+        })
+    }
+}
+
+impl ProgressObserver for EventWriter {
+    fn progress(&self, event: &ProgressEvent) {
+        let mut line = serde_json::to_vec(event).expect("Failed to serialise a progress event.");
+        line.push(b'\n');
+
+        // The events are only informative: failing to write them must not fail the key request.
+        if let Err(error) = (&self.out)
+            .write_all(&line)
+            .and_then(|_| (&self.out).flush())
+        {
+            log::warn!("Failed to write a progress event: {error}");
+        }
+    }
+}
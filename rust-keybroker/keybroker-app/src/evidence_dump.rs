@@ -0,0 +1,161 @@
+// Copyright 2024 Contributors to the Veraison project.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Saving of the generated evidence, so that a failed verification can be replayed later.
+//!
+//! The raw evidence bytes are written to the requested path, and a sidecar JSON document with the
+//! nonce, the media type and the time of the generation is written next to it, with an extra `.json`
+//! extension.
+use chrono::{SecondsFormat, Utc};
+use keybroker_client::error::{Error as KeybrokerError, Result, RuntimeErrorKind};
+use keybroker_client::{EvidenceObserver, GeneratedEvidence};
+use serde::Serialize;
+use std::ffi::OsString;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// The content of the sidecar JSON document.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+struct EvidenceMetadata<'a> {
+    /// The challenge from the keybroker server, base64-encoded.
+    nonce: &'a str,
+    media_type: &'a str,
+    /// The time the evidence was generated at, in RFC 3339 format.
+    timestamp: String,
+}
+
+/// An EvidenceObserver writing the evidence to a file.
+#[derive(Debug)]
+pub struct EvidenceDump {
+    path: PathBuf,
+    force: bool,
+}
+
+impl EvidenceDump {
+    /// Write the evidence to `path`, overwriting existing files only if `force` is set.
+    pub fn new(path: PathBuf, force: bool) -> EvidenceDump {
+        EvidenceDump { path, force }
+    }
+
+    /// The path of the sidecar JSON document.
+    pub fn metadata_path(&self) -> PathBuf {
+        let mut path = OsString::from(self.path.as_os_str());
+        path.push(".json");
+        PathBuf::from(path)
+    }
+
+    fn create(&self, path: &Path) -> Result<File> {
+        let mut options = OpenOptions::new();
+        options.write(true);
+        if self.force {
+            options.create(true).truncate(true);
+        } else {
+            options.create_new(true);
+        }
+
+        options
+            .open(path)
+            .map_err(|error| observer_error(path, error))
+    }
+}
+
+fn observer_error(path: &Path, error: impl std::fmt::Display) -> KeybrokerError {
+    KeybrokerError::RuntimeError(RuntimeErrorKind::EvidenceObserver(format!(
+        "{}: {error}",
+        path.display()
+    )))
+}
+
+impl EvidenceObserver for EvidenceDump {
+    fn evidence_generated(&self, evidence: &GeneratedEvidence) -> Result<()> {
+        let metadata = EvidenceMetadata {
+            nonce: evidence.challenge,
+            media_type: evidence.media_type,
+            timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+        };
+        let metadata_path = self.metadata_path();
+
+        // Check both files before creating any, so that an existing sidecar does not leave an empty
+        // evidence file behind.
+        if !self.force {
+            if let Some(path) = [&self.path, &metadata_path]
+                .into_iter()
+                .find(|p| p.exists())
+            {
+                return Err(observer_error(
+                    path,
+                    "the file already exists (use --force to overwrite it)",
+                ));
+            }
+        }
+
+        let mut evidence_file = self.create(&self.path)?;
+        let mut metadata_file = self.create(&metadata_path)?;
+
+        evidence_file
+            .write_all(evidence.evidence)
+            .map_err(|error| observer_error(&self.path, error))?;
+        serde_json::to_writer_pretty(&mut metadata_file, &metadata)
+            .map_err(|error| observer_error(&metadata_path, error))?;
+
+        log::info!(
+            "Evidence written to {} (metadata in {})",
+            self.path.display(),
+            metadata_path.display()
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EVIDENCE: GeneratedEvidence = GeneratedEvidence {
+        evidence: b"evidence",
+        challenge: "bm9uY2U=",
+        media_type: "application/eat-collection",
+    };
+
+    fn dump_path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("keybroker-app-{}-{name}.cbor", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(EvidenceDump::new(path.clone(), false).metadata_path());
+        path
+    }
+
+    #[test]
+    fn writes_evidence_and_metadata() {
+        let dump = EvidenceDump::new(dump_path("write"), false);
+        dump.evidence_generated(&EVIDENCE).unwrap();
+
+        assert_eq!(std::fs::read(&dump.path).unwrap(), b"evidence");
+        let metadata: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(dump.metadata_path()).unwrap()).unwrap();
+        assert_eq!(metadata["nonce"], "bm9uY2U=");
+        assert_eq!(metadata["media-type"], "application/eat-collection");
+        assert!(metadata["timestamp"].is_string());
+    }
+
+    #[test]
+    fn overwrite_requires_force() {
+        let path = dump_path("force");
+        std::fs::write(&path, b"previous").unwrap();
+
+        assert!(matches!(
+            EvidenceDump::new(path.clone(), false).evidence_generated(&EVIDENCE),
+            Err(KeybrokerError::RuntimeError(
+                RuntimeErrorKind::EvidenceObserver(_)
+            ))
+        ));
+        assert_eq!(std::fs::read(&path).unwrap(), b"previous");
+
+        EvidenceDump::new(path.clone(), true)
+            .evidence_generated(&EVIDENCE)
+            .unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"evidence");
+    }
+}
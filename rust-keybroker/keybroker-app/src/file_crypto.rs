@@ -0,0 +1,167 @@
+// Copyright 2024 Contributors to the Veraison project.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Encryption and decryption of files with a key retrieved from the keybroker.
+//!
+//! This makes the demo tangible: a file is encrypted beforehand with the secret held by the keybroker,
+//! and can only be decrypted by a client that successfully attested to the keybroker and thus received
+//! that secret.
+//!
+//! The secret is used as an AES-256-GCM key, so it must be exactly 32 bytes long. An encrypted file is
+//! made of the 12-byte random nonce, followed by the ciphertext and the 16-byte authentication tag.
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Nonce};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+/// The size of an AES-256-GCM key, in bytes.
+pub const KEY_SIZE: usize = 32;
+
+/// The size of an AES-GCM nonce, in bytes.
+const NONCE_SIZE: usize = 12;
+
+/// The size of an AES-GCM authentication tag, in bytes.
+const TAG_SIZE: usize = 16;
+
+/// Errors in the encryption or decryption of a file.
+#[derive(Error, Debug)]
+pub enum FileCryptoError {
+    /// The key is not an AES-256 key.
+    #[error("The key is {0} bytes long, but AES-256-GCM needs a {KEY_SIZE}-byte key")]
+    WrongKeySize(usize),
+
+    /// The file was not encrypted with this key, or it was tampered with.
+    #[error(
+        "Authentication tag mismatch: the file was not encrypted with this key, or it was modified"
+    )]
+    TagMismatch,
+
+    /// Reading or writing a file failed.
+    #[error("I/O error on {0}: {1}")]
+    Io(PathBuf, std::io::Error),
+}
+
+type Result<T> = std::result::Result<T, FileCryptoError>;
+
+fn cipher(key: &[u8]) -> Result<Aes256Gcm> {
+    Aes256Gcm::new_from_slice(key).map_err(|_| FileCryptoError::WrongKeySize(key.len()))
+}
+
+/// Encrypt data with the given key, returning the nonce followed by the ciphertext and tag.
+pub fn encrypt(key: &[u8], plaintext: &[u8]) -> Result<Vec<u8>> {
+    let cipher = cipher(key)?;
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+
+    // Encryption can only fail for plaintexts larger than 64 GiB.
+    let ciphertext = cipher
+        .encrypt(&nonce, plaintext)
+        .expect("The plaintext is too large.");
+
+    let mut encrypted = nonce.to_vec();
+    encrypted.extend_from_slice(&ciphertext);
+    Ok(encrypted)
+}
+
+/// Decrypt data produced by [`encrypt`] with the given key.
+pub fn decrypt(key: &[u8], encrypted: &[u8]) -> Result<Zeroizing<Vec<u8>>> {
+    let cipher = cipher(key)?;
+
+    // A truncated file can't carry a valid tag.
+    let Some((nonce, ciphertext)) = encrypted
+        .split_first_chunk::<NONCE_SIZE>()
+        .filter(|(_, ciphertext)| ciphertext.len() >= TAG_SIZE)
+    else {
+        return Err(FileCryptoError::TagMismatch);
+    };
+
+    cipher
+        .decrypt(&Nonce::from(*nonce), ciphertext)
+        .map(Zeroizing::new)
+        .map_err(|_| FileCryptoError::TagMismatch)
+}
+
+fn read(path: &Path) -> Result<Zeroizing<Vec<u8>>> {
+    std::fs::read(path)
+        .map(Zeroizing::new)
+        .map_err(|error| FileCryptoError::Io(path.to_path_buf(), error))
+}
+
+fn write(path: &Path, data: &[u8]) -> Result<()> {
+    std::fs::write(path, data).map_err(|error| FileCryptoError::Io(path.to_path_buf(), error))
+}
+
+/// Read the key from a file, checking its size.
+pub fn read_key(path: &Path) -> Result<Zeroizing<Vec<u8>>> {
+    let key = read(path)?;
+    if key.len() != KEY_SIZE {
+        return Err(FileCryptoError::WrongKeySize(key.len()));
+    }
+    Ok(key)
+}
+
+/// Encrypt the `input` file into the `output` file.
+pub fn encrypt_file(key: &[u8], input: &Path, output: &Path) -> Result<()> {
+    let plaintext = read(input)?;
+    write(output, &encrypt(key, &plaintext)?)
+}
+
+/// Decrypt the `input` file into the `output` file.
+pub fn decrypt_file(key: &[u8], input: &Path, output: &Path) -> Result<()> {
+    let encrypted = read(input)?;
+    write(output, &decrypt(key, &encrypted)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; KEY_SIZE] = [0x42; KEY_SIZE];
+
+    #[test]
+    fn round_trip() {
+        let encrypted = encrypt(&KEY, b"The plans of the Death Star").unwrap();
+        assert_eq!(
+            *decrypt(&KEY, &encrypted).unwrap(),
+            b"The plans of the Death Star"
+        );
+    }
+
+    #[test]
+    fn wrong_key_size() {
+        assert!(matches!(
+            encrypt(b"May the force be with you.", b"data"),
+            Err(FileCryptoError::WrongKeySize(26))
+        ));
+    }
+
+    #[test]
+    fn tag_mismatch() {
+        let mut encrypted = encrypt(&KEY, b"The plans of the Death Star").unwrap();
+
+        assert!(matches!(
+            decrypt(&[0x24; KEY_SIZE], &encrypted),
+            Err(FileCryptoError::TagMismatch)
+        ));
+
+        let last = encrypted.len() - 1;
+        encrypted[last] ^= 1;
+        assert!(matches!(
+            decrypt(&KEY, &encrypted),
+            Err(FileCryptoError::TagMismatch)
+        ));
+
+        assert!(matches!(
+            decrypt(&KEY, &encrypted[..NONCE_SIZE]),
+            Err(FileCryptoError::TagMismatch)
+        ));
+    }
+
+    #[test]
+    fn missing_file() {
+        assert!(matches!(
+            decrypt_file(&KEY, Path::new("/nonexistent/file"), Path::new("/dev/null")),
+            Err(FileCryptoError::Io(_, _))
+        ));
+    }
+}
@@ -1,33 +1,706 @@
 // Copyright 2024 Contributors to the Veraison project.
 // SPDX-License-Identifier: Apache-2.0
 
-use clap::Parser;
+use clap::builder::{PossibleValuesParser, TypedValueParser};
+use clap::{Parser, Subcommand, ValueEnum};
+use events::EventWriter;
+use evidence_dump::EvidenceDump;
 use keybroker_client::error::Error as KeybrokerError;
-use keybroker_client::{CcaExampleToken, KeyBrokerClient, TsmAttestationReport};
+use keybroker_client::error::Result as KeybrokerResult;
+use keybroker_client::error::RuntimeErrorKind;
+use keybroker_client::protocol::attestation_result_claims;
+use keybroker_client::session::PendingKeyRequest;
+use keybroker_client::{
+    EvidenceProvider, EvidenceSource, FileEvidence, KeyBrokerClient, RetrievedKey,
+    RsaWrappingAlgorithm, DEFAULT_CHALLENGE_RESTARTS, EVIDENCE_MEDIA_TYPE,
+};
+use keybroker_common::{ServerInfo, Timings};
+use std::path::{Path, PathBuf};
 use std::process;
+use zeroize::Zeroizing;
+
+#[cfg(target_os = "linux")]
+mod agent;
+mod events;
+mod evidence_dump;
+mod file_crypto;
+mod stress;
 
 /// Structure for parsing and storing the command-line arguments
 #[derive(Clone, Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(
+    version,
+    about,
+    long_about = None,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 struct Args {
     /// The address this client should connect to to request a key.
-    #[arg(short, long, default_value = "http://127.0.0.1:8088")]
+    #[arg(short, long, global = true, default_value = "http://127.0.0.1:8088")]
     endpoint: String,
 
-    /// Use a CCA example token (instead of the TSM report)
-    #[arg(short, long, default_value_t = false)]
+    /// Use a CCA example token (instead of the TSM report), the same as '--evidence-source mock'
+    #[arg(
+        short,
+        long,
+        global = true,
+        default_value_t = false,
+        conflicts_with = "evidence_source"
+    )]
     mock_evidence: bool,
 
+    /// Where the evidence comes from. 'auto' picks the first of tsm and rsi which is usable on
+    /// this platform, and falls back to mock
+    #[arg(long, global = true, value_enum, default_value_t = EvidenceChoice::Tsm)]
+    evidence_source: EvidenceChoice,
+
+    /// Submit the evidence captured in this file, raw or base64-encoded, whatever the challenge.
+    /// It only verifies with a keybroker server started with '--mock-challenge'
+    #[arg(
+        long,
+        global = true,
+        value_name = "PATH",
+        conflicts_with_all = ["mock_evidence", "evidence_source"]
+    )]
+    evidence_file: Option<PathBuf>,
+
+    /// The media type of the evidence in --evidence-file, the CCA attestation token by default
+    #[arg(
+        long,
+        global = true,
+        value_name = "MEDIA_TYPE",
+        requires = "evidence_file"
+    )]
+    evidence_type: Option<String>,
+
+    /// Have the key wrapped to the RSA key-pair in this PEM file (PKCS#8 or PKCS#1), rather than
+    /// to an ephemeral one
+    #[arg(long, global = true)]
+    wrapping_key: Option<PathBuf>,
+
+    /// The algorithm with which the key is wrapped to the RSA key-pair
+    #[arg(long, global = true, value_enum, default_value_t = WrappingAlgChoice::Rsa15)]
+    wrapping_alg: WrappingAlgChoice,
+
+    /// The size of the ephemeral RSA key-pair the key is wrapped to, in bits
+    #[arg(
+        long,
+        global = true,
+        default_value_t = 2048,
+        conflicts_with = "wrapping_key",
+        value_parser = PossibleValuesParser::new(["2048", "3072", "4096"])
+            .map(|bits| bits.parse::<usize>().unwrap())
+    )]
+    wrapping_bits: usize,
+
+    /// Extend this realm extensible measurement (0 to 3) with the thumbprint of the wrapping key
+    /// before producing the evidence, for the keybroker servers started with
+    /// '--bind-wrapping-key'. It needs --wrapping-key, as the measurement can only be extended once
+    /// per realm boot
+    #[arg(long, global = true, requires = "wrapping_key", value_parser = clap::value_parser!(u32).range(0..4))]
+    bind_wrapping_key: Option<u32>,
+
+    /// Send this header with every request to the keybroker server, as 'Name: value'. Can be
+    /// repeated
+    #[arg(long = "header", global = true, value_parser = parse_header)]
+    headers: Vec<(String, String)>,
+
+    /// Trust the CA certificates in this PEM file for an HTTPS keybroker server, on top of the
+    /// built-in roots, such as the certificate written by 'keybroker-server --tls-self-signed'
+    #[arg(long, global = true)]
+    ca_cert: Option<PathBuf>,
+
+    /// Only trust an HTTPS keybroker server whose certificate has this public key, given as the
+    /// hex SHA-256 digest of its SubjectPublicKeyInfo, on top of its certificate chain
+    #[arg(long, global = true, value_name = "HEX", value_parser = parse_spki_sha256)]
+    pinned_spki_sha256: Option<[u8; 32]>,
+
+    /// Do NOT verify the certificate of an HTTPS keybroker server, which anyone in the middle can
+    /// then impersonate. Only for lab setups; a pinned public key is still checked
+    #[arg(long, global = true, default_value_t = false)]
+    insecure: bool,
+
+    /// Write the generated evidence to this file before submitting it, and its nonce, media type
+    /// and timestamp to the same path with an extra '.json' extension
+    #[arg(long, global = true)]
+    show_evidence: Option<PathBuf>,
+
+    /// Write newline-delimited JSON progress events to this file descriptor, inherited from the
+    /// parent process
+    #[cfg(unix)]
+    #[arg(long, global = true, conflicts_with = "events_file")]
+    events_fd: Option<i32>,
+
+    /// Write newline-delimited JSON progress events to this file
+    #[arg(long, global = true)]
+    events_file: Option<PathBuf>,
+
+    /// Overwrite the files written by --show-evidence if they already exist
+    #[arg(
+        long,
+        global = true,
+        requires = "show_evidence",
+        default_value_t = false
+    )]
+    force: bool,
+
+    /// How many times a key request is restarted with a new challenge when the keybroker server
+    /// says that its challenge expired, such as when the evidence is slow to generate
+    #[arg(long, global = true, default_value_t = DEFAULT_CHALLENGE_RESTARTS)]
+    challenge_restarts: u32,
+
+    /// Give up on the evidence if it is not produced within this many seconds, as when the
+    /// attestation request hangs, and cancel its challenge
+    #[arg(long, global = true, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    evidence_timeout: Option<u64>,
+
+    /// Send this correlation ID with the key requests, rather than a random UUID, to find them in
+    /// the keybroker server logs
+    #[arg(long, global = true, value_name = "ID")]
+    correlation_id: Option<String>,
+
+    /// Before anything else, wait for the keybroker server to answer, for at most this many
+    /// seconds (30 if not given), such as when it is started along with the client
+    #[arg(
+        long,
+        global = true,
+        value_name = "SECS",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "30"
+    )]
+    wait_for_server: Option<u64>,
+
+    /// Print how long each phase of the key request took, whether it succeeded or failed. The
+    /// progress events also include the timings
+    #[arg(long, global = true, default_value_t = false)]
+    timings: bool,
+
     /// Increase verbosity
-    #[arg(short, long, action = clap::ArgAction::Count)]
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
     verbosity: u8,
 
     /// Silence all output
-    #[arg(short, long, default_value_t = false)]
+    #[arg(short, long, global = true, default_value_t = false)]
     quiet: bool,
 
     /// The key name to use
-    key_name: String,
+    #[arg(required = true)]
+    key_name: Option<String>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+/// The sources of evidence that can be chosen on the command line.
+#[derive(Clone, Copy, ValueEnum, Debug)]
+enum EvidenceChoice {
+    /// A TSM attestation report, from configfs-tsm
+    Tsm,
+    /// An attestation token from the RSI device
+    Rsi,
+    /// The CCA example token
+    Mock,
+    /// Whatever is available on the platform
+    Auto,
+}
+
+/// The RSA wrapping algorithms that can be chosen on the command line.
+#[derive(Clone, Copy, ValueEnum, Debug)]
+enum WrappingAlgChoice {
+    /// RSAES-PKCS1-v1_5
+    #[value(name = "RSA1_5")]
+    Rsa15,
+    /// RSAES-OAEP with SHA-1
+    #[value(name = "RSA-OAEP")]
+    RsaOaep,
+    /// RSAES-OAEP with SHA-256
+    #[value(name = "RSA-OAEP-256")]
+    RsaOaep256,
+}
+
+impl From<WrappingAlgChoice> for RsaWrappingAlgorithm {
+    fn from(choice: WrappingAlgChoice) -> Self {
+        match choice {
+            WrappingAlgChoice::Rsa15 => RsaWrappingAlgorithm::Rsa15,
+            WrappingAlgChoice::RsaOaep => RsaWrappingAlgorithm::RsaOaep,
+            WrappingAlgChoice::RsaOaep256 => RsaWrappingAlgorithm::RsaOaep256,
+        }
+    }
+}
+
+#[derive(Clone, Subcommand, Debug)]
+enum Command {
+    /// Print the capabilities of the keybroker server
+    ServerInfo,
+
+    /// Get a key from the keybroker server, and optionally use it to decrypt a file
+    GetKey {
+        /// The key name to use
+        key_name: String,
+
+        /// Decrypt this file (prepared with encrypt-file) with the retrieved key, used as an AES-256-GCM key
+        #[arg(long, requires = "out")]
+        decrypt_file: Option<PathBuf>,
+
+        /// Where to write the decrypted file
+        #[arg(long, requires = "decrypt_file")]
+        out: Option<PathBuf>,
+
+        /// Get the key this many times, as a capacity check of the keybroker server, and report
+        /// the latency of each phase. The evidence must be mock
+        #[arg(
+            long,
+            value_parser = clap::value_parser!(u32).range(1..),
+            conflicts_with = "decrypt_file"
+        )]
+        stress: Option<u32>,
+
+        /// How many of the --stress key requests are in flight at once
+        #[arg(
+            long,
+            value_parser = clap::value_parser!(u32).range(1..),
+            default_value_t = 1,
+            requires = "stress"
+        )]
+        concurrency: u32,
+
+        /// Generate a single wrapping key-pair per client for the --stress key requests, rather
+        /// than one per request, so that only the costs of the keybroker server are measured
+        #[arg(long, requires = "stress", default_value_t = false)]
+        reuse_wrapping_key: bool,
+    },
+
+    /// Request the challenge for a key, and save the session to a file, so that the evidence can be
+    /// produced elsewhere and submitted with import-session
+    ExportSession {
+        /// The key name to use
+        key_name: String,
+
+        /// Where to write the session
+        #[arg(long)]
+        session: PathBuf,
+
+        /// File holding the passphrase protecting the private wrapping key in the session file
+        #[arg(long)]
+        passphrase_file: PathBuf,
+    },
+
+    /// Submit the evidence produced for a session saved with export-session, and get the key
+    ImportSession {
+        /// The session file written by export-session
+        #[arg(long)]
+        session: PathBuf,
+
+        /// File holding the passphrase the session was exported with
+        #[arg(long)]
+        passphrase_file: PathBuf,
+
+        /// File holding the raw evidence, generated for the challenge of the session
+        #[arg(long)]
+        evidence: PathBuf,
+
+        /// The media type of the evidence, the CCA attestation token by default
+        #[arg(long, value_name = "MEDIA_TYPE")]
+        media_type: Option<String>,
+    },
+
+    /// Get a key once, and serve it to the local processes on a Unix socket until interrupted
+    #[cfg(target_os = "linux")]
+    Agent {
+        /// The Unix socket to serve the key on
+        #[arg(long)]
+        listen: PathBuf,
+
+        /// The key name to use
+        #[arg(long)]
+        key: String,
+
+        /// A user ID whose processes may get the key, in addition to the user running the agent.
+        /// Can be repeated
+        #[arg(long = "allow-uid")]
+        allow_uids: Vec<u32>,
+
+        /// A group ID whose processes may get the key. Can be repeated
+        #[arg(long = "allow-gid")]
+        allow_gids: Vec<u32>,
+
+        /// Get the key again at this interval, in seconds. The key is also refreshed on SIGHUP
+        #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+        refresh_secs: Option<u64>,
+    },
+
+    /// Encrypt a file with an AES-256-GCM key, so that it can later be decrypted with get-key --decrypt-file
+    EncryptFile {
+        /// File holding the 32-byte key, which must be the secret held by the keybroker server
+        #[arg(long)]
+        key: PathBuf,
+
+        /// The file to encrypt
+        #[arg(long = "in")]
+        input: PathBuf,
+
+        /// Where to write the encrypted file
+        #[arg(long)]
+        out: PathBuf,
+    },
+}
+
+/// Parse a header given on the command line, as 'Name: value'.
+fn parse_header(header: &str) -> Result<(String, String), String> {
+    match header.split_once(':') {
+        Some((name, value)) if !name.trim().is_empty() => {
+            Ok((name.trim().to_string(), value.trim().to_string()))
+        }
+        _ => Err(format!(
+            "'{header}' is not a header, expecting 'Name: value'"
+        )),
+    }
+}
+
+/// Parse a public key pin given on the command line, as 64 hex digits.
+fn parse_spki_sha256(pin: &str) -> Result<[u8; 32], String> {
+    hex::decode(pin)
+        .ok()
+        .and_then(|digest| digest.try_into().ok())
+        .ok_or_else(|| format!("'{pin}' is not a SHA-256 digest, expecting 64 hex digits"))
+}
+
+/// Print the capabilities of the keybroker server in a human-readable form.
+fn print_server_info(info: &ServerInfo) {
+    println!("Keybroker server version {}", info.version);
+    println!("Accepted evidence media types:");
+    for media_type in &info.evidence_media_types {
+        println!("  - {media_type}");
+    }
+    println!("Supported wrapping algorithms:");
+    for alg in &info.wrapping_algorithms {
+        println!("  - {alg}");
+    }
+    println!(
+        "Challenges: {}",
+        if info.mock_challenge {
+            "static CCA example token nonce (mock mode)"
+        } else {
+            "random"
+        }
+    );
+    match &info.verifier.url {
+        Some(url) => println!("Verifier: {} ({url})", info.verifier.mode),
+        None => println!("Verifier: {}", info.verifier.mode),
+    }
+}
+
+/// Get the key from the keybroker server, along with the attestation result if requested.
+fn get_key<EP: EvidenceProvider>(
+    client: &KeyBrokerClient,
+    key_name: &str,
+    evidence_provider: &EP,
+    with_attestation_result: bool,
+) -> KeybrokerResult<RetrievedKey> {
+    if with_attestation_result {
+        client.get_key_with_attestation_result(key_name, evidence_provider)
+    } else {
+        client
+            .get_key(key_name, evidence_provider)
+            .map(|key| RetrievedKey {
+                key,
+                attestation_result: None,
+            })
+    }
+}
+
+/// The evidence provider chosen on the command line.
+enum Evidence {
+    Source(EvidenceSource),
+    File(FileEvidence),
+}
+
+impl EvidenceProvider for Evidence {
+    fn get_evidence(&self, challenge: &str) -> KeybrokerResult<Vec<u8>> {
+        match self {
+            Evidence::Source(source) => source.get_evidence(challenge),
+            Evidence::File(file) => file.get_evidence(challenge),
+        }
+    }
+
+    fn media_type(&self) -> String {
+        match self {
+            Evidence::Source(source) => source.media_type(),
+            Evidence::File(file) => file.media_type(),
+        }
+    }
+
+    fn name(&self) -> String {
+        match self {
+            Evidence::Source(source) => source.name(),
+            Evidence::File(file) => file.name(),
+        }
+    }
+
+    fn detach(&self) -> Option<Box<dyn EvidenceProvider + Send>> {
+        match self {
+            Evidence::Source(source) => source.detach(),
+            Evidence::File(file) => file.detach(),
+        }
+    }
+}
+
+/// Choose where the evidence comes from: the evidence file if there is one, the CCA example token
+/// in mock mode, or the chosen source otherwise, detecting what is available on the platform in
+/// auto mode.
+fn evidence_provider(
+    evidence_file: Option<&Path>,
+    evidence_type: Option<&str>,
+    mock_evidence: bool,
+    choice: EvidenceChoice,
+) -> Evidence {
+    if let Some(path) = evidence_file {
+        return Evidence::File(FileEvidence::new(
+            path,
+            evidence_type.unwrap_or(EVIDENCE_MEDIA_TYPE),
+        ));
+    }
+    if mock_evidence {
+        return Evidence::Source(EvidenceSource::Mock);
+    }
+
+    Evidence::Source(match choice {
+        EvidenceChoice::Tsm => EvidenceSource::Tsm,
+        EvidenceChoice::Rsi => EvidenceSource::Rsi,
+        EvidenceChoice::Mock => EvidenceSource::Mock,
+        EvidenceChoice::Auto => EvidenceSource::detect(),
+    })
+}
+
+/// Create the client for the keybroker server, as configured on the command line, but without
+/// the observers.
+fn configure_client(args: &Args) -> Result<KeyBrokerClient, String> {
+    let mut builder = KeyBrokerClient::builder().endpoint(&args.endpoint);
+    if let Some(path) = &args.ca_cert {
+        builder = builder.root_certificate(path);
+    }
+    if let Some(pin) = args.pinned_spki_sha256 {
+        builder = builder.pinned_spki_sha256(pin);
+    }
+    if args.insecure {
+        builder = builder.danger_accept_invalid_certs();
+    }
+    let mut client = builder
+        .build()
+        .map_err(|error| error.to_string())?
+        .challenge_restarts(args.challenge_restarts)
+        .rsa_wrapping_algorithm(args.wrapping_alg.into())
+        .rsa_wrapping_key_bits(args.wrapping_bits)
+        .map_err(|error| error.to_string())?;
+    if let Some(path) = &args.wrapping_key {
+        client = client
+            .wrapping_key_from_pem(path)
+            .map_err(|error| error.to_string())?;
+    }
+    if let Some(rem) = args.bind_wrapping_key {
+        client = client.bind_wrapping_key(rem);
+    }
+    if let Some(secs) = args.evidence_timeout {
+        client = client.evidence_timeout(std::time::Duration::from_secs(secs));
+    }
+    if let Some(correlation_id) = &args.correlation_id {
+        client = client.correlation_id(correlation_id);
+    }
+    for (name, value) in &args.headers {
+        client = client
+            .default_header(name, value)
+            .map_err(|error| error.to_string())?;
+    }
+    Ok(client)
+}
+
+/// Run the --stress key requests, returning the exit code: 0 if they all succeeded, and 2 if any
+/// failed or they could not run.
+fn run_stress(args: &Args, key_name: &str, flows: u32, concurrency: u32, reuse: bool) -> i32 {
+    #[cfg(unix)]
+    let events = args.events_file.is_some() || args.events_fd.is_some();
+    #[cfg(not(unix))]
+    let events = args.events_file.is_some();
+
+    // The server must not be measured along with the platform evidence.
+    if !args.mock_evidence && !matches!(args.evidence_source, EvidenceChoice::Mock) {
+        log::error!("--stress only runs with the mock evidence, see --mock-evidence");
+        return 2;
+    }
+    if events || args.show_evidence.is_some() {
+        log::error!("--stress can't report the progress events, nor show the evidence");
+        return 2;
+    }
+
+    match stress::run(
+        || configure_client(args),
+        key_name,
+        flows,
+        concurrency,
+        reuse,
+    ) {
+        Ok(true) => 0,
+        Ok(false) => 2,
+        Err(error) => {
+            log::error!("The stress test failed: {error}");
+            2
+        }
+    }
+}
+
+/// Read a passphrase from a file, ignoring the trailing end of line.
+fn read_passphrase(path: &Path) -> std::io::Result<Zeroizing<Vec<u8>>> {
+    let mut passphrase = Zeroizing::new(std::fs::read(path)?);
+    while passphrase
+        .last()
+        .is_some_and(|c| *c == b'\n' || *c == b'\r')
+    {
+        passphrase.pop();
+    }
+    Ok(passphrase)
+}
+
+/// Request the challenge for a key, and save the session to a file.
+fn export_session(
+    client: &KeyBrokerClient,
+    key_name: &str,
+    session: &Path,
+    passphrase_file: &Path,
+    with_attestation_result: bool,
+) -> KeybrokerResult<PendingKeyRequest> {
+    let passphrase = read_passphrase(passphrase_file).map_err(|error| {
+        KeybrokerError::RuntimeError(RuntimeErrorKind::Session(format!(
+            "{}: {error}",
+            passphrase_file.display()
+        )))
+    })?;
+    let request = client.start_key_request(key_name, with_attestation_result)?;
+    request.export_session(session, &passphrase)?;
+    Ok(request)
+}
+
+/// Submit the evidence produced for a session, and get the key.
+fn import_session(
+    client: &KeyBrokerClient,
+    session: &Path,
+    passphrase_file: &Path,
+    evidence: &Path,
+    media_type: Option<&str>,
+) -> KeybrokerResult<RetrievedKey> {
+    let read_error = |path: &Path, error: std::io::Error| {
+        KeybrokerError::RuntimeError(RuntimeErrorKind::Session(format!(
+            "{}: {error}",
+            path.display()
+        )))
+    };
+
+    let passphrase =
+        read_passphrase(passphrase_file).map_err(|error| read_error(passphrase_file, error))?;
+    let request = PendingKeyRequest::import_session(session, &passphrase)?;
+    let evidence = std::fs::read(evidence).map_err(|error| read_error(evidence, error))?;
+
+    log::info!(
+        "Submitting the evidence for key '{}' from session {}",
+        request.key_name(),
+        session.display()
+    );
+    client.complete_key_request(
+        &request,
+        media_type.unwrap_or(EVIDENCE_MEDIA_TYPE),
+        &evidence,
+    )
+}
+
+/// Report a failed key request, returning the exit code: 1 for a genuine attestation failure, and 2
+/// for any other kind of error (crypto, network connectivity, ...).
+fn report_failure(error: KeybrokerError) -> i32 {
+    if let KeybrokerError::AttestationFailure(reason, details, instance) = error {
+        log::info!("Attestation failure :-( ! {reason}: {details}");
+        if let Some(instance) = instance {
+            log::info!("The keybroker server logged the failure as {instance}.");
+        }
+        1
+    } else {
+        log::error!("The key request failed with: {error:?}");
+        2
+    }
+}
+
+/// Get the key, and serve it on a Unix socket until the agent is told to exit.
+#[cfg(target_os = "linux")]
+fn run_agent<EP: EvidenceProvider>(
+    signals: nix::sys::signal::SigSet,
+    client: &KeyBrokerClient,
+    key_name: &str,
+    evidence_provider: &EP,
+    socket: &Path,
+    policy: agent::PeerPolicy,
+    refresh: Option<std::time::Duration>,
+) -> i32 {
+    let key = match client.get_key(key_name, evidence_provider) {
+        Ok(key) => key,
+        Err(error) => return report_failure(error),
+    };
+    log::info!("Attestation success :-) ! Got key '{key_name}'");
+
+    let retrieve = || match client.get_key(key_name, evidence_provider) {
+        Ok(key) => Some(key),
+        Err(error) => {
+            report_failure(error);
+            None
+        }
+    };
+    match agent::run(signals, socket, policy, key, refresh, retrieve) {
+        Ok(()) => 0,
+        Err(error) => {
+            log::error!("The agent failed: {error}");
+            2
+        }
+    }
+}
+
+/// Print how long the phases of a key request took, the skipped ones being shown as '-'.
+fn print_timings(timings: &Timings) {
+    let format_duration =
+        |duration: std::time::Duration| format!("{:.1}ms", duration.as_secs_f64() * 1000.0);
+
+    println!("  {:<26}{:>10}", "phase", "time");
+    for (phase, duration) in timings.phases() {
+        println!(
+            "  {phase:<26}{:>10}",
+            duration.map_or("-".to_string(), format_duration)
+        );
+    }
+    println!("  {:<26}{:>10}", "total", format_duration(timings.total()));
+}
+
+/// Log a summary of the claims of the attestation result returned by the keybroker server.
+fn log_attestation_result(attestation_result: &str) {
+    let claims = match attestation_result_claims(attestation_result) {
+        Ok(claims) => claims,
+        Err(error) => {
+            log::warn!("Failed to decode the attestation result: {error}");
+            return;
+        }
+    };
+
+    // The client can't verify the signature, the keybroker server is the one relying on it.
+    let mut summary = "Attestation result (signature not checked by the client):\n".to_string();
+    summary.push_str(&format!("    Profile: {}\n", claims["eat_profile"]));
+    summary.push_str(&format!("    Issued at: {}\n", claims["iat"]));
+    summary.push_str(&format!("    Verifier: {}\n", claims["ear.verifier-id"]));
+    if let Some(submods) = claims["submods"].as_object() {
+        for (submod, appraisal) in submods {
+            summary.push_str(&format!(
+                "    Status for submod {submod}: {}\n",
+                appraisal["ear.status"]
+            ));
+        }
+    }
+
+    log::info!("{summary}");
 }
 
 fn main() {
@@ -39,34 +712,420 @@ fn main() {
         .init()
         .unwrap();
 
-    let client = KeyBrokerClient::new(&args.endpoint);
+    // The agent handles its signals in a thread of its own, they have to be blocked before the
+    // client starts its threads.
+    #[cfg(target_os = "linux")]
+    let agent_signals = match args.command {
+        Some(Command::Agent { .. }) => match agent::block_signals() {
+            Ok(signals) => Some(signals),
+            Err(error) => {
+                log::error!("{error}");
+                process::exit(2)
+            }
+        },
+        _ => None,
+    };
 
-    let attestation_result = if args.mock_evidence {
-        client.get_key(&args.key_name, &CcaExampleToken {})
-    } else {
-        client.get_key(&args.key_name, &TsmAttestationReport {})
+    let mut client = match configure_client(&args) {
+        Ok(client) => client,
+        Err(error) => {
+            log::error!("{error}");
+            process::exit(2)
+        }
     };
+    if let Some(secs) = args.wait_for_server {
+        // Encrypting a file does not involve the server.
+        if !matches!(args.command, Some(Command::EncryptFile { .. })) {
+            if let Err(error) = client.wait_for_server(std::time::Duration::from_secs(secs)) {
+                log::error!("{error}");
+                process::exit(2)
+            }
+        }
+    }
+    if let Some(Command::GetKey {
+        key_name,
+        stress: Some(flows),
+        concurrency,
+        reuse_wrapping_key,
+        ..
+    }) = &args.command
+    {
+        process::exit(run_stress(
+            &args,
+            key_name,
+            *flows,
+            *concurrency,
+            *reuse_wrapping_key,
+        ))
+    }
 
-    // If the attestation was successful, print the key we got from the keybroker and exit with code 0.
+    if let Some(path) = args.show_evidence {
+        client = client.with_evidence_observer(EvidenceDump::new(path, args.force));
+    }
+
+    let events = args.events_file.as_ref().map(|path| {
+        EventWriter::create(path).map_err(|error| {
+            format!(
+                "Failed to create the events file {}: {error}",
+                path.display()
+            )
+        })
+    });
+    #[cfg(unix)]
+    let events = match args.events_fd {
+        Some(fd) => Some(EventWriter::from_fd(fd).map_err(|error| {
+            format!("Failed to use file descriptor {fd} for the events: {error}")
+        })),
+        None => events,
+    };
+    match events {
+        Some(Ok(events)) => client = client.with_progress_observer(events),
+        Some(Err(error)) => {
+            log::error!("{error}");
+            process::exit(2)
+        }
+        None => {}
+    }
+
+    // The attestation result is only requested when it will be shown.
+    let with_attestation_result = args.verbosity > 0 && !args.quiet;
+
+    let (attestation_result, decrypt_file) = match args.command {
+        Some(Command::ServerInfo) => {
+            let code = match client.server_info() {
+                Ok(info) => {
+                    print_server_info(&info);
+                    0
+                }
+                Err(error) => {
+                    log::error!("The server information request failed with: {error:?}");
+                    2
+                }
+            };
+            process::exit(code)
+        }
+
+        Some(Command::EncryptFile { key, input, out }) => {
+            let code = match file_crypto::read_key(&key)
+                .and_then(|key| file_crypto::encrypt_file(&key, &input, &out))
+            {
+                Ok(()) => {
+                    log::info!("{} encrypted to {}", input.display(), out.display());
+                    0
+                }
+                Err(error) => {
+                    log::error!("The encryption failed: {error}");
+                    2
+                }
+            };
+            process::exit(code)
+        }
+
+        #[cfg(target_os = "linux")]
+        Some(Command::Agent {
+            listen,
+            key,
+            allow_uids,
+            allow_gids,
+            refresh_secs,
+        }) => {
+            // The user running the agent can always get the key.
+            let mut uids = allow_uids;
+            uids.push(nix::unistd::geteuid().as_raw());
+            let policy = agent::PeerPolicy {
+                uids,
+                gids: allow_gids,
+            };
+
+            process::exit(run_agent(
+                agent_signals.expect("The signals are blocked for the agent."),
+                &client,
+                &key,
+                &evidence_provider(
+                    args.evidence_file.as_deref(),
+                    args.evidence_type.as_deref(),
+                    args.mock_evidence,
+                    args.evidence_source,
+                ),
+                &listen,
+                policy,
+                refresh_secs.map(std::time::Duration::from_secs),
+            ))
+        }
+
+        Some(Command::ExportSession {
+            key_name,
+            session,
+            passphrase_file,
+        }) => {
+            let code = match export_session(
+                &client,
+                &key_name,
+                &session,
+                &passphrase_file,
+                with_attestation_result,
+            ) {
+                Ok(request) => {
+                    // The challenge is what the evidence has to be produced for.
+                    println!("{}", request.challenge());
+                    0
+                }
+                Err(error) => {
+                    log::error!("The session export failed with: {error:?}");
+                    2
+                }
+            };
+            process::exit(code)
+        }
+
+        Some(Command::ImportSession {
+            session,
+            passphrase_file,
+            evidence,
+            media_type,
+        }) => (
+            import_session(
+                &client,
+                &session,
+                &passphrase_file,
+                &evidence,
+                media_type.as_deref(),
+            ),
+            None,
+        ),
+
+        Some(Command::GetKey {
+            key_name,
+            decrypt_file,
+            out,
+            ..
+        }) => (
+            get_key(
+                &client,
+                &key_name,
+                &evidence_provider(
+                    args.evidence_file.as_deref(),
+                    args.evidence_type.as_deref(),
+                    args.mock_evidence,
+                    args.evidence_source,
+                ),
+                with_attestation_result,
+            ),
+            decrypt_file.zip(out),
+        ),
+
+        // Can't fail, as the key name is required when there is no subcommand.
+        None => (
+            get_key(
+                &client,
+                &args.key_name.unwrap(),
+                &evidence_provider(
+                    args.evidence_file.as_deref(),
+                    args.evidence_type.as_deref(),
+                    args.mock_evidence,
+                    args.evidence_source,
+                ),
+                with_attestation_result,
+            ),
+            None,
+        ),
+    };
+
+    if args.timings {
+        print_timings(&client.timings());
+    }
+
+    // The correlation ID finds the key request in the keybroker server logs, for bug reports.
+    if let Some(correlation_id) = client.last_correlation_id() {
+        if !args.quiet {
+            eprintln!("Correlation ID: {correlation_id}");
+        }
+    }
+
+    // If the attestation was successful, print the key we got from the keybroker (or use it to decrypt
+    // the file) and exit with code 0.
     // If the attestation failed for genuine attestation related error, print the reason and exit with code 1.
     // For any other kind of error (crypto, network connectivity, ...), print an hopefully useful message to diagnose the issue and exit with code 2.
     let code = match attestation_result {
-        Ok(key) => {
-            let plainstring_key = String::from_utf8(key).unwrap();
-            log::info!("Attestation success :-) ! The key returned from the keybroker is '{plainstring_key}'");
-            0
-        }
+        Ok(retrieved_key) => {
+            if let Some(attestation_result) = &retrieved_key.attestation_result {
+                log_attestation_result(attestation_result);
+            }
 
-        Err(error) => {
-            if let KeybrokerError::AttestationFailure(reason, details) = error {
-                log::info!("Attestation failure :-( ! {reason}: {details}");
-                1
-            } else {
-                log::error!("The key request failed with: {error:?}");
-                2
+            let key = retrieved_key.key.expose_secret();
+            match decrypt_file {
+                Some((input, out)) => match file_crypto::decrypt_file(key, &input, &out) {
+                    Ok(()) => {
+                        log::info!(
+                            "Attestation success :-) ! {} decrypted to {} with the key returned from the keybroker",
+                            input.display(),
+                            out.display()
+                        );
+                        0
+                    }
+                    Err(error) => {
+                        log::error!("Attestation success, but the decryption failed: {error}");
+                        2
+                    }
+                },
+                None => {
+                    // A structured secret document is pretty-printed, rather than shown on a single line.
+                    let document = serde_json::from_slice::<serde_json::Value>(key)
+                        .ok()
+                        .filter(|document| document.is_object())
+                        .and_then(|document| serde_json::to_string_pretty(&document).ok());
+                    match (document, std::str::from_utf8(key)) {
+                        (Some(document), _) => log::info!("Attestation success :-) ! The key returned from the keybroker is the document:\n{}", Zeroizing::new(document).as_str()),
+                        (None, Ok(plainstring_key)) => log::info!("Attestation success :-) ! The key returned from the keybroker is '{plainstring_key}'"),
+                        (None, Err(_)) => log::info!("Attestation success :-) ! The key returned from the keybroker is {key:02x?}"),
+                    }
+                    0
+                }
             }
         }
+
+        Err(error) => report_failure(error),
     };
 
     process::exit(code)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn provider(args: &[&str]) -> Evidence {
+        let args = Args::try_parse_from(args).unwrap();
+        evidence_provider(
+            args.evidence_file.as_deref(),
+            args.evidence_type.as_deref(),
+            args.mock_evidence,
+            args.evidence_source,
+        )
+    }
+
+    #[test]
+    fn evidence_file() {
+        let evidence = provider(&[
+            "keybroker-app",
+            "--evidence-file",
+            "token.cbor",
+            "--evidence-type",
+            "application/eat+cwt",
+            "skywalker",
+        ]);
+        assert!(matches!(evidence, Evidence::File(_)));
+        assert_eq!(evidence.media_type(), "application/eat+cwt");
+        assert_eq!(evidence.name(), "file token.cbor");
+
+        // The CCA attestation token by default, also after a subcommand.
+        let evidence = provider(&[
+            "keybroker-app",
+            "get-key",
+            "skywalker",
+            "--evidence-file",
+            "token.cbor",
+        ]);
+        assert!(matches!(evidence, Evidence::File(_)));
+        assert_eq!(evidence.media_type(), EVIDENCE_MEDIA_TYPE);
+
+        assert!(matches!(
+            provider(&["keybroker-app", "-m", "skywalker"]),
+            Evidence::Source(EvidenceSource::Mock)
+        ));
+
+        // The evidence comes from one place only, and the media type is only for the file.
+        for conflicting in [
+            &[
+                "keybroker-app",
+                "-m",
+                "--evidence-file",
+                "token.cbor",
+                "skywalker",
+            ][..],
+            &[
+                "keybroker-app",
+                "--evidence-source",
+                "rsi",
+                "--evidence-file",
+                "token.cbor",
+                "skywalker",
+            ],
+            &[
+                "keybroker-app",
+                "--evidence-type",
+                "application/eat+cwt",
+                "skywalker",
+            ],
+        ] {
+            assert!(
+                Args::try_parse_from(conflicting).is_err(),
+                "{conflicting:?} was accepted"
+            );
+        }
+    }
+
+    #[test]
+    fn wrapping_options() {
+        let args = Args::try_parse_from([
+            "keybroker-app",
+            "--wrapping-alg",
+            "RSA-OAEP",
+            "--wrapping-bits",
+            "3072",
+            "skywalker",
+        ])
+        .unwrap();
+        assert!(matches!(args.wrapping_alg, WrappingAlgChoice::RsaOaep));
+        assert_eq!(args.wrapping_bits, 3072);
+        assert!(configure_client(&args).is_ok());
+
+        let args = Args::try_parse_from(["keybroker-app", "skywalker"]).unwrap();
+        assert!(matches!(args.wrapping_alg, WrappingAlgChoice::Rsa15));
+        assert_eq!(args.wrapping_bits, 2048);
+
+        for invalid in [
+            &["keybroker-app", "--wrapping-bits", "1024", "skywalker"][..],
+            &[
+                "keybroker-app",
+                "--wrapping-alg",
+                "RSA-OAEP-384",
+                "skywalker",
+            ],
+            &[
+                "keybroker-app",
+                "--wrapping-key",
+                "key.pem",
+                "--wrapping-bits",
+                "4096",
+                "skywalker",
+            ],
+        ] {
+            assert!(
+                Args::try_parse_from(invalid).is_err(),
+                "{invalid:?} was accepted"
+            );
+        }
+    }
+
+    #[test]
+    fn spki_pins() {
+        let pin = "a665ba5185eefbcdb2c515a4e08ec40d511be4f6422c767e37a70bdd2dfa58d3";
+        let digest = parse_spki_sha256(pin).unwrap();
+        assert_eq!(digest[..2], [0xa6, 0x65]);
+        assert_eq!(parse_spki_sha256(&pin.to_uppercase()), Ok(digest));
+
+        for invalid in [
+            &pin[..62],
+            &pin[1..],
+            "",
+            &format!("{pin}00"),
+            &pin.replace('a', "g"),
+        ] {
+            assert!(
+                parse_spki_sha256(invalid).is_err(),
+                "{invalid} was accepted"
+            );
+        }
+    }
+}
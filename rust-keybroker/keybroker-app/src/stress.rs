@@ -0,0 +1,303 @@
+// Copyright 2024 Contributors to the Veraison project.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Capacity checks of a keybroker server, running many complete key requests (flows), several of
+//! them at once, and reporting the latency of each of their phases.
+//!
+//! The flows always attest with the CCA example token, so that only the keybroker server and its
+//! verifier are measured. Each worker thread has a client of its own, whose progress events time
+//! the phases of its flows.
+use keybroker_client::{CcaExampleToken, KeyBrokerClient, ProgressObserver};
+use keybroker_common::ProgressEvent;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Barrier;
+use std::time::{Duration, Instant};
+
+/// The phases of a flow, as told apart by the progress events.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Phase {
+    /// Generating the wrapping key-pair, if not reused.
+    WrappingKey,
+    /// Requesting the challenge.
+    Challenge,
+    /// Generating the evidence.
+    Evidence,
+    /// Submitting the evidence, up to the response of the server.
+    Submission,
+    /// Reading and unwrapping the key.
+    Unwrap,
+    /// The whole flow, for the successful ones only.
+    Total,
+}
+
+impl Phase {
+    const ALL: [Phase; 6] = [
+        Phase::WrappingKey,
+        Phase::Challenge,
+        Phase::Evidence,
+        Phase::Submission,
+        Phase::Unwrap,
+        Phase::Total,
+    ];
+
+    /// The phase that ends with an event, if any.
+    fn ended_by(event: &ProgressEvent) -> Option<Phase> {
+        match event {
+            ProgressEvent::ChallengeRequested { .. } => Some(Phase::WrappingKey),
+            ProgressEvent::ChallengeReceived { .. } => Some(Phase::Challenge),
+            ProgressEvent::EvidenceGenerated { .. } => Some(Phase::Evidence),
+            ProgressEvent::EvidenceSubmitted { .. } => Some(Phase::Submission),
+            ProgressEvent::KeyReceived { .. } => Some(Phase::Unwrap),
+            ProgressEvent::Failed { .. } => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Phase::WrappingKey => "wrapping-key",
+            Phase::Challenge => "challenge",
+            Phase::Evidence => "evidence",
+            Phase::Submission => "submission",
+            Phase::Unwrap => "unwrap",
+            Phase::Total => "total",
+        }
+    }
+}
+
+/// The outcome of some flows.
+#[derive(Debug, Default)]
+struct Tally {
+    succeeded: u32,
+    /// The number of failed flows, by error.
+    failures: BTreeMap<String, u32>,
+    /// The latency of each phase completed by the flows.
+    latencies: BTreeMap<Phase, Vec<Duration>>,
+}
+
+impl Tally {
+    fn merge(&mut self, other: Tally) {
+        self.succeeded += other.succeeded;
+        for (error, count) in other.failures {
+            *self.failures.entry(error).or_default() += count;
+        }
+        for (phase, latencies) in other.latencies {
+            self.latencies.entry(phase).or_default().extend(latencies);
+        }
+    }
+}
+
+/// A ProgressObserver timing the phases of the flows of one client.
+#[derive(Debug, Clone)]
+struct PhaseTimer {
+    state: Rc<RefCell<(Instant, Tally)>>,
+}
+
+impl PhaseTimer {
+    fn new() -> PhaseTimer {
+        PhaseTimer {
+            state: Rc::new(RefCell::new((Instant::now(), Tally::default()))),
+        }
+    }
+
+    /// Start timing a new flow.
+    fn start(&self) {
+        self.state.borrow_mut().0 = Instant::now();
+    }
+
+    fn record(&self, phase: Phase, latency: Duration) {
+        let mut state = self.state.borrow_mut();
+        state.1.latencies.entry(phase).or_default().push(latency);
+    }
+
+    fn into_tally(self) -> Tally {
+        std::mem::take(&mut self.state.borrow_mut().1)
+    }
+}
+
+impl ProgressObserver for PhaseTimer {
+    fn progress(&self, event: &ProgressEvent) {
+        if let Some(phase) = Phase::ended_by(event) {
+            let now = Instant::now();
+            let since = std::mem::replace(&mut self.state.borrow_mut().0, now);
+            self.record(phase, now - since);
+        }
+    }
+}
+
+/// The latency below which are `percent`% of the sorted latencies (nearest rank).
+fn percentile(sorted: &[Duration], percent: usize) -> Duration {
+    let rank = (sorted.len() * percent).div_ceil(100).max(1);
+    sorted[rank - 1]
+}
+
+fn format_latency(latency: Duration) -> String {
+    format!("{:.1}ms", latency.as_secs_f64() * 1000.0)
+}
+
+/// Run the flows of one worker, taking them from the shared count of remaining flows.
+fn run_worker(client: KeyBrokerClient, key_name: &str, remaining: &AtomicU32) -> Tally {
+    let timer = PhaseTimer::new();
+    let client = client.with_progress_observer(timer.clone());
+    let mut succeeded = 0;
+    let mut failures = BTreeMap::<String, u32>::new();
+
+    while remaining
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+        .is_ok()
+    {
+        timer.start();
+        let started = Instant::now();
+        match client.get_key(key_name, &CcaExampleToken {}) {
+            Ok(_) => {
+                timer.record(Phase::Total, started.elapsed());
+                succeeded += 1;
+            }
+            Err(error) => *failures.entry(error.to_string()).or_default() += 1,
+        }
+    }
+
+    let mut tally = timer.into_tally();
+    tally.succeeded = succeeded;
+    tally.failures = failures;
+    tally
+}
+
+/// Run `flows` key requests for `key_name`, `concurrency` of them at once, with the clients built
+/// by `make_client`, and print the outcome. Returns whether all the flows succeeded.
+pub fn run(
+    make_client: impl Fn() -> Result<KeyBrokerClient, String> + Sync,
+    key_name: &str,
+    flows: u32,
+    concurrency: u32,
+    reuse_wrapping_key: bool,
+) -> Result<bool, String> {
+    let concurrency = concurrency.min(flows);
+    let remaining = AtomicU32::new(flows);
+    // The clients are built, and their wrapping key-pairs generated, before any flow starts.
+    let ready = Barrier::new(1 + concurrency as usize);
+
+    let mut tally = Tally::default();
+    let mut started = Instant::now();
+    std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..concurrency)
+            .map(|_| {
+                scope.spawn(|| {
+                    let client = make_client().map(|client| {
+                        if reuse_wrapping_key {
+                            client.reuse_wrapping_key()
+                        } else {
+                            client
+                        }
+                    });
+                    ready.wait();
+                    client.map(|client| run_worker(client, key_name, &remaining))
+                })
+            })
+            .collect();
+        ready.wait();
+        started = Instant::now();
+        workers
+            .into_iter()
+            .try_for_each(|worker| -> Result<(), String> {
+                tally.merge(worker.join().expect("A stress worker panicked.")?);
+                Ok(())
+            })
+    })?;
+    let elapsed = started.elapsed();
+
+    println!(
+        "{flows} flows for key '{key_name}', {concurrency} at once, in {:.2}s ({:.1} flows/s)",
+        elapsed.as_secs_f64(),
+        f64::from(flows) / elapsed.as_secs_f64()
+    );
+    let failed: u32 = tally.failures.values().sum();
+    println!("  {} succeeded, {failed} failed", tally.succeeded);
+    for (error, count) in &tally.failures {
+        println!("    {count} x {error}");
+    }
+
+    println!(
+        "  {:<14}{:>10}{:>10}{:>10}{:>10}",
+        "phase", "p50", "p90", "p99", "max"
+    );
+    for phase in Phase::ALL {
+        let Some(latencies) = tally.latencies.get_mut(&phase) else {
+            continue;
+        };
+        latencies.sort();
+        println!(
+            "  {:<14}{:>10}{:>10}{:>10}{:>10}",
+            phase.name(),
+            format_latency(percentile(latencies, 50)),
+            format_latency(percentile(latencies, 90)),
+            format_latency(percentile(latencies, 99)),
+            format_latency(latencies[latencies.len() - 1])
+        );
+    }
+
+    Ok(failed == 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles() {
+        let latencies: Vec<_> = (1..=200).map(Duration::from_millis).collect();
+        assert_eq!(percentile(&latencies, 50), Duration::from_millis(100));
+        assert_eq!(percentile(&latencies, 99), Duration::from_millis(198));
+        assert_eq!(percentile(&latencies, 100), Duration::from_millis(200));
+
+        let latencies = [Duration::from_millis(7)];
+        assert_eq!(percentile(&latencies, 50), Duration::from_millis(7));
+        assert_eq!(percentile(&latencies, 99), Duration::from_millis(7));
+    }
+
+    #[test]
+    fn timed_phases() {
+        let timer = PhaseTimer::new();
+        timer.start();
+        for event in [
+            ProgressEvent::ChallengeRequested {
+                key_name: "skywalker".to_string(),
+                url: "http://127.0.0.1:8088/keys/v1/key/skywalker".to_string(),
+            },
+            ProgressEvent::ChallengeReceived {
+                nonce_length: 64,
+                accept: vec![],
+            },
+            ProgressEvent::Failed {
+                code: None,
+                detail: "connection refused".to_string(),
+                timings: None,
+            },
+        ] {
+            timer.progress(&event);
+        }
+
+        let tally = timer.clone().into_tally();
+        assert_eq!(
+            tally.latencies.keys().copied().collect::<Vec<_>>(),
+            [Phase::WrappingKey, Phase::Challenge]
+        );
+        assert!(tally
+            .latencies
+            .values()
+            .all(|latencies| latencies.len() == 1));
+
+        let mut total = Tally::default();
+        total.merge(tally);
+        total.merge(Tally {
+            succeeded: 2,
+            failures: BTreeMap::from([("nope".to_string(), 1)]),
+            latencies: BTreeMap::from([(Phase::Challenge, vec![Duration::ZERO])]),
+        });
+        assert_eq!(total.succeeded, 2);
+        assert_eq!(total.failures["nope"], 1);
+        assert_eq!(total.latencies[&Phase::Challenge].len(), 2);
+    }
+}
@@ -0,0 +1,80 @@
+// Copyright 2024 Contributors to the Veraison project.
+// SPDX-License-Identifier: Apache-2.0
+
+//! An evidence provider written outside of keybroker-client, getting a key with the evidence
+//! produced by an external attester program.
+//!
+//! The program is given the challenge, base64-encoded, as its last argument, and writes the
+//! evidence to its standard output:
+//!
+//! ```console
+//! $ cargo run --example command_evidence -- http://127.0.0.1:8088 skywalker \
+//!     'application/eat-collection; profile="http://arm.com/CCA-SSD/1.0.0"' my-attester --raw
+//! ```
+use keybroker_client::error::{Error as KeybrokerError, Result, RuntimeErrorKind};
+use keybroker_client::{EvidenceProvider, KeyBrokerClient};
+use std::process::{Command, ExitCode};
+
+/// The evidence written by an attester program.
+struct CommandEvidence {
+    program: String,
+    args: Vec<String>,
+    media_type: String,
+}
+
+impl EvidenceProvider for CommandEvidence {
+    fn get_evidence(&self, challenge: &str) -> Result<Vec<u8>> {
+        let failed = |detail: String| {
+            KeybrokerError::RuntimeError(RuntimeErrorKind::EvidenceGeneration(format!(
+                "{}: {detail}",
+                self.program
+            )))
+        };
+
+        let output = Command::new(&self.program)
+            .args(&self.args)
+            .arg(challenge)
+            .output()
+            .map_err(|error| failed(error.to_string()))?;
+        if !output.status.success() {
+            return Err(failed(format!(
+                "{}, {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        Ok(output.stdout)
+    }
+
+    fn media_type(&self) -> String {
+        self.media_type.clone()
+    }
+
+    fn name(&self) -> String {
+        format!("'{}' command", self.program)
+    }
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let [endpoint, key_name, media_type, program, program_args @ ..] = args.as_slice() else {
+        eprintln!("Usage: command_evidence <endpoint> <key name> <media type> <program> [args...]");
+        return ExitCode::FAILURE;
+    };
+
+    let provider = CommandEvidence {
+        program: program.clone(),
+        args: program_args.to_vec(),
+        media_type: media_type.clone(),
+    };
+    match KeyBrokerClient::new(endpoint).get_key(key_name, &provider) {
+        Ok(key) => {
+            println!("{}", String::from_utf8_lossy(key.expose_secret()));
+            ExitCode::SUCCESS
+        }
+        Err(error) => {
+            eprintln!("The key request failed: {error}");
+            ExitCode::FAILURE
+        }
+    }
+}
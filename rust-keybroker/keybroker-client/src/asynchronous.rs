@@ -0,0 +1,346 @@
+// Copyright 2024 Contributors to the Veraison project.
+// SPDX-License-Identifier: Apache-2.0
+
+//! An asynchronous client for the keybroker server, for the targets without the blocking one, such
+//! as wasm32-unknown-unknown, where the requests go through the fetch API of the browser.
+//!
+//! The protocol is the same as with the blocking client, but the wrapping key-pair is supplied by
+//! the caller through the [`WrappingKeyPair`] trait, whose operations are asynchronous so that it
+//! can be implemented with WebCrypto in a browser. It is implemented for `rsa::RsaPrivateKey`.
+//!
+//! This client only requests keys: it has no key cache, no split key requests, and does not
+//! compress the evidence. In a browser, the keybroker server must allow the cross-origin requests
+//! of the page (see its `--cors-origin` option).
+use crate::error::{Error as KeybrokerError, Result, RuntimeErrorKind};
+use crate::protocol::{
+    decrypt_content, parse_attestation_challenge, parse_error_information, parse_server_info,
+    parse_wrapped_key_data, resolve_location, unwrap_key_data, WrappedKey,
+};
+use crate::{EvidenceProvider, ProgressObserver, SecretKeyMaterial};
+use keybroker_common::{
+    base64, sanitise_correlation_id, BackgroundCheckKeyRequest, ProgressEvent, PublicWrappingKey,
+    ServerInfo,
+};
+use reqwest::StatusCode;
+use rsa::{RsaPrivateKey, RsaPublicKey};
+use std::future::Future;
+use url::Url;
+use zeroize::Zeroizing;
+
+/// The trait that must be implemented to have the keys wrapped to a key-pair, whose private part
+/// never leaves the implementation.
+pub trait WrappingKeyPair {
+    /// The public part of the key-pair, as a JWK with the wrapping algorithm to use.
+    fn public_key(&self) -> impl Future<Output = Result<PublicWrappingKey>>;
+
+    /// Decrypt a key wrapped to the public part of the key-pair with the given algorithm.
+    fn unwrap_key(
+        &self,
+        algorithm: &str,
+        wrapped_key: &[u8],
+    ) -> impl Future<Output = Result<Zeroizing<Vec<u8>>>>;
+}
+
+impl WrappingKeyPair for RsaPrivateKey {
+    async fn public_key(&self) -> Result<PublicWrappingKey> {
+        PublicWrappingKey::try_from(&RsaPublicKey::from(self)).map_err(|error| {
+            KeybrokerError::RuntimeError(RuntimeErrorKind::UnsupportedWrappingKey(
+                error.to_string(),
+            ))
+        })
+    }
+
+    async fn unwrap_key(&self, algorithm: &str, wrapped_key: &[u8]) -> Result<Zeroizing<Vec<u8>>> {
+        unwrap_key_data(self, algorithm, wrapped_key)
+    }
+}
+
+/// Read the whole body of a response from the keybroker server.
+async fn response_body(resp: reqwest::Response) -> Result<Vec<u8>> {
+    match resp.bytes().await {
+        Ok(body) => Ok(body.to_vec()),
+        Err(error) => Err(KeybrokerError::RuntimeError(
+            RuntimeErrorKind::HTTPResponse(format!("{error:?}")),
+        )),
+    }
+}
+
+/// Build the error for an unsuccessful response from the keybroker server, from the error
+/// information in its body when there is one.
+async fn error_response(resp: reqwest::Response) -> KeybrokerError {
+    let status = resp.status();
+    match response_body(resp)
+        .await
+        .and_then(|body| parse_error_information(&body))
+    {
+        Ok(error_info) => KeybrokerError::RuntimeError(RuntimeErrorKind::ServerError(
+            error_info.r#type,
+            error_info.detail,
+        )),
+        Err(_) => {
+            KeybrokerError::RuntimeError(RuntimeErrorKind::HTTPResponse(format!("{status:?}")))
+        }
+    }
+}
+
+fn connect_error(url: &str, error: reqwest::Error) -> KeybrokerError {
+    KeybrokerError::RuntimeError(RuntimeErrorKind::HTTPConnect(
+        url.to_string(),
+        format!("{error:?}"),
+    ))
+}
+
+/// The AsyncKeyBrokerClient models the communication with a keybroker server, without blocking.
+pub struct AsyncKeyBrokerClient {
+    /// The asynchronous HTTP client.
+    client: reqwest::Client,
+
+    /// The keybroker URL base address.
+    keybroker_url_base: String,
+
+    /// Told about each stage of the key requests, if set.
+    progress_observer: Option<Box<dyn ProgressObserver>>,
+
+    /// The correlation identifier sent with the key requests, if set.
+    correlation_id: Option<String>,
+}
+
+impl AsyncKeyBrokerClient {
+    /// Create a session to the keybroker server located at addr:port.
+    pub fn new(endpoint: &str) -> AsyncKeyBrokerClient {
+        AsyncKeyBrokerClient {
+            client: reqwest::Client::new(),
+            keybroker_url_base: endpoint.trim_end_matches('/').to_string(),
+            progress_observer: None,
+            correlation_id: None,
+        }
+    }
+
+    /// Set an observer, which is told about each stage of the key requests.
+    pub fn with_progress_observer(
+        mut self,
+        observer: impl ProgressObserver + 'static,
+    ) -> AsyncKeyBrokerClient {
+        self.progress_observer = Some(Box::new(observer));
+        self
+    }
+
+    /// Send `correlation_id` with the key requests, so that the keybroker server logs and lifecycle
+    /// events of the key requests can be tied to a job of the caller. Unlike the blocking client,
+    /// this client sends none by default.
+    pub fn correlation_id(mut self, correlation_id: &str) -> AsyncKeyBrokerClient {
+        self.correlation_id = sanitise_correlation_id(correlation_id);
+        self
+    }
+
+    /// Tell the progress observer, if any, about a stage of a key request.
+    fn report(&self, event: ProgressEvent) {
+        if let Some(observer) = &self.progress_observer {
+            observer.progress(&event);
+        }
+    }
+
+    /// Get the capabilities of the keybroker server.
+    pub async fn server_info(&self) -> Result<ServerInfo> {
+        let info_url = format!("{}/keys/v1/info", self.keybroker_url_base);
+
+        log::info!("Requesting the keybroker server information from URL {info_url}");
+
+        let resp = self
+            .client
+            .get(&info_url)
+            .send()
+            .await
+            .map_err(|error| connect_error(&info_url, error))?;
+        match resp.status() {
+            StatusCode::OK => parse_server_info(&response_body(resp).await?),
+            status => Err(KeybrokerError::RuntimeError(
+                RuntimeErrorKind::HTTPResponse(format!("{status:?}")),
+            )),
+        }
+    }
+
+    /// Request the key, returning the challenge and the URL where to submit the evidence.
+    async fn request_challenge(
+        &self,
+        key_name: &str,
+        pubkey: PublicWrappingKey,
+    ) -> Result<(String, Url)> {
+        let key_request = BackgroundCheckKeyRequest {
+            pubkey,
+            return_attestation_result: None,
+            correlation_id: self.correlation_id.clone(),
+        };
+
+        let key_request_url = format!("{}/keys/v1/key/{}", self.keybroker_url_base, key_name);
+        let key_request_url = Url::parse(&key_request_url).map_err(|error| {
+            KeybrokerError::RuntimeError(RuntimeErrorKind::InvalidEndpoint(
+                self.keybroker_url_base.clone(),
+                error.to_string(),
+            ))
+        })?;
+
+        log::info!(
+            "Requesting key named '{key_name}' from the keybroker server with URL {key_request_url}"
+        );
+        self.report(ProgressEvent::ChallengeRequested {
+            key_name: key_name.to_string(),
+            url: key_request_url.to_string(),
+        });
+
+        let resp = self
+            .client
+            .post(key_request_url.clone())
+            .json(&key_request)
+            .send()
+            .await
+            .map_err(|error| connect_error(key_request_url.as_str(), error))?;
+        if !resp.status().is_success() {
+            return Err(error_response(resp).await);
+        }
+
+        // The evidence submission URL may be relative, for example when the keybroker server is
+        // behind a reverse proxy.
+        let evidence_submission_url = resolve_location(
+            &key_request_url,
+            resp.headers()
+                .get(reqwest::header::LOCATION)
+                .map(|location| location.as_bytes()),
+        )?;
+
+        let ac = parse_attestation_challenge(&response_body(resp).await?)?;
+        self.report(ProgressEvent::ChallengeReceived {
+            nonce_length: base64::decode("the attestation challenge", &ac.challenge)
+                .map(|nonce| nonce.len())
+                .unwrap_or_default(),
+            accept: ac.accept,
+        });
+
+        Ok((ac.challenge, evidence_submission_url))
+    }
+
+    /// Submit the evidence, returning the wrapped key.
+    ///
+    /// Redirects are followed by the HTTP client (the fetch API, in a browser).
+    async fn submit_evidence(
+        &self,
+        evidence_submission_url: &Url,
+        media_type: &str,
+        evidence: &[u8],
+    ) -> Result<WrappedKey> {
+        log::info!("Submitting evidence to URL {evidence_submission_url}");
+
+        let resp = self
+            .client
+            .post(evidence_submission_url.clone())
+            .header(reqwest::header::CONTENT_TYPE, media_type)
+            .body(base64::encode(evidence))
+            .send()
+            .await
+            .map_err(|error| connect_error(evidence_submission_url.as_str(), error))?;
+        self.report(ProgressEvent::EvidenceSubmitted {
+            url: resp.url().to_string(),
+        });
+
+        match resp.status() {
+            StatusCode::OK => parse_wrapped_key_data(&response_body(resp).await?),
+            StatusCode::FORBIDDEN => {
+                let error_info = parse_error_information(&response_body(resp).await?)?;
+                Err(KeybrokerError::AttestationFailure(
+                    error_info.r#type,
+                    error_info.detail,
+                    error_info.instance,
+                ))
+            }
+            StatusCode::CONFLICT => {
+                let error_info = parse_error_information(&response_body(resp).await?)?;
+                Err(KeybrokerError::RuntimeError(
+                    RuntimeErrorKind::ChallengeAlreadyRedeemed(error_info.detail),
+                ))
+            }
+            _ => Err(error_response(resp).await),
+        }
+    }
+
+    async fn retrieve_key<EP: EvidenceProvider, W: WrappingKeyPair>(
+        &self,
+        key_name: &str,
+        evidence_provider: &EP,
+        wrapping_key: &W,
+    ) -> Result<Zeroizing<Vec<u8>>> {
+        let pubkey = wrapping_key.public_key().await?;
+        let algorithm = pubkey.alg.clone();
+
+        let (challenge, evidence_submission_url) =
+            match self.request_challenge(key_name, pubkey).await {
+                Ok(data) => data,
+                // Errors reported by the server keep their code, so that applications can match on it.
+                Err(error @ KeybrokerError::RuntimeError(RuntimeErrorKind::ServerError(_, _))) => {
+                    return Err(error)
+                }
+                Err(error) => {
+                    return Err(KeybrokerError::RuntimeError(
+                        RuntimeErrorKind::ChallengeRetrieval(format!("{error:?}")),
+                    ))
+                }
+            };
+
+        let evidence = evidence_provider
+            .get_evidence(&challenge)
+            .map_err(|error| {
+                KeybrokerError::RuntimeError(RuntimeErrorKind::EvidenceGeneration(format!(
+                    "{error:?}"
+                )))
+            })?;
+        let media_type = evidence_provider.media_type();
+        self.report(ProgressEvent::EvidenceGenerated {
+            size: evidence.len(),
+            media_type: media_type.clone(),
+        });
+
+        let wrapped_key = self
+            .submit_evidence(&evidence_submission_url, &media_type, &evidence)
+            .await?;
+        match &wrapped_key.content_encryption {
+            None => {
+                wrapping_key
+                    .unwrap_key(&algorithm, &wrapped_key.ciphertext)
+                    .await
+            }
+            // The content encryption key is unwrapped by the key-pair, and the key decrypted here.
+            Some(content_encryption) => {
+                let cek = wrapping_key
+                    .unwrap_key(&algorithm, &content_encryption.wrapped_cek)
+                    .await?;
+                decrypt_content(&cek, content_encryption, &wrapped_key.ciphertext)
+            }
+        }
+    }
+
+    /// Request a key, attesting with the evidence of the provider, and return it in plain text
+    /// once unwrapped with the wrapping key-pair.
+    pub async fn get_key<EP: EvidenceProvider, W: WrappingKeyPair>(
+        &self,
+        key_name: &str,
+        evidence_provider: &EP,
+        wrapping_key: &W,
+    ) -> Result<SecretKeyMaterial> {
+        let result = self
+            .retrieve_key(key_name, evidence_provider, wrapping_key)
+            .await
+            .map(|key| SecretKeyMaterial::new(&key));
+        // The phases are not timed, as there is no monotonic clock in the browsers.
+        match &result {
+            Ok(key) => self.report(ProgressEvent::KeyReceived {
+                size: key.len(),
+                timings: None,
+            }),
+            Err(error) => self.report(ProgressEvent::Failed {
+                code: error.code().cloned(),
+                detail: error.to_string(),
+                timings: None,
+            }),
+        }
+        result
+    }
+}
@@ -0,0 +1,103 @@
+// Copyright 2024 Contributors to the Veraison project.
+// SPDX-License-Identifier: Apache-2.0
+
+//! An in-memory cache of the keys retrieved from the keybroker server.
+//!
+//! The keys are only ever held in memory, in containers that wipe them when they are evicted or
+//! when the cache is dropped. They are never written to disk.
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use zeroize::Zeroizing;
+
+/// A key in the cache, with the time after which it must not be used anymore.
+struct CachedKey {
+    key: Zeroizing<Vec<u8>>,
+    expiry: Instant,
+}
+
+/// The keys retrieved from the keybroker server, keyed by key name, each kept for the same time.
+pub(crate) struct KeyCache {
+    ttl: Duration,
+    keys: Mutex<HashMap<String, CachedKey>>,
+}
+
+impl KeyCache {
+    pub(crate) fn new(ttl: Duration) -> KeyCache {
+        KeyCache {
+            ttl,
+            keys: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub(crate) fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// Get a copy of a key, unless it is not in the cache or it has expired.
+    pub(crate) fn get(&self, key_name: &str) -> Option<Zeroizing<Vec<u8>>> {
+        let mut keys = self.keys.lock().expect("Poisoned key cache lock.");
+        match keys.get(key_name) {
+            Some(cached) if Instant::now() < cached.expiry => Some(cached.key.clone()),
+            Some(_) => {
+                keys.remove(key_name);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Add a key to the cache, replacing any previous one with the same name.
+    pub(crate) fn insert(&self, key_name: &str, key: &[u8]) {
+        let now = Instant::now();
+        let mut keys = self.keys.lock().expect("Poisoned key cache lock.");
+        keys.retain(|_, cached| now < cached.expiry);
+        keys.insert(
+            key_name.to_string(),
+            CachedKey {
+                key: Zeroizing::new(key.to_vec()),
+                expiry: now + self.ttl,
+            },
+        );
+    }
+
+    /// Remove a key from the cache.
+    pub(crate) fn invalidate(&self, key_name: &str) {
+        self.keys
+            .lock()
+            .expect("Poisoned key cache lock.")
+            .remove(key_name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cached_until_expiry() {
+        let cache = KeyCache::new(Duration::from_millis(50));
+        cache.insert("skywalker", b"May the force be with you.");
+
+        assert_eq!(
+            cache.get("skywalker").as_deref(),
+            Some(&b"May the force be with you.".to_vec())
+        );
+        assert!(cache.get("vader").is_none());
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(cache.get("skywalker").is_none());
+        assert!(cache.keys.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn invalidated() {
+        let cache = KeyCache::new(Duration::from_secs(60));
+        cache.insert("skywalker", b"May the force be with you.");
+        cache.insert("vader", b"I am your father.");
+
+        cache.invalidate("skywalker");
+        assert!(cache.get("skywalker").is_none());
+        assert!(cache.get("vader").is_some());
+    }
+}
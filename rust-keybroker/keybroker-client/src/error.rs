@@ -1,14 +1,17 @@
 // Copyright 2024 Contributors to the Veraison project.
 // SPDX-License-Identifier: Apache-2.0
 
+use keybroker_common::ErrorCode;
+use std::time::Duration;
 use thiserror::Error;
 
 /// Top-level error type for a keybroker client.
 #[derive(Error, Debug)]
 pub enum Error {
-    /// Represents genuine attestation failures.
+    /// Represents genuine attestation failures, with the error code and the details reported by the server,
+    /// and the identifier of the failure instance, if the server gave one, which it logged along with it.
     #[error("Attestation failure: {0} ({1})")]
-    AttestationFailure(String, String),
+    AttestationFailure(ErrorCode, String, Option<String>),
     /// Represents all kind of runtime errors that can be faced by a client, like a bogus HTTP connection for example.
     #[error(transparent)]
     RuntimeError(#[from] RuntimeErrorKind),
@@ -26,18 +29,36 @@ pub enum RuntimeErrorKind {
     #[error("Unhandled HTTP response: {0}")]
     HTTPResponse(String),
 
+    /// The keybroker server reported an error, with its error code and details.
+    #[error("The keybroker server reported {0}: {1}")]
+    ServerError(ErrorCode, String),
+
     /// Represents errors due to base64 decoding.
-    #[error("Failed to base64-decode {0} with error: {1}")]
-    Base64Decode(String, String),
+    #[error(transparent)]
+    Base64Decode(#[from] keybroker_common::base64::Base64FieldError),
 
     /// Represents errors due to base64 decoding.
     #[error("Failed to JSON-deserialize {0} with error: {1}")]
     JSONDeserialize(String, String),
 
     /// Represents errors related to TSM report generation.
-    #[error(transparent)]
+    #[cfg(feature = "native")]
+    #[error("configfs-tsm attestation report error: {0}")]
     TSMReport(#[from] tsm_report::TsmReportError),
 
+    /// Represents errors related to the RSI device, when getting the attestation token from it or
+    /// extending a realm extensible measurement.
+    #[error("RSI device error: {0}")]
+    Rsi(String),
+
+    /// The wrapping key file is not a valid PEM-encoded private key.
+    #[error("Invalid wrapping key: {0}")]
+    InvalidWrappingKey(String),
+
+    /// The wrapping key is a valid private key, but not one the keys can be wrapped to.
+    #[error("Unsupported wrapping key: {0}")]
+    UnsupportedWrappingKey(String),
+
     /// Represents errors in the key decryption.
     #[error("Failed to decrypt {0} with error: {1}")]
     Decrypt(String, String),
@@ -46,6 +67,10 @@ pub enum RuntimeErrorKind {
     #[error("Challenge retrieval error: {0}")]
     ChallengeRetrieval(String),
 
+    /// Represents the error when the challenge was already redeemed, for example by an earlier attempt.
+    #[error("{0} Request the key again to get a new challenge.")]
+    ChallengeAlreadyRedeemed(String),
+
     /// Represents the error when the challenge has an incorrect number of bytes.
     #[error("Challenge length error, expecting {0} but got {1} instead")]
     ChallengeLength(usize, usize),
@@ -54,9 +79,75 @@ pub enum RuntimeErrorKind {
     #[error("Evidence generation error: {0}")]
     EvidenceGeneration(String),
 
+    /// Represents the error when the evidence provider, named, did not produce the evidence in time.
+    #[error("Evidence generation error: the {0} evidence provider did not produce the evidence within {1:?}")]
+    EvidenceGenerationTimeout(String, Duration),
+
+    /// Represents errors reported by an evidence observer.
+    #[error("Evidence observer error: {0}")]
+    EvidenceObserver(String),
+
     /// Used when the response from the keybroker is missing the location field.
     #[error("Missing location field in HTTP requets")]
     MissingLocation,
+
+    /// Used when the location field of a response from the keybroker can not be parsed as a URL.
+    #[error("Invalid location field '{0}' in HTTP response: {1}")]
+    InvalidLocation(String, String),
+
+    /// Used when a header to send with the requests has an invalid name or value.
+    #[error("Invalid HTTP header '{0}': {1}")]
+    InvalidHeader(String, String),
+
+    /// Used when a header to send with the requests is one that the client sets itself.
+    #[error("The HTTP header '{0}' is set by the client, it can not be overridden")]
+    RestrictedHeader(String),
+
+    /// Used when the root certificates to trust can not be parsed.
+    #[error("Invalid root certificate: {0}")]
+    InvalidRootCertificate(String),
+
+    /// Used when the keybroker endpoint can not be parsed as a URL.
+    #[error("Invalid keybroker endpoint '{0}': {1}")]
+    InvalidEndpoint(String, String),
+
+    /// Represents errors in the export or the import of a session file.
+    #[error("Session error: {0}")]
+    Session(String),
+
+    /// Represents the error when the keybroker server no longer knows the challenge of an imported
+    /// session, because it has expired or was already redeemed.
+    #[error("The session is stale: {0} Export a new session.")]
+    StaleSession(String),
+
+    /// Represents the error when the keybroker server did not answer before the deadline of
+    /// [`crate::KeyBrokerClient::wait_for_server`], with the last error.
+    #[error("The keybroker server at {0} never became ready: {1}")]
+    ServerNotReady(String, String),
+
+    /// Represents the error when a key expected to hold a JSON document does not.
+    #[error("The key is not a valid JSON document: {0}")]
+    InvalidKeyDocument(String),
+}
+
+impl Error {
+    /// The error code reported by the keybroker server, if the error comes from it.
+    pub fn code(&self) -> Option<&ErrorCode> {
+        match self {
+            Error::AttestationFailure(code, _, _)
+            | Error::RuntimeError(RuntimeErrorKind::ServerError(code, _)) => Some(code),
+            _ => None,
+        }
+    }
+
+    /// The identifier of the failure instance reported by the keybroker server, to quote when
+    /// reporting the failure, as the server logged it along with the failure.
+    pub fn instance(&self) -> Option<&str> {
+        match self {
+            Error::AttestationFailure(_, _, instance) => instance.as_deref(),
+            _ => None,
+        }
+    }
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -1,24 +1,125 @@
 // Copyright 2024 Contributors to the Veraison project.
 // SPDX-License-Identifier: Apache-2.0
 
-use base64::engine::general_purpose::URL_SAFE_NO_PAD;
-use base64::prelude::*;
-use keybroker_common::{
-    BackgroundCheckKeyRequest, ErrorInformation, PublicWrappingKey, WrappedKeyData,
-};
-use reqwest::StatusCode;
-use rsa::{traits::PublicKeyParts, BigUint, Pkcs1v15Encrypt, RsaPrivateKey, RsaPublicKey};
-use tsm_report::{TsmReportData, TsmReportPath, TsmReportProvider};
+use keybroker_common::ProgressEvent;
 
+pub mod asynchronous;
+#[cfg(feature = "native")]
+mod cache;
 pub mod error;
-use crate::error::Error as KeybrokerError;
+#[cfg(feature = "native")]
+mod native;
+pub mod protocol;
+#[cfg(feature = "native")]
+mod rsi;
+mod secret;
+#[cfg(feature = "native")]
+pub mod session;
+#[cfg(feature = "native")]
+pub mod tls;
+#[cfg(feature = "native")]
+mod wrapping_key;
 use crate::error::Result;
-use crate::error::RuntimeErrorKind;
+#[cfg(feature = "native")]
+pub use crate::native::*;
+pub use crate::secret::SecretKeyMaterial;
 
 /// The trait that must be implemented so a KeybrokerClient can retrieve the evidence it has
 /// to submit to the Keybroker server.
+///
+/// Implementing it is all it takes to get keys with evidence from another source than the
+/// providers of this crate, such as another attester device, see
+/// `examples/command_evidence.rs`. An error from the provider fails the key request with
+/// [`RuntimeErrorKind::EvidenceGeneration`], after the challenge is cancelled.
+///
+/// [`RuntimeErrorKind::EvidenceGeneration`]: crate::error::RuntimeErrorKind::EvidenceGeneration
 pub trait EvidenceProvider {
+    /// Produce the evidence for a challenge, given as the base64 encoding of the nonce the
+    /// keybroker server issued, which the evidence must include.
     fn get_evidence(&self, challenge: &str) -> Result<Vec<u8>>;
+
+    /// The media type the evidence is submitted with, which the keybroker server must accept: the
+    /// CCA attestation token, [`EVIDENCE_MEDIA_TYPE`], by default.
+    fn media_type(&self) -> String {
+        EVIDENCE_MEDIA_TYPE.to_string()
+    }
+
+    /// The name of the provider, as given in the errors.
+    fn name(&self) -> String {
+        std::any::type_name::<Self>().to_string()
+    }
+
+    /// A copy of the provider that can be moved to a worker thread, so that the generation of the
+    /// evidence can be abandoned when it outlasts the evidence timeout of the client. The providers
+    /// without one, as by default, are never timed out.
+    fn detach(&self) -> Option<Box<dyn EvidenceProvider + Send>> {
+        None
+    }
+
+    /// Whether the evidence is a mock, which does not come from the platform, such as the CCA
+    /// example token: the measurements of the realm are then not touched to bind the wrapping key
+    /// to it. The providers are not mocks by default.
+    fn is_mock(&self) -> bool {
+        false
+    }
+}
+
+/// The media type of the evidence submitted to the keybroker server.
+pub const EVIDENCE_MEDIA_TYPE: &str =
+    "application/eat-collection; profile=\"http://arm.com/CCA-SSD/1.0.0\"";
+
+/// How many times [`KeyBrokerClient::get_key`] restarts a key request with a new challenge by
+/// default, when the keybroker server says that the challenge expired or was already redeemed.
+pub const DEFAULT_CHALLENGE_RESTARTS: u32 = 2;
+
+/// The sizes, in bits, of the ephemeral RSA wrapping key-pairs that [`KeyBrokerClient`] can
+/// generate, the first being the default.
+pub const RSA_WRAPPING_KEY_BITS: [usize; 3] = [2048, 3072, 4096];
+
+/// The type of the ephemeral wrapping key-pairs that [`KeyBrokerClient`] generates to have the
+/// keys wrapped to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WrappingKeyType {
+    /// An RSA key-pair, 2048-bit by default, with the RSA1_5 wrapping algorithm by default.
+    #[default]
+    Rsa,
+
+    /// A P-256 key-pair, with the ECDH-ES+A256KW wrapping algorithm.
+    P256,
+
+    /// An X25519 key-pair, with the ECDH-ES+A256KW wrapping algorithm.
+    X25519,
+}
+
+/// The evidence produced by an EvidenceProvider, as it is about to be submitted to the keybroker
+/// server.
+#[derive(Debug)]
+pub struct GeneratedEvidence<'a> {
+    /// The raw evidence bytes (before they get base64-encoded for submission).
+    pub evidence: &'a [u8],
+
+    /// The challenge the evidence was generated for, as sent by the keybroker server.
+    pub challenge: &'a str,
+
+    /// The media type the evidence is submitted with.
+    pub media_type: &'a str,
+}
+
+/// The trait that can be implemented to inspect the evidence before a KeybrokerClient submits it,
+/// for example to save it for a later replay.
+///
+/// The observer is called before the submission, so it sees the evidence even when the
+/// submission fails. An error from the observer aborts the key request.
+pub trait EvidenceObserver {
+    fn evidence_generated(&self, evidence: &GeneratedEvidence) -> Result<()>;
+}
+
+/// The trait that can be implemented to follow the progress of the key requests of a
+/// KeybrokerClient, for example to update a user interface.
+///
+/// The events are only informative: the observer can not abort the key request.
+pub trait ProgressObserver {
+    fn progress(&self, event: &ProgressEvent);
 }
 
 /// The CCA example token.
@@ -167,277 +268,27 @@ impl EvidenceProvider for CcaExampleToken {
     fn get_evidence(&self, _challenge: &str) -> Result<Vec<u8>> {
         Ok(CCA_EXAMPLE_TOKEN.to_vec())
     }
-}
-
-/// A TSM attestation report implementation of EvidenceProvider.
-///
-/// The TsmAttestationReport implementation of the EvidenceProvider trait uses
-/// Linux's TSM attestation report infrastructure to construct an evidence from
-/// a challenge.
-pub struct TsmAttestationReport {}
-
-impl EvidenceProvider for TsmAttestationReport {
-    fn get_evidence(&self, challenge: &str) -> Result<Vec<u8>> {
-        match TsmReportPath::new(TsmReportProvider::Cca) {
-            Ok(tsm_report_path) => match URL_SAFE_NO_PAD.decode(challenge) {
-                Ok(challenge) => {
-                    log::info!("Challenge ({} bytes) = {:02x?}", challenge.len(), challenge);
-                    if challenge.len() != 64 {
-                        return Err(KeybrokerError::RuntimeError(
-                            RuntimeErrorKind::ChallengeLength(64, challenge.len()),
-                        ));
-                    };
-                    match tsm_report_path.attestation_report(TsmReportData::Cca(challenge)) {
-                        Ok(ar) => Ok(ar),
-                        Err(error) => Err(KeybrokerError::RuntimeError(
-                            RuntimeErrorKind::TSMReport(error),
-                        )),
-                    }
-                }
-                Err(error) => Err(KeybrokerError::RuntimeError(
-                    RuntimeErrorKind::Base64Decode(
-                        "the attestation challenge".to_string(),
-                        format!("{error:?}"),
-                    ),
-                )),
-            },
-            Err(error) => Err(KeybrokerError::RuntimeError(RuntimeErrorKind::TSMReport(
-                error,
-            ))),
-        }
-    }
-}
-
-#[derive(Debug)]
-struct AttestationChallenge {
-    pub challenge: String,
-    pub evidence_submission_url: String,
-}
 
-/// The KeyBrokerSession models the communication with a keybroker server.
-#[derive(Debug)]
-pub struct KeyBrokerClient {
-    /// The client this session will use to interact with the keybroker server
-    /// over HTTP with post calls. A blocking client is used for simplicity.
-    client: reqwest::blocking::Client,
-
-    /// The keybroker URL base address.
-    keybroker_url_base: String,
-}
-
-impl KeyBrokerClient {
-    /// Create a session to the keybroker server located at addr:port.
-    pub fn new(endpoint: &str) -> KeyBrokerClient {
-        KeyBrokerClient {
-            client: reqwest::blocking::Client::new(),
-            keybroker_url_base: endpoint.to_string(),
-        }
+    fn name(&self) -> String {
+        "CCA example token".to_string()
     }
 
-    /// The first API call to request the key. This gets all the required
-    /// attestation challenge material: the challenge it self, and the url
-    /// where to submit the evidence.
-    fn request_key(
-        self: &KeyBrokerClient,
-        key_name: &str,
-        pub_key: &RsaPublicKey,
-    ) -> Result<AttestationChallenge> {
-        // Create base64 strings for the public key modulus and exponent parts.
-        let k_mod_base64 = URL_SAFE_NO_PAD.encode(BigUint::to_bytes_be(pub_key.n()));
-        let k_exp_base64 = URL_SAFE_NO_PAD.encode(BigUint::to_bytes_be(pub_key.e()));
-
-        // ... and turn them into an API-level input
-        let key_request = BackgroundCheckKeyRequest {
-            pubkey: PublicWrappingKey {
-                kty: "RSA".to_string(),
-                alg: "RSA1_5".to_string(),
-                n: k_mod_base64,
-                e: k_exp_base64,
-            },
-        };
-
-        // Construct the URL to request the key.
-        let key_request_url = format!("{}/keys/v1/key/{}", self.keybroker_url_base, key_name);
-
-        log::info!(
-            "Requesting key named '{key_name}' from the keybroker server with URL {key_request_url}"
-        );
-
-        // Make the first API call to request the key.
-        match self.client.post(&key_request_url).json(&key_request).send() {
-            Ok(resp) => {
-                let evidence_submission_url = match resp.headers().get(reqwest::header::LOCATION) {
-                    Some(url) => url.to_str().unwrap().to_owned(),
-                    None => {
-                        return Err(KeybrokerError::RuntimeError(
-                            RuntimeErrorKind::MissingLocation,
-                        ))
-                    }
-                };
-
-                let ac = match resp.json::<keybroker_common::AttestationChallenge>() {
-                    Ok(ac) => ac,
-                    Err(error) => {
-                        return Err(KeybrokerError::RuntimeError(
-                            RuntimeErrorKind::JSONDeserialize(
-                                "the attestation challenge".to_string(),
-                                format!("{error:?}"),
-                            ),
-                        ))
-                    }
-                };
-
-                Ok(AttestationChallenge {
-                    challenge: ac.challenge,
-                    evidence_submission_url,
-                })
-            }
-            Err(error) => Err(KeybrokerError::RuntimeError(RuntimeErrorKind::HTTPConnect(
-                key_request_url,
-                format!("{error:?}"),
-            ))),
-        }
-    }
-
-    /// Submit the evidence.
-    /// In case of success, this returns the decoded key from the server.
-    fn submit_evidence(
-        self: &KeyBrokerClient,
-        evidence_submission_url: &str,
-        evidence: &[u8],
-    ) -> Result<Vec<u8>> {
-        log::info!("Submitting evidence to URL {evidence_submission_url}");
-
-        // Make the second API call to submit the evidence.
-        match self
-            .client
-            .post(evidence_submission_url)
-            .header(
-                reqwest::header::CONTENT_TYPE,
-                "application/eat-collection; profile=\"http://arm.com/CCA-SSD/1.0.0\"",
-            )
-            .body(URL_SAFE_NO_PAD.encode(evidence))
-            .send()
-        {
-            Ok(resp) => {
-                let wrapped_data_name = "the wrapped data from the server".to_string();
-                match resp.status() {
-                    // Assume first that we are following the happy path: our evidence was "accepted".
-                    StatusCode::OK => {
-                        let wrapped_data = match resp.json::<WrappedKeyData>() {
-                            Ok(wrapped_data) => wrapped_data,
-                            Err(error) => {
-                                return Err(KeybrokerError::RuntimeError(
-                                    RuntimeErrorKind::JSONDeserialize(
-                                        "the evidence WrappedKeyData".to_string(),
-                                        format!("{error:?}"),
-                                    ),
-                                ))
-                            }
-                        };
-                        match URL_SAFE_NO_PAD.decode(wrapped_data.data) {
-                            Ok(ciphertext) => Ok(ciphertext),
-                            Err(error) => Err(KeybrokerError::RuntimeError(
-                                RuntimeErrorKind::Base64Decode(
-                                    wrapped_data_name,
-                                    format!("{error:?}"),
-                                ),
-                            )),
-                        }
-                    }
-
-                    // Our evidence has been rejected for some "good" reasons.
-                    StatusCode::FORBIDDEN => {
-                        let wrapped_data = match resp.json::<ErrorInformation>() {
-                            Ok(wrapped_data) => wrapped_data,
-                            Err(error) => {
-                                return Err(KeybrokerError::RuntimeError(
-                                    RuntimeErrorKind::JSONDeserialize(
-                                        "the evidence ErrorInformation".to_string(),
-                                        format!("{error:?}"),
-                                    ),
-                                ))
-                            }
-                        };
-                        Err(crate::error::Error::AttestationFailure(
-                            wrapped_data.r#type,
-                            wrapped_data.detail,
-                        ))
-                    }
-
-                    // We have a genuine and/or unhandled error :-()
-                    status => Err(KeybrokerError::RuntimeError(
-                        RuntimeErrorKind::HTTPResponse(format!("{status:?}")),
-                    )),
-                }
-            }
-
-            Err(error) => Err(KeybrokerError::RuntimeError(RuntimeErrorKind::HTTPConnect(
-                evidence_submission_url.to_string(),
-                format!("{error:?}"),
-            ))),
-        }
+    fn detach(&self) -> Option<Box<dyn EvidenceProvider + Send>> {
+        Some(Box::new(CcaExampleToken {}))
     }
 
-    /// Get the wrapped key, decryption left to the caller.
-    pub fn get_wrapped_key<EP: EvidenceProvider>(
-        self: &KeyBrokerClient,
-        key_name: &str,
-        evidence_provider: &EP,
-        pub_key: &RsaPublicKey,
-    ) -> Result<Vec<u8>> {
-        // First API call: request the challenge.
-        let data = match self.request_key(key_name, pub_key) {
-            Ok(data) => data,
-            Err(error) => {
-                return Err(KeybrokerError::RuntimeError(
-                    RuntimeErrorKind::ChallengeRetrieval(format!("{error:?}")),
-                ))
-            }
-        };
-
-        // Produce the evidence.
-        let evidence = match evidence_provider.get_evidence(&data.challenge) {
-            Ok(evidence) => evidence,
-            Err(error) => {
-                // TODO: we may want to notify the keybroker server that something went wrong on our side and that it
-                // should release any resource it has allocated for us. This could possibly be done using some
-                // form of "abandon" request, or we could simulate this by submitting a bogus evidence (which will
-                // not give a successful attestation, but that's expected). OTOH, the server will also need to
-                // have some form of timeout associated with all the key requests and have some garbage collection
-                // pass to be run from time to time. A well behaved client could be kind though and notify the
-                // keybroker server.
-                return Err(KeybrokerError::RuntimeError(
-                    RuntimeErrorKind::EvidenceGeneration(format!("{error:?}")),
-                ));
-            }
-        };
-
-        // Second API call: submit the evidence, and return the attestation result.
-        self.submit_evidence(&data.evidence_submission_url, &evidence)
+    fn is_mock(&self) -> bool {
+        true
     }
+}
 
-    /// This returns the plain text.
-    pub fn get_key<EP: EvidenceProvider>(
-        self: &KeyBrokerClient,
-        key_name: &str,
-        evidence_provider: &EP,
-    ) -> Result<Vec<u8>> {
-        // Create an ephemeral wrapping key-pair for our own use.
-        let mut rng = rand::thread_rng();
-        let priv_key = RsaPrivateKey::new(&mut rng, 1024 /* bits */)
-            .expect("Failed to generate ephemeral wrapping key.");
-        let pub_key = RsaPublicKey::from(&priv_key);
+/// A key retrieved from the keybroker server, along with the attestation result if it was requested.
+#[derive(Debug)]
+pub struct RetrievedKey {
+    /// The plain text key.
+    pub key: SecretKeyMaterial,
 
-        match self.get_wrapped_key(key_name, evidence_provider, &pub_key) {
-            Ok(ciphertext) => match priv_key.decrypt(Pkcs1v15Encrypt, &ciphertext) {
-                Ok(plaintext) => Ok(plaintext),
-                Err(error) => Err(KeybrokerError::RuntimeError(RuntimeErrorKind::Decrypt(
-                    "ciphertext".to_string(),
-                    format!("{error:?}"),
-                ))),
-            },
-            other => other,
-        }
-    }
+    /// The attestation result (EAR) as a signed JWT, which the client can keep as a "passport" for
+    /// later use.
+    pub attestation_result: Option<String>,
 }
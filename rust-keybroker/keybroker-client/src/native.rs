@@ -0,0 +1,1971 @@
+// Copyright 2024 Contributors to the Veraison project.
+// SPDX-License-Identifier: Apache-2.0
+
+//! The blocking client, with the evidence providers and the wrapping key-pairs which need the
+//! operating system, built with the `native` feature. Its public items are re-exported at the root
+//! of the crate.
+use crate::cache::KeyCache;
+use crate::error::{Error as KeybrokerError, Result, RuntimeErrorKind};
+use crate::protocol::{
+    parse_attestation_challenge, parse_error_information, parse_server_info,
+    parse_wrapped_key_data, resolve_location, unwrap_ecdh_wrapped_key, unwrap_wrapped_key,
+    EcdhPrivateKey, WrappedKey, RSA_OAEP_256_ALGORITHM, RSA_OAEP_ALGORITHM, RSA_PKCS15_ALGORITHM,
+};
+use crate::session::PendingKeyRequest;
+use crate::tls::{self, TlsSettings};
+use crate::{
+    rsi, wrapping_key, CcaExampleToken, EvidenceObserver, EvidenceProvider, GeneratedEvidence,
+    ProgressObserver, RetrievedKey, SecretKeyMaterial, WrappingKeyType, DEFAULT_CHALLENGE_RESTARTS,
+    RSA_WRAPPING_KEY_BITS,
+};
+use flate2::{write::GzEncoder, Compression};
+use keybroker_common::{
+    base64, sanitise_correlation_id, BackgroundCheckKeyRequest, ErrorCode, ProgressEvent,
+    PublicWrappingKey, ServerInfo, Timings,
+};
+use reqwest::header::{self, HeaderMap, HeaderName, HeaderValue};
+use reqwest::StatusCode;
+use rsa::{RsaPrivateKey, RsaPublicKey};
+use rustls::pki_types::{pem::PemObject, CertificateDer};
+use serde::de::DeserializeOwned;
+use std::cell::{Cell, OnceCell, RefCell};
+use std::fmt;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::{Duration, Instant};
+use tsm_report::{TsmReportData, TsmReportPath, TsmReportProvider};
+use url::Url;
+use zeroize::Zeroizing;
+
+/// The algorithm with which the keys are wrapped to the RSA key-pairs of [`KeyBrokerClient`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RsaWrappingAlgorithm {
+    /// RSA1_5, the RSAES-PKCS1-v1_5 encryption.
+    #[default]
+    Rsa15,
+
+    /// RSA-OAEP, the RSAES-OAEP encryption with SHA-1.
+    RsaOaep,
+
+    /// RSA-OAEP-256, the RSAES-OAEP encryption with SHA-256.
+    RsaOaep256,
+}
+
+impl RsaWrappingAlgorithm {
+    /// The name of the algorithm, as in the `alg` member of the wrapping key.
+    pub fn name(self) -> &'static str {
+        match self {
+            RsaWrappingAlgorithm::Rsa15 => RSA_PKCS15_ALGORITHM,
+            RsaWrappingAlgorithm::RsaOaep => RSA_OAEP_ALGORITHM,
+            RsaWrappingAlgorithm::RsaOaep256 => RSA_OAEP_256_ALGORITHM,
+        }
+    }
+}
+
+/// A TSM attestation report implementation of EvidenceProvider.
+///
+/// The TsmAttestationReport implementation of the EvidenceProvider trait uses
+/// Linux's TSM attestation report infrastructure to construct an evidence from
+/// a challenge.
+pub struct TsmAttestationReport {}
+
+impl EvidenceProvider for TsmAttestationReport {
+    fn get_evidence(&self, challenge: &str) -> Result<Vec<u8>> {
+        match TsmReportPath::new(TsmReportProvider::Cca) {
+            Ok(tsm_report_path) => match base64::decode("the attestation challenge", challenge) {
+                Ok(challenge) => {
+                    log::info!("Challenge ({} bytes) = {:02x?}", challenge.len(), challenge);
+                    if challenge.len() != 64 {
+                        return Err(KeybrokerError::RuntimeError(
+                            RuntimeErrorKind::ChallengeLength(64, challenge.len()),
+                        ));
+                    };
+                    match tsm_report_path.attestation_report(TsmReportData::Cca(challenge)) {
+                        Ok(ar) => Ok(ar),
+                        Err(error) => Err(KeybrokerError::RuntimeError(
+                            RuntimeErrorKind::TSMReport(error),
+                        )),
+                    }
+                }
+                Err(error) => Err(KeybrokerError::RuntimeError(
+                    RuntimeErrorKind::Base64Decode(error),
+                )),
+            },
+            Err(error) => Err(KeybrokerError::RuntimeError(RuntimeErrorKind::TSMReport(
+                error,
+            ))),
+        }
+    }
+
+    fn name(&self) -> String {
+        "configfs-tsm".to_string()
+    }
+
+    fn detach(&self) -> Option<Box<dyn EvidenceProvider + Send>> {
+        Some(Box::new(TsmAttestationReport {}))
+    }
+}
+
+/// An RSI attestation token implementation of EvidenceProvider.
+///
+/// The RsiAttestationReport implementation of the EvidenceProvider trait gets the CCA
+/// attestation token for a challenge from the Realm Services Interface device (`/dev/rsi`),
+/// for the kernels which do not provide configfs-tsm.
+pub struct RsiAttestationReport {}
+
+impl EvidenceProvider for RsiAttestationReport {
+    fn get_evidence(&self, challenge: &str) -> Result<Vec<u8>> {
+        let challenge = base64::decode("the attestation challenge", challenge)
+            .map_err(|error| KeybrokerError::RuntimeError(RuntimeErrorKind::Base64Decode(error)))?;
+        log::info!("Challenge ({} bytes) = {:02x?}", challenge.len(), challenge);
+
+        let challenge: [u8; rsi::RSI_CHALLENGE_SIZE] =
+            challenge.as_slice().try_into().map_err(|_| {
+                KeybrokerError::RuntimeError(RuntimeErrorKind::ChallengeLength(
+                    rsi::RSI_CHALLENGE_SIZE,
+                    challenge.len(),
+                ))
+            })?;
+
+        rsi::attestation_token(&challenge)
+    }
+
+    fn name(&self) -> String {
+        "RSI device".to_string()
+    }
+
+    fn detach(&self) -> Option<Box<dyn EvidenceProvider + Send>> {
+        Some(Box::new(RsiAttestationReport {}))
+    }
+}
+
+/// An EvidenceProvider replaying previously captured evidence from a file.
+///
+/// The file holds the evidence either as is, such as a CBOR-encoded CCA attestation token, or
+/// base64-encoded, as in the body of an evidence submission. The challenge is ignored, so the
+/// evidence only verifies with a keybroker server using mock challenges, which always issues the
+/// nonce the evidence was captured with. This is for debugging the verifier and the policies.
+#[derive(Debug, Clone)]
+pub struct FileEvidence {
+    path: PathBuf,
+    media_type: String,
+}
+
+impl FileEvidence {
+    /// Replay the evidence in the file at `path`, submitting it with the given media type.
+    pub fn new(path: impl Into<PathBuf>, media_type: &str) -> FileEvidence {
+        FileEvidence {
+            path: path.into(),
+            media_type: media_type.to_string(),
+        }
+    }
+}
+
+impl EvidenceProvider for FileEvidence {
+    fn get_evidence(&self, _challenge: &str) -> Result<Vec<u8>> {
+        log::warn!(
+            "Submitting the evidence in {} whatever the challenge: it only verifies with a keybroker server using mock challenges.",
+            self.path.display()
+        );
+        let evidence = std::fs::read(&self.path).map_err(|error| {
+            KeybrokerError::RuntimeError(RuntimeErrorKind::EvidenceGeneration(format!(
+                "{}: {error}",
+                self.path.display()
+            )))
+        })?;
+
+        // Binary evidence is never valid base64 text.
+        match std::str::from_utf8(&evidence)
+            .ok()
+            .and_then(|text| base64::decode("the evidence", text).ok())
+        {
+            Some(decoded) if !decoded.is_empty() => Ok(decoded),
+            _ => Ok(evidence),
+        }
+    }
+
+    fn media_type(&self) -> String {
+        self.media_type.clone()
+    }
+
+    fn name(&self) -> String {
+        format!("file {}", self.path.display())
+    }
+
+    fn detach(&self) -> Option<Box<dyn EvidenceProvider + Send>> {
+        Some(Box::new(self.clone()))
+    }
+
+    fn is_mock(&self) -> bool {
+        true
+    }
+}
+
+/// An EvidenceProvider chosen at run time, either explicitly or by probing the platform.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvidenceSource {
+    /// Evidence from the configfs-tsm CCA provider, see [`TsmAttestationReport`].
+    Tsm,
+
+    /// Evidence from the RSI device, see [`RsiAttestationReport`].
+    Rsi,
+
+    /// The CCA example token, see [`CcaExampleToken`].
+    Mock,
+}
+
+impl EvidenceSource {
+    /// Probe for a usable configfs-tsm CCA provider, then for a usable RSI device, and select the
+    /// first one present, falling back to the CCA example token otherwise.
+    pub fn detect() -> EvidenceSource {
+        let tsm_error = match TsmReportPath::new(TsmReportProvider::Cca) {
+            Ok(_) => {
+                log::info!("A configfs-tsm CCA provider was detected, using TSM attestation reports as evidence");
+                return EvidenceSource::Tsm;
+            }
+            Err(error) => error,
+        };
+
+        let rsi_error = match rsi::open_device() {
+            Ok(_) => {
+                log::info!("No usable configfs-tsm CCA provider was detected ({tsm_error}), but an RSI device was: using RSI attestation tokens as evidence");
+                return EvidenceSource::Rsi;
+            }
+            Err(error) => error,
+        };
+
+        log::warn!("No usable configfs-tsm CCA provider was detected ({tsm_error}).");
+        log::warn!("No usable RSI device was detected ({rsi_error}).");
+        log::warn!("USING MOCK EVIDENCE: the CCA example token is submitted instead of an attestation token from the platform, it is only accepted by a keybroker server using mock challenges.");
+        EvidenceSource::Mock
+    }
+}
+
+impl EvidenceProvider for EvidenceSource {
+    fn get_evidence(&self, challenge: &str) -> Result<Vec<u8>> {
+        match self {
+            EvidenceSource::Tsm => TsmAttestationReport {}.get_evidence(challenge),
+            EvidenceSource::Rsi => RsiAttestationReport {}.get_evidence(challenge),
+            EvidenceSource::Mock => CcaExampleToken {}.get_evidence(challenge),
+        }
+    }
+
+    fn name(&self) -> String {
+        match self {
+            EvidenceSource::Tsm => TsmAttestationReport {}.name(),
+            EvidenceSource::Rsi => RsiAttestationReport {}.name(),
+            EvidenceSource::Mock => CcaExampleToken {}.name(),
+        }
+    }
+
+    fn detach(&self) -> Option<Box<dyn EvidenceProvider + Send>> {
+        Some(Box::new(*self))
+    }
+
+    fn is_mock(&self) -> bool {
+        *self == EvidenceSource::Mock
+    }
+}
+
+/// Build the error for an unsuccessful response from the keybroker server, from the error
+/// information in its body when there is one.
+fn error_response(resp: reqwest::blocking::Response) -> KeybrokerError {
+    let status = resp.status();
+    match response_body(resp).and_then(|body| parse_error_information(&body)) {
+        Ok(error_info) => KeybrokerError::RuntimeError(RuntimeErrorKind::ServerError(
+            error_info.r#type,
+            error_info.detail,
+        )),
+        Err(_) => {
+            KeybrokerError::RuntimeError(RuntimeErrorKind::HTTPResponse(format!("{status:?}")))
+        }
+    }
+}
+
+/// The headers the client sets itself on its requests, or which depend on how they are sent, and
+/// which can therefore not be set by the user.
+static RESTRICTED_HEADERS: [HeaderName; 6] = [
+    header::HOST,
+    header::CONTENT_LENGTH,
+    header::CONTENT_TYPE,
+    header::CONTENT_ENCODING,
+    header::TRANSFER_ENCODING,
+    header::CONNECTION,
+];
+
+/// Whether a key request failed because its challenge went stale before the evidence was submitted,
+/// in which case it can be restarted with a new challenge.
+///
+/// A challenge that was already redeemed is not stale: its redemption may have failed on its own
+/// account, for example because the evidence was not in policy, which a restart would not change.
+fn is_stale_challenge(error: &KeybrokerError) -> bool {
+    matches!(
+        error.code(),
+        Some(ErrorCode::ChallengeExpired | ErrorCode::ChallengeNotFound)
+    )
+}
+
+/// Check the value of a header, `name` being only used in the errors.
+fn header_value(name: &str, value: &str) -> Result<HeaderValue> {
+    HeaderValue::from_str(value).map_err(|error| {
+        KeybrokerError::RuntimeError(RuntimeErrorKind::InvalidHeader(
+            name.to_string(),
+            error.to_string(),
+        ))
+    })
+}
+
+/// How long an idle connection to the keybroker server is kept open for the next request.
+const POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// The interval between two polls of [`KeyBrokerClient::wait_for_server`], which is also the least
+/// time allowed to each of them.
+const SERVER_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// The most time allowed to each poll of [`KeyBrokerClient::wait_for_server`].
+const MAX_SERVER_POLL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The time allowed to each poll of [`KeyBrokerClient::wait_for_server`], for an overall `timeout`:
+/// a quarter of it, so that a TLS handshake fits, within bounds.
+fn server_poll_timeout(timeout: Duration) -> Duration {
+    (timeout / 4).clamp(SERVER_POLL_INTERVAL, MAX_SERVER_POLL_TIMEOUT)
+}
+
+/// Whether the keybroker server at `url` accepts TCP connections.
+fn accepts_connections(url: &str, timeout: Duration) -> bool {
+    let Ok(addresses) = Url::parse(url).map(|url| url.socket_addrs(|| None)) else {
+        return false;
+    };
+    addresses.is_ok_and(|addresses| {
+        addresses
+            .iter()
+            .any(|address| std::net::TcpStream::connect_timeout(address, timeout).is_ok())
+    })
+}
+
+/// Build the HTTP client, sending the given headers with every request, and trusting the server
+/// as the TLS settings tell.
+///
+/// The connections are kept alive and pooled, so that the challenge request and the evidence
+/// submission of a key request go over the same connection, and HTTPS only costs one handshake.
+/// HTTP/2 is used when the server offers it during the TLS handshake.
+fn http_client(
+    endpoint: &str,
+    headers: &HeaderMap,
+    tls: &TlsSettings,
+) -> Result<reqwest::blocking::Client> {
+    let invalid = |detail: String| {
+        KeybrokerError::RuntimeError(RuntimeErrorKind::InvalidRootCertificate(detail))
+    };
+
+    // Redirects are handled explicitly, see submit_evidence().
+    let mut builder = reqwest::blocking::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .pool_idle_timeout(POOL_IDLE_TIMEOUT)
+        .tcp_keepalive(POOL_IDLE_TIMEOUT)
+        .default_headers(headers.clone());
+    if tls.custom_verification() {
+        let config = tls
+            .client_config()
+            .map_err(|error| invalid(error.to_string()))?;
+        builder = builder.use_preconfigured_tls(config);
+    } else {
+        for certificate in &tls.root_certificates {
+            let certificate = reqwest::Certificate::from_der(certificate)
+                .map_err(|error| invalid(error.to_string()))?;
+            builder = builder.add_root_certificate(certificate);
+        }
+    }
+    // Such as when the TLS backend can not be set up.
+    builder.build().map_err(|error| {
+        KeybrokerError::RuntimeError(RuntimeErrorKind::HTTPConnect(
+            endpoint.to_string(),
+            format!("{error:?}"),
+        ))
+    })
+}
+
+/// Create an ephemeral RSA wrapping key-pair of the given size for our own use.
+fn wrapping_key_pair(bits: usize) -> RsaPrivateKey {
+    let mut rng = rand::thread_rng();
+    RsaPrivateKey::new(&mut rng, bits).expect("Failed to generate ephemeral wrapping key.")
+}
+
+/// The private part of the wrapping key-pair of a key request.
+enum WrappingPrivateKey {
+    Rsa(Box<RsaPrivateKey>, RsaWrappingAlgorithm),
+    Ecdh(EcdhPrivateKey),
+}
+
+impl WrappingPrivateKey {
+    /// Create an ephemeral wrapping key-pair of the given type, the RSA ones being of the given
+    /// size and used with the given algorithm.
+    fn generate(
+        key_type: WrappingKeyType,
+        rsa_bits: usize,
+        rsa_algorithm: RsaWrappingAlgorithm,
+    ) -> WrappingPrivateKey {
+        let mut rng = rand::thread_rng();
+        match key_type {
+            WrappingKeyType::Rsa => {
+                WrappingPrivateKey::Rsa(Box::new(wrapping_key_pair(rsa_bits)), rsa_algorithm)
+            }
+            WrappingKeyType::P256 => {
+                WrappingPrivateKey::Ecdh(EcdhPrivateKey::P256(p256::SecretKey::random(&mut rng)))
+            }
+            WrappingKeyType::X25519 => WrappingPrivateKey::Ecdh(EcdhPrivateKey::X25519(
+                x25519_dalek::StaticSecret::random_from_rng(rng),
+            )),
+        }
+    }
+
+    /// The public part of the key-pair, as sent to the keybroker server.
+    fn public_key(&self) -> Result<PublicWrappingKey> {
+        match self {
+            WrappingPrivateKey::Rsa(priv_key, algorithm) => {
+                rsa_wrapping_key(&priv_key.to_public_key(), *algorithm)
+            }
+            WrappingPrivateKey::Ecdh(priv_key) => Ok(priv_key.public_wrapping_key()),
+        }
+    }
+
+    /// Decrypt a key wrapped to the public part of the key-pair.
+    fn unwrap(&self, wrapped_key: &WrappedKey) -> Result<Zeroizing<Vec<u8>>> {
+        match self {
+            WrappingPrivateKey::Rsa(priv_key, algorithm) => {
+                unwrap_wrapped_key(priv_key, algorithm.name(), wrapped_key)
+            }
+            WrappingPrivateKey::Ecdh(priv_key) => unwrap_ecdh_wrapped_key(priv_key, wrapped_key),
+        }
+    }
+}
+
+/// The wrapping key of an RSA public key, for the given wrapping algorithm.
+fn rsa_wrapping_key(
+    pub_key: &RsaPublicKey,
+    algorithm: RsaWrappingAlgorithm,
+) -> Result<PublicWrappingKey> {
+    let mut wrapping_key = PublicWrappingKey::try_from(pub_key).map_err(|error| {
+        KeybrokerError::RuntimeError(RuntimeErrorKind::UnsupportedWrappingKey(error.to_string()))
+    })?;
+    wrapping_key.alg = algorithm.name().to_string();
+    Ok(wrapping_key)
+}
+
+/// Create a random (version 4) UUID, in its hyphenated form, as the correlation identifier of a key
+/// request.
+fn random_uuid() -> String {
+    uuid::Uuid::new_v4().to_string()
+}
+
+/// Compress data with gzip.
+fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    // Writing to a Vec can't fail.
+    encoder
+        .write_all(data)
+        .expect("Failed to compress the evidence.");
+    encoder.finish().expect("Failed to compress the evidence.")
+}
+
+/// Read the whole body of a response from the keybroker server.
+fn response_body(resp: reqwest::blocking::Response) -> Result<Vec<u8>> {
+    match resp.bytes() {
+        Ok(body) => Ok(body.to_vec()),
+        Err(error) => Err(KeybrokerError::RuntimeError(
+            RuntimeErrorKind::HTTPResponse(format!("{error:?}")),
+        )),
+    }
+}
+
+#[derive(Debug)]
+struct AttestationChallenge {
+    pub challenge: String,
+    pub evidence_submission_url: Url,
+}
+
+/// The KeyBrokerSession models the communication with a keybroker server.
+pub struct KeyBrokerClient {
+    /// The client this session will use to interact with the keybroker server
+    /// over HTTP with post calls. A blocking client is used for simplicity.
+    /// It is shared by all the requests, so that they reuse its connection.
+    /// It is built on first use, so that a failure to build it is reported by the request.
+    client: OnceCell<reqwest::blocking::Client>,
+
+    /// The keybroker URL base address.
+    keybroker_url_base: String,
+
+    /// Called with the evidence before it is submitted, if set.
+    evidence_observer: Option<Box<dyn EvidenceObserver>>,
+
+    /// Told about each stage of the key requests, if set.
+    progress_observer: Option<Box<dyn ProgressObserver>>,
+
+    /// Whether the evidence is submitted gzip-compressed.
+    compress_evidence: bool,
+
+    /// The retrieved keys, if caching is enabled.
+    cache: Option<KeyCache>,
+
+    /// The key-pair the keys are wrapped to, if it is not an ephemeral one.
+    wrapping_key: Option<RsaPrivateKey>,
+
+    /// Whether that key-pair was generated by [`KeyBrokerClient::reuse_wrapping_key`], rather than
+    /// loaded from a file.
+    wrapping_key_generated: bool,
+
+    /// The type of the ephemeral wrapping key-pairs.
+    wrapping_key_type: WrappingKeyType,
+
+    /// The size of the ephemeral RSA wrapping key-pairs, in bits.
+    rsa_wrapping_key_bits: usize,
+
+    /// The algorithm with which the keys are wrapped to RSA key-pairs.
+    rsa_wrapping_algorithm: RsaWrappingAlgorithm,
+
+    /// The headers sent with every request, on top of those of the client.
+    headers: HeaderMap,
+
+    /// How the server is trusted over HTTPS.
+    tls: TlsSettings,
+
+    /// How long the phases of the last key request took.
+    timings: Cell<Timings>,
+
+    /// How many times a key request is restarted when its challenge went stale.
+    challenge_restarts: u32,
+
+    /// The realm extensible measurement binding the wrapping key, if the evidence binds it.
+    binding_measurement: Option<u32>,
+
+    /// How long the evidence provider is given to produce the evidence, if it is limited.
+    evidence_timeout: Option<Duration>,
+
+    /// The correlation identifier sent with every key request, if set, rather than a random one.
+    correlation_id: Option<String>,
+
+    /// The correlation identifier of the last key request.
+    last_correlation_id: RefCell<Option<String>>,
+}
+
+impl fmt::Debug for KeyBrokerClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyBrokerClient")
+            .field("client", &self.client.get())
+            .field("keybroker_url_base", &self.keybroker_url_base)
+            .field("evidence_observer", &self.evidence_observer.is_some())
+            .field("progress_observer", &self.progress_observer.is_some())
+            .field("compress_evidence", &self.compress_evidence)
+            .field("cache_ttl", &self.cache.as_ref().map(KeyCache::ttl))
+            .field("wrapping_key", &self.wrapping_key.is_some())
+            .field("wrapping_key_type", &self.wrapping_key_type)
+            .field("rsa_wrapping_key_bits", &self.rsa_wrapping_key_bits)
+            .field("rsa_wrapping_algorithm", &self.rsa_wrapping_algorithm)
+            .field("headers", &self.headers)
+            .field("root_certificates", &self.tls.root_certificates.len())
+            .field("pinned_spki_sha256", &self.tls.pinned_spki_sha256)
+            .field("accept_invalid_certs", &self.tls.accept_invalid_certs)
+            .field("timings", &self.timings.get())
+            .field("challenge_restarts", &self.challenge_restarts)
+            .field("binding_measurement", &self.binding_measurement)
+            .field("evidence_timeout", &self.evidence_timeout)
+            .field("correlation_id", &self.correlation_id)
+            .field("last_correlation_id", &self.last_correlation_id.borrow())
+            .finish()
+    }
+}
+
+impl KeyBrokerClient {
+    /// Create a session to the keybroker server located at addr:port.
+    ///
+    /// The HTTP client is only built by the first request, which fails with
+    /// [`RuntimeErrorKind::HTTPConnect`] should it not be built. Use [`KeyBrokerClient::builder`]
+    /// to have such failures, and those of the TLS settings, reported at once.
+    pub fn new(endpoint: &str) -> KeyBrokerClient {
+        KeyBrokerClient {
+            client: OnceCell::new(),
+            keybroker_url_base: endpoint.trim_end_matches('/').to_string(),
+            evidence_observer: None,
+            progress_observer: None,
+            compress_evidence: false,
+            cache: None,
+            wrapping_key: None,
+            wrapping_key_generated: false,
+            wrapping_key_type: WrappingKeyType::default(),
+            rsa_wrapping_key_bits: RSA_WRAPPING_KEY_BITS[0],
+            rsa_wrapping_algorithm: RsaWrappingAlgorithm::default(),
+            headers: HeaderMap::new(),
+            tls: TlsSettings::default(),
+            timings: Cell::new(Timings::default()),
+            challenge_restarts: DEFAULT_CHALLENGE_RESTARTS,
+            binding_measurement: None,
+            evidence_timeout: None,
+            correlation_id: None,
+            last_correlation_id: RefCell::new(None),
+        }
+    }
+
+    /// Start building a client, with the settings which can fail to apply, such as the root
+    /// certificates to trust.
+    pub fn builder() -> KeyBrokerClientBuilder {
+        KeyBrokerClientBuilder::default()
+    }
+
+    /// Build the HTTP client again, on its next use, after a change of its settings.
+    fn rebuild_client(&mut self) {
+        self.client = OnceCell::new();
+    }
+
+    /// The HTTP client, built on first use.
+    fn client(self: &KeyBrokerClient) -> Result<&reqwest::blocking::Client> {
+        if let Some(client) = self.client.get() {
+            return Ok(client);
+        }
+        let client = http_client(&self.keybroker_url_base, &self.headers, &self.tls)?;
+        Ok(self.client.get_or_init(|| client))
+    }
+
+    /// Set an observer, which is given the evidence before each submission.
+    pub fn with_evidence_observer(
+        mut self,
+        observer: impl EvidenceObserver + 'static,
+    ) -> KeyBrokerClient {
+        self.evidence_observer = Some(Box::new(observer));
+        self
+    }
+
+    /// Set an observer, which is told about each stage of the key requests.
+    pub fn with_progress_observer(
+        mut self,
+        observer: impl ProgressObserver + 'static,
+    ) -> KeyBrokerClient {
+        self.progress_observer = Some(Box::new(observer));
+        self
+    }
+
+    /// Tell the progress observer, if any, about a stage of a key request.
+    fn report(self: &KeyBrokerClient, event: ProgressEvent) {
+        if let Some(observer) = &self.progress_observer {
+            observer.progress(&event);
+        }
+    }
+
+    /// Tell the progress observer, if any, how a key request ended.
+    fn report_outcome<T>(self: &KeyBrokerClient, result: &Result<T>, size: impl Fn(&T) -> usize) {
+        match result {
+            Ok(key) => self.report(ProgressEvent::KeyReceived {
+                size: size(key),
+                timings: Some(self.timings.get()),
+            }),
+            Err(error) => self.report_failure(error),
+        }
+    }
+
+    /// Tell the progress observer, if any, that a key request failed.
+    fn report_failure(self: &KeyBrokerClient, error: &KeybrokerError) {
+        self.report(ProgressEvent::Failed {
+            code: error.code().cloned(),
+            detail: error.to_string(),
+            timings: Some(self.timings.get()),
+        });
+    }
+
+    /// How long the phases of the last key request took, whether it succeeded or failed. A key
+    /// found in the cache has no phase timed.
+    pub fn timings(self: &KeyBrokerClient) -> Timings {
+        self.timings.get()
+    }
+
+    /// Run a phase of a key request, recording how long it took in its `phase` member of the
+    /// timings, even if it failed.
+    fn timed<T>(
+        self: &KeyBrokerClient,
+        phase: impl FnOnce(&mut Timings) -> &mut Option<Duration>,
+        run: impl FnOnce() -> T,
+    ) -> T {
+        let started = Instant::now();
+        let result = run();
+        let mut timings = self.timings.get();
+        *phase(&mut timings) = Some(started.elapsed());
+        self.timings.set(timings);
+        result
+    }
+
+    /// Submit the evidence gzip-compressed, which saves bandwidth with large evidence. The keybroker
+    /// server must support the gzip Content-Encoding.
+    pub fn compress_evidence(mut self, compress: bool) -> KeyBrokerClient {
+        self.compress_evidence = compress;
+        self
+    }
+
+    /// Restart a key request with a new challenge, and new evidence, up to `restarts` times when the
+    /// keybroker server says that its challenge expired or was already redeemed, as happens when
+    /// the evidence takes longer to generate than the challenges live. Any other failure, such as
+    /// a rejection by the policy, ends the key request. This is [`DEFAULT_CHALLENGE_RESTARTS`]
+    /// by default.
+    pub fn challenge_restarts(mut self, restarts: u32) -> KeyBrokerClient {
+        self.challenge_restarts = restarts;
+        self
+    }
+
+    /// Extend the realm extensible measurement `rem` (0 to 3) with the RFC 7638 thumbprint of the
+    /// wrapping key before producing the evidence, through the RSI device, so that the evidence
+    /// binds the wrapping key for the keybroker servers which check it.
+    ///
+    /// The measurements are only reset when the realm restarts, so this needs a wrapping key that
+    /// stays the same, see [`KeyBrokerClient::wrapping_key_from_pem`] and
+    /// [`KeyBrokerClient::reuse_wrapping_key`], and a measurement that nothing else extends. The
+    /// mock evidence providers, see [`EvidenceProvider::is_mock`], are left out.
+    pub fn bind_wrapping_key(mut self, rem: u32) -> KeyBrokerClient {
+        self.binding_measurement = Some(rem);
+        self
+    }
+
+    /// Give up on the evidence if the evidence provider does not produce it within `timeout`, as
+    /// happens when the attestation request hangs on some platforms, failing with
+    /// [`RuntimeErrorKind::EvidenceGenerationTimeout`]. The challenge is then cancelled, so that
+    /// the keybroker server does not hold it until it expires.
+    ///
+    /// The provider is run on a worker thread, which is abandoned if it does not finish in time.
+    /// The providers which can not be moved to another thread, see
+    /// [`EvidenceProvider::detach`], are never timed out.
+    pub fn evidence_timeout(mut self, timeout: Duration) -> KeyBrokerClient {
+        self.evidence_timeout = Some(timeout);
+        self
+    }
+
+    /// Send `correlation_id` with every key request, rather than a random UUID for each, so that
+    /// the keybroker server logs and lifecycle events of the key requests can be tied to a job of
+    /// the caller. It is sanitised as the server does, see
+    /// [`keybroker_common::sanitise_correlation_id`].
+    pub fn correlation_id(mut self, correlation_id: &str) -> KeyBrokerClient {
+        self.correlation_id = sanitise_correlation_id(correlation_id);
+        self
+    }
+
+    /// The correlation identifier sent with the last key request, which the keybroker server
+    /// includes in its logs and lifecycle events for that request. A key found in the cache has
+    /// none, as no request was made.
+    pub fn last_correlation_id(self: &KeyBrokerClient) -> Option<String> {
+        self.last_correlation_id.borrow().clone()
+    }
+
+    /// Start a key request: reset the timings, and pick its correlation identifier.
+    fn start_request(self: &KeyBrokerClient) {
+        self.timings.take();
+        self.last_correlation_id.replace(Some(
+            self.correlation_id.clone().unwrap_or_else(random_uuid),
+        ));
+    }
+
+    /// Keep the keys returned by [`KeyBrokerClient::get_key`] in memory for `ttl`, so that
+    /// requesting the same key again within that time does not attest again. The cache is
+    /// disabled by default, and it is never written to disk.
+    pub fn cache_ttl(mut self, ttl: Duration) -> KeyBrokerClient {
+        self.cache = Some(KeyCache::new(ttl));
+        self
+    }
+
+    /// Have the keys wrapped to the RSA key-pair in a PEM file, such as one provisioned in the
+    /// TEE, rather than to an ephemeral key-pair generated for each request. The key must be
+    /// between 2048 and 4096 bits long.
+    ///
+    /// Note that a session exported from a [`PendingKeyRequest`] then holds this key-pair.
+    pub fn wrapping_key_from_pem(mut self, path: &Path) -> Result<KeyBrokerClient> {
+        self.wrapping_key = Some(wrapping_key::load_wrapping_key(path)?);
+        self.wrapping_key_generated = false;
+        Ok(self)
+    }
+
+    /// Generate an ephemeral wrapping key-pair once, and have all the keys wrapped to it, rather
+    /// than generating one for each request. This does nothing if a key-pair was already set.
+    pub fn reuse_wrapping_key(mut self) -> KeyBrokerClient {
+        if self.wrapping_key.is_none() {
+            self.wrapping_key = Some(wrapping_key_pair(self.rsa_wrapping_key_bits));
+            self.wrapping_key_generated = true;
+        }
+        self
+    }
+
+    /// Have the keys wrapped to ephemeral key-pairs of the given type, rather than to RSA ones.
+    ///
+    /// This only applies to the key requests completed at once, such as with
+    /// [`KeyBrokerClient::get_key`], and not to the split key requests, whose wrapping keys are
+    /// always RSA ones. A key-pair set with [`KeyBrokerClient::wrapping_key_from_pem`] or
+    /// [`KeyBrokerClient::reuse_wrapping_key`] takes precedence.
+    pub fn wrapping_key_type(mut self, key_type: WrappingKeyType) -> KeyBrokerClient {
+        self.wrapping_key_type = key_type;
+        self
+    }
+
+    /// Generate the ephemeral RSA wrapping key-pairs with this many bits, one of
+    /// [`RSA_WRAPPING_KEY_BITS`], rather than 2048. Larger key-pairs take longer to generate.
+    ///
+    /// A key-pair already generated by [`KeyBrokerClient::reuse_wrapping_key`] is generated again
+    /// with this size.
+    pub fn rsa_wrapping_key_bits(mut self, bits: usize) -> Result<KeyBrokerClient> {
+        if !RSA_WRAPPING_KEY_BITS.contains(&bits) {
+            return Err(KeybrokerError::RuntimeError(
+                RuntimeErrorKind::UnsupportedWrappingKey(format!(
+                    "{bits}-bit RSA wrapping keys are not supported, they must be {:?} bits long",
+                    RSA_WRAPPING_KEY_BITS
+                )),
+            ));
+        }
+        self.rsa_wrapping_key_bits = bits;
+        if self.wrapping_key_generated && self.wrapping_key.is_some() {
+            self.wrapping_key = Some(wrapping_key_pair(bits));
+        }
+        Ok(self)
+    }
+
+    /// Have the keys wrapped to RSA key-pairs with this algorithm, rather than RSA1_5, including
+    /// to the key-pairs set with [`KeyBrokerClient::wrapping_key_from_pem`] or
+    /// [`KeyBrokerClient::reuse_wrapping_key`].
+    pub fn rsa_wrapping_algorithm(mut self, algorithm: RsaWrappingAlgorithm) -> KeyBrokerClient {
+        self.rsa_wrapping_algorithm = algorithm;
+        self
+    }
+
+    /// Send this User-Agent with every request, instead of the default one of the HTTP library.
+    pub fn user_agent(mut self, user_agent: &str) -> Result<KeyBrokerClient> {
+        let value = header_value(header::USER_AGENT.as_str(), user_agent)?;
+        self.headers.insert(header::USER_AGENT, value);
+        self.rebuild_client();
+        Ok(self)
+    }
+
+    /// Send a header with every request, such as one an API gateway routes on. A header can be
+    /// given several times, with different values. The headers that the client sets itself
+    /// (Host, Content-Length, Content-Type, Content-Encoding, Transfer-Encoding and Connection)
+    /// are refused.
+    pub fn default_header(mut self, name: &str, value: &str) -> Result<KeyBrokerClient> {
+        let header_name = HeaderName::from_bytes(name.as_bytes()).map_err(|error| {
+            KeybrokerError::RuntimeError(RuntimeErrorKind::InvalidHeader(
+                name.to_string(),
+                error.to_string(),
+            ))
+        })?;
+        if RESTRICTED_HEADERS.contains(&header_name) {
+            return Err(KeybrokerError::RuntimeError(
+                RuntimeErrorKind::RestrictedHeader(name.to_string()),
+            ));
+        }
+
+        let value = header_value(name, value)?;
+        self.headers.append(header_name, value);
+        self.rebuild_client();
+        Ok(self)
+    }
+
+    /// Trust the certificates of a PEM bundle as roots for HTTPS, on top of the built-in ones,
+    /// such as the self-signed certificate of a demonstration keybroker server.
+    pub fn root_certificates(mut self, pem: &[u8]) -> Result<KeyBrokerClient> {
+        let invalid = |detail: String| {
+            KeybrokerError::RuntimeError(RuntimeErrorKind::InvalidRootCertificate(detail))
+        };
+
+        let certificates = CertificateDer::pem_slice_iter(pem)
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|error| invalid(error.to_string()))?;
+        if certificates.is_empty() {
+            return Err(invalid("no certificate found".to_string()));
+        }
+        tls::check_root_certificates(&certificates).map_err(|error| invalid(error.to_string()))?;
+        self.tls.root_certificates.extend(certificates);
+        self.rebuild_client();
+        self.client()?;
+        Ok(self)
+    }
+
+    /// Only trust a keybroker server whose certificate has this public key, given as the SHA-256
+    /// digest of its DER-encoded SubjectPublicKeyInfo (see [`tls::spki_sha256`]), on top of the
+    /// verification of its certificate chain. A server presenting another key fails to connect,
+    /// with [`RuntimeErrorKind::HTTPConnect`].
+    pub fn pinned_spki_sha256(mut self, digest: [u8; 32]) -> KeyBrokerClient {
+        self.tls.pinned_spki_sha256 = Some(digest);
+        self.rebuild_client();
+        self
+    }
+
+    /// Accept any certificate from the keybroker server, whoever issued it and whatever host it
+    /// is for, which leaves the keys open to whoever is in the middle. This is only for lab
+    /// setups, and a warning is logged when it is set. A pinned public key is still checked.
+    pub fn danger_accept_invalid_certs(mut self) -> KeyBrokerClient {
+        log::warn!(
+            "The certificate of the keybroker server at {} is NOT verified: anyone in the middle \
+             can impersonate it. Only do this in a lab.",
+            self.keybroker_url_base
+        );
+        self.tls.accept_invalid_certs = true;
+        self.rebuild_client();
+        self
+    }
+
+    /// The key-pair to have the keys wrapped to.
+    fn wrapping_key_pair(self: &KeyBrokerClient) -> RsaPrivateKey {
+        match &self.wrapping_key {
+            Some(wrapping_key) => wrapping_key.clone(),
+            None => wrapping_key_pair(self.rsa_wrapping_key_bits),
+        }
+    }
+
+    /// The key-pair to have a key wrapped to, in a key request completed at once.
+    fn request_wrapping_key(self: &KeyBrokerClient) -> WrappingPrivateKey {
+        match &self.wrapping_key {
+            Some(wrapping_key) => {
+                WrappingPrivateKey::Rsa(Box::new(wrapping_key.clone()), self.rsa_wrapping_algorithm)
+            }
+            None => WrappingPrivateKey::generate(
+                self.wrapping_key_type,
+                self.rsa_wrapping_key_bits,
+                self.rsa_wrapping_algorithm,
+            ),
+        }
+    }
+
+    /// Remove a key from the cache, so that the next request for it attests again.
+    pub fn invalidate(self: &KeyBrokerClient, key_name: &str) {
+        if let Some(cache) = &self.cache {
+            cache.invalidate(key_name);
+        }
+    }
+
+    /// Get the capabilities of the keybroker server.
+    pub fn server_info(self: &KeyBrokerClient) -> Result<ServerInfo> {
+        let info_url = format!("{}/keys/v1/info", self.keybroker_url_base);
+
+        log::info!("Requesting the keybroker server information from URL {info_url}");
+
+        match self.client()?.get(&info_url).send() {
+            Ok(resp) => match resp.status() {
+                StatusCode::OK => parse_server_info(&response_body(resp)?),
+                status => Err(KeybrokerError::RuntimeError(
+                    RuntimeErrorKind::HTTPResponse(format!("{status:?}")),
+                )),
+            },
+            Err(error) => Err(KeybrokerError::RuntimeError(RuntimeErrorKind::HTTPConnect(
+                info_url,
+                format!("{error:?}"),
+            ))),
+        }
+    }
+
+    /// Wait for the keybroker server to answer, for at most `timeout`, such as when it is started
+    /// along with the client.
+    ///
+    /// The information endpoint of the server is polled, over the same connections, with the same
+    /// TLS configuration and headers as the key requests, each poll being allowed a quarter of
+    /// `timeout`, between 250 milliseconds and 5 seconds. Older servers, which do not have it, are
+    /// ready as soon as they answer. When a poll fails without an answer, the server is still
+    /// ready if it accepts TCP connections, the key requests telling what is wrong with it. Fails
+    /// with [`RuntimeErrorKind::ServerNotReady`], with the last error, if the server did not
+    /// answer in time.
+    pub fn wait_for_server(self: &KeyBrokerClient, timeout: Duration) -> Result<()> {
+        let info_url = format!("{}/keys/v1/info", self.keybroker_url_base);
+        let deadline = Instant::now() + timeout;
+        let poll_timeout = server_poll_timeout(timeout);
+        let client = self.client()?;
+
+        log::info!("Waiting for the keybroker server at {info_url}");
+
+        loop {
+            let last_error = match client.get(&info_url).timeout(poll_timeout).send() {
+                Ok(resp) if resp.status().is_success() => return Ok(()),
+                Ok(resp) if resp.status() == StatusCode::NOT_FOUND => {
+                    log::debug!("The keybroker server has no information endpoint, it is older.");
+                    return Ok(());
+                }
+                // A proxy in front of a server which is still starting, for instance.
+                Ok(resp) => format!("{info_url} answered {}", resp.status()),
+                Err(error) if accepts_connections(&info_url, poll_timeout) => {
+                    log::debug!(
+                        "The keybroker server accepts connections, but did not answer: {error:?}"
+                    );
+                    return Ok(());
+                }
+                Err(error) => format!("{error:?}"),
+            };
+            log::debug!("The keybroker server is not ready yet: {last_error}");
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(KeybrokerError::RuntimeError(
+                    RuntimeErrorKind::ServerNotReady(self.keybroker_url_base.clone(), last_error),
+                ));
+            }
+            std::thread::sleep(SERVER_POLL_INTERVAL.min(deadline - now));
+        }
+    }
+
+    /// The first API call to request the key. This gets all the required
+    /// attestation challenge material: the challenge it self, and the url
+    /// where to submit the evidence.
+    fn request_key(
+        self: &KeyBrokerClient,
+        key_name: &str,
+        pubkey: &PublicWrappingKey,
+        return_attestation_result: bool,
+    ) -> Result<AttestationChallenge> {
+        let key_request = BackgroundCheckKeyRequest {
+            pubkey: pubkey.clone(),
+            // Only send the flag when set, so that requests stay unchanged for older servers.
+            return_attestation_result: return_attestation_result.then_some(true),
+            correlation_id: self.last_correlation_id(),
+        };
+
+        // Construct the URL to request the key.
+        let key_request_url = format!("{}/keys/v1/key/{}", self.keybroker_url_base, key_name);
+        let key_request_url = Url::parse(&key_request_url).map_err(|error| {
+            KeybrokerError::RuntimeError(RuntimeErrorKind::InvalidEndpoint(
+                self.keybroker_url_base.clone(),
+                error.to_string(),
+            ))
+        })?;
+
+        log::info!(
+            "Requesting key named '{key_name}' from the keybroker server with URL {key_request_url}{}",
+            self.last_correlation_id()
+                .map(|correlation_id| format!(", correlation ID {correlation_id}"))
+                .unwrap_or_default()
+        );
+        self.report(ProgressEvent::ChallengeRequested {
+            key_name: key_name.to_string(),
+            url: key_request_url.to_string(),
+        });
+
+        // Make the first API call to request the key.
+        match self
+            .client()?
+            .post(key_request_url.clone())
+            .json(&key_request)
+            .send()
+        {
+            Ok(resp) if !resp.status().is_success() => Err(error_response(resp)),
+            Ok(resp) => {
+                // The evidence submission URL may be relative, for example when the keybroker
+                // server is behind a reverse proxy.
+                let evidence_submission_url = resolve_location(
+                    &key_request_url,
+                    resp.headers()
+                        .get(reqwest::header::LOCATION)
+                        .map(|location| location.as_bytes()),
+                )?;
+
+                let ac = parse_attestation_challenge(&response_body(resp)?)?;
+                self.report(ProgressEvent::ChallengeReceived {
+                    nonce_length: base64::decode("the attestation challenge", &ac.challenge)
+                        .map(|nonce| nonce.len())
+                        .unwrap_or_default(),
+                    accept: ac.accept,
+                });
+
+                Ok(AttestationChallenge {
+                    challenge: ac.challenge,
+                    evidence_submission_url,
+                })
+            }
+            Err(error) => Err(KeybrokerError::RuntimeError(RuntimeErrorKind::HTTPConnect(
+                key_request_url.to_string(),
+                format!("{error:?}"),
+            ))),
+        }
+    }
+
+    /// Post the evidence to the given URL.
+    fn post_evidence(
+        self: &KeyBrokerClient,
+        evidence_submission_url: &Url,
+        media_type: &str,
+        evidence: &[u8],
+    ) -> Result<reqwest::blocking::Response> {
+        let mut request = self
+            .client()?
+            .post(evidence_submission_url.clone())
+            .header(reqwest::header::CONTENT_TYPE, media_type);
+        request = if self.compress_evidence {
+            request
+                .header(reqwest::header::CONTENT_ENCODING, "gzip")
+                .body(gzip(base64::encode(evidence).as_bytes()))
+        } else {
+            request.body(base64::encode(evidence))
+        };
+
+        request.send().map_err(|error| {
+            KeybrokerError::RuntimeError(RuntimeErrorKind::HTTPConnect(
+                evidence_submission_url.to_string(),
+                format!("{error:?}"),
+            ))
+        })
+    }
+
+    /// Submit the evidence.
+    /// In case of success, this returns the decoded key from the server.
+    fn submit_evidence(
+        self: &KeyBrokerClient,
+        evidence_submission_url: &Url,
+        media_type: &str,
+        evidence: &[u8],
+    ) -> Result<WrappedKey> {
+        log::info!("Submitting evidence to URL {evidence_submission_url}");
+
+        // Make the second API call to submit the evidence.
+        let mut resp = self.post_evidence(evidence_submission_url, media_type, evidence)?;
+
+        // Follow a single temporary or permanent redirect, which preserves the method and the body,
+        // as some reverse proxies use them to move the evidence submission endpoint.
+        if matches!(
+            resp.status(),
+            StatusCode::TEMPORARY_REDIRECT | StatusCode::PERMANENT_REDIRECT
+        ) {
+            let redirect_url = resolve_location(
+                evidence_submission_url,
+                resp.headers()
+                    .get(reqwest::header::LOCATION)
+                    .map(|location| location.as_bytes()),
+            )?;
+
+            // Read the body of the redirect, so that its connection can be reused.
+            let _ = resp.bytes();
+
+            log::info!("Evidence submission redirected to URL {redirect_url}");
+            resp = self.post_evidence(&redirect_url, media_type, evidence)?;
+        }
+        self.report(ProgressEvent::EvidenceSubmitted {
+            url: resp.url().to_string(),
+        });
+
+        match resp.status() {
+            // Assume first that we are following the happy path: our evidence was "accepted".
+            StatusCode::OK => parse_wrapped_key_data(&response_body(resp)?),
+
+            // Our evidence has been rejected for some "good" reasons.
+            StatusCode::FORBIDDEN => {
+                let error_info = parse_error_information(&response_body(resp)?)?;
+                Err(crate::error::Error::AttestationFailure(
+                    error_info.r#type,
+                    error_info.detail,
+                    error_info.instance,
+                ))
+            }
+
+            // The challenge was already used, typically by an earlier attempt of a retried submission.
+            StatusCode::CONFLICT => {
+                let error_info = parse_error_information(&response_body(resp)?)?;
+                Err(KeybrokerError::RuntimeError(
+                    RuntimeErrorKind::ChallengeAlreadyRedeemed(error_info.detail),
+                ))
+            }
+
+            // We have a genuine and/or unhandled error :-()
+            _ => Err(error_response(resp)),
+        }
+    }
+
+    /// Get the wrapped key, decryption left to the caller, with the algorithm set with
+    /// [`KeyBrokerClient::rsa_wrapping_algorithm`].
+    pub fn get_wrapped_key<EP: EvidenceProvider>(
+        self: &KeyBrokerClient,
+        key_name: &str,
+        evidence_provider: &EP,
+        pub_key: &RsaPublicKey,
+    ) -> Result<Vec<u8>> {
+        self.start_request();
+        let result = rsa_wrapping_key(pub_key, self.rsa_wrapping_algorithm)
+            .and_then(|pubkey| {
+                self.retrieve_wrapped_key(key_name, evidence_provider, &pubkey, false)
+            })
+            .map(|wrapped_key| wrapped_key.ciphertext);
+        self.report_outcome(&result, Vec::len);
+        result
+    }
+
+    /// Request the challenge for a key, keeping the code of the errors reported by the server.
+    fn request_challenge(
+        self: &KeyBrokerClient,
+        key_name: &str,
+        pubkey: &PublicWrappingKey,
+        return_attestation_result: bool,
+    ) -> Result<AttestationChallenge> {
+        match self.request_key(key_name, pubkey, return_attestation_result) {
+            Ok(data) => Ok(data),
+            // Errors reported by the server keep their code, so that applications can match on it.
+            Err(error @ KeybrokerError::RuntimeError(RuntimeErrorKind::ServerError(_, _))) => {
+                Err(error)
+            }
+            Err(error) => Err(KeybrokerError::RuntimeError(
+                RuntimeErrorKind::ChallengeRetrieval(format!("{error:?}")),
+            )),
+        }
+    }
+
+    /// Let the observer see the evidence, then submit it.
+    fn submit_generated_evidence(
+        self: &KeyBrokerClient,
+        challenge: &str,
+        evidence_submission_url: &Url,
+        media_type: &str,
+        evidence: &[u8],
+    ) -> Result<WrappedKey> {
+        // Let the observer see the evidence before submitting it, so that it gets it even if the
+        // submission fails.
+        if let Some(observer) = &self.evidence_observer {
+            observer.evidence_generated(&GeneratedEvidence {
+                evidence,
+                challenge,
+                media_type,
+            })?;
+        }
+        self.report(ProgressEvent::EvidenceGenerated {
+            size: evidence.len(),
+            media_type: media_type.to_string(),
+        });
+
+        self.timed(
+            |timings| &mut timings.evidence_round_trip,
+            || self.submit_evidence(evidence_submission_url, media_type, evidence),
+        )
+    }
+
+    /// Get the wrapped key, optionally along with the attestation result, restarting with a new
+    /// challenge when the server says that the challenge went stale.
+    fn retrieve_wrapped_key<EP: EvidenceProvider>(
+        self: &KeyBrokerClient,
+        key_name: &str,
+        evidence_provider: &EP,
+        pubkey: &PublicWrappingKey,
+        return_attestation_result: bool,
+    ) -> Result<WrappedKey> {
+        let mut restarts = 0;
+        loop {
+            match self.attest_with_challenge(
+                key_name,
+                evidence_provider,
+                pubkey,
+                return_attestation_result,
+            ) {
+                Err(error) if restarts < self.challenge_restarts && is_stale_challenge(&error) => {
+                    restarts += 1;
+                    log::warn!(
+                        "Restarting the request for key '{key_name}' with a new challenge \
+                         ({restarts}/{}): {error}",
+                        self.challenge_restarts
+                    );
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Have the evidence provider produce the evidence for a challenge, on a worker thread which is
+    /// abandoned after the evidence timeout, if there is one.
+    fn generate_evidence<EP: EvidenceProvider>(
+        self: &KeyBrokerClient,
+        evidence_provider: &EP,
+        challenge: &str,
+    ) -> Result<Vec<u8>> {
+        let Some(timeout) = self.evidence_timeout else {
+            return evidence_provider.get_evidence(challenge);
+        };
+        let Some(worker_provider) = evidence_provider.detach() else {
+            log::warn!(
+                "The {} evidence provider can not be run on a worker thread, it is not timed out.",
+                evidence_provider.name()
+            );
+            return evidence_provider.get_evidence(challenge);
+        };
+
+        // A hung attestation request can not be interrupted, so the worker is left behind if it
+        // does not finish in time, its result being dropped.
+        let (sender, receiver) = mpsc::channel();
+        let challenge = challenge.to_string();
+        std::thread::Builder::new()
+            .name("evidence-generation".to_string())
+            .spawn(move || {
+                let _ = sender.send(worker_provider.get_evidence(&challenge));
+            })
+            .map_err(|error| {
+                KeybrokerError::RuntimeError(RuntimeErrorKind::EvidenceGeneration(
+                    error.to_string(),
+                ))
+            })?;
+
+        match receiver.recv_timeout(timeout) {
+            Ok(result) => result,
+            Err(RecvTimeoutError::Timeout) => {
+                log::warn!(
+                    "The {} evidence provider did not produce the evidence within {timeout:?}, giving up on it.",
+                    evidence_provider.name()
+                );
+                Err(KeybrokerError::RuntimeError(
+                    RuntimeErrorKind::EvidenceGenerationTimeout(evidence_provider.name(), timeout),
+                ))
+            }
+            Err(RecvTimeoutError::Disconnected) => Err(KeybrokerError::RuntimeError(
+                RuntimeErrorKind::EvidenceGeneration(format!(
+                    "the {} evidence provider panicked",
+                    evidence_provider.name()
+                )),
+            )),
+        }
+    }
+
+    /// Tell the keybroker server that no evidence will be submitted for a challenge, so that it
+    /// releases it. This is only a courtesy, so its failures, such as with older servers which can
+    /// not cancel challenges, are ignored.
+    fn cancel_challenge(self: &KeyBrokerClient, evidence_submission_url: &Url) {
+        log::info!("Cancelling the challenge at URL {evidence_submission_url}");
+        let client = match self.client() {
+            Ok(client) => client,
+            Err(error) => {
+                log::debug!("Failed to cancel the challenge: {error}");
+                return;
+            }
+        };
+        match client.delete(evidence_submission_url.clone()).send() {
+            Ok(resp) if resp.status().is_success() => {}
+            Ok(resp) => log::debug!(
+                "The keybroker server did not cancel the challenge: {}",
+                resp.status()
+            ),
+            Err(error) => log::debug!("Failed to cancel the challenge: {error:?}"),
+        }
+    }
+
+    /// Request a challenge, then produce and submit the evidence for it.
+    fn attest_with_challenge<EP: EvidenceProvider>(
+        self: &KeyBrokerClient,
+        key_name: &str,
+        evidence_provider: &EP,
+        pubkey: &PublicWrappingKey,
+        return_attestation_result: bool,
+    ) -> Result<WrappedKey> {
+        // First API call: request the challenge.
+        let data = self.timed(
+            |timings| &mut timings.challenge_round_trip,
+            || self.request_challenge(key_name, pubkey, return_attestation_result),
+        )?;
+
+        // Produce the evidence, binding the wrapping key first if asked to.
+        let evidence = match self.timed(
+            |timings| &mut timings.evidence_generation,
+            || {
+                match self.binding_measurement {
+                    Some(_) if evidence_provider.is_mock() => log::warn!(
+                        "The wrapping key is not bound to the mock evidence of the {}.",
+                        evidence_provider.name()
+                    ),
+                    Some(rem) => rsi::bind_wrapping_key(rem, pubkey)?,
+                    None => {}
+                }
+                self.generate_evidence(evidence_provider, &data.challenge)
+            },
+        ) {
+            Ok(evidence) => evidence,
+            Err(error) => {
+                // Be kind to the keybroker server, which would otherwise hold the challenge until
+                // it expires.
+                self.cancel_challenge(&data.evidence_submission_url);
+                return Err(match error {
+                    error @ KeybrokerError::RuntimeError(
+                        RuntimeErrorKind::EvidenceGenerationTimeout(_, _),
+                    ) => error,
+                    error => KeybrokerError::RuntimeError(RuntimeErrorKind::EvidenceGeneration(
+                        format!("{error:?}"),
+                    )),
+                });
+            }
+        };
+
+        // Second API call: submit the evidence, and return the attestation result.
+        self.submit_generated_evidence(
+            &data.challenge,
+            &data.evidence_submission_url,
+            &evidence_provider.media_type(),
+            &evidence,
+        )
+    }
+
+    /// Request the challenge for a key, without submitting any evidence.
+    ///
+    /// This is the first half of a split key request, for evidence produced by an attester without
+    /// network access: the returned request can be saved with
+    /// [`PendingKeyRequest::export_session`], and completed with
+    /// [`KeyBrokerClient::complete_key_request`], possibly by another process.
+    pub fn start_key_request(
+        self: &KeyBrokerClient,
+        key_name: &str,
+        return_attestation_result: bool,
+    ) -> Result<PendingKeyRequest> {
+        self.start_request();
+        let wrapping_key = self.timed(
+            |timings| &mut timings.wrapping_key_generation,
+            || self.wrapping_key_pair(),
+        );
+        let data = self
+            .timed(
+                |timings| &mut timings.challenge_round_trip,
+                || {
+                    let pubkey = rsa_wrapping_key(
+                        &RsaPublicKey::from(&wrapping_key),
+                        self.rsa_wrapping_algorithm,
+                    )?;
+                    self.request_challenge(key_name, &pubkey, return_attestation_result)
+                },
+            )
+            .inspect_err(|error| self.report_failure(error))?;
+
+        Ok(PendingKeyRequest {
+            key_name: key_name.to_string(),
+            challenge: data.challenge,
+            evidence_submission_url: data.evidence_submission_url,
+            return_attestation_result,
+            wrapping_key,
+            wrapping_algorithm: self.rsa_wrapping_algorithm,
+        })
+    }
+
+    /// Submit evidence of media type `media_type` (for example [`EVIDENCE_MEDIA_TYPE`]), produced
+    /// for the challenge of a pending key request, and return the plain text key, along with the
+    /// attestation result if it was requested.
+    ///
+    /// Fails with [`RuntimeErrorKind::StaleSession`] if the keybroker server no longer knows the
+    /// challenge, for example because it has expired.
+    ///
+    /// [`EVIDENCE_MEDIA_TYPE`]: crate::EVIDENCE_MEDIA_TYPE
+    pub fn complete_key_request(
+        self: &KeyBrokerClient,
+        request: &PendingKeyRequest,
+        media_type: &str,
+        evidence: &[u8],
+    ) -> Result<RetrievedKey> {
+        self.timings.take();
+        let result = self.complete_pending_key_request(request, media_type, evidence);
+        self.report_outcome(&result, |retrieved_key| retrieved_key.key.len());
+        result
+    }
+
+    fn complete_pending_key_request(
+        self: &KeyBrokerClient,
+        request: &PendingKeyRequest,
+        media_type: &str,
+        evidence: &[u8],
+    ) -> Result<RetrievedKey> {
+        let wrapped_key = match self.submit_generated_evidence(
+            &request.challenge,
+            &request.evidence_submission_url,
+            media_type,
+            evidence,
+        ) {
+            Ok(wrapped_key) => wrapped_key,
+            Err(KeybrokerError::AttestationFailure(
+                ErrorCode::ChallengeNotFound | ErrorCode::ChallengeExpired,
+                detail,
+                _,
+            ))
+            | Err(KeybrokerError::RuntimeError(RuntimeErrorKind::ChallengeAlreadyRedeemed(
+                detail,
+            ))) => {
+                return Err(KeybrokerError::RuntimeError(
+                    RuntimeErrorKind::StaleSession(detail),
+                ))
+            }
+            Err(error) => return Err(error),
+        };
+
+        let key = self.timed(
+            |timings| &mut timings.unwrap,
+            || {
+                unwrap_wrapped_key(
+                    &request.wrapping_key,
+                    request.wrapping_algorithm.name(),
+                    &wrapped_key,
+                )
+            },
+        )?;
+        Ok(RetrievedKey {
+            key: SecretKeyMaterial::new(&key),
+            attestation_result: wrapped_key.attestation_result,
+        })
+    }
+
+    /// This returns the plain text.
+    ///
+    /// If caching is enabled, a key retrieved within the cache TTL is returned without attesting.
+    /// A key request whose challenge expired before the evidence was submitted is restarted, as
+    /// set with [`KeyBrokerClient::challenge_restarts`].
+    pub fn get_key<EP: EvidenceProvider>(
+        self: &KeyBrokerClient,
+        key_name: &str,
+        evidence_provider: &EP,
+    ) -> Result<SecretKeyMaterial> {
+        if let Some(key) = self.cache.as_ref().and_then(|cache| cache.get(key_name)) {
+            log::info!("Key '{key_name}' found in the cache");
+            self.timings.take();
+            self.last_correlation_id.take();
+            self.report(ProgressEvent::KeyReceived {
+                size: key.len(),
+                timings: Some(Timings::default()),
+            });
+            return Ok(SecretKeyMaterial::new(&key));
+        }
+
+        self.retrieve_key(key_name, evidence_provider, false)
+            .map(|retrieved_key| retrieved_key.key)
+    }
+
+    /// This returns the plain text in an ordinary buffer, which is neither redacted nor wiped from
+    /// memory when it is dropped. It is meant for short demonstrations: prefer `get_key`.
+    pub fn get_key_raw<EP: EvidenceProvider>(
+        self: &KeyBrokerClient,
+        key_name: &str,
+        evidence_provider: &EP,
+    ) -> Result<Vec<u8>> {
+        self.get_key(key_name, evidence_provider)
+            .map(|key| key.expose_secret().to_vec())
+    }
+
+    /// This returns the value of a key holding a structured secret document, deserialised from
+    /// JSON into `T`, which can simply be `serde_json::Value`.
+    ///
+    /// The caching rules are the same as for `get_key`.
+    pub fn get_key_json<T: DeserializeOwned, EP: EvidenceProvider>(
+        self: &KeyBrokerClient,
+        key_name: &str,
+        evidence_provider: &EP,
+    ) -> Result<T> {
+        let key = self.get_key(key_name, evidence_provider)?;
+        serde_json::from_slice(key.expose_secret()).map_err(|error| {
+            KeybrokerError::RuntimeError(RuntimeErrorKind::InvalidKeyDocument(error.to_string()))
+        })
+    }
+
+    /// This returns the plain text, along with the attestation result that the keybroker server
+    /// obtained from the verifier.
+    ///
+    /// This always attests, as the attestation result is expected to be fresh, but the key is
+    /// still cached if caching is enabled.
+    pub fn get_key_with_attestation_result<EP: EvidenceProvider>(
+        self: &KeyBrokerClient,
+        key_name: &str,
+        evidence_provider: &EP,
+    ) -> Result<RetrievedKey> {
+        self.retrieve_key(key_name, evidence_provider, true)
+    }
+
+    fn retrieve_key<EP: EvidenceProvider>(
+        self: &KeyBrokerClient,
+        key_name: &str,
+        evidence_provider: &EP,
+        return_attestation_result: bool,
+    ) -> Result<RetrievedKey> {
+        self.start_request();
+        let result = self.attest_for_key(key_name, evidence_provider, return_attestation_result);
+        self.report_outcome(&result, |retrieved_key| retrieved_key.key.len());
+        result
+    }
+
+    /// Attest, then unwrap and cache the returned key.
+    fn attest_for_key<EP: EvidenceProvider>(
+        self: &KeyBrokerClient,
+        key_name: &str,
+        evidence_provider: &EP,
+        return_attestation_result: bool,
+    ) -> Result<RetrievedKey> {
+        let priv_key = self.timed(
+            |timings| &mut timings.wrapping_key_generation,
+            || self.request_wrapping_key(),
+        );
+        let pubkey = priv_key.public_key()?;
+
+        let wrapped_key = self.retrieve_wrapped_key(
+            key_name,
+            evidence_provider,
+            &pubkey,
+            return_attestation_result,
+        )?;
+
+        let key = self.timed(
+            |timings| &mut timings.unwrap,
+            || priv_key.unwrap(&wrapped_key),
+        )?;
+        // The ephemeral private key is not needed anymore, it is wiped as it is dropped.
+        drop(priv_key);
+        if let Some(cache) = &self.cache {
+            cache.insert(key_name, &key);
+        }
+
+        Ok(RetrievedKey {
+            key: SecretKeyMaterial::new(&key),
+            attestation_result: wrapped_key.attestation_result,
+        })
+    }
+}
+
+/// Builds a [`KeyBrokerClient`] out of the settings which can fail to apply, reporting their
+/// failures, and that of the HTTP client, from [`KeyBrokerClientBuilder::build`]. The other
+/// settings are applied to the built client.
+#[derive(Debug, Default)]
+pub struct KeyBrokerClientBuilder {
+    /// The keybroker server to connect to.
+    endpoint: Option<String>,
+
+    /// The PEM files of the root certificates to trust.
+    root_certificates: Vec<PathBuf>,
+
+    /// The public key the server certificate must have, if pinned.
+    pinned_spki_sha256: Option<[u8; 32]>,
+
+    /// Whether any certificate is accepted from the server.
+    accept_invalid_certs: bool,
+}
+
+impl KeyBrokerClientBuilder {
+    /// The keybroker server to connect to, such as `https://keybroker.example:8088`.
+    pub fn endpoint(mut self, endpoint: &str) -> KeyBrokerClientBuilder {
+        self.endpoint = Some(endpoint.to_string());
+        self
+    }
+
+    /// Trust the certificates of this PEM file as roots for HTTPS, see
+    /// [`KeyBrokerClient::root_certificates`].
+    pub fn root_certificate(mut self, path: impl Into<PathBuf>) -> KeyBrokerClientBuilder {
+        self.root_certificates.push(path.into());
+        self
+    }
+
+    /// Only trust a server certificate with this public key, see
+    /// [`KeyBrokerClient::pinned_spki_sha256`].
+    pub fn pinned_spki_sha256(mut self, digest: [u8; 32]) -> KeyBrokerClientBuilder {
+        self.pinned_spki_sha256 = Some(digest);
+        self
+    }
+
+    /// Accept any certificate from the server, see
+    /// [`KeyBrokerClient::danger_accept_invalid_certs`].
+    pub fn danger_accept_invalid_certs(mut self) -> KeyBrokerClientBuilder {
+        self.accept_invalid_certs = true;
+        self
+    }
+
+    /// Build the client, along with its HTTP client. Fails with
+    /// [`RuntimeErrorKind::InvalidEndpoint`] without an endpoint, with
+    /// [`RuntimeErrorKind::InvalidRootCertificate`] when a root certificate can not be read or
+    /// parsed, and with [`RuntimeErrorKind::HTTPConnect`] when the HTTP client, such as its TLS
+    /// backend, can not be set up.
+    pub fn build(self) -> Result<KeyBrokerClient> {
+        let endpoint = self.endpoint.ok_or_else(|| {
+            KeybrokerError::RuntimeError(RuntimeErrorKind::InvalidEndpoint(
+                String::new(),
+                "no endpoint given".to_string(),
+            ))
+        })?;
+
+        let mut client = KeyBrokerClient::new(&endpoint);
+        for path in &self.root_certificates {
+            let pem = std::fs::read(path).map_err(|error| {
+                KeybrokerError::RuntimeError(RuntimeErrorKind::InvalidRootCertificate(format!(
+                    "{}: {error}",
+                    path.display()
+                )))
+            })?;
+            client = client
+                .root_certificates(&pem)
+                .map_err(|error| match error {
+                    KeybrokerError::RuntimeError(RuntimeErrorKind::InvalidRootCertificate(
+                        detail,
+                    )) => KeybrokerError::RuntimeError(RuntimeErrorKind::InvalidRootCertificate(
+                        format!("{}: {detail}", path.display()),
+                    )),
+                    error => error,
+                })?;
+        }
+        if let Some(digest) = self.pinned_spki_sha256 {
+            client = client.pinned_spki_sha256(digest);
+        }
+        if self.accept_invalid_certs {
+            client = client.danger_accept_invalid_certs();
+        }
+        client.client()?;
+        Ok(client)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::unwrap_key_data;
+    use crate::CCA_EXAMPLE_TOKEN;
+
+    #[test]
+    fn default_headers() {
+        let client = KeyBrokerClient::new("http://127.0.0.1:8088")
+            .user_agent("keybroker-test/1.0")
+            .and_then(|client| client.default_header("X-Route", "blue"))
+            .and_then(|client| client.default_header("x-route", "green"))
+            .unwrap();
+
+        assert_eq!(client.headers[header::USER_AGENT], "keybroker-test/1.0");
+        let routes: Vec<_> = client.headers.get_all("X-Route").iter().collect();
+        assert_eq!(routes, ["blue", "green"]);
+    }
+
+    #[test]
+    fn correlation_ids() {
+        let client = KeyBrokerClient::new("http://127.0.0.1:8088");
+        assert_eq!(client.last_correlation_id(), None);
+
+        // Each key request gets a random UUID.
+        client.start_request();
+        let first = client.last_correlation_id().unwrap();
+        assert_eq!(first.len(), 36);
+        assert_eq!(&first[14..15], "4");
+        client.start_request();
+        assert_ne!(client.last_correlation_id().unwrap(), first);
+
+        // Unless the caller set one.
+        let client = client.correlation_id("nightly job #7");
+        client.start_request();
+        assert_eq!(
+            client.last_correlation_id().as_deref(),
+            Some("nightly_job__7")
+        );
+    }
+
+    #[test]
+    fn restricted_and_invalid_headers() {
+        for name in ["Host", "content-length", "Content-Type"] {
+            assert!(matches!(
+                KeyBrokerClient::new("http://127.0.0.1:8088").default_header(name, "value"),
+                Err(KeybrokerError::RuntimeError(RuntimeErrorKind::RestrictedHeader(header))) if header == name
+            ));
+        }
+
+        for (name, value) in [("X Route", "blue"), ("X-Route", "blue\nX-Injected: 1")] {
+            assert!(matches!(
+                KeyBrokerClient::new("http://127.0.0.1:8088").default_header(name, value),
+                Err(KeybrokerError::RuntimeError(
+                    RuntimeErrorKind::InvalidHeader(..)
+                ))
+            ));
+        }
+    }
+
+    #[test]
+    fn rsa_wrapping_settings() {
+        use rsa::traits::PublicKeyParts;
+
+        for bits in [1024, 2049, 8192] {
+            assert!(matches!(
+                KeyBrokerClient::new("http://127.0.0.1:8088").rsa_wrapping_key_bits(bits),
+                Err(KeybrokerError::RuntimeError(
+                    RuntimeErrorKind::UnsupportedWrappingKey(_)
+                ))
+            ));
+        }
+
+        let client = KeyBrokerClient::new("http://127.0.0.1:8088")
+            .rsa_wrapping_key_bits(3072)
+            .unwrap()
+            .rsa_wrapping_algorithm(RsaWrappingAlgorithm::RsaOaep);
+        assert_eq!(client.wrapping_key_pair().n().bits(), 3072);
+        let pubkey = client.request_wrapping_key().public_key().unwrap();
+        assert_eq!(pubkey.alg, RSA_OAEP_ALGORITHM);
+
+        // The algorithm also applies to a provisioned key-pair.
+        let client = client
+            .rsa_wrapping_algorithm(RsaWrappingAlgorithm::RsaOaep256)
+            .reuse_wrapping_key();
+        let pubkey = client.request_wrapping_key().public_key().unwrap();
+        assert_eq!(pubkey.alg, RSA_OAEP_256_ALGORITHM);
+
+        // Whatever the order the client is built in.
+        let client = client.rsa_wrapping_key_bits(4096).unwrap();
+        assert_eq!(client.wrapping_key_pair().n().bits(), 4096);
+        assert_eq!(client.wrapping_key_pair(), client.wrapping_key_pair());
+    }
+
+    #[test]
+    fn reused_wrapping_key() {
+        let client = KeyBrokerClient::new("http://127.0.0.1:8088");
+        assert_ne!(client.wrapping_key_pair(), client.wrapping_key_pair());
+
+        let client = client.reuse_wrapping_key();
+        let wrapping_key = client.wrapping_key_pair();
+        assert_eq!(client.wrapping_key_pair(), wrapping_key);
+        assert_eq!(
+            client.reuse_wrapping_key().wrapping_key_pair(),
+            wrapping_key
+        );
+    }
+
+    #[test]
+    fn wait_for_server() {
+        // Nothing listens on a port just released.
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let client = KeyBrokerClient::new(&format!("http://127.0.0.1:{port}"));
+        let started = Instant::now();
+        assert!(matches!(
+            client.wait_for_server(Duration::from_millis(600)),
+            Err(KeybrokerError::RuntimeError(
+                RuntimeErrorKind::ServerNotReady(..)
+            ))
+        ));
+        assert!(started.elapsed() >= Duration::from_millis(600));
+
+        // A server without the information endpoint is ready as soon as it answers.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0; 1024];
+            let _ = std::io::Read::read(&mut stream, &mut request);
+            stream
+                .write_all(b"HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\n\r\n")
+                .unwrap();
+        });
+        KeyBrokerClient::new(&endpoint)
+            .wait_for_server(Duration::from_secs(10))
+            .expect("The server is ready.");
+        server.join().unwrap();
+
+        // A server which accepts connections, but does not answer in time, is ready too.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        KeyBrokerClient::new(&endpoint)
+            .wait_for_server(Duration::from_secs(2))
+            .expect("The server is ready.");
+        drop(listener);
+    }
+
+    #[test]
+    fn server_poll_timeout_bounds() {
+        assert_eq!(
+            server_poll_timeout(Duration::from_millis(600)),
+            SERVER_POLL_INTERVAL
+        );
+        assert_eq!(
+            server_poll_timeout(Duration::from_secs(10)),
+            Duration::from_millis(2500)
+        );
+        assert_eq!(
+            server_poll_timeout(Duration::from_secs(60)),
+            MAX_SERVER_POLL_TIMEOUT
+        );
+    }
+
+    /// Only compiles if the values of type T are wiped from memory when they are dropped.
+    fn assert_zeroized_on_drop<T: zeroize::ZeroizeOnDrop>(_: &T) {}
+
+    #[test]
+    fn sensitive_material_zeroized_on_drop() {
+        let wrapping_key = KeyBrokerClient::new("http://127.0.0.1:8088").wrapping_key_pair();
+        assert_zeroized_on_drop(&wrapping_key);
+
+        let wrapped_key = RsaPublicKey::from(&wrapping_key)
+            .encrypt(
+                &mut rand::thread_rng(),
+                rsa::Pkcs1v15Encrypt,
+                b"May the force be with you.",
+            )
+            .unwrap();
+        let key = unwrap_key_data(&wrapping_key, RSA_PKCS15_ALGORITHM, &wrapped_key).unwrap();
+        assert_zeroized_on_drop(&key);
+        let key = SecretKeyMaterial::new(&key);
+        assert_zeroized_on_drop(&key);
+        assert_eq!(key.expose_secret(), b"May the force be with you.");
+    }
+
+    #[test]
+    fn mock_evidence_providers() {
+        assert!(CcaExampleToken {}.is_mock());
+        assert!(FileEvidence::new("evidence.cbor", crate::EVIDENCE_MEDIA_TYPE).is_mock());
+        assert!(EvidenceSource::Mock.is_mock());
+        assert!(!EvidenceSource::Tsm.is_mock());
+        assert!(!EvidenceSource::Rsi.is_mock());
+        assert!(!TsmAttestationReport {}.is_mock());
+        assert!(!RsiAttestationReport {}.is_mock());
+    }
+
+    #[test]
+    fn file_evidence() {
+        let path =
+            std::env::temp_dir().join(format!("keybroker-client-{}-evidence", std::process::id()));
+        let evidence = FileEvidence::new(&path, "application/eat+cwt");
+        assert_eq!(evidence.media_type(), "application/eat+cwt");
+
+        // The raw token, or its base64 encoding, whatever the challenge.
+        std::fs::write(&path, CCA_EXAMPLE_TOKEN).unwrap();
+        assert_eq!(evidence.get_evidence("AAAA").unwrap(), CCA_EXAMPLE_TOKEN);
+        std::fs::write(&path, format!("{}\n", base64::encode(CCA_EXAMPLE_TOKEN))).unwrap();
+        assert_eq!(evidence.get_evidence("AAAA").unwrap(), CCA_EXAMPLE_TOKEN);
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(
+            evidence.get_evidence("AAAA"),
+            Err(KeybrokerError::RuntimeError(
+                RuntimeErrorKind::EvidenceGeneration(_)
+            ))
+        ));
+    }
+
+    #[test]
+    fn root_certificates() {
+        let pem = std::fs::read(
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("../../testdata/tls-cert.pem"),
+        )
+        .unwrap();
+        let client = KeyBrokerClient::new("https://127.0.0.1:8088")
+            .root_certificates(&pem)
+            .unwrap();
+        assert_eq!(client.tls.root_certificates.len(), 1);
+
+        for pem in [
+            "not a certificate",
+            "-----BEGIN CERTIFICATE-----\nbm90IGEgY2VydGlmaWNhdGU=\n-----END CERTIFICATE-----\n",
+        ] {
+            assert!(matches!(
+                KeyBrokerClient::new("https://127.0.0.1:8088").root_certificates(pem.as_bytes()),
+                Err(KeybrokerError::RuntimeError(
+                    RuntimeErrorKind::InvalidRootCertificate(_)
+                ))
+            ));
+        }
+    }
+
+    #[test]
+    fn builder() {
+        let cert = Path::new(env!("CARGO_MANIFEST_DIR")).join("../../testdata/tls-cert.pem");
+        let client = KeyBrokerClient::builder()
+            .endpoint("https://127.0.0.1:8088")
+            .root_certificate(&cert)
+            .pinned_spki_sha256([0; 32])
+            .build()
+            .unwrap();
+        assert_eq!(client.tls.root_certificates.len(), 1);
+        assert_eq!(client.tls.pinned_spki_sha256, Some([0; 32]));
+        assert!(client.client.get().is_some());
+
+        assert!(matches!(
+            KeyBrokerClient::builder().build(),
+            Err(KeybrokerError::RuntimeError(
+                RuntimeErrorKind::InvalidEndpoint(..)
+            ))
+        ));
+        match KeyBrokerClient::builder()
+            .endpoint("https://127.0.0.1:8088")
+            .root_certificate("missing-cert.pem")
+            .build()
+        {
+            Err(KeybrokerError::RuntimeError(RuntimeErrorKind::InvalidRootCertificate(detail))) => {
+                assert!(detail.starts_with("missing-cert.pem: "), "{detail}")
+            }
+            other => panic!("unexpected result: {other:?}"),
+        }
+    }
+}
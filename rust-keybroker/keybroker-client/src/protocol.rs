@@ -0,0 +1,749 @@
+// Copyright 2024 Contributors to the Veraison project.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Decoding of the response bodies returned by the keybroker server.
+//!
+//! The server's responses are untrusted inputs, so they are decoded by pure functions which report
+//! malformed data as errors rather than panicking. These functions are also the entry points used by
+//! the fuzz targets.
+use crate::error::{Error as KeybrokerError, Result, RuntimeErrorKind};
+use aes_gcm::aead::generic_array::GenericArray;
+use aes_kw::KekAes256;
+use keybroker_common::jwk::{
+    self, canonical_wrapping_algorithm, ecdh_es_key_wrapping_key, A256GCM_ENCRYPTION,
+    CURVE_COORDINATE_SIZE, EC_KEY_TYPE, KEY_WRAPPING_KEY_SIZE, OKP_KEY_TYPE, P256_CURVE,
+    X25519_CURVE,
+};
+use keybroker_common::{
+    base64, AttestationChallenge, EphemeralPublicKey, ErrorInformation, PublicWrappingKey,
+    ServerInfo, WrappedKeyData,
+};
+use p256::elliptic_curve::sec1::ToEncodedPoint;
+use rsa::{Oaep, Pkcs1v15Encrypt, RsaPrivateKey};
+use sha1::Sha1;
+use sha2::Sha256;
+use url::Url;
+use zeroize::Zeroizing;
+
+pub use keybroker_common::jwk::{
+    ECDH_ES_A256KW_ALGORITHM, LEGACY_OAEP_ALGORITHM, RSA_OAEP_256_ALGORITHM, RSA_OAEP_ALGORITHM,
+    RSA_PKCS15_ALGORITHM,
+};
+
+/// Resolve the raw value of a Location header against the URL of the request it was returned for.
+///
+/// Relative references, as produced by a reverse proxy rewriting the Location header, are resolved
+/// against `base`, while absolute URLs (possibly with a rewritten host) are used as is.
+pub fn resolve_location(base: &Url, location: Option<&[u8]>) -> Result<Url> {
+    let location = location.ok_or(KeybrokerError::RuntimeError(
+        RuntimeErrorKind::MissingLocation,
+    ))?;
+
+    let invalid = |error: String| {
+        KeybrokerError::RuntimeError(RuntimeErrorKind::InvalidLocation(
+            String::from_utf8_lossy(location).into_owned(),
+            error,
+        ))
+    };
+
+    let location = std::str::from_utf8(location).map_err(|error| invalid(error.to_string()))?;
+    base.join(location.trim())
+        .map_err(|error| invalid(error.to_string()))
+}
+
+/// Decode the JSON attestation challenge returned in response to a key request.
+pub fn parse_attestation_challenge(body: &[u8]) -> Result<AttestationChallenge> {
+    serde_json::from_slice::<AttestationChallenge>(body).map_err(|error| {
+        KeybrokerError::RuntimeError(RuntimeErrorKind::JSONDeserialize(
+            "the attestation challenge".to_string(),
+            format!("{error:?}"),
+        ))
+    })
+}
+
+/// The decoded response to a successful evidence submission.
+#[derive(Debug)]
+pub struct WrappedKey {
+    /// The wrapped key, still encrypted with the wrapping key, or with the content encryption key
+    /// if there is one.
+    pub ciphertext: Vec<u8>,
+
+    /// The content encryption of a key too large to be wrapped directly.
+    pub content_encryption: Option<ContentEncryption>,
+
+    /// The attestation result (EAR) as a signed JWT, if it was requested with the key.
+    pub attestation_result: Option<String>,
+}
+
+/// The AES-256-GCM encryption of a key too large to be wrapped directly.
+#[derive(Debug)]
+pub struct ContentEncryption {
+    /// The content encryption key, still encrypted with the wrapping key.
+    pub wrapped_cek: Vec<u8>,
+
+    /// The initialisation vector of the content encryption.
+    pub iv: Vec<u8>,
+
+    /// The ephemeral public key of the server, with the ECDH-ES+A256KW wrapping algorithm.
+    pub epk: Option<EphemeralPublicKey>,
+
+    /// The identifier of the released key, which the content encryption is bound to. It is empty
+    /// if the server did not tell it.
+    pub key_id: String,
+}
+
+/// Decode the JSON wrapped key data returned in response to a successful evidence submission.
+pub fn parse_wrapped_key_data(body: &[u8]) -> Result<WrappedKey> {
+    let wrapped_data = serde_json::from_slice::<WrappedKeyData>(body).map_err(|error| {
+        KeybrokerError::RuntimeError(RuntimeErrorKind::JSONDeserialize(
+            "the evidence WrappedKeyData".to_string(),
+            format!("{error:?}"),
+        ))
+    })?;
+
+    let ciphertext = base64::decode("the wrapped data from the server", &wrapped_data.data)
+        .map_err(|error| KeybrokerError::RuntimeError(RuntimeErrorKind::Base64Decode(error)))?;
+
+    let content_encryption = match (wrapped_data.wrapped_cek, wrapped_data.iv, wrapped_data.enc) {
+        (None, None, None) if wrapped_data.epk.is_none() => None,
+        (Some(wrapped_cek), Some(iv), Some(enc)) => {
+            if enc != A256GCM_ENCRYPTION {
+                return Err(KeybrokerError::RuntimeError(RuntimeErrorKind::Decrypt(
+                    "ciphertext".to_string(),
+                    format!("unsupported content encryption algorithm {enc}"),
+                )));
+            }
+            let decode = |name, value: &str| {
+                base64::decode(name, value).map_err(|error| {
+                    KeybrokerError::RuntimeError(RuntimeErrorKind::Base64Decode(error))
+                })
+            };
+            Some(ContentEncryption {
+                wrapped_cek: decode(
+                    "the wrapped content encryption key from the server",
+                    &wrapped_cek,
+                )?,
+                iv: decode("the content encryption IV from the server", &iv)?,
+                epk: wrapped_data.epk,
+                key_id: wrapped_data.key_id.unwrap_or_default(),
+            })
+        }
+        _ => {
+            return Err(KeybrokerError::RuntimeError(
+                RuntimeErrorKind::JSONDeserialize(
+                    "the evidence WrappedKeyData".to_string(),
+                    "wrapped-cek, iv and enc must be given together, and epk only with them"
+                        .to_string(),
+                ),
+            ))
+        }
+    };
+
+    Ok(WrappedKey {
+        ciphertext,
+        content_encryption,
+        attestation_result: wrapped_data.attestation_result,
+    })
+}
+
+/// Decode the claims of an attestation result (EAR) JWT.
+///
+/// The signature of the JWT is NOT verified: the claims are only decoded for display purposes, the
+/// keybroker server being the one that relied on them.
+pub fn attestation_result_claims(attestation_result: &str) -> Result<serde_json::Value> {
+    let payload = attestation_result.split('.').nth(1).unwrap_or_default();
+    let payload = base64::decode("the attestation result claims", payload)
+        .map_err(|error| KeybrokerError::RuntimeError(RuntimeErrorKind::Base64Decode(error)))?;
+
+    serde_json::from_slice(&payload).map_err(|error| {
+        KeybrokerError::RuntimeError(RuntimeErrorKind::JSONDeserialize(
+            "the attestation result claims".to_string(),
+            format!("{error:?}"),
+        ))
+    })
+}
+
+/// Decode the JSON error information returned when an evidence submission is rejected.
+pub fn parse_error_information(body: &[u8]) -> Result<ErrorInformation> {
+    serde_json::from_slice::<ErrorInformation>(body).map_err(|error| {
+        KeybrokerError::RuntimeError(RuntimeErrorKind::JSONDeserialize(
+            "the evidence ErrorInformation".to_string(),
+            format!("{error:?}"),
+        ))
+    })
+}
+
+/// Decode the JSON server information returned by the information endpoint.
+pub fn parse_server_info(body: &[u8]) -> Result<ServerInfo> {
+    serde_json::from_slice::<ServerInfo>(body).map_err(|error| {
+        KeybrokerError::RuntimeError(RuntimeErrorKind::JSONDeserialize(
+            "the ServerInfo".to_string(),
+            format!("{error:?}"),
+        ))
+    })
+}
+
+/// Decrypt the wrapped key data with the private part of the wrapping key pair, according to
+/// the wrapping algorithm that was requested from the server, under its standard or legacy name.
+///
+/// The plain text is wiped from memory when it is dropped.
+pub fn unwrap_key_data(
+    priv_key: &RsaPrivateKey,
+    alg: &str,
+    ciphertext: &[u8],
+) -> Result<Zeroizing<Vec<u8>>> {
+    let plaintext = match canonical_wrapping_algorithm(alg) {
+        Some(RSA_PKCS15_ALGORITHM) => priv_key.decrypt(Pkcs1v15Encrypt, ciphertext),
+        Some(RSA_OAEP_ALGORITHM) => priv_key.decrypt(Oaep::new::<Sha1>(), ciphertext),
+        Some(RSA_OAEP_256_ALGORITHM) => priv_key.decrypt(Oaep::new::<Sha256>(), ciphertext),
+        _ => {
+            return Err(KeybrokerError::RuntimeError(RuntimeErrorKind::Decrypt(
+                "ciphertext".to_string(),
+                format!("unsupported wrapping algorithm {alg}"),
+            )))
+        }
+    };
+
+    plaintext.map(Zeroizing::new).map_err(|error| {
+        KeybrokerError::RuntimeError(RuntimeErrorKind::Decrypt(
+            "ciphertext".to_string(),
+            format!("{error:?}"),
+        ))
+    })
+}
+
+/// Decrypt a key encrypted with an AES-256-GCM content encryption key, once the content
+/// encryption key is unwrapped.
+///
+/// The plain text is wiped from memory when it is dropped.
+pub fn decrypt_content(
+    cek: &[u8],
+    content_encryption: &ContentEncryption,
+    ciphertext: &[u8],
+) -> Result<Zeroizing<Vec<u8>>> {
+    jwk::decrypt_content(
+        cek,
+        &content_encryption.iv,
+        &content_encryption.key_id,
+        ciphertext,
+    )
+    .map_err(|error| {
+        KeybrokerError::RuntimeError(RuntimeErrorKind::Decrypt(
+            "ciphertext".to_string(),
+            error.to_string(),
+        ))
+    })
+}
+
+/// Decrypt a wrapped key with the private part of the wrapping key pair, according to the
+/// wrapping algorithm that was requested from the server, whether it was wrapped directly or
+/// with a content encryption key.
+///
+/// The plain text is wiped from memory when it is dropped.
+pub fn unwrap_wrapped_key(
+    priv_key: &RsaPrivateKey,
+    alg: &str,
+    wrapped_key: &WrappedKey,
+) -> Result<Zeroizing<Vec<u8>>> {
+    match &wrapped_key.content_encryption {
+        None => unwrap_key_data(priv_key, alg, &wrapped_key.ciphertext),
+        Some(content_encryption) => {
+            let cek = unwrap_key_data(priv_key, alg, &content_encryption.wrapped_cek)?;
+            decrypt_content(&cek, content_encryption, &wrapped_key.ciphertext)
+        }
+    }
+}
+
+/// An elliptic-curve private key, which the keys can be wrapped to with the ECDH-ES+A256KW wrapping
+/// algorithm.
+pub enum EcdhPrivateKey {
+    /// A key on the NIST P-256 curve, sent as an EC wrapping key.
+    P256(p256::SecretKey),
+
+    /// An X25519 key, sent as an OKP wrapping key.
+    X25519(x25519_dalek::StaticSecret),
+}
+
+impl EcdhPrivateKey {
+    /// The public part of the key, as a wrapping key for the ECDH-ES+A256KW algorithm.
+    pub fn public_wrapping_key(&self) -> PublicWrappingKey {
+        match self {
+            EcdhPrivateKey::P256(secret) => {
+                let point = secret.public_key().to_encoded_point(false);
+                PublicWrappingKey {
+                    kty: EC_KEY_TYPE.to_string(),
+                    alg: ECDH_ES_A256KW_ALGORITHM.to_string(),
+                    crv: Some(P256_CURVE.to_string()),
+                    x: point.x().map(base64::encode),
+                    y: point.y().map(base64::encode),
+                    ..Default::default()
+                }
+            }
+            EcdhPrivateKey::X25519(secret) => PublicWrappingKey {
+                kty: OKP_KEY_TYPE.to_string(),
+                alg: ECDH_ES_A256KW_ALGORITHM.to_string(),
+                crv: Some(X25519_CURVE.to_string()),
+                x: Some(base64::encode(
+                    x25519_dalek::PublicKey::from(secret).as_bytes(),
+                )),
+                ..Default::default()
+            },
+        }
+    }
+
+    /// The ECDH-ES+A256KW key wrapping key, derived from the ECDH shared secret of the key and of
+    /// the ephemeral public key of the server.
+    fn key_wrapping_key(
+        &self,
+        epk: &EphemeralPublicKey,
+    ) -> Result<Zeroizing<[u8; KEY_WRAPPING_KEY_SIZE]>> {
+        let invalid = |detail: &str| {
+            KeybrokerError::RuntimeError(RuntimeErrorKind::Decrypt(
+                "the ephemeral public key".to_string(),
+                detail.to_string(),
+            ))
+        };
+        let coordinate = |value: Option<&String>| -> Result<Vec<u8>> {
+            let value = value.ok_or_else(|| invalid("a coordinate is missing"))?;
+            let bytes = base64::decode("the ephemeral public key from the server", value).map_err(
+                |error| KeybrokerError::RuntimeError(RuntimeErrorKind::Base64Decode(error)),
+            )?;
+            if bytes.len() != CURVE_COORDINATE_SIZE {
+                return Err(invalid("a coordinate is not 32 bytes long"));
+            }
+            Ok(bytes)
+        };
+
+        match self {
+            EcdhPrivateKey::P256(secret) => {
+                if (epk.kty.as_str(), epk.crv.as_str()) != (EC_KEY_TYPE, P256_CURVE) {
+                    return Err(invalid("it is not a P-256 key"));
+                }
+                let mut sec1 = vec![0x04];
+                sec1.extend(coordinate(Some(&epk.x))?);
+                sec1.extend(coordinate(epk.y.as_ref())?);
+                let epk = p256::PublicKey::from_sec1_bytes(&sec1)
+                    .map_err(|_| invalid("its point is not on the P-256 curve"))?;
+
+                let shared_secret =
+                    p256::ecdh::diffie_hellman(secret.to_nonzero_scalar(), epk.as_affine());
+                // The x and y coordinates, without the SEC1 tag of the uncompressed points.
+                let public_key = secret.public_key().to_encoded_point(false);
+                Ok(ecdh_es_key_wrapping_key(
+                    shared_secret.raw_secret_bytes(),
+                    &sec1[1..],
+                    &public_key.as_bytes()[1..],
+                ))
+            }
+            EcdhPrivateKey::X25519(secret) => {
+                if (epk.kty.as_str(), epk.crv.as_str()) != (OKP_KEY_TYPE, X25519_CURVE) {
+                    return Err(invalid("it is not an X25519 key"));
+                }
+                let epk: [u8; CURVE_COORDINATE_SIZE] = coordinate(Some(&epk.x))?
+                    .try_into()
+                    .expect("The length was checked.");
+
+                let shared_secret = secret.diffie_hellman(&x25519_dalek::PublicKey::from(epk));
+                if !shared_secret.was_contributory() {
+                    return Err(invalid("it is of small order"));
+                }
+                Ok(ecdh_es_key_wrapping_key(
+                    shared_secret.as_bytes(),
+                    &epk,
+                    x25519_dalek::PublicKey::from(secret).as_bytes(),
+                ))
+            }
+        }
+    }
+}
+
+/// Decrypt a key wrapped with ECDH-ES+A256KW to the public part of an elliptic-curve key, which
+/// is always encrypted with a content encryption key.
+///
+/// The key wrapping key is derived with the Concat KDF of RFC 7518 from the ECDH shared secret of
+/// the key and of the ephemeral public key of the server, bound to both public keys, as the server
+/// does. The plain text is wiped from memory when it is dropped.
+pub fn unwrap_ecdh_wrapped_key(
+    priv_key: &EcdhPrivateKey,
+    wrapped_key: &WrappedKey,
+) -> Result<Zeroizing<Vec<u8>>> {
+    let decrypt_error = |detail: &str| {
+        KeybrokerError::RuntimeError(RuntimeErrorKind::Decrypt(
+            "ciphertext".to_string(),
+            detail.to_string(),
+        ))
+    };
+
+    let (content_encryption, epk) = wrapped_key
+        .content_encryption
+        .as_ref()
+        .and_then(|content_encryption| Some((content_encryption, content_encryption.epk.as_ref()?)))
+        .ok_or_else(|| decrypt_error("the server did not wrap the key with ECDH-ES"))?;
+
+    let kek = priv_key.key_wrapping_key(epk)?;
+
+    let cek = KekAes256::new(GenericArray::from_slice(kek.as_ref()))
+        .unwrap_vec(&content_encryption.wrapped_cek)
+        .map(Zeroizing::new)
+        .map_err(|_| decrypt_error("the content encryption key failed to unwrap"))?;
+    decrypt_content(&cek, content_encryption, &wrapped_key.ciphertext)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use keybroker_common::BackgroundCheckKeyRequest;
+    use rsa::pkcs8::DecodePrivateKey;
+    use rsa::{traits::PublicKeyParts, RsaPublicKey};
+
+    fn key_request_url() -> Url {
+        Url::parse("http://127.0.0.1:8088/keys/v1/key/skywalker").unwrap()
+    }
+
+    #[test]
+    fn absolute_location() {
+        let location = resolve_location(
+            &key_request_url(),
+            Some(b"http://127.0.0.1:8088/keys/v1/evidence/1234"),
+        )
+        .expect("valid location");
+        assert_eq!(
+            location.as_str(),
+            "http://127.0.0.1:8088/keys/v1/evidence/1234"
+        );
+    }
+
+    #[test]
+    fn relative_location() {
+        let location = resolve_location(&key_request_url(), Some(b"/keys/v1/evidence/1234"))
+            .expect("valid location");
+        assert_eq!(
+            location.as_str(),
+            "http://127.0.0.1:8088/keys/v1/evidence/1234"
+        );
+
+        let location = resolve_location(&key_request_url(), Some(b"../evidence/1234"))
+            .expect("valid location");
+        assert_eq!(
+            location.as_str(),
+            "http://127.0.0.1:8088/keys/v1/evidence/1234"
+        );
+    }
+
+    #[test]
+    fn rewritten_host_location() {
+        let location = resolve_location(
+            &key_request_url(),
+            Some(b"https://keybroker.example/broker/keys/v1/evidence/1234"),
+        )
+        .expect("valid location");
+        assert_eq!(
+            location.as_str(),
+            "https://keybroker.example/broker/keys/v1/evidence/1234"
+        );
+    }
+
+    #[test]
+    fn missing_or_invalid_location() {
+        assert!(matches!(
+            resolve_location(&key_request_url(), None),
+            Err(KeybrokerError::RuntimeError(
+                RuntimeErrorKind::MissingLocation
+            ))
+        ));
+        assert!(matches!(
+            resolve_location(&key_request_url(), Some(b"http://[::1")),
+            Err(KeybrokerError::RuntimeError(RuntimeErrorKind::InvalidLocation(value, _)))
+                if value == "http://[::1"
+        ));
+        assert!(matches!(
+            resolve_location(&key_request_url(), Some(b"\xff")),
+            Err(KeybrokerError::RuntimeError(
+                RuntimeErrorKind::InvalidLocation(_, _)
+            ))
+        ));
+    }
+
+    /// Decrypts the wrapping test vectors published by the server, proving that both ends agree
+    /// on the wrapping conventions.
+    #[test]
+    fn wrapping_vectors() {
+        let vectors: serde_json::Value =
+            serde_json::from_str(include_str!("../../../testdata/wrapping-vectors.json"))
+                .expect("Failed to parse the test vectors.");
+
+        for vector in vectors["vectors"].as_array().expect("No test vectors.") {
+            let priv_key =
+                RsaPrivateKey::from_pkcs8_pem(vector["private-key-pem"].as_str().unwrap())
+                    .expect("Failed to parse the test vector private key.");
+
+            // The request must convey the public part of the wrapping key pair.
+            let request: BackgroundCheckKeyRequest =
+                serde_json::from_str(vector["request"].as_str().unwrap())
+                    .expect("Failed to parse the test vector request.");
+            let pub_key = RsaPublicKey::from(&priv_key);
+            assert_eq!(
+                base64::decode("n", request.pubkey.n.as_deref().unwrap()).unwrap(),
+                pub_key.n().to_bytes_be()
+            );
+            assert_eq!(
+                base64::decode("e", request.pubkey.e.as_deref().unwrap()).unwrap(),
+                pub_key.e().to_bytes_be()
+            );
+
+            let wrapped_key =
+                parse_wrapped_key_data(vector["response"].as_str().unwrap().as_bytes())
+                    .expect("Failed to parse the test vector response.");
+            let plaintext = unwrap_wrapped_key(&priv_key, &request.pubkey.alg, &wrapped_key)
+                .expect("Failed to unwrap the test vector response.");
+
+            assert_eq!(
+                *plaintext,
+                vector["plaintext"].as_str().unwrap().as_bytes(),
+                "test vector {}",
+                vector["name"]
+            );
+        }
+    }
+
+    /// Each wrapping algorithm identifier is decrypted with its own padding and hash, which the
+    /// server uses too.
+    #[test]
+    fn wrapping_algorithm_identifiers() {
+        use rsa::traits::PaddingScheme;
+
+        fn round_trip<P: PaddingScheme>(priv_key: &RsaPrivateKey, alg: &str, padding: P) {
+            let ciphertext = RsaPublicKey::from(priv_key)
+                .encrypt(&mut rand::thread_rng(), padding, b"skywalker")
+                .unwrap();
+            assert_eq!(
+                *unwrap_key_data(priv_key, alg, &ciphertext).unwrap(),
+                b"skywalker",
+                "{alg}"
+            );
+        }
+
+        let priv_key = RsaPrivateKey::new(&mut rand::thread_rng(), 1024).unwrap();
+        round_trip(&priv_key, RSA_PKCS15_ALGORITHM, Pkcs1v15Encrypt);
+        round_trip(&priv_key, RSA_OAEP_ALGORITHM, Oaep::new::<Sha1>());
+        round_trip(&priv_key, RSA_OAEP_256_ALGORITHM, Oaep::new::<Sha256>());
+        round_trip(&priv_key, LEGACY_OAEP_ALGORITHM, Oaep::new::<Sha256>());
+
+        // RSA-OAEP is the SHA-1 variant, as in JOSE.
+        let ciphertext = RsaPublicKey::from(&priv_key)
+            .encrypt(&mut rand::thread_rng(), Oaep::new::<Sha256>(), b"skywalker")
+            .unwrap();
+        assert!(unwrap_key_data(&priv_key, RSA_OAEP_ALGORITHM, &ciphertext).is_err());
+        assert!(unwrap_key_data(&priv_key, "RSA-OAEP-384", &ciphertext).is_err());
+    }
+
+    #[test]
+    fn wrapped_key_with_attestation_result() {
+        let wrapped_key =
+            parse_wrapped_key_data(br#"{"data": "AAEC", "attestation-result": "a.b.c"}"#)
+                .expect("valid wrapped key data");
+        assert_eq!(wrapped_key.ciphertext, [0, 1, 2]);
+        assert_eq!(wrapped_key.attestation_result.as_deref(), Some("a.b.c"));
+
+        let wrapped_key =
+            parse_wrapped_key_data(br#"{"data": "AAEC"}"#).expect("valid wrapped key data");
+        assert!(wrapped_key.attestation_result.is_none());
+        assert!(wrapped_key.content_encryption.is_none());
+    }
+
+    /// A 4 KiB key, too large to be wrapped directly, is encrypted with a content encryption key.
+    #[test]
+    fn content_encryption_round_trip() {
+        let priv_key = RsaPrivateKey::new(&mut rand::thread_rng(), 2048).unwrap();
+        let key: Vec<u8> = (0..4096).map(|i| (i % 251) as u8).collect();
+        let cek = [0x42; 32];
+        let iv = [0x24; 12];
+        let ciphertext = jwk::encrypt_content(&cek, &iv, "skywalker", &key).unwrap();
+        let wrapped_cek = RsaPublicKey::from(&priv_key)
+            .encrypt(&mut rand::thread_rng(), Oaep::new::<Sha256>(), &cek)
+            .unwrap();
+        let body_for = |key_id: &str, enc: &str, data: &[u8]| {
+            serde_json::json!({
+                "data": base64::encode(data),
+                "wrapped-cek": base64::encode(&wrapped_cek),
+                "iv": base64::encode(iv),
+                "enc": enc,
+                "key-id": key_id,
+            })
+            .to_string()
+        };
+        let body = |enc: &str, data: &[u8]| body_for("skywalker", enc, data);
+
+        let wrapped_key = parse_wrapped_key_data(body("A256GCM", &ciphertext).as_bytes())
+            .expect("valid wrapped key data");
+        assert!(wrapped_key.content_encryption.is_some());
+        let plaintext = unwrap_wrapped_key(&priv_key, RSA_OAEP_256_ALGORITHM, &wrapped_key)
+            .expect("Failed to unwrap the key.");
+        assert_eq!(*plaintext, key);
+
+        // The content encryption is authenticated.
+        let mut tampered = ciphertext.clone();
+        tampered[0] ^= 1;
+        let wrapped_key = parse_wrapped_key_data(body("A256GCM", &tampered).as_bytes()).unwrap();
+        assert!(unwrap_wrapped_key(&priv_key, RSA_OAEP_256_ALGORITHM, &wrapped_key).is_err());
+
+        // So is the identifier of the key it is bound to.
+        let wrapped_key =
+            parse_wrapped_key_data(body_for("vader", "A256GCM", &ciphertext).as_bytes()).unwrap();
+        assert!(unwrap_wrapped_key(&priv_key, RSA_OAEP_256_ALGORITHM, &wrapped_key).is_err());
+
+        assert!(parse_wrapped_key_data(body("A128GCM", &ciphertext).as_bytes()).is_err());
+        // The content encryption members only come together.
+        assert!(parse_wrapped_key_data(br#"{"data": "AAEC", "iv": "AAEC"}"#).is_err());
+    }
+
+    /// The body of a key wrapped with ECDH-ES+A256KW the way the server does it, given the
+    /// ephemeral public key of the server, the shared secret and the party information.
+    fn ecdh_wrapped_key_body(
+        epk: serde_json::Value,
+        shared_secret: &[u8],
+        (apu, apv): (&[u8], &[u8]),
+        key: &[u8],
+    ) -> String {
+        let kek = ecdh_es_key_wrapping_key(shared_secret, apu, apv);
+        let cek = [0x42; 32];
+        let iv = [0x24; 12];
+        let ciphertext = jwk::encrypt_content(&cek, &iv, "skywalker", key).unwrap();
+        let wrapped_cek = KekAes256::new(GenericArray::from_slice(kek.as_ref()))
+            .wrap_vec(&cek)
+            .unwrap();
+        serde_json::json!({
+            "data": base64::encode(ciphertext),
+            "wrapped-cek": base64::encode(wrapped_cek),
+            "iv": base64::encode(iv),
+            "enc": "A256GCM",
+            "epk": epk,
+            "apu": base64::encode(apu),
+            "apv": base64::encode(apv),
+            "key-id": "skywalker",
+        })
+        .to_string()
+    }
+
+    #[test]
+    fn ecdh_round_trip_p256() {
+        let secret = p256::SecretKey::random(&mut rand::thread_rng());
+        let public_key = secret.public_key();
+        let priv_key = EcdhPrivateKey::P256(secret);
+        let wrapping_key = priv_key.public_wrapping_key();
+        assert!(wrapping_key.validate().is_ok());
+        assert_eq!(wrapping_key.alg, ECDH_ES_A256KW_ALGORITHM);
+
+        let ephemeral = p256::SecretKey::random(&mut rand::thread_rng());
+        let point = ephemeral.public_key().to_encoded_point(false);
+        let epk = serde_json::json!({
+            "kty": "EC",
+            "crv": "P-256",
+            "x": base64::encode(point.x().unwrap()),
+            "y": base64::encode(point.y().unwrap()),
+        });
+        let shared_secret =
+            p256::ecdh::diffie_hellman(ephemeral.to_nonzero_scalar(), public_key.as_affine());
+        let recipient = public_key.to_encoded_point(false);
+        let party_info = (&point.as_bytes()[1..], &recipient.as_bytes()[1..]);
+
+        for key in [&b"skywalker"[..], &[0x5a; 4096]] {
+            let body = ecdh_wrapped_key_body(
+                epk.clone(),
+                shared_secret.raw_secret_bytes(),
+                party_info,
+                key,
+            );
+            let wrapped_key = parse_wrapped_key_data(body.as_bytes()).unwrap();
+            assert_eq!(
+                *unwrap_ecdh_wrapped_key(&priv_key, &wrapped_key).unwrap(),
+                key
+            );
+        }
+
+        // The key wrapping key is bound to the wrapping key.
+        let body = ecdh_wrapped_key_body(
+            epk.clone(),
+            shared_secret.raw_secret_bytes(),
+            (party_info.0, &[0; 64]),
+            b"key",
+        );
+        let wrapped_key = parse_wrapped_key_data(body.as_bytes()).unwrap();
+        assert!(unwrap_ecdh_wrapped_key(&priv_key, &wrapped_key).is_err());
+
+        // The ephemeral key must be on the curve of the wrapping key.
+        let mut off_curve = epk.clone();
+        off_curve["y"] = off_curve["x"].clone();
+        let body = ecdh_wrapped_key_body(
+            off_curve,
+            shared_secret.raw_secret_bytes(),
+            party_info,
+            b"key",
+        );
+        let wrapped_key = parse_wrapped_key_data(body.as_bytes()).unwrap();
+        assert!(unwrap_ecdh_wrapped_key(&priv_key, &wrapped_key).is_err());
+    }
+
+    #[test]
+    fn ecdh_round_trip_x25519() {
+        let secret = x25519_dalek::StaticSecret::random_from_rng(rand::thread_rng());
+        let public_key = x25519_dalek::PublicKey::from(&secret);
+        let priv_key = EcdhPrivateKey::X25519(secret);
+        let wrapping_key = priv_key.public_wrapping_key();
+        assert!(wrapping_key.validate().is_ok());
+        assert_eq!(
+            wrapping_key.x.as_deref(),
+            Some(base64::encode(public_key.as_bytes()).as_str())
+        );
+
+        let ephemeral = x25519_dalek::StaticSecret::random_from_rng(rand::thread_rng());
+        let epk = serde_json::json!({
+            "kty": "OKP",
+            "crv": "X25519",
+            "x": base64::encode(x25519_dalek::PublicKey::from(&ephemeral).as_bytes()),
+        });
+        let shared_secret = ephemeral.diffie_hellman(&public_key);
+        let ephemeral_public_key = x25519_dalek::PublicKey::from(&ephemeral);
+        let party_info = (
+            &ephemeral_public_key.as_bytes()[..],
+            &public_key.as_bytes()[..],
+        );
+
+        for key in [&b"skywalker"[..], &[0x5a; 4096]] {
+            let body =
+                ecdh_wrapped_key_body(epk.clone(), shared_secret.as_bytes(), party_info, key);
+            let wrapped_key = parse_wrapped_key_data(body.as_bytes()).unwrap();
+            assert_eq!(
+                *unwrap_ecdh_wrapped_key(&priv_key, &wrapped_key).unwrap(),
+                key
+            );
+        }
+
+        // An ephemeral key of another curve, or a key wrapped to an RSA key, can't be unwrapped.
+        let p256_epk =
+            serde_json::json!({ "kty": "EC", "crv": "P-256", "x": epk["x"], "y": epk["x"] });
+        let body = ecdh_wrapped_key_body(p256_epk, shared_secret.as_bytes(), party_info, b"key");
+        let wrapped_key = parse_wrapped_key_data(body.as_bytes()).unwrap();
+        assert!(unwrap_ecdh_wrapped_key(&priv_key, &wrapped_key).is_err());
+        let wrapped_key = parse_wrapped_key_data(br#"{"data": "AAEC"}"#).unwrap();
+        assert!(unwrap_ecdh_wrapped_key(&priv_key, &wrapped_key).is_err());
+
+        // The ephemeral key only comes with the content encryption.
+        assert!(
+            parse_wrapped_key_data(format!(r#"{{"data": "AAEC", "epk": {epk}}}"#).as_bytes())
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn attestation_result_claims_are_decoded() {
+        let jwt = format!(
+            "eyJhbGciOiJFUzI1NiJ9.{}.c2ln",
+            base64::encode(br#"{"eat_profile": "tag:github.com,2023:veraison/ear"}"#)
+        );
+        let claims = attestation_result_claims(&jwt).expect("valid claims");
+        assert_eq!(claims["eat_profile"], "tag:github.com,2023:veraison/ear");
+
+        assert!(attestation_result_claims("not a JWT").is_err());
+    }
+}
@@ -0,0 +1,182 @@
+// Copyright 2024 Contributors to the Veraison project.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Access to the CCA attestation token through the Realm Services Interface (RSI) device, for the
+//! kernels that expose it as `/dev/rsi` rather than through configfs-tsm, and to the realm
+//! extensible measurements, which configfs-tsm does not give access to.
+//!
+//! The errors are worded so that a missing kernel driver can be told apart from a lack of
+//! permissions on the device.
+use crate::error::{Error as KeybrokerError, Result, RuntimeErrorKind};
+use keybroker_common::jwk::THUMBPRINT_SIZE;
+use keybroker_common::PublicWrappingKey;
+use std::fs::{File, OpenOptions};
+use std::io::ErrorKind;
+use std::os::fd::AsRawFd;
+
+/// The RSI device exposed by the kernel.
+pub(crate) const RSI_DEVICE: &str = "/dev/rsi";
+
+/// The size of the challenge, as expected by the RSI attestation interface.
+pub(crate) const RSI_CHALLENGE_SIZE: usize = 64;
+
+/// The maximum size of the attestation token the RSI device can return.
+const RSI_MAX_TOKEN_SIZE: usize = 4096;
+
+/// The argument of the attestation token ioctl, matching the kernel's `struct rsi_attestation`.
+#[repr(C)]
+struct RsiAttestation {
+    challenge: [u8; RSI_CHALLENGE_SIZE],
+    token_len: u64,
+    token: [u8; RSI_MAX_TOKEN_SIZE],
+}
+
+/// The size of the realm measurements, whatever the hash algorithm of the realm.
+const RSI_MEASUREMENT_SIZE: usize = 64;
+
+/// The number of realm extensible measurements.
+pub(crate) const RSI_EXTENSIBLE_MEASUREMENTS: u32 = 4;
+
+/// The argument of the measurement ioctls, matching the kernel's `struct rsi_measurement`.
+#[repr(C)]
+struct RsiMeasurement {
+    /// The measurement, 0 being the realm initial measurement and 1 to 4 the extensible ones.
+    index: u32,
+    data_len: u32,
+    data: [u8; RSI_MEASUREMENT_SIZE],
+}
+
+nix::ioctl_readwrite!(rsi_measurement_read, b'x', 192, RsiMeasurement);
+nix::ioctl_write_ptr!(rsi_measurement_extend, b'x', 193, RsiMeasurement);
+nix::ioctl_readwrite!(rsi_attestation_token, b'x', 194, RsiAttestation);
+
+fn rsi_error(detail: String) -> KeybrokerError {
+    KeybrokerError::RuntimeError(RuntimeErrorKind::Rsi(detail))
+}
+
+/// Open the RSI device, which tells whether the RSI attestation interface is usable.
+pub(crate) fn open_device() -> Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(RSI_DEVICE)
+        .map_err(|error| match error.kind() {
+            ErrorKind::NotFound => rsi_error(format!(
+                "{RSI_DEVICE} does not exist, this kernel does not provide the RSI attestation interface"
+            )),
+            ErrorKind::PermissionDenied => rsi_error(format!(
+                "permission denied opening {RSI_DEVICE}, check the permissions of the device or run as root"
+            )),
+            _ => rsi_error(format!("failed to open {RSI_DEVICE}: {error}")),
+        })
+}
+
+/// Get a CCA attestation token for the given challenge from the RSI device.
+pub(crate) fn attestation_token(challenge: &[u8; RSI_CHALLENGE_SIZE]) -> Result<Vec<u8>> {
+    let device = open_device()?;
+
+    let mut attestation = Box::new(RsiAttestation {
+        challenge: *challenge,
+        token_len: 0,
+        token: [0; RSI_MAX_TOKEN_SIZE],
+    });
+
+    // SAFETY: the argument is a properly sized and aligned RsiAttestation, which the kernel only
+    // writes within the bounds of.
+    unsafe { rsi_attestation_token(device.as_raw_fd(), &mut *attestation) }
+        .map_err(|errno| ioctl_error("attestation token request", errno))?;
+
+    let token_len = usize::try_from(attestation.token_len).unwrap_or(usize::MAX);
+    if token_len == 0 || token_len > RSI_MAX_TOKEN_SIZE {
+        return Err(rsi_error(format!(
+            "{RSI_DEVICE} returned an attestation token of invalid length {}",
+            attestation.token_len
+        )));
+    }
+
+    Ok(attestation.token[..token_len].to_vec())
+}
+
+/// Describe the failure of an ioctl on the RSI device.
+fn ioctl_error(request: &str, errno: nix::errno::Errno) -> KeybrokerError {
+    match errno {
+        nix::errno::Errno::ENOTTY => rsi_error(format!(
+            "the driver behind {RSI_DEVICE} does not support the {request}"
+        )),
+        nix::errno::Errno::EPERM | nix::errno::Errno::EACCES => rsi_error(format!(
+            "permission denied for the {request} to {RSI_DEVICE}"
+        )),
+        errno => rsi_error(format!("the {request} to {RSI_DEVICE} failed: {errno}")),
+    }
+}
+
+/// Extend the realm extensible measurement `rem` (0 to 3) with the thumbprint of the wrapping key,
+/// so that the evidence binds it, unless it already was.
+///
+/// The measurements are only reset when the realm restarts, so a measurement can only bind one
+/// wrapping key: one which is neither still at its initial zero value, nor already binding the
+/// wrapping key, is refused.
+pub(crate) fn bind_wrapping_key(rem: u32, wrapping_key: &PublicWrappingKey) -> Result<()> {
+    if rem >= RSI_EXTENSIBLE_MEASUREMENTS {
+        return Err(rsi_error(format!(
+            "there is no realm extensible measurement {rem}, they are numbered from 0 to {}",
+            RSI_EXTENSIBLE_MEASUREMENTS - 1
+        )));
+    }
+    let device = open_device()?;
+
+    let mut measurement = RsiMeasurement {
+        index: rem + 1,
+        data_len: 0,
+        data: [0; RSI_MEASUREMENT_SIZE],
+    };
+    // SAFETY: the argument is a properly sized and aligned RsiMeasurement, which the kernel only
+    // writes within the bounds of.
+    unsafe { rsi_measurement_read(device.as_raw_fd(), &mut measurement) }
+        .map_err(|errno| ioctl_error("measurement read", errno))?;
+
+    // The measurements of the SHA-256 realms are the first 32 bytes, the rest being zero.
+    let current = &measurement.data[..THUMBPRINT_SIZE];
+    if current == wrapping_key.binding_measurement() {
+        log::info!("The realm extensible measurement {rem} already binds the wrapping key.");
+        return Ok(());
+    }
+    if measurement.data.iter().any(|byte| *byte != 0) {
+        return Err(rsi_error(format!(
+            "the realm extensible measurement {rem} was already extended, with something else \
+             than the thumbprint of the wrapping key"
+        )));
+    }
+
+    let mut extension = RsiMeasurement {
+        index: rem + 1,
+        data_len: THUMBPRINT_SIZE as u32,
+        data: [0; RSI_MEASUREMENT_SIZE],
+    };
+    extension.data[..THUMBPRINT_SIZE].copy_from_slice(&wrapping_key.thumbprint());
+    // SAFETY: the argument is a properly sized and aligned RsiMeasurement, which the kernel only
+    // reads.
+    unsafe { rsi_measurement_extend(device.as_raw_fd(), &extension) }
+        .map_err(|errno| ioctl_error("measurement extension", errno))?;
+    log::info!("Extended the realm extensible measurement {rem} with the wrapping key thumbprint.");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn attestation_layout() {
+        // The ioctl number encodes the size of the argument, which must match the kernel's.
+        assert_eq!(
+            std::mem::size_of::<RsiAttestation>(),
+            RSI_CHALLENGE_SIZE + 8 + RSI_MAX_TOKEN_SIZE
+        );
+        assert_eq!(
+            std::mem::size_of::<RsiMeasurement>(),
+            4 + 4 + RSI_MEASUREMENT_SIZE
+        );
+    }
+}
@@ -0,0 +1,64 @@
+// Copyright 2024 Contributors to the Veraison project.
+// SPDX-License-Identifier: Apache-2.0
+
+//! The container of the keys retrieved from the keybroker server, which keeps them from being
+//! logged or copied around by accident.
+use secrecy::{ExposeSecret, SecretSlice};
+use zeroize::ZeroizeOnDrop;
+
+/// The plain text of a key retrieved from the keybroker server.
+///
+/// The key is only reachable through [`SecretKeyMaterial::expose_secret`], its `Debug` form is
+/// redacted, and it is wiped from memory when it is dropped.
+pub struct SecretKeyMaterial(SecretSlice<u8>);
+
+impl SecretKeyMaterial {
+    /// Copy a key into a new container. The copy is made into an allocation of the exact size, so
+    /// that no unwiped copy is left behind by a reallocation: the caller remains in charge of
+    /// wiping `key`.
+    pub fn new(key: &[u8]) -> SecretKeyMaterial {
+        SecretKeyMaterial(SecretSlice::from(Box::<[u8]>::from(key)))
+    }
+
+    /// The plain text of the key.
+    pub fn expose_secret(&self) -> &[u8] {
+        self.0.expose_secret()
+    }
+
+    /// The size of the key, in bytes.
+    pub fn len(&self) -> usize {
+        self.expose_secret().len()
+    }
+
+    /// Whether the key is empty.
+    pub fn is_empty(&self) -> bool {
+        self.expose_secret().is_empty()
+    }
+}
+
+impl std::fmt::Debug for SecretKeyMaterial {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SecretKeyMaterial([REDACTED, {} bytes])", self.len())
+    }
+}
+
+// The key is wiped by the SecretSlice.
+impl ZeroizeOnDrop for SecretKeyMaterial {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacted() {
+        let key = SecretKeyMaterial::new(b"May the force be with you.");
+
+        assert_eq!(key.expose_secret(), b"May the force be with you.");
+        assert_eq!(key.len(), 26);
+        assert_eq!(
+            format!("{key:?}"),
+            "SecretKeyMaterial([REDACTED, 26 bytes])"
+        );
+        assert!(SecretKeyMaterial::new(b"").is_empty());
+    }
+}
@@ -0,0 +1,301 @@
+// Copyright 2024 Contributors to the Veraison project.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Split key requests, for attesters without network access.
+//!
+//! The challenge is requested by a host with network access, which saves the session to a file. The
+//! evidence is then produced elsewhere (for example in a realm that only has a serial link to the
+//! outside), and a later invocation, possibly on another machine, imports the session, submits the
+//! evidence and decrypts the key.
+//!
+//! The session file is a JSON document. The challenge and the evidence submission URL are in clear, as
+//! they are needed to produce and submit the evidence, but the private wrapping key is stored as an
+//! encrypted PKCS#8 document (PBES2 with scrypt and AES-256-CBC), protected by a passphrase.
+use crate::error::{Error as KeybrokerError, Result, RuntimeErrorKind};
+use crate::RsaWrappingAlgorithm;
+use pkcs8::{DecodePrivateKey, EncodePrivateKey, LineEnding};
+use rsa::RsaPrivateKey;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+use url::Url;
+
+/// The version of the session file format.
+const SESSION_VERSION: u32 = 1;
+
+/// The content of a session file.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+struct SessionFile {
+    version: u32,
+    key_name: String,
+    /// The challenge from the keybroker server, base64-encoded.
+    challenge: String,
+    evidence_submission_url: String,
+    return_attestation_result: bool,
+    /// The private wrapping key, as a passphrase-encrypted PKCS#8 PEM document.
+    wrapping_key: String,
+    /// The algorithm the key is wrapped with, RSA1_5 in the sessions exported before it was saved.
+    #[serde(default = "default_wrapping_algorithm")]
+    wrapping_algorithm: String,
+}
+
+fn default_wrapping_algorithm() -> String {
+    RsaWrappingAlgorithm::Rsa15.name().to_string()
+}
+
+/// A key request for which a challenge was obtained, but no evidence submitted yet.
+pub struct PendingKeyRequest {
+    pub(crate) key_name: String,
+    pub(crate) challenge: String,
+    pub(crate) evidence_submission_url: Url,
+    pub(crate) return_attestation_result: bool,
+    pub(crate) wrapping_key: RsaPrivateKey,
+    pub(crate) wrapping_algorithm: RsaWrappingAlgorithm,
+}
+
+impl fmt::Debug for PendingKeyRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // The private wrapping key is deliberately left out.
+        f.debug_struct("PendingKeyRequest")
+            .field("key_name", &self.key_name)
+            .field("challenge", &self.challenge)
+            .field("evidence_submission_url", &self.evidence_submission_url)
+            .field("return_attestation_result", &self.return_attestation_result)
+            .field("wrapping_algorithm", &self.wrapping_algorithm)
+            .finish()
+    }
+}
+
+fn session_error(path: &Path, error: impl fmt::Display) -> KeybrokerError {
+    KeybrokerError::RuntimeError(RuntimeErrorKind::Session(format!(
+        "{}: {error}",
+        path.display()
+    )))
+}
+
+impl PendingKeyRequest {
+    /// The name of the requested key.
+    pub fn key_name(&self) -> &str {
+        &self.key_name
+    }
+
+    /// The challenge from the keybroker server, base64-encoded, that the evidence must be generated for.
+    pub fn challenge(&self) -> &str {
+        &self.challenge
+    }
+
+    /// The URL the evidence will be submitted to.
+    pub fn evidence_submission_url(&self) -> &Url {
+        &self.evidence_submission_url
+    }
+
+    /// Save the session to a file, with the private wrapping key encrypted with `passphrase`.
+    ///
+    /// An existing file is overwritten. On Unix, the file is only readable by its owner.
+    pub fn export_session(&self, path: &Path, passphrase: &[u8]) -> Result<()> {
+        let wrapping_key = self
+            .wrapping_key
+            .to_pkcs8_encrypted_pem(rand::thread_rng(), passphrase, LineEnding::LF)
+            .map_err(|error| session_error(path, error))?;
+
+        let session = SessionFile {
+            version: SESSION_VERSION,
+            key_name: self.key_name.clone(),
+            challenge: self.challenge.clone(),
+            evidence_submission_url: self.evidence_submission_url.to_string(),
+            return_attestation_result: self.return_attestation_result,
+            wrapping_key: wrapping_key.to_string(),
+            wrapping_algorithm: self.wrapping_algorithm.name().to_string(),
+        };
+
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+
+        let mut file = options
+            .open(path)
+            .map_err(|error| session_error(path, error))?;
+        // The mode only applies to a file that is created, an existing one keeps its permissions
+        // unless they are set.
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            file.set_permissions(std::fs::Permissions::from_mode(0o600))
+                .map_err(|error| session_error(path, error))?;
+        }
+        serde_json::to_writer_pretty(&mut file, &session)
+            .map_err(|error| session_error(path, error))?;
+        file.write_all(b"\n")
+            .map_err(|error| session_error(path, error))?;
+
+        log::info!(
+            "Session for key '{}' exported to {}",
+            self.key_name,
+            path.display()
+        );
+        Ok(())
+    }
+
+    /// Load a session saved with [`PendingKeyRequest::export_session`].
+    pub fn import_session(path: &Path, passphrase: &[u8]) -> Result<PendingKeyRequest> {
+        let session = std::fs::read(path).map_err(|error| session_error(path, error))?;
+        let session: SessionFile =
+            serde_json::from_slice(&session).map_err(|error| session_error(path, error))?;
+
+        if session.version != SESSION_VERSION {
+            return Err(session_error(
+                path,
+                format!("unsupported session file version {}", session.version),
+            ));
+        }
+
+        let wrapping_algorithm = [
+            RsaWrappingAlgorithm::Rsa15,
+            RsaWrappingAlgorithm::RsaOaep,
+            RsaWrappingAlgorithm::RsaOaep256,
+        ]
+        .into_iter()
+        .find(|algorithm| algorithm.name() == session.wrapping_algorithm)
+        .ok_or_else(|| {
+            session_error(
+                path,
+                format!(
+                    "unsupported wrapping algorithm {}",
+                    session.wrapping_algorithm
+                ),
+            )
+        })?;
+
+        let evidence_submission_url = Url::parse(&session.evidence_submission_url)
+            .map_err(|error| session_error(path, error))?;
+        let wrapping_key =
+            RsaPrivateKey::from_pkcs8_encrypted_pem(&session.wrapping_key, passphrase).map_err(
+                |error| {
+                    session_error(
+                        path,
+                        format!("failed to decrypt the wrapping key (wrong passphrase?): {error}"),
+                    )
+                },
+            )?;
+
+        Ok(PendingKeyRequest {
+            key_name: session.key_name,
+            challenge: session.challenge,
+            evidence_submission_url,
+            return_attestation_result: session.return_attestation_result,
+            wrapping_key,
+            wrapping_algorithm,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rsa::traits::PublicKeyParts;
+    use std::path::PathBuf;
+
+    fn session_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "keybroker-client-{}-{name}.json",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn pending_key_request() -> PendingKeyRequest {
+        PendingKeyRequest {
+            key_name: "skywalker".to_string(),
+            challenge: "bm9uY2U".to_string(),
+            evidence_submission_url: Url::parse("http://127.0.0.1:8088/keys/v1/evidence/1234")
+                .unwrap(),
+            return_attestation_result: true,
+            wrapping_key: RsaPrivateKey::new(&mut rand::thread_rng(), 1024).unwrap(),
+            wrapping_algorithm: RsaWrappingAlgorithm::RsaOaep256,
+        }
+    }
+
+    #[test]
+    fn session_round_trip() {
+        let path = session_path("round-trip");
+        let request = pending_key_request();
+        request.export_session(&path, b"passphrase").unwrap();
+
+        // The wrapping key must not be stored in clear.
+        let session = std::fs::read_to_string(&path).unwrap();
+        assert!(session.contains("BEGIN ENCRYPTED PRIVATE KEY"));
+
+        let imported = PendingKeyRequest::import_session(&path, b"passphrase").unwrap();
+        assert_eq!(imported.key_name(), "skywalker");
+        assert_eq!(imported.challenge(), "bm9uY2U");
+        assert_eq!(
+            imported.evidence_submission_url(),
+            request.evidence_submission_url()
+        );
+        assert!(imported.return_attestation_result);
+        assert_eq!(imported.wrapping_key.n(), request.wrapping_key.n());
+        assert_eq!(
+            imported.wrapping_algorithm,
+            RsaWrappingAlgorithm::RsaOaep256
+        );
+    }
+
+    #[test]
+    fn session_without_wrapping_algorithm() {
+        let path = session_path("no-algorithm");
+        pending_key_request()
+            .export_session(&path, b"passphrase")
+            .unwrap();
+
+        // The sessions exported before the algorithm was saved were all for RSA1_5.
+        let mut session: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        session
+            .as_object_mut()
+            .unwrap()
+            .remove("wrapping-algorithm")
+            .unwrap();
+        std::fs::write(&path, serde_json::to_vec(&session).unwrap()).unwrap();
+
+        let imported = PendingKeyRequest::import_session(&path, b"passphrase").unwrap();
+        assert_eq!(imported.wrapping_algorithm, RsaWrappingAlgorithm::Rsa15);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn session_file_mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // A file that already exists is made private as well.
+        let path = session_path("mode");
+        std::fs::write(&path, b"").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        pending_key_request()
+            .export_session(&path, b"passphrase")
+            .unwrap();
+
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn session_wrong_passphrase() {
+        let path = session_path("passphrase");
+        pending_key_request()
+            .export_session(&path, b"passphrase")
+            .unwrap();
+
+        assert!(matches!(
+            PendingKeyRequest::import_session(&path, b"not the passphrase"),
+            Err(KeybrokerError::RuntimeError(RuntimeErrorKind::Session(_)))
+        ));
+    }
+}
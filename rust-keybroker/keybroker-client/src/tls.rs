@@ -0,0 +1,200 @@
+// Copyright 2024 Contributors to the Veraison project.
+// SPDX-License-Identifier: Apache-2.0
+
+//! The TLS settings of [`crate::KeyBrokerClient`], for the keybroker servers reached over HTTPS.
+//!
+//! On top of the built-in roots, the client can trust custom root certificates, such as the
+//! self-signed certificate of a demonstration server. It can also pin the public key of the
+//! server, by the SHA-256 digest of the SubjectPublicKeyInfo of its certificate, as
+//! `openssl x509 -pubkey -noout | openssl pkey -pubin -outform der | sha256sum` gives it, so that
+//! only that server is trusted, even by a certificate authority that would issue certificates to
+//! others. The pin is checked on top of the certificate chain, unless the chain is not verified
+//! at all, which is only fit for lab setups.
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use x509_cert::der::{Decode, Encode};
+use x509_cert::Certificate;
+
+/// How the client trusts the keybroker server.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct TlsSettings {
+    /// The root certificates trusted on top of the built-in ones.
+    pub root_certificates: Vec<CertificateDer<'static>>,
+
+    /// The SHA-256 digest of the SubjectPublicKeyInfo the certificate of the server must have, if
+    /// it is pinned.
+    pub pinned_spki_sha256: Option<[u8; 32]>,
+
+    /// Whether any certificate is accepted, whoever issued it and whatever it is for.
+    pub accept_invalid_certs: bool,
+}
+
+impl TlsSettings {
+    /// Whether the certificate of the server is verified by the client itself, rather than by the
+    /// HTTP library.
+    pub fn custom_verification(&self) -> bool {
+        self.pinned_spki_sha256.is_some() || self.accept_invalid_certs
+    }
+
+    /// The TLS configuration verifying the certificate of the server as set.
+    pub fn client_config(&self) -> Result<ClientConfig, rustls::Error> {
+        let provider = Arc::new(ring::default_provider());
+        let chain = if self.accept_invalid_certs {
+            None
+        } else {
+            let mut roots = RootCertStore {
+                roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+            };
+            for certificate in &self.root_certificates {
+                roots.add(certificate.clone())?;
+            }
+            Some(
+                WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+                    .build()
+                    .map_err(|error| rustls::Error::General(error.to_string()))?,
+            )
+        };
+        let verifier = PinningVerifier {
+            chain,
+            pinned_spki_sha256: self.pinned_spki_sha256,
+            provider: provider.clone(),
+        };
+
+        let mut config = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(verifier))
+            .with_no_client_auth();
+        // The HTTP library only offers HTTP/2 in the configurations it builds itself.
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        Ok(config)
+    }
+}
+
+/// Check that the root certificates can be parsed, as they are only used once the client connects.
+pub(crate) fn check_root_certificates(
+    certificates: &[CertificateDer<'static>],
+) -> Result<(), rustls::Error> {
+    let mut roots = RootCertStore::empty();
+    for certificate in certificates {
+        roots.add(certificate.clone())?;
+    }
+    Ok(())
+}
+
+/// Verifies the certificate chain of the server, unless invalid certificates are accepted, and
+/// that its public key is the pinned one, if there is one.
+#[derive(Debug)]
+struct PinningVerifier {
+    chain: Option<Arc<WebPkiServerVerifier>>,
+    pinned_spki_sha256: Option<[u8; 32]>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if let Some(chain) = &self.chain {
+            chain.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)?;
+        }
+
+        if let Some(pinned) = &self.pinned_spki_sha256 {
+            let presented = spki_sha256(end_entity).ok_or(rustls::Error::InvalidCertificate(
+                rustls::CertificateError::BadEncoding,
+            ))?;
+            if presented != *pinned {
+                return Err(rustls::Error::General(format!(
+                    "the public key of the server certificate (SPKI SHA-256 {}) is not the pinned one",
+                    hex::encode(presented)
+                )));
+            }
+        }
+
+        Ok(ServerCertVerified::assertion())
+    }
+
+    // The server proves that it holds the private key of its certificate, even when the
+    // certificate itself is not verified.
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+/// The SHA-256 digest of the SubjectPublicKeyInfo of a DER-encoded X.509 certificate, which is
+/// what [`crate::KeyBrokerClient::pinned_spki_sha256`] takes, or `None` if the certificate is
+/// malformed.
+pub fn spki_sha256(certificate: &[u8]) -> Option<[u8; 32]> {
+    let certificate = Certificate::from_der(certificate).ok()?;
+    let spki = certificate
+        .tbs_certificate
+        .subject_public_key_info
+        .to_der()
+        .ok()?;
+    Some(Sha256::digest(spki).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::pki_types::pem::PemObject;
+    use std::path::Path;
+
+    #[test]
+    fn spki_digest() {
+        let certificate = CertificateDer::from_pem_file(
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("../../testdata/tls-cert.pem"),
+        )
+        .unwrap();
+
+        // As given by openssl for the certificate.
+        assert_eq!(
+            spki_sha256(&certificate).map(hex::encode).as_deref(),
+            Some("a665ba5185eefbcdb2c515a4e08ec40d511be4f6422c767e37a70bdd2dfa58d3")
+        );
+
+        // A truncated certificate has none.
+        assert_eq!(spki_sha256(&certificate[..certificate.len() / 2]), None);
+        assert_eq!(spki_sha256(b"not a certificate"), None);
+    }
+}
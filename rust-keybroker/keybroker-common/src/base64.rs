@@ -0,0 +1,78 @@
+// Copyright 2024 Contributors to the Veraison project.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Base64 encoding and decoding of the binary fields transacted between the client and the server.
+//!
+//! The keybroker API encodes binary data with the URL-safe alphabet, without padding. For
+//! interoperability, decoding is tolerant: the standard alphabet, padding, and surrounding
+//! whitespace are accepted as well. Both the client and the server use this module, so that they
+//! behave the same way and report decoding errors with the same messages.
+use ::base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use ::base64::prelude::*;
+
+/// The error returned when a field fails to decode, naming the offending field.
+#[derive(thiserror::Error, Debug)]
+#[error("Failed to base64-decode {field}: {source}")]
+pub struct Base64FieldError {
+    /// The name of the field that failed to decode.
+    pub field: String,
+
+    /// The underlying decoding error.
+    pub source: ::base64::DecodeError,
+}
+
+/// Encode binary data with the URL-safe alphabet, without padding.
+pub fn encode<T: AsRef<[u8]>>(bytes: T) -> String {
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// Decode the base64-encoded value of the named field.
+///
+/// Both the URL-safe and the standard alphabets are accepted, with or without padding.
+pub fn decode(field_name: &str, value: &str) -> Result<Vec<u8>, Base64FieldError> {
+    let value: String = value
+        .trim()
+        .trim_end_matches('=')
+        .chars()
+        .map(|c| match c {
+            '+' => '-',
+            '/' => '_',
+            c => c,
+        })
+        .collect();
+
+    URL_SAFE_NO_PAD
+        .decode(value)
+        .map_err(|source| Base64FieldError {
+            field: field_name.to_string(),
+            source,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let bytes: Vec<u8> = (0..=255).collect();
+        assert_eq!(decode("bytes", &encode(&bytes)).unwrap(), bytes);
+    }
+
+    #[test]
+    fn alphabets_and_padding_are_tolerated() {
+        let bytes = [0xfb, 0xff, 0xbf, 0x00];
+        for value in ["-_-_AA", "+/+/AA", "-_-_AA==", "+/+/AA==", " -_-_AA\n"] {
+            assert_eq!(decode("bytes", value).unwrap(), bytes, "{value}");
+        }
+    }
+
+    #[test]
+    fn error_names_the_field() {
+        let error = decode("the wrapped data", "not base64!").unwrap_err();
+        assert_eq!(error.field, "the wrapped data");
+        assert!(error
+            .to_string()
+            .starts_with("Failed to base64-decode the wrapped data:"));
+    }
+}
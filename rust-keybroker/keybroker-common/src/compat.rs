@@ -0,0 +1,302 @@
+// Copyright 2024 Contributors to the Veraison project.
+// SPDX-License-Identifier: Apache-2.0
+
+//! The compatibility contract of the API types.
+//!
+//! Servers and clients of different versions must keep talking to each other, so newer versions
+//! only ever add members to the JSON documents. Deserialising any of the API types thus ignores
+//! the members it does not know of: a client built against an older version of this crate keeps
+//! working with a newer server, which may add members to an [`AttestationChallenge`] or a
+//! [`WrappedKeyData`] for instance.
+//!
+//! The request types, which a server deserialises from its clients, can also be deserialised
+//! strictly, denying the members they do not know of. For a server, an unexpected member is more
+//! likely to be the sign of a broken client than of a newer one, which a `400 Bad Request` tells
+//! better than silently ignoring it. The choice is made for each type, with [`UnknownFields`]:
+//!
+//! ```
+//! use keybroker_common::{BackgroundCheckKeyRequest, StrictDeserialize, UnknownFields};
+//!
+//! let request = serde_json::json!({
+//!     "pubkey": { "kty": "RSA", "alg": "RSA-OAEP", "n": "AQAB", "e": "AQAB" },
+//!     "return-attestation-results": true,
+//! });
+//! let tolerant = BackgroundCheckKeyRequest::from_json_value(request.clone(), UnknownFields::Ignore);
+//! assert!(tolerant.is_ok());
+//! let strict = BackgroundCheckKeyRequest::from_json_value(request, UnknownFields::Deny);
+//! assert!(strict.is_err());
+//! ```
+//!
+//! [`AttestationChallenge`]: crate::AttestationChallenge
+//! [`WrappedKeyData`]: crate::WrappedKeyData
+use crate::{BackgroundCheckKeyRequest, PublicWrappingKey};
+use serde::de::DeserializeOwned;
+
+/// How the members of a JSON document which are not fields of the type are treated when it is
+/// deserialised.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UnknownFields {
+    /// The unknown members are ignored, which is the behaviour of serde for all the API types.
+    #[default]
+    Ignore,
+
+    /// The unknown members make the deserialisation fail.
+    Deny,
+}
+
+/// An API type which can also be deserialised strictly, denying the unknown members.
+pub trait StrictDeserialize: DeserializeOwned {
+    /// The same type, with `#[serde(deny_unknown_fields)]`.
+    type Strict: DeserializeOwned + Into<Self>;
+
+    /// Deserialise a JSON value, treating its unknown members as `unknown_fields` says.
+    fn from_json_value(
+        value: serde_json::Value,
+        unknown_fields: UnknownFields,
+    ) -> serde_json::Result<Self> {
+        match unknown_fields {
+            UnknownFields::Ignore => serde_json::from_value(value),
+            UnknownFields::Deny => serde_json::from_value::<Self::Strict>(value).map(Into::into),
+        }
+    }
+}
+
+/// [`BackgroundCheckKeyRequest`], denying the unknown members.
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct StrictBackgroundCheckKeyRequest {
+    pubkey: StrictPublicWrappingKey,
+    return_attestation_result: Option<bool>,
+    correlation_id: Option<String>,
+}
+
+impl From<StrictBackgroundCheckKeyRequest> for BackgroundCheckKeyRequest {
+    fn from(request: StrictBackgroundCheckKeyRequest) -> Self {
+        BackgroundCheckKeyRequest {
+            pubkey: request.pubkey.into(),
+            return_attestation_result: request.return_attestation_result,
+            correlation_id: request.correlation_id,
+        }
+    }
+}
+
+impl StrictDeserialize for BackgroundCheckKeyRequest {
+    type Strict = StrictBackgroundCheckKeyRequest;
+}
+
+/// [`PublicWrappingKey`], denying the unknown members.
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StrictPublicWrappingKey {
+    kty: String,
+    alg: String,
+    n: Option<String>,
+    e: Option<String>,
+    crv: Option<String>,
+    x: Option<String>,
+    y: Option<String>,
+}
+
+impl From<StrictPublicWrappingKey> for PublicWrappingKey {
+    fn from(key: StrictPublicWrappingKey) -> Self {
+        PublicWrappingKey {
+            kty: key.kty,
+            alg: key.alg,
+            n: key.n,
+            e: key.e,
+            crv: key.crv,
+            x: key.x,
+            y: key.y,
+        }
+    }
+}
+
+impl StrictDeserialize for PublicWrappingKey {
+    type Strict = StrictPublicWrappingKey;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        AttestationChallenge, AttestationChallengeEncodings, ErrorCode, ErrorInformation,
+        ServerInfo, VerifierInfo, WrappedKeyData,
+    };
+    use serde_json::{json, Value};
+
+    /// Add an unknown member to a JSON object, and to the objects it holds.
+    fn with_unknown_members(mut value: Value) -> Value {
+        if let Value::Object(members) = &mut value {
+            for member in members.values_mut() {
+                *member = with_unknown_members(member.take());
+            }
+            members.insert("added-in-a-later-version".to_string(), json!({ "a": [1] }));
+        }
+        value
+    }
+
+    /// Serialise a value, add unknown members to it, and deserialise it again.
+    fn round_trip<T: serde::Serialize + DeserializeOwned>(value: &T) -> T {
+        let json = with_unknown_members(serde_json::to_value(value).unwrap());
+        serde_json::from_value(json).expect("unknown members are ignored")
+    }
+
+    fn pubkey() -> PublicWrappingKey {
+        PublicWrappingKey {
+            kty: "RSA".to_string(),
+            alg: "RSA-OAEP".to_string(),
+            n: Some("AQAB".to_string()),
+            e: Some("AQAB".to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn unknown_members_ignored() {
+        let challenge = AttestationChallenge {
+            challenge: "AAAA".to_string(),
+            accept: vec!["application/eat-collection".to_string()],
+            correlation_id: None,
+        };
+        assert_eq!(round_trip(&challenge).challenge, "AAAA");
+
+        let encodings = AttestationChallengeEncodings {
+            challenge,
+            challenge_hex: "000000".to_string(),
+            challenge_base64: "AAAA".to_string(),
+        };
+        assert_eq!(round_trip(&encodings).challenge_hex, "000000");
+
+        let request = BackgroundCheckKeyRequest {
+            pubkey: pubkey(),
+            return_attestation_result: Some(true),
+            correlation_id: Some("job-42".to_string()),
+        };
+        assert_eq!(round_trip(&request).return_attestation_result, Some(true));
+        assert_eq!(
+            round_trip(&request).correlation_id.as_deref(),
+            Some("job-42")
+        );
+        assert_eq!(round_trip(&pubkey()).alg, "RSA-OAEP");
+
+        let error = ErrorInformation::new(ErrorCode::PolicyRejected, "not in policy", None);
+        assert_eq!(round_trip(&error).r#type, ErrorCode::PolicyRejected);
+
+        // The error information of the earlier versions of the server has a bare error code.
+        let earlier: ErrorInformation =
+            serde_json::from_value(json!({ "type": "PolicyRejected", "detail": "not in policy" }))
+                .unwrap();
+        assert_eq!(earlier.r#type, ErrorCode::PolicyRejected);
+        assert_eq!(earlier.instance, None);
+
+        let wrapped = WrappedKeyData {
+            data: "AAAA".to_string(),
+            wrapped_cek: None,
+            iv: None,
+            enc: None,
+            epk: None,
+            apu: None,
+            apv: None,
+            key_id: Some("skywalker".to_string()),
+            attestation_result: None,
+        };
+        assert_eq!(round_trip(&wrapped).key_id.as_deref(), Some("skywalker"));
+
+        // The content encryption members are absent from the keys wrapped directly, as they are
+        // unknown to the earlier versions of the clients.
+        let json = serde_json::to_value(&wrapped).unwrap();
+        assert_eq!(json, json!({ "data": "AAAA", "key-id": "skywalker" }));
+
+        let info = ServerInfo {
+            version: "0.1.0".to_string(),
+            evidence_media_types: vec!["application/eat-collection".to_string()],
+            wrapping_algorithms: vec!["RSA-OAEP".to_string()],
+            mock_challenge: false,
+            verifier: VerifierInfo {
+                mode: "remote".to_string(),
+                url: None,
+            },
+        };
+        assert_eq!(round_trip(&info).verifier.mode, "remote");
+    }
+
+    /// A request with all its members, the struct literals being exhaustive so that a member added
+    /// to the API types must be added here too.
+    fn full_request() -> BackgroundCheckKeyRequest {
+        BackgroundCheckKeyRequest {
+            pubkey: PublicWrappingKey {
+                kty: "EC".to_string(),
+                alg: "ECDH-ES+A256KW".to_string(),
+                n: Some("AQAB".to_string()),
+                e: Some("AQAB".to_string()),
+                crv: Some("P-256".to_string()),
+                x: Some("AQAB".to_string()),
+                y: Some("AQAB".to_string()),
+            },
+            return_attestation_result: Some(true),
+            correlation_id: Some("job-42".to_string()),
+        }
+    }
+
+    #[test]
+    fn strict_types_in_step() {
+        // Every member of the API types is a known member of their strict counterpart, and comes
+        // back unchanged.
+        let json = serde_json::to_value(full_request()).unwrap();
+        let strict = BackgroundCheckKeyRequest::from_json_value(json.clone(), UnknownFields::Deny)
+            .expect("the strict type misses a member of the API type");
+        assert_eq!(serde_json::to_value(strict).unwrap(), json);
+
+        let json = serde_json::to_value(full_request().pubkey).unwrap();
+        let strict = PublicWrappingKey::from_json_value(json.clone(), UnknownFields::Deny)
+            .expect("the strict type misses a member of the API type");
+        assert_eq!(serde_json::to_value(strict).unwrap(), json);
+    }
+
+    #[test]
+    fn strict_request() {
+        let request = json!({
+            "pubkey": { "kty": "RSA", "alg": "RSA-OAEP", "n": "AQAB", "e": "AQAB" },
+            "return-attestation-result": true,
+            "correlation-id": "job-42",
+        });
+        for unknown_fields in [UnknownFields::Ignore, UnknownFields::Deny] {
+            let parsed =
+                BackgroundCheckKeyRequest::from_json_value(request.clone(), unknown_fields)
+                    .unwrap();
+            assert_eq!(parsed.pubkey.alg, "RSA-OAEP");
+            assert_eq!(parsed.return_attestation_result, Some(true));
+            assert_eq!(parsed.correlation_id.as_deref(), Some("job-42"));
+        }
+
+        // The optional members can still be left out.
+        let minimal = json!({ "pubkey": request["pubkey"] });
+        assert!(BackgroundCheckKeyRequest::from_json_value(minimal, UnknownFields::Deny).is_ok());
+
+        // The members of the elliptic-curve keys are known members.
+        let ec_request = json!({
+            "pubkey": { "kty": "EC", "alg": "ECDH-ES+A256KW", "crv": "P-256", "x": "AQAB", "y": "AQAB" },
+        });
+        let parsed =
+            BackgroundCheckKeyRequest::from_json_value(ec_request, UnknownFields::Deny).unwrap();
+        assert_eq!(parsed.pubkey.crv.as_deref(), Some("P-256"));
+
+        // An unknown member, at the top level or in the wrapping key.
+        for request in [
+            with_unknown_members(json!({ "pubkey": request["pubkey"] })),
+            json!({ "pubkey": with_unknown_members(request["pubkey"].clone()) }),
+        ] {
+            assert!(BackgroundCheckKeyRequest::from_json_value(
+                request.clone(),
+                UnknownFields::Ignore
+            )
+            .is_ok());
+            let error = BackgroundCheckKeyRequest::from_json_value(request, UnknownFields::Deny)
+                .unwrap_err();
+            assert!(
+                error.to_string().contains("added-in-a-later-version"),
+                "{error}"
+            );
+        }
+    }
+}
@@ -0,0 +1,56 @@
+// Copyright 2024 Contributors to the Veraison project.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Correlation identifiers, which tie together the server logs, the lifecycle events and the
+//! responses of a key request.
+//!
+//! A client can give a correlation identifier in its key request: the server keeps it with the
+//! challenge, includes it in its log lines and lifecycle events for the key request, and echoes it
+//! in the challenge and in the error information. It is free-form, but it ends up in logs, so it is
+//! sanitised first, by both the client and the server.
+
+/// The maximum length of a correlation identifier, in characters. Longer ones are truncated.
+pub const MAX_CORRELATION_ID_LENGTH: usize = 64;
+
+/// Sanitise a correlation identifier: the characters other than ASCII letters, digits, `-`, `_`,
+/// `.` and `:` are replaced with `_`, and it is truncated to [`MAX_CORRELATION_ID_LENGTH`]
+/// characters. An empty identifier is no identifier.
+pub fn sanitise_correlation_id(correlation_id: &str) -> Option<String> {
+    let sanitised: String = correlation_id
+        .chars()
+        .take(MAX_CORRELATION_ID_LENGTH)
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    (!sanitised.is_empty()).then_some(sanitised)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sanitise() {
+        let uuid = "0b6c4a3e-7f1d-4c52-9a8e-2d5f1b7c9e04";
+        assert_eq!(sanitise_correlation_id(uuid).as_deref(), Some(uuid));
+        assert_eq!(
+            sanitise_correlation_id("job 42\n[ok]").as_deref(),
+            Some("job_42__ok_")
+        );
+        assert_eq!(
+            sanitise_correlation_id("ü").as_deref(),
+            Some("_"),
+            "one replacement per character, not per byte"
+        );
+        assert_eq!(
+            sanitise_correlation_id(&"a".repeat(100)).map(|id| id.len()),
+            Some(MAX_CORRELATION_ID_LENGTH)
+        );
+        assert_eq!(sanitise_correlation_id(""), None);
+    }
+}
@@ -0,0 +1,338 @@
+// Copyright 2024 Contributors to the Veraison project.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Stable error codes, conveyed in the `type` field of the error information returned by the server.
+//!
+//! The server emits these codes and the client converts them back, so that applications can match on
+//! them rather than on HTTP statuses or error messages. Codes that are not known to this version of the
+//! library are preserved as [`ErrorCode::Other`], so that newer servers can introduce codes without
+//! breaking older clients.
+//!
+//! In the error information, which is a problem details document (RFC 9457), the codes are
+//! conveyed as URIs, see [`ErrorCode::uri`]. The bare codes of the earlier versions of the server
+//! are still understood.
+use serde::{Deserialize, Deserializer, Serializer};
+use std::fmt;
+
+/// The prefix of the URIs standing for the error codes in the `type` field of the error information.
+pub const ERROR_TYPE_URI_PREFIX: &str = "tag:veraison,2024:keybroker/";
+
+/// The error codes of the keybroker API.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(from = "String", into = "String")]
+pub enum ErrorCode {
+    /// The requested key is not in the store.
+    KeyNotFound,
+
+    /// The key identifier does not comply with the key identifier policy.
+    InvalidKeyId,
+
+    /// The body of the key request is not a valid key request, or has members the server does not
+    /// know of while it denies them.
+    InvalidKeyRequest,
+
+    /// The challenge identifier does not match any issued challenge.
+    ChallengeNotFound,
+
+    /// The challenge was issued, but it has expired.
+    ChallengeExpired,
+
+    /// The challenge was already redeemed.
+    ChallengeAlreadyRedeemed,
+
+    /// The evidence is not correctly base64-encoded.
+    InvalidEvidenceEncoding,
+
+    /// The Content-Type header of the evidence submission is malformed.
+    InvalidContentType,
+
+    /// The Content-Encoding of the evidence submission is not supported by the server.
+    UnsupportedContentEncoding,
+
+    /// The evidence, once decompressed, exceeds the maximum size accepted by the server.
+    EvidenceTooLarge,
+
+    /// The evidence is not structurally valid for its media type.
+    MalformedEvidence,
+
+    /// The evidence media type is not supported by the server.
+    UnsupportedMediaType,
+
+    /// The challenge was not issued for the media type of the evidence.
+    MediaTypeMismatch,
+
+    /// The attestation result is not in policy.
+    PolicyRejected,
+
+    /// The external policy engine could not be reached, or it did not respond as expected.
+    PolicyEngineUnavailable,
+
+    /// No attestation result could be obtained for the evidence.
+    AttestationFailure,
+
+    /// The verifier could not be reached, or it did not respond as expected.
+    VerifierUnavailable,
+
+    /// The verifier rejected the credentials of the server.
+    VerifierAuthenticationFailure,
+
+    /// The evidence submission was not handled within the deadline of the server.
+    DeadlineExceeded,
+
+    /// The key could not be wrapped with the wrapping key supplied by the client.
+    KeyWrappingFailure,
+
+    /// The wrapping key algorithm requested by the client is not permitted for the key.
+    UnsupportedWrappingKeyAlgorithm,
+
+    /// The wrapping key supplied by the client is too weak: its modulus is too short, or its public
+    /// exponent is too small or even.
+    WeakWrappingKey,
+
+    /// The keys can not be changed, as the key store is read-only.
+    KeyStoreReadOnly,
+
+    /// The key identifier is already taken, by an alias of another key.
+    KeyIdTaken,
+
+    /// The value given for a key is not valid base64, or is empty.
+    InvalidKeyValue,
+
+    /// The key was released too often recently, it can only be released again later.
+    ReleaseRateExceeded,
+
+    /// None of the representations of the response offered by the server is acceptable to the
+    /// client, as per its Accept header.
+    NotAcceptable,
+
+    /// The known-good reference values are malformed.
+    InvalidReferenceValues,
+
+    /// The server was started without a source of known-good reference values.
+    NoReferenceValuesSource,
+
+    /// The known-good reference values could not be persisted.
+    ReferenceValuesPersistenceFailure,
+
+    /// The admin operation was not authenticated with the admin token, or is disabled.
+    AdminAuthenticationFailure,
+
+    /// The admin operation was aborted before it completed, such as when the server shuts down.
+    AdminOperationAborted,
+
+    /// A code that is not known to this version of the library.
+    Other(String),
+}
+
+impl ErrorCode {
+    /// The code, as conveyed in the `type` field.
+    pub fn as_str(&self) -> &str {
+        match self {
+            ErrorCode::KeyNotFound => "KeyNotFound",
+            ErrorCode::InvalidKeyId => "InvalidKeyId",
+            ErrorCode::InvalidKeyRequest => "InvalidKeyRequest",
+            ErrorCode::ChallengeNotFound => "ChallengeNotFound",
+            ErrorCode::ChallengeExpired => "ChallengeExpired",
+            ErrorCode::ChallengeAlreadyRedeemed => "ChallengeAlreadyRedeemed",
+            ErrorCode::InvalidEvidenceEncoding => "InvalidEvidenceEncoding",
+            ErrorCode::InvalidContentType => "InvalidContentType",
+            ErrorCode::UnsupportedContentEncoding => "UnsupportedContentEncoding",
+            ErrorCode::EvidenceTooLarge => "EvidenceTooLarge",
+            ErrorCode::MalformedEvidence => "MalformedEvidence",
+            ErrorCode::UnsupportedMediaType => "UnsupportedMediaType",
+            ErrorCode::MediaTypeMismatch => "MediaTypeMismatch",
+            ErrorCode::PolicyRejected => "PolicyRejected",
+            ErrorCode::PolicyEngineUnavailable => "PolicyEngineUnavailable",
+            ErrorCode::AttestationFailure => "AttestationFailure",
+            ErrorCode::VerifierUnavailable => "VerifierUnavailable",
+            ErrorCode::VerifierAuthenticationFailure => "VerifierAuthenticationFailure",
+            ErrorCode::DeadlineExceeded => "DeadlineExceeded",
+            ErrorCode::KeyWrappingFailure => "KeyWrappingFailure",
+            ErrorCode::UnsupportedWrappingKeyAlgorithm => "UnsupportedWrappingKeyAlgorithm",
+            ErrorCode::WeakWrappingKey => "WeakWrappingKey",
+            ErrorCode::KeyStoreReadOnly => "KeyStoreReadOnly",
+            ErrorCode::KeyIdTaken => "KeyIdTaken",
+            ErrorCode::InvalidKeyValue => "InvalidKeyValue",
+            ErrorCode::ReleaseRateExceeded => "ReleaseRateExceeded",
+            ErrorCode::NotAcceptable => "NotAcceptable",
+            ErrorCode::InvalidReferenceValues => "InvalidReferenceValues",
+            ErrorCode::NoReferenceValuesSource => "NoReferenceValuesSource",
+            ErrorCode::ReferenceValuesPersistenceFailure => "ReferenceValuesPersistenceFailure",
+            ErrorCode::AdminAuthenticationFailure => "AdminAuthenticationFailure",
+            ErrorCode::AdminOperationAborted => "AdminOperationAborted",
+            ErrorCode::Other(code) => code,
+        }
+    }
+
+    /// The URI standing for the code in the `type` field of the error information: the code in
+    /// kebab case, after [`ERROR_TYPE_URI_PREFIX`], such as
+    /// `tag:veraison,2024:keybroker/policy-rejected`. A code not known to this version of the
+    /// library which is already a URI stands for itself.
+    pub fn uri(&self) -> String {
+        let code = self.as_str();
+        if matches!(self, ErrorCode::Other(_)) && code.contains(':') {
+            return code.to_string();
+        }
+        let mut uri = ERROR_TYPE_URI_PREFIX.to_string();
+        for (index, c) in code.chars().enumerate() {
+            if index > 0 && c.is_ascii_uppercase() {
+                uri.push('-');
+            }
+            uri.push(c.to_ascii_lowercase());
+        }
+        uri
+    }
+
+    /// The code a URI stands for, or the code itself if it is given bare, as by the earlier
+    /// versions of the server. The URIs of the codes not known to this version of the library are
+    /// preserved as they are.
+    pub fn from_uri(uri: &str) -> ErrorCode {
+        let Some(name) = uri.strip_prefix(ERROR_TYPE_URI_PREFIX) else {
+            return ErrorCode::from(uri.to_string());
+        };
+        let code: String = name
+            .split('-')
+            .map(|word| {
+                let mut chars = word.chars();
+                chars.next().map_or(String::new(), |first| {
+                    first.to_ascii_uppercase().to_string() + chars.as_str()
+                })
+            })
+            .collect();
+        match ErrorCode::from(code) {
+            ErrorCode::Other(_) => ErrorCode::Other(uri.to_string()),
+            code => code,
+        }
+    }
+
+    /// A short summary of the code, such as "Policy rejected", as the `title` of the error
+    /// information.
+    pub fn title(&self) -> String {
+        let mut title = String::new();
+        for (index, c) in self.as_str().chars().enumerate() {
+            if index > 0 && c.is_ascii_uppercase() {
+                title.push(' ');
+                title.push(c.to_ascii_lowercase());
+            } else {
+                title.push(c);
+            }
+        }
+        title
+    }
+}
+
+/// Serialisation of the error codes as the URIs standing for them, for the `type` field of the
+/// error information. The bare codes are also deserialised.
+pub(crate) mod problem_type {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(code: &ErrorCode, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&code.uri())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<ErrorCode, D::Error> {
+        Ok(ErrorCode::from_uri(&String::deserialize(deserializer)?))
+    }
+}
+
+impl From<String> for ErrorCode {
+    fn from(code: String) -> Self {
+        match code.as_str() {
+            "KeyNotFound" => ErrorCode::KeyNotFound,
+            "InvalidKeyId" => ErrorCode::InvalidKeyId,
+            "InvalidKeyRequest" => ErrorCode::InvalidKeyRequest,
+            "ChallengeNotFound" => ErrorCode::ChallengeNotFound,
+            "ChallengeExpired" => ErrorCode::ChallengeExpired,
+            "ChallengeAlreadyRedeemed" => ErrorCode::ChallengeAlreadyRedeemed,
+            "InvalidEvidenceEncoding" => ErrorCode::InvalidEvidenceEncoding,
+            "InvalidContentType" => ErrorCode::InvalidContentType,
+            "UnsupportedContentEncoding" => ErrorCode::UnsupportedContentEncoding,
+            "EvidenceTooLarge" => ErrorCode::EvidenceTooLarge,
+            "MalformedEvidence" => ErrorCode::MalformedEvidence,
+            "UnsupportedMediaType" => ErrorCode::UnsupportedMediaType,
+            "MediaTypeMismatch" => ErrorCode::MediaTypeMismatch,
+            "PolicyRejected" => ErrorCode::PolicyRejected,
+            "PolicyEngineUnavailable" => ErrorCode::PolicyEngineUnavailable,
+            "AttestationFailure" => ErrorCode::AttestationFailure,
+            "VerifierUnavailable" => ErrorCode::VerifierUnavailable,
+            "VerifierAuthenticationFailure" => ErrorCode::VerifierAuthenticationFailure,
+            "DeadlineExceeded" => ErrorCode::DeadlineExceeded,
+            "KeyWrappingFailure" => ErrorCode::KeyWrappingFailure,
+            "UnsupportedWrappingKeyAlgorithm" => ErrorCode::UnsupportedWrappingKeyAlgorithm,
+            "WeakWrappingKey" => ErrorCode::WeakWrappingKey,
+            "KeyStoreReadOnly" => ErrorCode::KeyStoreReadOnly,
+            "KeyIdTaken" => ErrorCode::KeyIdTaken,
+            "InvalidKeyValue" => ErrorCode::InvalidKeyValue,
+            "ReleaseRateExceeded" => ErrorCode::ReleaseRateExceeded,
+            "NotAcceptable" => ErrorCode::NotAcceptable,
+            "InvalidReferenceValues" => ErrorCode::InvalidReferenceValues,
+            "NoReferenceValuesSource" => ErrorCode::NoReferenceValuesSource,
+            "ReferenceValuesPersistenceFailure" => ErrorCode::ReferenceValuesPersistenceFailure,
+            "AdminAuthenticationFailure" => ErrorCode::AdminAuthenticationFailure,
+            "AdminOperationAborted" => ErrorCode::AdminOperationAborted,
+            _ => ErrorCode::Other(code),
+        }
+    }
+}
+
+impl From<ErrorCode> for String {
+    fn from(code: ErrorCode) -> Self {
+        match code {
+            ErrorCode::Other(code) => code,
+            code => code.as_str().to_string(),
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_code_round_trip() {
+        let json = serde_json::to_string(&ErrorCode::PolicyRejected).unwrap();
+        assert_eq!(json, r#""PolicyRejected""#);
+        assert_eq!(
+            serde_json::from_str::<ErrorCode>(&json).unwrap(),
+            ErrorCode::PolicyRejected
+        );
+    }
+
+    #[test]
+    fn unknown_code_is_preserved() {
+        let code = serde_json::from_str::<ErrorCode>(r#""SomethingNew""#).unwrap();
+        assert_eq!(code, ErrorCode::Other("SomethingNew".to_string()));
+        assert_eq!(serde_json::to_string(&code).unwrap(), r#""SomethingNew""#);
+    }
+
+    #[test]
+    fn uri() {
+        let code = ErrorCode::VerifierAuthenticationFailure;
+        assert_eq!(
+            code.uri(),
+            "tag:veraison,2024:keybroker/verifier-authentication-failure"
+        );
+        assert_eq!(ErrorCode::from_uri(&code.uri()), code);
+        assert_eq!(ErrorCode::from_uri("InvalidKeyId"), ErrorCode::InvalidKeyId);
+        assert_eq!(ErrorCode::InvalidKeyId.title(), "Invalid key id");
+
+        // The codes of newer servers are preserved, whether they are URIs or bare.
+        let newer = "tag:veraison,2024:keybroker/something-new";
+        assert_eq!(
+            ErrorCode::from_uri(newer),
+            ErrorCode::Other(newer.to_string())
+        );
+        assert_eq!(ErrorCode::from_uri(newer).uri(), newer);
+        assert_eq!(ErrorCode::from_uri("SomethingNew").uri(), newer);
+        assert_eq!(
+            ErrorCode::from_uri("https://errors.example/new").uri(),
+            "https://errors.example/new"
+        );
+    }
+}
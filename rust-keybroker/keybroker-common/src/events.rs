@@ -0,0 +1,181 @@
+// Copyright 2024 Contributors to the Veraison project.
+// SPDX-License-Identifier: Apache-2.0
+
+//! The progress events of a key request, as reported by the clients to whoever drives them.
+//!
+//! They are serialised as JSON objects, with the name of the event in the `event` member, e.g.
+//! `{"event":"challenge-received","nonce-length":64,"accept":["..."]}`. A key request reports
+//! the stages it goes through in order, and ends with either `key-received` or `failed`.
+use crate::{ErrorCode, EvidenceContentType};
+use serde_with::{serde_as, DurationMicroSeconds};
+use std::time::Duration;
+
+/// How long the phases of a key request took, as measured by the client with a monotonic clock.
+///
+/// The phases a key request did not go through, because it failed before them or did not need
+/// them, are missing. In JSON, the durations are in microseconds, e.g.
+/// `{"wrapping-key-generation":5120,"challenge-round-trip":1870}`.
+#[serde_with::skip_serializing_none]
+#[serde_as]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Timings {
+    /// Generating the wrapping key-pair, or copying it when it is reused.
+    #[serde_as(as = "Option<DurationMicroSeconds<u64>>")]
+    pub wrapping_key_generation: Option<Duration>,
+
+    /// Requesting the challenge, up to reading the response of the server.
+    #[serde_as(as = "Option<DurationMicroSeconds<u64>>")]
+    pub challenge_round_trip: Option<Duration>,
+
+    /// Generating the evidence for the challenge.
+    #[serde_as(as = "Option<DurationMicroSeconds<u64>>")]
+    pub evidence_generation: Option<Duration>,
+
+    /// Submitting the evidence, up to reading the response of the server, which includes the
+    /// verification of the evidence.
+    #[serde_as(as = "Option<DurationMicroSeconds<u64>>")]
+    pub evidence_round_trip: Option<Duration>,
+
+    /// Unwrapping the key.
+    #[serde_as(as = "Option<DurationMicroSeconds<u64>>")]
+    pub unwrap: Option<Duration>,
+}
+
+impl Timings {
+    /// The phases, in the order a key request goes through them, named as in JSON.
+    pub fn phases(&self) -> [(&'static str, Option<Duration>); 5] {
+        [
+            ("wrapping-key-generation", self.wrapping_key_generation),
+            ("challenge-round-trip", self.challenge_round_trip),
+            ("evidence-generation", self.evidence_generation),
+            ("evidence-round-trip", self.evidence_round_trip),
+            ("unwrap", self.unwrap),
+        ]
+    }
+
+    /// The time spent in all the phases the key request went through.
+    pub fn total(&self) -> Duration {
+        self.phases()
+            .into_iter()
+            .filter_map(|(_, duration)| duration)
+            .sum()
+    }
+}
+
+/// A stage of a key request.
+#[serde_with::skip_serializing_none]
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(
+    tag = "event",
+    rename_all = "kebab-case",
+    rename_all_fields = "kebab-case"
+)]
+pub enum ProgressEvent {
+    /// The challenge is being requested for a key.
+    ChallengeRequested { key_name: String, url: String },
+
+    /// The challenge was received.
+    ChallengeReceived {
+        /// The size of the challenge (nonce) in bytes.
+        nonce_length: usize,
+        /// The evidence media types accepted by the server.
+        accept: Vec<EvidenceContentType>,
+    },
+
+    /// The evidence was generated for the challenge.
+    EvidenceGenerated { size: usize, media_type: String },
+
+    /// The evidence was submitted to the server.
+    EvidenceSubmitted { url: String },
+
+    /// The key was received, and unwrapped. Its value is never part of the event.
+    KeyReceived {
+        size: usize,
+        /// How long the phases of the key request took, if the client measures them.
+        timings: Option<Timings>,
+    },
+
+    /// The key request failed, with the error code reported by the server if there is one.
+    Failed {
+        code: Option<ErrorCode>,
+        detail: String,
+        /// How long the phases of the key request took, if the client measures them.
+        timings: Option<Timings>,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn event_round_trip() {
+        let event = ProgressEvent::ChallengeReceived {
+            nonce_length: 64,
+            accept: vec!["application/eat-collection".to_string()],
+        };
+
+        let json = serde_json::to_string(&event).unwrap();
+        assert_eq!(
+            json,
+            r#"{"event":"challenge-received","nonce-length":64,"accept":["application/eat-collection"]}"#
+        );
+        assert_eq!(serde_json::from_str::<ProgressEvent>(&json).unwrap(), event);
+    }
+
+    #[test]
+    fn failure_without_code() {
+        let event = ProgressEvent::Failed {
+            code: None,
+            detail: "connection refused".to_string(),
+            timings: None,
+        };
+
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"event":"failed","detail":"connection refused"}"#
+        );
+
+        let event = ProgressEvent::Failed {
+            code: Some(ErrorCode::PolicyRejected),
+            detail: "nope".to_string(),
+            timings: None,
+        };
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"event":"failed","code":"PolicyRejected","detail":"nope"}"#
+        );
+    }
+
+    #[test]
+    fn timings() {
+        let timings = Timings {
+            wrapping_key_generation: Some(Duration::from_micros(5120)),
+            challenge_round_trip: Some(Duration::from_millis(2)),
+            ..Timings::default()
+        };
+        assert_eq!(timings.total(), Duration::from_micros(7120));
+
+        let event = ProgressEvent::KeyReceived {
+            size: 26,
+            timings: Some(timings),
+        };
+        let json = serde_json::to_string(&event).unwrap();
+        assert_eq!(
+            json,
+            r#"{"event":"key-received","size":26,"timings":{"wrapping-key-generation":5120,"challenge-round-trip":2000}}"#
+        );
+        assert_eq!(serde_json::from_str::<ProgressEvent>(&json).unwrap(), event);
+
+        // The events of the clients which do not measure the phases are unchanged.
+        assert_eq!(
+            serde_json::to_string(&ProgressEvent::KeyReceived {
+                size: 26,
+                timings: None
+            })
+            .unwrap(),
+            r#"{"event":"key-received","size":26}"#
+        );
+    }
+}
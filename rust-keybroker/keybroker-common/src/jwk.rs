@@ -0,0 +1,635 @@
+// Copyright 2024 Contributors to the Veraison project.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Conversions between [`PublicWrappingKey`] and its standard representation, an RFC 7517 JSON Web
+//! Key, so that tools can pass wrapping keys around as JWK strings.
+//!
+//! Parsing a JWK validates it the way the keybroker server would: the key type must be `RSA`, with
+//! one of the RSA wrapping algorithms, or `EC` (on P-256) or `OKP` (on X25519), with the
+//! ECDH-ES+A256KW wrapping algorithm, and the members of the key must be base64url-encoded without
+//! padding, as RFC 7518 requires. The other members of the JWK (such as `kid` or `use`) are ignored.
+//!
+//! A wrapping key is identified by its RFC 7638 thumbprint, which a client can extend a realm
+//! extensible measurement with, so that the evidence binds the wrapping key: the
+//! [`binding measurement`](PublicWrappingKey::binding_measurement) is the value the measurement then
+//! takes, as the keybroker server checks it.
+//!
+//! The keys too large to be wrapped directly, and those wrapped with ECDH-ES+A256KW, are encrypted
+//! with an AES-256-GCM content encryption key, see [`encrypt_content`] and [`decrypt_content`].
+//!
+//! With the `rsa` feature, a wrapping key can also be built from an `rsa::RsaPublicKey`.
+use crate::PublicWrappingKey;
+use ::base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use ::base64::prelude::*;
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::str::FromStr;
+use zeroize::{Zeroize, Zeroizing};
+
+/// The type of the RSA wrapping keys.
+pub const RSA_KEY_TYPE: &str = "RSA";
+
+/// The type of the elliptic-curve wrapping keys, on the P-256 curve.
+pub const EC_KEY_TYPE: &str = "EC";
+
+/// The type of the octet key pair wrapping keys, on the X25519 curve (RFC 8037).
+pub const OKP_KEY_TYPE: &str = "OKP";
+
+/// The NIST P-256 curve, of the EC wrapping keys.
+pub const P256_CURVE: &str = "P-256";
+
+/// The X25519 curve, of the OKP wrapping keys.
+pub const X25519_CURVE: &str = "X25519";
+
+/// The size of the coordinates of the P-256 points and of the X25519 public keys, in bytes.
+pub const CURVE_COORDINATE_SIZE: usize = 32;
+
+/// The RSA PKCS#1 v1.5 wrapping algorithm.
+pub const RSA_PKCS15_ALGORITHM: &str = "RSA1_5";
+
+/// The RSA OAEP wrapping algorithm, with SHA-1 for both the label hash and MGF1, as RFC 7518
+/// defines it.
+pub const RSA_OAEP_ALGORITHM: &str = "RSA-OAEP";
+
+/// The RSA OAEP wrapping algorithm, with SHA-256 for both the label hash and MGF1.
+pub const RSA_OAEP_256_ALGORITHM: &str = "RSA-OAEP-256";
+
+/// The legacy name of the RSA OAEP wrapping algorithm with SHA-256, which is still accepted as
+/// [`RSA_OAEP_256_ALGORITHM`].
+pub const LEGACY_OAEP_ALGORITHM: &str = "OAEP";
+
+/// The ECDH-ES wrapping algorithm with AES-256 Key Wrap, for the EC and OKP keys. The key wrapping
+/// key is derived from the ECDH shared secret with [`ecdh_es_key_wrapping_key`].
+pub const ECDH_ES_A256KW_ALGORITHM: &str = "ECDH-ES+A256KW";
+
+/// The wrapping algorithms the keybroker supports, under their RFC 7518 names.
+pub const WRAPPING_ALGORITHMS: [&str; 4] = [
+    RSA_PKCS15_ALGORITHM,
+    RSA_OAEP_ALGORITHM,
+    RSA_OAEP_256_ALGORITHM,
+    ECDH_ES_A256KW_ALGORITHM,
+];
+
+/// The RFC 7518 name of a supported wrapping algorithm, given either under that name or under a
+/// legacy one.
+pub fn canonical_wrapping_algorithm(alg: &str) -> Option<&'static str> {
+    match alg {
+        LEGACY_OAEP_ALGORITHM => Some(RSA_OAEP_256_ALGORITHM),
+        alg => WRAPPING_ALGORITHMS.into_iter().find(|known| *known == alg),
+    }
+}
+
+/// Whether a supported wrapping algorithm, under its standard or legacy name, can be used with
+/// keys of the given type.
+pub fn algorithm_fits_key_type(alg: &str, kty: &str) -> bool {
+    match canonical_wrapping_algorithm(alg) {
+        Some(ECDH_ES_A256KW_ALGORITHM) => kty == EC_KEY_TYPE || kty == OKP_KEY_TYPE,
+        Some(_) => kty == RSA_KEY_TYPE,
+        None => false,
+    }
+}
+
+/// The AES-256-GCM content encryption algorithm, with which the keys too large to be wrapped
+/// directly are encrypted.
+pub const A256GCM_ENCRYPTION: &str = "A256GCM";
+
+/// The size of the AES-256-GCM content encryption keys, in bytes.
+pub const CEK_SIZE: usize = 32;
+
+/// The size of the AES-256-GCM initialisation vectors, in bytes.
+pub const IV_SIZE: usize = 12;
+
+/// The size of the SHA-256 thumbprints and binding measurements.
+pub const THUMBPRINT_SIZE: usize = 32;
+
+/// The size of the AES-256 key wrapping keys of ECDH-ES+A256KW, in bytes.
+pub const KEY_WRAPPING_KEY_SIZE: usize = 32;
+
+/// Derive the ECDH-ES+A256KW key wrapping key from an ECDH shared secret, with the Concat KDF of
+/// RFC 7518 section 4.6.2.
+///
+/// The key is bound to both parties: the `apu` (Agreement PartyUInfo) is the ephemeral public key
+/// of the server, and the `apv` (Agreement PartyVInfo) the wrapping key of the client, as the
+/// concatenation of their `x` and `y` members for EC keys, or as their `x` member for OKP keys.
+pub fn ecdh_es_key_wrapping_key(
+    shared_secret: &[u8],
+    apu: &[u8],
+    apv: &[u8],
+) -> Zeroizing<[u8; KEY_WRAPPING_KEY_SIZE]> {
+    let mut kek = Zeroizing::new([0; KEY_WRAPPING_KEY_SIZE]);
+    concat_kdf(
+        shared_secret,
+        ECDH_ES_A256KW_ALGORITHM,
+        apu,
+        apv,
+        kek.as_mut(),
+    );
+    kek
+}
+
+/// The Concat KDF of NIST SP 800-56A, with SHA-256 and the `OtherInfo` of RFC 7518, for keys of up
+/// to 32 bytes, which take a single round.
+fn concat_kdf(shared_secret: &[u8], alg: &str, apu: &[u8], apv: &[u8], key: &mut [u8]) {
+    assert!(
+        key.len() <= 32,
+        "The Concat KDF only derives keys of up to 32 bytes."
+    );
+    let field = |hasher: &mut Sha256, data: &[u8]| {
+        hasher.update((data.len() as u32).to_be_bytes());
+        hasher.update(data);
+    };
+
+    let mut hasher = Sha256::new();
+    hasher.update(1u32.to_be_bytes());
+    hasher.update(shared_secret);
+    field(&mut hasher, alg.as_bytes());
+    field(&mut hasher, apu);
+    field(&mut hasher, apv);
+    hasher.update(((key.len() * 8) as u32).to_be_bytes());
+    let mut digest = hasher.finalize();
+    key.copy_from_slice(&digest[..key.len()]);
+    digest.as_mut_slice().zeroize();
+}
+
+/// Encrypt a key with an AES-256-GCM content encryption key, the authentication tag being appended
+/// to the ciphertext.
+///
+/// The identifier of the key, as in the `key-id` member of the wrapped key data, is the additional
+/// authenticated data, so that the encrypted key can't be passed off as another.
+pub fn encrypt_content(
+    cek: &[u8; CEK_SIZE],
+    iv: &[u8; IV_SIZE],
+    key_id: &str,
+    plaintext: &[u8],
+) -> Result<Vec<u8>, ContentEncryptionError> {
+    Aes256Gcm::new(cek.into())
+        .encrypt(
+            Nonce::from_slice(iv),
+            Payload {
+                msg: plaintext,
+                aad: key_id.as_bytes(),
+            },
+        )
+        .map_err(|_| ContentEncryptionError::EncryptionFailure)
+}
+
+/// Decrypt a key encrypted with [`encrypt_content`], for the identifier of the key it was encrypted
+/// for.
+///
+/// The plain text is wiped from memory when it is dropped.
+pub fn decrypt_content(
+    cek: &[u8],
+    iv: &[u8],
+    key_id: &str,
+    ciphertext: &[u8],
+) -> Result<Zeroizing<Vec<u8>>, ContentEncryptionError> {
+    if iv.len() != IV_SIZE {
+        return Err(ContentEncryptionError::InvalidIv(iv.len()));
+    }
+    Aes256Gcm::new_from_slice(cek)
+        .map_err(|_| ContentEncryptionError::InvalidKey(cek.len()))?
+        .decrypt(
+            Nonce::from_slice(iv),
+            Payload {
+                msg: ciphertext,
+                aad: key_id.as_bytes(),
+            },
+        )
+        .map(Zeroizing::new)
+        .map_err(|_| ContentEncryptionError::AuthenticationFailure)
+}
+
+/// The errors of the content encryption of keys.
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum ContentEncryptionError {
+    /// The content encryption key is not an AES-256 key.
+    #[error("The content encryption key is {0} bytes long, rather than 32.")]
+    InvalidKey(usize),
+
+    /// The initialisation vector is not of the AES-GCM size.
+    #[error("The content encryption IV is {0} bytes long, rather than 12.")]
+    InvalidIv(usize),
+
+    /// The key could not be encrypted.
+    #[error("The content could not be encrypted.")]
+    EncryptionFailure,
+
+    /// The ciphertext, or the key identifier it is bound to, was tampered with.
+    #[error("The content encryption failed to authenticate.")]
+    AuthenticationFailure,
+}
+
+/// The errors of the conversions of wrapping keys.
+#[derive(thiserror::Error, Debug)]
+pub enum JwkError {
+    /// The JWK is not a JSON object with the expected members.
+    #[error("The JWK is not valid: {0}")]
+    Json(#[from] serde_json::Error),
+
+    /// The key type is not supported for wrapping.
+    #[error("The key type '{0}' is not supported for wrapping, only 'RSA', 'EC' and 'OKP' are.")]
+    UnsupportedKeyType(String),
+
+    /// The curve is not supported for the key type.
+    #[error("The curve '{0}' is not supported for wrapping with this key type.")]
+    UnsupportedCurve(String),
+
+    /// The algorithm is not one of the supported wrapping algorithms, or not one for the key type.
+    #[error("The algorithm '{0}' is not supported for wrapping with this key type.")]
+    UnsupportedAlgorithm(String),
+
+    /// A member the key type requires is missing from the JWK.
+    #[error("The '{0}' member is missing from the JWK.")]
+    MissingMember(&'static str),
+
+    /// A member of the JWK is not base64url-encoded.
+    #[error("The '{0}' member of the JWK is not base64url-encoded: {1}")]
+    InvalidEncoding(&'static str, ::base64::DecodeError),
+
+    /// The key is larger than the largest the keybroker server accepts.
+    #[error("The key is {0} bits long, larger than the largest wrapping key accepted.")]
+    KeyTooLarge(usize),
+}
+
+impl PublicWrappingKey {
+    /// Check that the key type, curve, algorithm and encoding of the key are supported.
+    pub fn validate(&self) -> Result<(), JwkError> {
+        let (curve, members) = match self.kty.as_str() {
+            RSA_KEY_TYPE => (None, vec![("n", &self.n), ("e", &self.e)]),
+            EC_KEY_TYPE => (Some(P256_CURVE), vec![("x", &self.x), ("y", &self.y)]),
+            OKP_KEY_TYPE => (Some(X25519_CURVE), vec![("x", &self.x)]),
+            kty => return Err(JwkError::UnsupportedKeyType(kty.to_string())),
+        };
+        if !algorithm_fits_key_type(&self.alg, &self.kty) {
+            return Err(JwkError::UnsupportedAlgorithm(self.alg.clone()));
+        }
+        if let Some(curve) = curve {
+            match &self.crv {
+                None => return Err(JwkError::MissingMember("crv")),
+                Some(crv) if crv != curve => return Err(JwkError::UnsupportedCurve(crv.clone())),
+                Some(_) => {}
+            }
+        }
+        for (member, value) in members {
+            let value = value.as_ref().ok_or(JwkError::MissingMember(member))?;
+            URL_SAFE_NO_PAD
+                .decode(value)
+                .map_err(|error| JwkError::InvalidEncoding(member, error))?;
+        }
+        Ok(())
+    }
+
+    /// The RFC 7638 SHA-256 thumbprint of the key, which only covers the key type and the public
+    /// key (the modulus and exponent, or the curve and coordinates), and so does not depend on the
+    /// wrapping algorithm.
+    pub fn thumbprint(&self) -> [u8; THUMBPRINT_SIZE] {
+        // The required members, in lexicographic order, without whitespace.
+        let members = match self.kty.as_str() {
+            EC_KEY_TYPE => serde_json::json!({
+                "crv": self.crv, "kty": self.kty, "x": self.x, "y": self.y
+            }),
+            OKP_KEY_TYPE => serde_json::json!({ "crv": self.crv, "kty": self.kty, "x": self.x }),
+            _ => serde_json::json!({ "e": self.e, "kty": self.kty, "n": self.n }),
+        };
+        Sha256::digest(members.to_string()).into()
+    }
+
+    /// The value of a SHA-256 realm extensible measurement extended once, from its initial zero
+    /// value, with the thumbprint of the key.
+    pub fn binding_measurement(&self) -> [u8; THUMBPRINT_SIZE] {
+        Sha256::new()
+            .chain_update([0; THUMBPRINT_SIZE])
+            .chain_update(self.thumbprint())
+            .finalize()
+            .into()
+    }
+
+    /// The same key, to be used with another wrapping algorithm for its type.
+    pub fn with_algorithm(self, alg: &str) -> Result<PublicWrappingKey, JwkError> {
+        if !algorithm_fits_key_type(alg, &self.kty) {
+            return Err(JwkError::UnsupportedAlgorithm(alg.to_string()));
+        }
+        Ok(PublicWrappingKey {
+            alg: alg.to_string(),
+            ..self
+        })
+    }
+}
+
+impl FromStr for PublicWrappingKey {
+    type Err = JwkError;
+
+    fn from_str(jwk: &str) -> Result<Self, Self::Err> {
+        let key: PublicWrappingKey = serde_json::from_str(jwk)?;
+        key.validate()?;
+        Ok(key)
+    }
+}
+
+impl fmt::Display for PublicWrappingKey {
+    /// Format the key as a JWK, in compact JSON.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let jwk = serde_json::to_string(self).map_err(|_| fmt::Error)?;
+        f.write_str(&jwk)
+    }
+}
+
+/// Build the wrapping key of an RSA public key, for the PKCS#1 v1.5 algorithm (use
+/// [`PublicWrappingKey::with_algorithm`] for another one).
+#[cfg(feature = "rsa")]
+impl TryFrom<&rsa::RsaPublicKey> for PublicWrappingKey {
+    type Error = JwkError;
+
+    fn try_from(key: &rsa::RsaPublicKey) -> Result<Self, Self::Error> {
+        use rsa::traits::PublicKeyParts;
+
+        // Larger keys can be built, but the server would refuse them.
+        let bits = key.n().bits();
+        if bits > rsa::RsaPublicKey::MAX_SIZE {
+            return Err(JwkError::KeyTooLarge(bits));
+        }
+
+        Ok(PublicWrappingKey {
+            kty: RSA_KEY_TYPE.to_string(),
+            alg: RSA_PKCS15_ALGORITHM.to_string(),
+            n: Some(URL_SAFE_NO_PAD.encode(key.n().to_bytes_be())),
+            e: Some(URL_SAFE_NO_PAD.encode(key.e().to_bytes_be())),
+            ..Default::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The RSA public key of RFC 7517, appendix A.1, with the key identifier dropped.
+    const JWK: &str = r#"{"kty":"RSA","alg":"RSA1_5","n":"0vx7agoebGcQSuuPiLJXZptN9nndrQmbXEps2aiAFbWhM78LhWx4cbbfAAtVT86zwu1RK7aPFFxuhDR1L6tSoc_BJECPebWKRXjBZCiFV4n3oknjhMstn64tZ_2W-5JsGY4Hc5n9yBXArwl93lqt7_RN5w6Cf0h4QyQ5v-65YGjQR0_FDW2QvzqY368QQMicAtaSqzs8KJZgnYb9c7d0zgdAZHzu6qMQvRL5hajrn1n91CbOpbISD08qNLyrdkt-bFTWhAI4vMQFh6WeZu0fM4lFd2NcRwr3XPksINHaQ-G_xBniIqbw0Ls1jF44-csFCur-kEgU8awapJzKnqDKgw","e":"AQAB"}"#;
+
+    #[test]
+    fn jwk_round_trip() {
+        let key: PublicWrappingKey = JWK.parse().unwrap();
+        assert_eq!(key.alg, RSA_PKCS15_ALGORITHM);
+        assert_eq!(key.e.as_deref(), Some("AQAB"));
+        assert_eq!(key.to_string(), JWK);
+
+        // The members the keybroker does not use are ignored.
+        let with_kid = JWK.replacen('{', r#"{"kid":"2011-04-29","use":"enc","#, 1);
+        assert_eq!(
+            with_kid.parse::<PublicWrappingKey>().unwrap().to_string(),
+            JWK
+        );
+    }
+
+    #[test]
+    fn invalid_jwks() {
+        assert!(matches!(
+            "not a JWK".parse::<PublicWrappingKey>(),
+            Err(JwkError::Json(_))
+        ));
+        assert!(matches!(
+            r#"{"kty":"RSA","n":"AQAB","e":"AQAB"}"#.parse::<PublicWrappingKey>(),
+            Err(JwkError::Json(_))
+        ));
+        assert!(matches!(
+            r#"{"kty":"RSA","alg":"RSA1_5","n":"AQAB"}"#.parse::<PublicWrappingKey>(),
+            Err(JwkError::MissingMember("e"))
+        ));
+        assert!(matches!(
+            JWK.replace(r#""kty":"RSA""#, r#""kty":"oct""#)
+                .parse::<PublicWrappingKey>(),
+            Err(JwkError::UnsupportedKeyType(kty)) if kty == "oct"
+        ));
+        // The RSA algorithms are not for the EC keys.
+        assert!(matches!(
+            JWK.replace(r#""kty":"RSA""#, r#""kty":"EC""#)
+                .parse::<PublicWrappingKey>(),
+            Err(JwkError::UnsupportedAlgorithm(alg)) if alg == "RSA1_5"
+        ));
+        assert!(matches!(
+            JWK.replace("RSA1_5", "RSA-OAEP-384")
+                .parse::<PublicWrappingKey>(),
+            Err(JwkError::UnsupportedAlgorithm(alg)) if alg == "RSA-OAEP-384"
+        ));
+
+        // Padding and the standard alphabet are not base64url.
+        for e in ["AQAB=", "AQ+B"] {
+            assert!(matches!(
+                JWK.replace("AQAB", e).parse::<PublicWrappingKey>(),
+                Err(JwkError::InvalidEncoding("e", _))
+            ));
+        }
+    }
+
+    // The EC public key of RFC 7517, appendix A.1, with the key identifier dropped.
+    const EC_JWK: &str = r#"{"kty":"EC","alg":"ECDH-ES+A256KW","crv":"P-256","x":"MKBCTNIcKUSDii11ySs3526iDZ8AiTo7Tu6KPAqv7D4","y":"4Etl6SRW2YiLUrN5vfvVHuhp7x8PxltmWWlbbM4IFyM"}"#;
+
+    // The X25519 public key of Bob in RFC 8037, appendix A.6.
+    const OKP_JWK: &str = r#"{"kty":"OKP","alg":"ECDH-ES+A256KW","crv":"X25519","x":"3p7bfXt9wbTTW2HC7OQ1Nz-DQ8hbeGdNrfx-FG-IK08"}"#;
+
+    #[test]
+    fn elliptic_curve_jwks() {
+        for jwk in [EC_JWK, OKP_JWK] {
+            let key: PublicWrappingKey = jwk.parse().unwrap();
+            assert_eq!(key.alg, ECDH_ES_A256KW_ALGORITHM);
+            assert!(key.n.is_none() && key.e.is_none());
+            assert_eq!(key.to_string(), jwk);
+
+            // The ECDH-ES algorithm is not for the RSA keys, nor the RSA ones for the EC keys.
+            assert!(matches!(
+                key.with_algorithm(RSA_OAEP_256_ALGORITHM),
+                Err(JwkError::UnsupportedAlgorithm(_))
+            ));
+            assert!(matches!(
+                JWK.replace("RSA1_5", ECDH_ES_A256KW_ALGORITHM)
+                    .parse::<PublicWrappingKey>(),
+                Err(JwkError::UnsupportedAlgorithm(_))
+            ));
+        }
+
+        assert!(matches!(
+            EC_JWK.replace("P-256", "P-384").parse::<PublicWrappingKey>(),
+            Err(JwkError::UnsupportedCurve(crv)) if crv == "P-384"
+        ));
+        assert!(matches!(
+            OKP_JWK
+                .replace("X25519", "P-256")
+                .parse::<PublicWrappingKey>(),
+            Err(JwkError::UnsupportedCurve(_))
+        ));
+        assert!(matches!(
+            OKP_JWK
+                .replace(r#","crv":"X25519""#, "")
+                .parse::<PublicWrappingKey>(),
+            Err(JwkError::MissingMember("crv"))
+        ));
+        let without_y = &EC_JWK[..EC_JWK.find(r#","y""#).unwrap()];
+        assert!(matches!(
+            format!("{without_y}}}").parse::<PublicWrappingKey>(),
+            Err(JwkError::MissingMember("y"))
+        ));
+    }
+
+    #[test]
+    fn elliptic_curve_thumbprints() {
+        // The thumbprint of the Ed25519 key of RFC 8037, appendix A.3, which is computed the same
+        // way as for the X25519 keys.
+        let key = PublicWrappingKey {
+            kty: OKP_KEY_TYPE.to_string(),
+            crv: Some("Ed25519".to_string()),
+            x: Some("11qYAYKxCrfVS_7TyWQHOg7hcvPapiMlrwIaaPcHURo".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            URL_SAFE_NO_PAD.encode(key.thumbprint()),
+            "kPrK_qmxVWaYVA9wwBF6Iuo3vVzz7TxHCTwXBygrS4k"
+        );
+
+        // The thumbprint of an EC key covers its four required members.
+        let key: PublicWrappingKey = EC_JWK.parse().unwrap();
+        let members = r#"{"crv":"P-256","kty":"EC","x":"MKBCTNIcKUSDii11ySs3526iDZ8AiTo7Tu6KPAqv7D4","y":"4Etl6SRW2YiLUrN5vfvVHuhp7x8PxltmWWlbbM4IFyM"}"#;
+        assert_eq!(
+            key.thumbprint(),
+            <[u8; THUMBPRINT_SIZE]>::from(Sha256::digest(members))
+        );
+    }
+
+    #[test]
+    fn thumbprint() {
+        // The thumbprint of the key of RFC 7638, section 3.1.
+        let key: PublicWrappingKey = JWK.parse().unwrap();
+        assert_eq!(
+            URL_SAFE_NO_PAD.encode(key.thumbprint()),
+            "NzbLsXh8uDCcd-6MNwXF4W_7noWXFZAfHkxZsRGC9Xs"
+        );
+        let key = key.with_algorithm(RSA_OAEP_ALGORITHM).unwrap();
+        assert_eq!(
+            URL_SAFE_NO_PAD.encode(key.thumbprint()),
+            "NzbLsXh8uDCcd-6MNwXF4W_7noWXFZAfHkxZsRGC9Xs"
+        );
+
+        let mut extended = [0; 2 * THUMBPRINT_SIZE];
+        extended[THUMBPRINT_SIZE..].copy_from_slice(&key.thumbprint());
+        assert_eq!(
+            key.binding_measurement(),
+            <[u8; THUMBPRINT_SIZE]>::from(Sha256::digest(extended))
+        );
+    }
+
+    #[test]
+    fn change_of_algorithm() {
+        let key: PublicWrappingKey = JWK.parse().unwrap();
+        let key = key.with_algorithm(RSA_OAEP_ALGORITHM).unwrap();
+        assert_eq!(key.alg, RSA_OAEP_ALGORITHM);
+        assert!(matches!(
+            key.with_algorithm("none"),
+            Err(JwkError::UnsupportedAlgorithm(_))
+        ));
+    }
+
+    #[test]
+    fn wrapping_algorithm_names() {
+        // The algorithm is kept as it is given, even under a legacy name, so that the JWK
+        // round-trips to the same string.
+        for alg in ["RSA1_5", "RSA-OAEP", "RSA-OAEP-256", "OAEP"] {
+            let jwk = JWK.replace("RSA1_5", alg);
+            let key: PublicWrappingKey = jwk.parse().unwrap();
+            assert_eq!(key.alg, alg);
+            assert_eq!(key.to_string(), jwk);
+        }
+
+        assert_eq!(canonical_wrapping_algorithm("RSA1_5"), Some("RSA1_5"));
+        assert_eq!(canonical_wrapping_algorithm("RSA-OAEP"), Some("RSA-OAEP"));
+        assert_eq!(
+            canonical_wrapping_algorithm("RSA-OAEP-256"),
+            Some("RSA-OAEP-256")
+        );
+        assert_eq!(canonical_wrapping_algorithm("OAEP"), Some("RSA-OAEP-256"));
+        assert_eq!(
+            canonical_wrapping_algorithm("ECDH-ES+A256KW"),
+            Some("ECDH-ES+A256KW")
+        );
+        assert_eq!(canonical_wrapping_algorithm("rsa-oaep"), None);
+    }
+
+    #[test]
+    fn concat_kdf_vector() {
+        // The ECDH-ES example of RFC 7518 appendix C.
+        let shared_secret = [
+            158, 86, 217, 29, 129, 113, 53, 211, 114, 131, 66, 131, 191, 132, 38, 156, 251, 49,
+            110, 163, 218, 128, 106, 72, 246, 218, 167, 121, 140, 254, 144, 196,
+        ];
+        let mut key = [0; 16];
+        concat_kdf(&shared_secret, "A128GCM", b"Alice", b"Bob", &mut key);
+        assert_eq!(
+            key,
+            [86, 170, 141, 234, 248, 35, 109, 32, 92, 34, 40, 205, 113, 167, 16, 26]
+        );
+
+        // The key wrapping keys are bound to both parties.
+        let kek = ecdh_es_key_wrapping_key(&shared_secret, b"Alice", b"Bob");
+        assert_ne!(
+            *kek,
+            *ecdh_es_key_wrapping_key(&shared_secret, b"Alice", b"Eve")
+        );
+        assert_ne!(
+            *kek,
+            *ecdh_es_key_wrapping_key(&shared_secret, b"Eve", b"Bob")
+        );
+    }
+
+    #[test]
+    fn content_encryption() {
+        let cek = [7; CEK_SIZE];
+        let iv = [9; IV_SIZE];
+        let ciphertext = encrypt_content(&cek, &iv, "skywalker", b"May the force be with you.")
+            .expect("Failed to encrypt the content.");
+        // The 16-byte authentication tag is appended.
+        assert_eq!(ciphertext.len(), 26 + 16);
+        assert_eq!(
+            *decrypt_content(&cek, &iv, "skywalker", &ciphertext).unwrap(),
+            b"May the force be with you."
+        );
+
+        // The ciphertext is bound to the key identifier.
+        assert_eq!(
+            decrypt_content(&cek, &iv, "vader", &ciphertext),
+            Err(ContentEncryptionError::AuthenticationFailure)
+        );
+        assert_eq!(
+            decrypt_content(&cek, &iv, "", &ciphertext),
+            Err(ContentEncryptionError::AuthenticationFailure)
+        );
+        assert_eq!(
+            decrypt_content(&cek[..16], &iv, "skywalker", &ciphertext),
+            Err(ContentEncryptionError::InvalidKey(16))
+        );
+        assert_eq!(
+            decrypt_content(&cek, &iv[..8], "skywalker", &ciphertext),
+            Err(ContentEncryptionError::InvalidIv(8))
+        );
+    }
+
+    #[cfg(feature = "rsa")]
+    #[test]
+    fn from_rsa_public_key() {
+        use rsa::{BigUint, RsaPublicKey};
+
+        let key: PublicWrappingKey = JWK.parse().unwrap();
+        let n = BigUint::from_bytes_be(&URL_SAFE_NO_PAD.decode(key.n.unwrap()).unwrap());
+        let rsa_key = RsaPublicKey::new(n, BigUint::from(65537u32)).unwrap();
+        assert_eq!(
+            PublicWrappingKey::try_from(&rsa_key).unwrap().to_string(),
+            JWK
+        );
+
+        let n = BigUint::from_bytes_be(&[0xc5; 1024]);
+        let rsa_key = RsaPublicKey::new_with_max_size(n, BigUint::from(65537u32), 8192).unwrap();
+        assert!(matches!(
+            PublicWrappingKey::try_from(&rsa_key),
+            Err(JwkError::KeyTooLarge(8192))
+        ));
+    }
+}
@@ -4,6 +4,22 @@
 //! This library provides the common data types that are defined in the OpenAPI schema for the keybroker,
 //! along with the serialization functionality that allows them to be transacted over HTTP. The small collection
 //! of data types in this library are consumed by both the server and the client.
+//!
+//! The JSON documents of the API only ever gain members in newer versions: deserialising any of
+//! the types ignores the members it does not know of, so that older clients keep working with newer
+//! servers. The request types can also be deserialised strictly, see [`compat`].
+
+pub mod base64;
+pub mod compat;
+pub mod correlation;
+mod error_code;
+mod events;
+pub mod jwk;
+
+pub use compat::{StrictDeserialize, UnknownFields};
+pub use correlation::{sanitise_correlation_id, MAX_CORRELATION_ID_LENGTH};
+pub use error_code::{ErrorCode, ERROR_TYPE_URI_PREFIX};
+pub use events::{ProgressEvent, Timings};
 
 /// Represents a single attestation challenge (nonce).
 ///
@@ -23,6 +39,31 @@ pub struct AttestationChallenge {
 
     /// List of acceptable evidence media types, such as "application/eat-collection; profile=http://arm.com/CCA-SSD/1.0.0".
     pub accept: Vec<EvidenceContentType>,
+
+    /// The correlation identifier of the key request, as sanitised by the server, if it had one.
+    pub correlation_id: Option<String>,
+}
+
+/// The media type of the [`AttestationChallengeEncodings`] representation of a challenge, which a
+/// client can ask for in the `Accept` header of its key request.
+pub const ATTESTATION_CHALLENGE_ENCODINGS_MEDIA_TYPE: &str =
+    "application/vnd.veraison.keybroker.attestation-challenge+json";
+
+/// An attestation challenge, with the challenge value (nonce) also given in alternative encodings,
+/// for the clients that would rather not decode base64.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct AttestationChallengeEncodings {
+    /// The challenge, as in the default representation.
+    #[serde(flatten)]
+    pub challenge: AttestationChallenge,
+
+    /// Lower-case hexadecimal encoding of the challenge value.
+    pub challenge_hex: String,
+
+    /// Base64 encoding of the challenge value, with the standard alphabet and padding (as expected
+    /// by `atob()` in browsers), where `challenge` uses the URL-safe alphabet without padding.
+    pub challenge_base64: String,
 }
 
 /// A request to access a key or secret string according to the "background check" interaction pattern
@@ -39,18 +80,64 @@ pub struct BackgroundCheckKeyRequest {
     /// it to the client. This is in order for confidentiality to be maintained without relying solely on TLS
     /// between the client and the server.
     pub pubkey: PublicWrappingKey,
+
+    /// Whether the server should return the attestation result (EAR) alongside the wrapped key, so that
+    /// the client can keep it as a "passport" for later use. The attestation result is not returned
+    /// unless this is explicitly set to true, to keep the responses small.
+    pub return_attestation_result: Option<bool>,
+
+    /// A free-form identifier of the key request, which the server includes in its logs and
+    /// lifecycle events, and echoes in its responses, see [`correlation`].
+    pub correlation_id: Option<String>,
 }
 
-/// Represents an error occurring within the API usage.
+/// The media type of the [`ErrorInformation`] returned by the server.
+pub const PROBLEM_JSON_MEDIA_TYPE: &str = "application/problem+json";
+
+/// Represents an error occurring within the API usage, as a problem details document (RFC 9457).
 #[serde_with::skip_serializing_none]
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct ErrorInformation {
-    /// Formal type string for the error.
-    pub r#type: String,
+    /// Formal type string for the error, which is one of the stable error codes. It is conveyed as
+    /// the URI standing for the code, see [`ErrorCode::uri`].
+    #[serde(with = "error_code::problem_type")]
+    pub r#type: ErrorCode,
+
+    /// A short summary of the error code.
+    pub title: Option<String>,
+
+    /// The HTTP status code of the response.
+    pub status: Option<u16>,
 
     /// Human-readable error details, giving more information about the error.
     pub detail: String,
+
+    /// A URI identifying this occurrence of the error, which the server logs along with it, so that
+    /// it can be traced when it is reported.
+    pub instance: Option<String>,
+
+    /// The correlation identifier of the key request the error is about, if it had one.
+    pub correlation_id: Option<String>,
+}
+
+impl ErrorInformation {
+    /// The error information for an error code and its details, about the key request with the
+    /// given correlation identifier, if any. The other members are filled in as the server responds.
+    pub fn new(
+        r#type: ErrorCode,
+        detail: impl Into<String>,
+        correlation_id: Option<String>,
+    ) -> Self {
+        ErrorInformation {
+            r#type,
+            title: None,
+            status: None,
+            detail: detail.into(),
+            instance: None,
+            correlation_id,
+        }
+    }
 }
 
 pub type EvidenceBytes = String;
@@ -66,30 +153,140 @@ pub type EvidenceContentType = String;
 /// Only the client (within its confidential compute environment) has the private part of the key pair, with
 /// which it can decrypt and use the data from the server.
 ///
-/// Only RSA keys are currently supported for wrapping.
+/// RSA keys (`kty` "RSA", with `n` and `e`) are supported for the RSA wrapping algorithms, and the P-256 (`kty`
+/// "EC", with `crv`, `x` and `y`) and X25519 (`kty` "OKP", with `crv` and `x`) keys for the ECDH-ES+A256KW
+/// wrapping algorithm, the members being those of the JWKs (RFC 7518 and RFC 8037).
 #[serde_with::skip_serializing_none]
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct PublicWrappingKey {
-    /// Public key type. This must be "RSA".
+    /// Public key type. This must be "RSA", "EC" or "OKP".
     pub kty: String,
 
-    /// Encryption algorithm. This must be either "RSA1_5" or "OAEP".
+    /// Encryption algorithm. For RSA keys, this must be one of "RSA1_5", "RSA-OAEP" (with SHA-1) or
+    /// "RSA-OAEP-256" (with SHA-256), as RFC 7518 names them. The legacy "OAEP" is accepted as "RSA-OAEP-256".
+    /// For EC and OKP keys, this must be "ECDH-ES+A256KW".
     pub alg: String,
 
-    /// Base64 encoding of the public key modulus.
-    pub n: String,
+    /// Base64 encoding of the public key modulus, for RSA keys.
+    pub n: Option<String>,
+
+    /// Base64 encoding of the public key exponent, for RSA keys.
+    pub e: Option<String>,
+
+    /// The curve of the key, for EC ("P-256") and OKP ("X25519") keys.
+    pub crv: Option<String>,
 
-    /// Base64 encoding of the public key exponent.
-    pub e: String,
+    /// Base64 encoding of the x coordinate of the public point for EC keys, or of the public key for OKP keys.
+    pub x: Option<String>,
+
+    /// Base64 encoding of the y coordinate of the public point, for EC keys.
+    pub y: Option<String>,
+}
+
+/// The ephemeral public key the server generated to wrap a key with ECDH-ES+A256KW, as a JWK with the same
+/// members as the [`PublicWrappingKey`] it was generated for, on the same curve.
+#[serde_with::skip_serializing_none]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct EphemeralPublicKey {
+    /// Public key type, "EC" or "OKP".
+    pub kty: String,
+
+    /// The curve of the key, "P-256" or "X25519".
+    pub crv: String,
+
+    /// Base64 encoding of the x coordinate of the public point for EC keys, or of the public key for OKP keys.
+    pub x: String,
+
+    /// Base64 encoding of the y coordinate of the public point, for EC keys.
+    pub y: Option<String>,
 }
 
 /// Wrapped/encrypted secret data returned from the server in the case of a successfully-verified attestation.
+///
+/// Secret data too large to be encrypted with the wrapping key directly is encrypted with a random content
+/// encryption key instead, which is itself encrypted with the wrapping key: `wrapped-cek`, `iv` and `enc` are
+/// then present. They are absent otherwise, as with the earlier versions of the server, except with the
+/// ECDH-ES+A256KW wrapping algorithm, which always uses a content encryption key, along with `epk`.
 #[serde_with::skip_serializing_none]
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct WrappedKeyData {
     /// Base64 encoding of encrypted data. The client should Base64-decode this string, and then RSA decrypt the
-    /// resulting vector of bytes in order to obtain the secret data payload.
+    /// resulting vector of bytes in order to obtain the secret data payload. With a `wrapped-cek`, the client
+    /// should instead decrypt the vector of bytes with the content encryption key.
     pub data: String,
+
+    /// Base64 encoding of the content encryption key, encrypted with the wrapping key like `data` otherwise is.
+    pub wrapped_cek: Option<String>,
+
+    /// Base64 encoding of the initialisation vector of the content encryption, with a `wrapped-cek`.
+    pub iv: Option<String>,
+
+    /// The content encryption algorithm, with a `wrapped-cek`. This must be "A256GCM", that is AES-256 in GCM
+    /// mode, with the `key-id` (or nothing, without one) as additional authenticated data, the 16-byte
+    /// authentication tag being appended to `data`. See [`jwk::encrypt_content`].
+    pub enc: Option<String>,
+
+    /// The ephemeral public key of the server, with the ECDH-ES+A256KW wrapping algorithm. The content
+    /// encryption key is then wrapped with AES-256 Key Wrap (RFC 3394), with the key derived by the Concat
+    /// KDF of RFC 7518 from the ECDH shared secret of this key and the wrapping key, see
+    /// [`jwk::ecdh_es_key_wrapping_key`]. The keys are always encrypted with a content encryption key with
+    /// this algorithm.
+    pub epk: Option<EphemeralPublicKey>,
+
+    /// Base64 encoding of the Agreement PartyUInfo of the key derivation, with `epk`: the ephemeral public
+    /// key of the server, as the concatenation of its `x` and `y` coordinates for EC keys.
+    pub apu: Option<String>,
+
+    /// Base64 encoding of the Agreement PartyVInfo of the key derivation, with `epk`: the wrapping key, as
+    /// the concatenation of its `x` and `y` coordinates for EC keys. Clients should derive it from their
+    /// own key rather than trust this member.
+    pub apv: Option<String>,
+
+    /// The identifier of the released key. This is the canonical identifier of the key, even if it
+    /// was requested under one of its aliases.
+    pub key_id: Option<String>,
+
+    /// The attestation result (EAR) obtained from the verifier, as a signed JWT. This is only present if the
+    /// client asked for it in its key request.
+    pub attestation_result: Option<String>,
+}
+
+/// Describes the capabilities of a keybroker server, as returned by its information endpoint.
+///
+/// This is what a client (or a human) needs to know to understand what the keybroker server will accept.
+/// It deliberately excludes any sensitive detail of the server configuration, such as file paths or
+/// credentials.
+#[serde_with::skip_serializing_none]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ServerInfo {
+    /// The version of the keybroker server.
+    pub version: String,
+
+    /// List of the evidence media types that the server accepts.
+    pub evidence_media_types: Vec<EvidenceContentType>,
+
+    /// List of the wrapping algorithms that the server supports, such as "RSA1_5".
+    pub wrapping_algorithms: Vec<String>,
+
+    /// Whether the server issues the static CCA example token nonce instead of random challenges.
+    pub mock_challenge: bool,
+
+    /// How the server appraises the evidence.
+    pub verifier: VerifierInfo,
+}
+
+/// Describes the verifier used by a keybroker server to appraise the evidence.
+#[serde_with::skip_serializing_none]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct VerifierInfo {
+    /// The verifier mode, such as "remote" when the evidence is appraised by a Veraison instance.
+    pub mode: String,
+
+    /// The URL of the remote verifier, stripped of any credentials.
+    pub url: Option<String>,
 }
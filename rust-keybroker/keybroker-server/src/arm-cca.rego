@@ -3,21 +3,105 @@ package arm_cca
 default allow := false
 
 allow if {
-    input.eat_profile == "tag:github.com,2023:veraison/ear"
+    input.ear.eat_profile == "tag:github.com,2023:veraison/ear"
+
+    submods_status_allowed
 
     # platform part
-    prec := input.submods.CCA_SSD_PLATFORM
-    prec["ear.status"] == "affirming"
+    platform_config_allowed
+    platform_lifecycle_allowed
 
     # realm part
-    rrec := input.submods.CCA_REALM
-    rrec["ear.status"] == "warning"
+    rrec := input.ear.submods.CCA_REALM
 
     rtv := rrec["ear.trustworthiness-vector"]
     rtv["instance-identity"] == 2
 
-    # check RIM value against known-good-values
+    # check RIM value against known-good-values, both being normalised to base64
     rclaims := rrec["ear.veraison.annotated-evidence"]
-    rim := rclaims["cca-realm-initial-measurement"]
-    rim in data["reference-values"]
-}
\ No newline at end of file
+    rim_allowed(rclaims["cca-realm-initial-measurement"])
+    key_constraints_met(rclaims["cca-realm-initial-measurement"])
+
+    wrapping_key_bound
+}
+
+# the platform must be affirming, while the realm is only expected to be in warning, unless the
+# server is started with --all-submods-affirming
+submods_status_allowed if {
+    not input.config["all-submods-affirming"]
+    input.ear.submods.CCA_SSD_PLATFORM["ear.status"] == "affirming"
+    input.ear.submods.CCA_REALM["ear.status"] == "warning"
+}
+
+submods_status_allowed if {
+    input.config["all-submods-affirming"]
+    data.keybroker.submods.all_affirming
+}
+
+# the known-good values are digests and prefix rules, possibly in named groups
+rim_allowed(rim) if rim_in_entries(rim, data["realm-initial-measurements"])
+
+# the requested key may only be released to some of the workloads, given as the known-good values
+key_constraints_met(rim) if not data.key_constraints["realm-initial-measurements"]
+
+key_constraints_met(rim) if rim_in_entries(rim, data.key_constraints["realm-initial-measurements"])
+
+rim_in_entries(rim, entries) if rim_matches(rim, entries)
+
+rim_in_entries(rim, entries) if {
+    some group in entries
+    rim_matches(rim, group.values)
+}
+
+rim_matches(rim, values) if rim in values
+
+rim_matches(rim, values) if {
+    some rule in values
+    keybroker.digest_has_prefix(rim, rule.prefix, object.get(rule, "min-length", 0))
+}
+
+platform_claims := input.ear.submods.CCA_SSD_PLATFORM["ear.veraison.annotated-evidence"]
+
+realm_claims := input.ear.submods.CCA_REALM["ear.veraison.annotated-evidence"]
+
+# the evidence need not bind the wrapping key, unless the server is started with
+# --bind-wrapping-key: a realm extensible measurement must then have been extended with the
+# thumbprint of the wrapping key
+default wrapping_key_bound := false
+
+wrapping_key_bound if not input.challenge["wrapping-key-binding"]
+
+wrapping_key_bound if {
+    some rem in realm_claims["cca-realm-extensible-measurements"]
+    keybroker.digest_equal(rem, input.challenge["wrapping-key-binding"])
+}
+
+# any platform configuration is allowed, unless known-good ones are given
+default platform_config_allowed := false
+
+platform_config_allowed if not data["platform-config-digests"]
+
+platform_config_allowed if {
+    some config in data["platform-config-digests"]
+    keybroker.digest_equal(platform_claims["cca-platform-config"], config)
+}
+
+# any lifecycle state is allowed, unless some are given
+default platform_lifecycle_allowed := false
+
+platform_lifecycle_allowed if not data["allowed-lifecycle-states"]
+
+platform_lifecycle_allowed if {
+    # the lifecycle state is given by the top four bits of the 16-bit lifecycle value
+    states := [
+        "unknown",
+        "assembly-and-test",
+        "psa-rot-provisioning",
+        "secured",
+        "non-psa-rot-debug",
+        "recoverable-psa-rot-debug",
+        "decommissioned",
+    ]
+    state := states[floor(platform_claims["cca-platform-lifecycle"] / 4096)]
+    state in data["allowed-lifecycle-states"]
+}
@@ -0,0 +1,236 @@
+// Copyright 2024 Contributors to the Veraison project.
+// SPDX-License-Identifier: Apache-2.0
+
+//! The audit trail of the key releases: one record for each evidence submission, whether the key
+//! was released or not, written as JSON lines to the file given with `--audit-log`:
+//!
+//! ```json
+//! {"timestamp":"2024-11-06T10:20:34.512Z","challenge-id":1234,"key-id":"skywalker","peer":"192.0.2.7","media-type":"application/eat-collection; profile=\"http://arm.com/CCA-SSD/1.0.0\"","submods":{"CCA_REALM":"warning","CCA_SSD_PLATFORM":"affirming"},"decision":"allowed","status":200}
+//! ```
+//!
+//! The records tell who asked for which key, and why it was released or not: the status of each
+//! submodule of the attestation result, the policy decision, and the HTTP status and error code of
+//! the response. Members that are not known for a submission, such as the key of a challenge which
+//! was never redeemed, or the policy decision of an evidence that the verifier rejected, are left
+//! out. Neither the evidence nor any key material is ever recorded.
+//!
+//! The records are written by a thread of their own, like the lifecycle events. The file is opened
+//! again on SIGHUP, so that it can be rotated: once it is renamed, the records go to a new file at
+//! the same path.
+use crate::lifecycle::Outcome;
+use chrono::{SecondsFormat, Utc};
+use keybroker_common::ErrorCode;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+
+/// The record of an evidence submission.
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct AuditRecord {
+    /// When the submission was answered, in RFC 3339 with milliseconds.
+    pub timestamp: String,
+    pub challenge_id: u32,
+
+    /// The key requested with the challenge, if the challenge was redeemed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key_id: Option<String>,
+
+    /// The correlation identifier of the key request, if the client gave one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+
+    /// The IP address of the client, as seen by the server.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peer: Option<String>,
+
+    /// The media type of the evidence, once it was found acceptable.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub media_type: Option<String>,
+
+    /// The status of each submodule of the attestation result, by submodule name.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub submods: BTreeMap<String, String>,
+
+    /// The policy decision, `allowed` or `denied`, if the attestation result was appraised.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub decision: Option<Outcome>,
+
+    /// The HTTP status of the response: 200 when the key was released.
+    pub status: u16,
+
+    /// The error code of the response, when the key was not released.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<ErrorCode>,
+}
+
+impl AuditRecord {
+    /// A record of a submission for a challenge, timestamped now.
+    pub fn new(challenge_id: u32) -> AuditRecord {
+        AuditRecord {
+            timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            challenge_id,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Message {
+    Record(AuditRecord),
+    Reopen,
+}
+
+/// Where the audit records are written, if anywhere.
+#[derive(Debug, Default)]
+pub struct AuditLog {
+    /// The channel to the writer of the audit log, if there is one.
+    writer: Option<Sender<Message>>,
+}
+
+impl AuditLog {
+    /// Write the records to a file, which is created or appended to.
+    pub fn open(path: &Path) -> std::io::Result<AuditLog> {
+        let file = open_log(path)?;
+        let (sender, receiver) = mpsc::channel();
+        let path = path.to_path_buf();
+        std::thread::Builder::new()
+            .name("audit-log".to_string())
+            .spawn(move || write_records(receiver, path, file))?;
+
+        Ok(AuditLog {
+            writer: Some(sender),
+        })
+    }
+
+    /// Record an evidence submission.
+    pub fn record(&self, record: AuditRecord) {
+        if let Some(writer) = &self.writer {
+            // The writer only goes away with the log.
+            let _ = writer.send(Message::Record(record));
+        }
+    }
+
+    /// Open the file again, after the records written so far, so that a rotated file is let go.
+    pub fn reopen(&self) {
+        if let Some(writer) = &self.writer {
+            let _ = writer.send(Message::Reopen);
+        }
+    }
+}
+
+fn open_log(path: &Path) -> std::io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Write the records, one JSON document per line, until the log goes away.
+fn write_records(messages: Receiver<Message>, path: PathBuf, mut out: File) {
+    for message in messages {
+        match message {
+            Message::Record(record) => {
+                let mut line =
+                    serde_json::to_vec(&record).expect("Failed to serialise an audit record.");
+                line.push(b'\n');
+
+                // Failing to write the audit log is worth shouting about, but must not stop the
+                // server.
+                if let Err(error) = out.write_all(&line).and_then(|_| out.flush()) {
+                    log::error!("Failed to write an audit record: {error}");
+                }
+            }
+            // On failure, the records keep going to the file that was open.
+            Message::Reopen => match open_log(&path) {
+                Ok(file) => out = file,
+                Err(error) => log::error!(
+                    "Failed to open the audit log {} again: {error}",
+                    path.display()
+                ),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_records(path: &Path) -> Vec<serde_json::Value> {
+        std::fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn record_lines() {
+        let path =
+            std::env::temp_dir().join(format!("keybroker-audit-{}.jsonl", std::process::id()));
+        let rotated = path.with_extension("jsonl.1");
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(&rotated);
+
+        // The file is rotated under the writer: the first record goes to the rotated file, and
+        // the second one to a new file once the log is opened again.
+        let file = open_log(&path).unwrap();
+        std::fs::rename(&path, &rotated).unwrap();
+
+        let (sender, receiver) = mpsc::channel();
+        let log = AuditLog {
+            writer: Some(sender),
+        };
+        log.record(AuditRecord {
+            key_id: Some("skywalker".to_string()),
+            peer: Some("192.0.2.7".to_string()),
+            media_type: Some("application/eat+cwt".to_string()),
+            submods: BTreeMap::from([
+                ("CCA_REALM".to_string(), "warning".to_string()),
+                ("CCA_SSD_PLATFORM".to_string(), "affirming".to_string()),
+            ]),
+            decision: Some(Outcome::Allowed),
+            status: 200,
+            ..AuditRecord::new(1234)
+        });
+        log.reopen();
+        log.record(AuditRecord {
+            correlation_id: Some("job-42".to_string()),
+            status: 403,
+            error: Some(ErrorCode::ChallengeNotFound),
+            ..AuditRecord::new(5678)
+        });
+        drop(log);
+        write_records(receiver, path.clone(), file);
+
+        let released = read_records(&rotated);
+        assert_eq!(released.len(), 1);
+        assert_eq!(released[0]["challenge-id"], 1234);
+        assert_eq!(released[0]["key-id"], "skywalker");
+        assert_eq!(released[0]["peer"], "192.0.2.7");
+        assert_eq!(released[0]["media-type"], "application/eat+cwt");
+        assert_eq!(released[0]["submods"]["CCA_REALM"], "warning");
+        assert_eq!(released[0]["submods"]["CCA_SSD_PLATFORM"], "affirming");
+        assert_eq!(released[0]["decision"], "allowed");
+        assert_eq!(released[0]["status"], 200);
+        assert!(released[0].get("error").is_none());
+        assert!(
+            chrono::DateTime::parse_from_rfc3339(released[0]["timestamp"].as_str().unwrap())
+                .is_ok()
+        );
+
+        let refused = read_records(&path);
+        assert_eq!(refused.len(), 1);
+        assert_eq!(refused[0]["challenge-id"], 5678);
+        assert_eq!(refused[0]["correlation-id"], "job-42");
+        assert_eq!(refused[0]["status"], 403);
+        assert_eq!(refused[0]["error"], "ChallengeNotFound");
+        for member in ["key-id", "peer", "media-type", "submods", "decision"] {
+            assert!(refused[0].get(member).is_none(), "{member}");
+        }
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&rotated).unwrap();
+    }
+}
@@ -0,0 +1,58 @@
+// Copyright 2024 Contributors to the Veraison project.
+// SPDX-License-Identifier: Apache-2.0
+
+//! A local look at the claims of the submitted CCA tokens, logged before the evidence is sent to
+//! the verifier, so that an operator can tell which realm initial measurement was claimed without
+//! digging it out of the attestation result.
+//!
+//! The token is only decoded, not verified: the trust decisions still come from the verifier.
+//! Tokens which can't be decoded are left for the verifier to reject, with a note in the log.
+use crate::digest::encode_digest;
+use ccatoken::token::Evidence;
+
+/// Summarise the realm claims of a CCA token.
+fn realm_claims_summary(evidence: &[u8]) -> Result<String, ccatoken::token::Error> {
+    let realm = Evidence::decode(&evidence.to_vec())?.realm_claims;
+    Ok(format!(
+        "realm initial measurement {}, personalization value {}, hash algorithm {}",
+        encode_digest(&realm.rim),
+        encode_digest(&realm.perso),
+        realm.hash_alg
+    ))
+}
+
+/// Log the realm claims of the CCA token submitted for a challenge.
+pub(crate) fn log_claims(challenge_id: u32, evidence: &[u8]) {
+    match realm_claims_summary(evidence) {
+        Ok(summary) => log::info!("CCA token submitted for challenge {challenge_id}: {summary}"),
+        Err(error) => log::info!(
+            "The CCA token submitted for challenge {challenge_id} could not be decoded locally, \
+             its claims are not logged: {error}"
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use keybroker_client::{CcaExampleToken, EvidenceProvider};
+
+    #[test]
+    fn example_token() {
+        let evidence = CcaExampleToken {}.get_evidence("").unwrap();
+        let summary = realm_claims_summary(&evidence).expect("the example token decodes");
+        assert!(
+            summary
+                .contains("realm initial measurement MRMUq3NiA1DPdYg0rlxl2ejC3H/r5ufZZUu+hk4wDUk="),
+            "{summary}"
+        );
+        assert!(summary.contains("hash algorithm sha-256"), "{summary}");
+    }
+
+    #[test]
+    fn malformed_token() {
+        assert!(realm_claims_summary(&[0xd9, 0x01, 0x8f, 0xa0]).is_err());
+        assert!(realm_claims_summary(b"not a token").is_err());
+        log_claims(1234, b"not a token");
+    }
+}
@@ -16,10 +16,36 @@
 //! Challenges are given their own unique identities (simple 32-bit integer values) and cached within the keybroker service,
 //! with the expectation that the client will later attempt to redeem the challenge by submitting an evidence bundle.
 //!
-use crate::error::Result;
+//! Once redeemed, a challenge is replaced by a short-lived tombstone, so that a client retrying its evidence submission
+//! (for example after a timeout) can be told that the challenge was already used, rather than that it never existed.
+//!
+//! The challenges are spread over shards by identity, each behind a lock of its own, so that concurrent key requests
+//! and evidence submissions only contend when their challenges share a shard. The lock of a shard is only held for the
+//! table operations: the identities and nonces are drawn before it is taken. Each shard keeps a running count of its
+//! pending challenges, so that they can be summarised without walking the table.
+//!
+//! A redeemed challenge can be put back in place of its tombstone, while the outcome of its redemption is pending,
+//! when the evidence could not be appraised because the verifier failed, so that the client can submit its evidence
+//! again. The challenge counts the attempts, and the server decides how many it allows.
+//!
+//! A challenge can only be redeemed within its time to live. Past that, it is reported as expired, even before it is
+//! swept from the table, so that a stale nonce is never redeemable. The server sweeps the expired challenges
+//! periodically, leaving tombstones in their place, so that clients that never come back do not hold memory forever.
+//!
+use crate::error::{ChallengeErrorKind, Error, Result};
 use keybroker_common::PublicWrappingKey;
-use rand::{rngs::StdRng, Rng, SeedableRng};
-use std::collections::HashMap;
+use rand::Rng;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime};
+
+/// How long the tombstone of a redeemed challenge is kept.
+const TOMBSTONE_LIFETIME: Duration = Duration::from_secs(300);
+
+/// The number of shards the challenges are spread over.
+const SHARDS: usize = 16;
 
 /// Represents a single challenge, and provides the challenge value ("nonce") while also remembering the information
 /// that the client provided in order to access a key.
@@ -39,12 +65,100 @@ pub struct Challenge {
 
     /// The challenge value (nonce) that the client must incorporate into the evidence bundle.
     pub challenge_value: Vec<u8>,
+
+    /// The media types of the evidence that the challenge was issued for. The challenge can only be redeemed
+    /// with evidence of one of these types.
+    pub media_types: Vec<String>,
+
+    /// Whether the client asked for the attestation result to be returned alongside the wrapped key.
+    pub return_attestation_result: bool,
+
+    /// The correlation identifier given by the client in its key request, once sanitised.
+    pub correlation_id: Option<String>,
+
+    /// When the challenge was issued.
+    pub created_at: SystemTime,
+
+    /// When the challenge expires, if it has not been redeemed by then.
+    pub expires_at: Instant,
+
+    /// The number of times the challenge was redeemed, and put back after the appraisal of the
+    /// evidence failed for want of a working verifier.
+    pub attempts: u32,
+}
+
+/// The outcome of the attempt to redeem a challenge.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RedemptionOutcome {
+    /// The evidence is still being verified.
+    Pending,
+
+    /// The attestation succeeded.
+    Succeeded,
+
+    /// The attestation failed.
+    Failed,
+
+    /// The submission was abandoned at its deadline, while the evidence was being appraised.
+    TimedOut,
+
+    /// The client cancelled the challenge, without submitting any evidence.
+    Cancelled,
+
+    /// The challenge expired before any evidence was submitted for it.
+    Expired,
 }
 
-/// This structure provides a hash map of challenges, keyed on the integer challenge identifier.
+impl fmt::Display for RedemptionOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            RedemptionOutcome::Pending => "the evidence is still being verified",
+            RedemptionOutcome::Succeeded => "the attestation succeeded",
+            RedemptionOutcome::Failed => "the attestation failed",
+            RedemptionOutcome::TimedOut => "the submission timed out",
+            RedemptionOutcome::Cancelled => "the challenge was cancelled",
+            RedemptionOutcome::Expired => "the challenge expired",
+        })
+    }
+}
+
+/// What is left of a challenge once it has been redeemed.
+#[derive(Debug)]
+struct Tombstone {
+    redeemed_at: Instant,
+    outcome: RedemptionOutcome,
+    correlation_id: Option<String>,
+}
+
+/// A summary of the pending challenges, which tells nothing about their values or wrapping keys.
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct PendingChallenges {
+    pub count: usize,
+
+    /// The age of the oldest pending challenge, in seconds, if there is any.
+    pub oldest_age_secs: Option<u64>,
+
+    /// The number of pending challenges, by key identifier.
+    pub by_key: BTreeMap<String, usize>,
+}
+
+/// This structure provides a sharded hash map of challenges, keyed on the integer challenge identifier.
 pub struct Challenger {
+    shards: Vec<Mutex<Shard>>,
+    /// How long a challenge can be redeemed for.
+    ttl: Duration,
+}
+
+/// The challenges whose identities fall in a shard, with the tombstones of those redeemed.
+#[derive(Default)]
+struct Shard {
     challenge_table: HashMap<u32, Challenge>,
-    rng: StdRng,
+    tombstones: HashMap<u32, Tombstone>,
+    /// The pending challenges, in the order they were issued.
+    issued: BTreeSet<(SystemTime, u32)>,
+    /// The number of pending challenges, by key identifier.
+    pending_by_key: HashMap<String, usize>,
 }
 
 // This is the challenge value from from https://git.trustedfirmware.org/TF-M/tf-m-tools/+/refs/heads/main/iat-verifier/tests/data/cca_example_token.cbor
@@ -58,76 +172,733 @@ const CCA_EXAMPLE_TOKEN_NONCE: &[u8] = &[
 ];
 
 impl Challenger {
-    pub fn new() -> Challenger {
+    /// Create a challenger, whose challenges can be redeemed for `ttl`.
+    pub fn new(ttl: Duration) -> Challenger {
         Challenger {
-            challenge_table: HashMap::new(),
-            rng: StdRng::from_entropy(),
+            shards: (0..SHARDS).map(|_| Mutex::default()).collect(),
+            ttl,
         }
     }
 
+    /// The shard holding the challenge with the given identity, locked.
+    fn shard(&self, challenge_id: u32) -> MutexGuard<'_, Shard> {
+        self.shards[challenge_id as usize % SHARDS]
+            .lock()
+            .expect("Poisoned challenger shard lock.")
+    }
+
     /// Allocate a new challenge and store it in the table.
     ///
-    /// The inputs are the identity of the key that the client wants to access, and the public wrapping
-    /// key that the client has specified to encrypt and protect the data in transit.
+    /// The inputs are the identity of the key that the client wants to access, the public wrapping
+    /// key that the client has specified to encrypt and protect the data in transit, and the media types
+    /// of the evidence that will be accepted to redeem the challenge, along with the size of the nonce
+    /// they expect, whether the attestation result should be returned with the wrapped key, and the
+    /// correlation identifier of the key request.
+    #[allow(clippy::too_many_arguments)]
     pub fn create_challenge(
-        &mut self,
+        &self,
         key_id: &str,
         wrapping_key: &PublicWrappingKey,
+        media_types: Vec<String>,
+        nonce_size: usize,
         mock_challenge: bool,
+        return_attestation_result: bool,
+        correlation_id: Option<String>,
     ) -> Challenge {
-        // All challenges are given random u32 identities
-        let mut challenge_id: u32 = self.rng.gen();
+        let mut rng = rand::thread_rng();
+        let challenge_value = if mock_challenge {
+            CCA_EXAMPLE_TOKEN_NONCE.to_vec()
+        } else {
+            let mut v: Vec<u8> = vec![0; nonce_size];
+            rng.fill(&mut v[..]);
+            v
+        };
 
-        // Simple lightweight collision avoidance - probably not needed given u32 distribution space,
-        // but it's easy to do. Also allows us to throw out zero if we get it.
-        while challenge_id == 0 || self.challenge_table.contains_key(&challenge_id) {
-            challenge_id = self.rng.gen();
-        }
+        loop {
+            // All challenges are given random u32 identities
+            let challenge_id: u32 = rng.gen();
 
-        let challenge = Challenge {
-            challenge_id,
-            key_id: key_id.to_owned(),
-            wrapping_key: wrapping_key.clone(),
-            challenge_value: if mock_challenge {
-                CCA_EXAMPLE_TOKEN_NONCE.to_vec()
-            } else {
-                let mut v: Vec<u8> = vec![0; 64];
-                self.rng.fill(&mut v[..]);
-                v
-            },
-        };
+            // Simple lightweight collision avoidance - probably not needed given u32 distribution space,
+            // but it's easy to do. Also allows us to throw out zero if we get it.
+            // Identities of recently redeemed challenges are not reused either, so that their tombstones stay accurate.
+            // The identity is only checked under the lock of its shard, where the challenge is stored.
+            if challenge_id == 0 {
+                continue;
+            }
+            let mut shard = self.shard(challenge_id);
+            if shard.challenge_table.contains_key(&challenge_id)
+                || shard.tombstones.contains_key(&challenge_id)
+            {
+                continue;
+            }
 
-        self.challenge_table.insert(challenge_id, challenge.clone());
+            let challenge = Challenge {
+                challenge_id,
+                key_id: key_id.to_owned(),
+                wrapping_key: wrapping_key.clone(),
+                challenge_value,
+                media_types,
+                return_attestation_result,
+                correlation_id,
+                created_at: SystemTime::now(),
+                expires_at: Instant::now() + self.ttl,
+                attempts: 0,
+            };
+            shard.insert(challenge.clone());
 
-        challenge
+            return challenge;
+        }
     }
 
     /// Looks up a challenge in the table and returns it, failing if no such challenge is found.
+    ///
+    /// A challenge that was recently redeemed is reported as such, along with the outcome of its redemption.
+    /// A challenge that expired is reported as such too, whether or not it was swept yet.
+    #[cfg(test)]
     pub fn get_challenge(&self, challenge_id: u32) -> Result<Challenge> {
-        let challenge = self.challenge_table.get(&challenge_id);
-        match challenge {
-            Some(c) => Ok(c.clone()),
-            None => Err(crate::error::Error::Challenge(
-                crate::error::ChallengeErrorKind::ChallengeNotFound,
-            )),
+        self.get_challenge_at(challenge_id, Instant::now())
+    }
+
+    /// Looks up a challenge as `get_challenge()` does, at `now`.
+    #[cfg(test)]
+    fn get_challenge_at(&self, challenge_id: u32, now: Instant) -> Result<Challenge> {
+        self.shard(challenge_id).get_at(challenge_id, now).cloned()
+    }
+
+    /// The correlation identifier of the key request of a challenge, whether it is pending or was
+    /// recently redeemed.
+    pub fn correlation_id(&self, challenge_id: u32) -> Option<String> {
+        let shard = self.shard(challenge_id);
+        match shard.challenge_table.get(&challenge_id) {
+            Some(challenge) => challenge.correlation_id.clone(),
+            None => shard
+                .tombstones
+                .get(&challenge_id)
+                .and_then(|tombstone| tombstone.correlation_id.clone()),
         }
     }
 
-    /// Deletes a challenge from the table, failing if no such challenge is found.
+    /// Redeems a challenge with evidence of the given media type, removing it from the table and
+    /// returning it, failing as `get_challenge()` does if it can't be redeemed.
     ///
     /// The key broker deletes challenges eagerly, rather than relying on a garbage collection mechanism.
     /// This is for the sake of simplicity, since this is only a demo keybroker. Challenges are deleted
     /// as soon as the client makes an attempt to redeem the challenge by providing an attestation
     /// token. This happens even if the attestation verification fails, meaning that the client only
     /// has one opportunity to redeem any given challenge, otherwise it needs to begin the key
-    /// request all over again.
-    pub fn delete_challenge(&mut self, challenge_id: u32) -> Result<()> {
-        let challenge = self.challenge_table.remove(&challenge_id);
-        match challenge {
-            Some(_c) => Ok(()),
-            None => Err(crate::error::Error::Challenge(
-                crate::error::ChallengeErrorKind::ChallengeNotFound,
-            )),
+    /// request all over again, unless the challenge is put back with `reinstate_challenge()`.
+    ///
+    /// The challenge is left in place if it was not issued for the media type, so that the client
+    /// can redeem it with evidence of the right type. Otherwise a tombstone is left in place of the
+    /// challenge, with a pending outcome until `record_outcome()` is called. The lookup and the
+    /// removal are done at once, so that a challenge is only ever redeemed by one submission.
+    pub fn redeem_challenge(&self, challenge_id: u32, media_type: &str) -> Result<Challenge> {
+        self.take_challenge(challenge_id, RedemptionOutcome::Pending, |challenge| {
+            if challenge
+                .media_types
+                .iter()
+                .any(|accepted| accepted == media_type)
+            {
+                Ok(())
+            } else {
+                Err(Error::Challenge(ChallengeErrorKind::MediaTypeMismatch(
+                    media_type.to_string(),
+                )))
+            }
+        })
+    }
+
+    /// Cancels a challenge for which no evidence will be submitted, removing it from the table and
+    /// returning it, failing as `get_challenge()` does if it can't be redeemed.
+    pub fn cancel_challenge(&self, challenge_id: u32) -> Result<Challenge> {
+        self.take_challenge(challenge_id, RedemptionOutcome::Cancelled, |_| Ok(()))
+    }
+
+    /// Removes a challenge which can be redeemed, and passes the `check`, leaving a tombstone with
+    /// `outcome` in its place.
+    fn take_challenge<F>(
+        &self,
+        challenge_id: u32,
+        outcome: RedemptionOutcome,
+        check: F,
+    ) -> Result<Challenge>
+    where
+        F: FnOnce(&Challenge) -> Result<()>,
+    {
+        let now = Instant::now();
+        let mut shard = self.shard(challenge_id);
+        shard.prune_tombstones(now);
+
+        check(shard.get_at(challenge_id, now)?)?;
+        Ok(shard
+            .remove(challenge_id, outcome, now)
+            .expect("The challenge was just found."))
+    }
+
+    /// Puts back a challenge redeemed with `redeem_challenge()` in place of its tombstone, counting
+    /// the failed attempt to redeem it, and tells whether it was put back.
+    ///
+    /// This is for the redemptions which failed for a runtime error, such as an unreachable
+    /// verifier, rather than for a verdict on the evidence. The challenge is only put back while the
+    /// outcome of its redemption is pending, and before it expires.
+    pub fn reinstate_challenge(&self, challenge: &Challenge) -> bool {
+        self.reinstate_challenge_at(challenge, Instant::now())
+    }
+
+    /// Puts back a challenge at `now`, as `reinstate_challenge()` does.
+    fn reinstate_challenge_at(&self, challenge: &Challenge, now: Instant) -> bool {
+        let challenge_id = challenge.challenge_id;
+        let mut shard = self.shard(challenge_id);
+        let pending = matches!(
+            shard.tombstones.get(&challenge_id),
+            Some(tombstone) if tombstone.outcome == RedemptionOutcome::Pending
+        );
+        if !pending || now >= challenge.expires_at {
+            return false;
+        }
+
+        shard.tombstones.remove(&challenge_id);
+        let mut challenge = challenge.clone();
+        challenge.attempts += 1;
+        shard.insert(challenge);
+
+        true
+    }
+
+    /// Removes the challenges that expired, leaving tombstones in their place, and returns them.
+    pub fn sweep_expired(&self) -> Vec<Challenge> {
+        self.sweep_expired_at(Instant::now())
+    }
+
+    /// Removes the challenges that expired at `now`, as `sweep_expired()` does.
+    ///
+    /// The shards are swept one at a time, so that the others can be used in the meantime.
+    fn sweep_expired_at(&self, now: Instant) -> Vec<Challenge> {
+        let mut swept = Vec::new();
+        for shard in &self.shards {
+            let mut shard = shard.lock().expect("Poisoned challenger shard lock.");
+            shard.prune_tombstones(now);
+
+            let expired: Vec<u32> = shard
+                .challenge_table
+                .values()
+                .filter(|challenge| now >= challenge.expires_at)
+                .map(|challenge| challenge.challenge_id)
+                .collect();
+            swept.extend(expired.into_iter().filter_map(|challenge_id| {
+                shard.remove(challenge_id, RedemptionOutcome::Expired, now)
+            }));
+        }
+        swept
+    }
+
+    /// Records the outcome of the redemption of a deleted challenge, if its tombstone is still there.
+    pub fn record_outcome(&self, challenge_id: u32, outcome: RedemptionOutcome) {
+        if let Some(tombstone) = self.shard(challenge_id).tombstones.get_mut(&challenge_id) {
+            tombstone.outcome = outcome;
+        }
+    }
+
+    /// Summarise the pending challenges.
+    ///
+    /// The shards are summarised one at a time, so the summary may be slightly out of date under
+    /// load, but it never counts a challenge twice.
+    pub fn pending(&self) -> PendingChallenges {
+        let mut pending = PendingChallenges {
+            count: 0,
+            oldest_age_secs: None,
+            by_key: BTreeMap::new(),
+        };
+        let mut oldest: Option<SystemTime> = None;
+        for shard in &self.shards {
+            let shard = shard.lock().expect("Poisoned challenger shard lock.");
+            pending.count += shard.challenge_table.len();
+            if let Some((created_at, _)) = shard.issued.first() {
+                oldest = Some(oldest.map_or(*created_at, |oldest| oldest.min(*created_at)));
+            }
+            for (key_id, count) in &shard.pending_by_key {
+                *pending.by_key.entry(key_id.clone()).or_default() += count;
+            }
+        }
+        pending.oldest_age_secs = oldest.map(|created_at| {
+            SystemTime::now()
+                .duration_since(created_at)
+                .unwrap_or_default()
+                .as_secs()
+        });
+        pending
+    }
+
+    /// Forget the tombstones that are older than their lifetime at `now`.
+    #[cfg(test)]
+    fn prune_tombstones(&self, now: Instant) {
+        for shard in &self.shards {
+            shard
+                .lock()
+                .expect("Poisoned challenger shard lock.")
+                .prune_tombstones(now);
+        }
+    }
+}
+
+impl Shard {
+    /// Stores a pending challenge.
+    fn insert(&mut self, challenge: Challenge) {
+        self.issued
+            .insert((challenge.created_at, challenge.challenge_id));
+        *self
+            .pending_by_key
+            .entry(challenge.key_id.clone())
+            .or_default() += 1;
+        self.challenge_table
+            .insert(challenge.challenge_id, challenge);
+    }
+
+    /// Looks up a challenge which can be redeemed at `now`, as `Challenger::get_challenge()` does.
+    fn get_at(&self, challenge_id: u32, now: Instant) -> Result<&Challenge> {
+        match self.challenge_table.get(&challenge_id) {
+            Some(c) if now >= c.expires_at => {
+                Err(Error::Challenge(ChallengeErrorKind::ChallengeExpired))
+            }
+            Some(c) => Ok(c),
+            None => match self.tombstones.get(&challenge_id) {
+                Some(tombstone)
+                    if now.saturating_duration_since(tombstone.redeemed_at)
+                        < TOMBSTONE_LIFETIME =>
+                {
+                    Err(Error::Challenge(match tombstone.outcome {
+                        RedemptionOutcome::Expired => ChallengeErrorKind::ChallengeExpired,
+                        outcome => ChallengeErrorKind::ChallengeAlreadyRedeemed(outcome),
+                    }))
+                }
+                _ => Err(Error::Challenge(ChallengeErrorKind::ChallengeNotFound)),
+            },
         }
     }
+
+    /// Removes a challenge from the table, leaving a tombstone with `outcome` in its place.
+    fn remove(
+        &mut self,
+        challenge_id: u32,
+        outcome: RedemptionOutcome,
+        now: Instant,
+    ) -> Option<Challenge> {
+        let challenge = self.challenge_table.remove(&challenge_id)?;
+        self.issued.remove(&(challenge.created_at, challenge_id));
+        if let Some(count) = self.pending_by_key.get_mut(&challenge.key_id) {
+            *count -= 1;
+            if *count == 0 {
+                self.pending_by_key.remove(&challenge.key_id);
+            }
+        }
+        self.tombstones.insert(
+            challenge_id,
+            Tombstone {
+                redeemed_at: now,
+                outcome,
+                correlation_id: challenge.correlation_id.clone(),
+            },
+        );
+        Some(challenge)
+    }
+
+    /// Forget the tombstones that are older than their lifetime at `now`.
+    fn prune_tombstones(&mut self, now: Instant) {
+        self.tombstones.retain(|_, tombstone| {
+            now.saturating_duration_since(tombstone.redeemed_at) < TOMBSTONE_LIFETIME
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TTL: Duration = Duration::from_secs(300);
+    const MEDIA_TYPE: &str = "application/eat+cwt";
+
+    fn challenge(challenger: &Challenger) -> u32 {
+        challenge_for(challenger, "skywalker")
+    }
+
+    fn challenge_for(challenger: &Challenger, key_id: &str) -> u32 {
+        let wrapping_key = PublicWrappingKey {
+            kty: "RSA".to_string(),
+            alg: "RSA1_5".to_string(),
+            n: Some("AQAB".to_string()),
+            e: Some("AQAB".to_string()),
+            ..Default::default()
+        };
+        challenger
+            .create_challenge(
+                key_id,
+                &wrapping_key,
+                vec![MEDIA_TYPE.to_string()],
+                64,
+                false,
+                false,
+                None,
+            )
+            .challenge_id
+    }
+
+    #[test]
+    fn unknown_challenge() {
+        assert!(matches!(
+            Challenger::new(TTL).get_challenge(1234),
+            Err(Error::Challenge(ChallengeErrorKind::ChallengeNotFound))
+        ));
+    }
+
+    #[test]
+    fn redeemed_challenge() {
+        let challenger = Challenger::new(TTL);
+        let challenge_id = challenge(&challenger);
+        challenger
+            .redeem_challenge(challenge_id, MEDIA_TYPE)
+            .unwrap();
+
+        assert!(matches!(
+            challenger.get_challenge(challenge_id),
+            Err(Error::Challenge(
+                ChallengeErrorKind::ChallengeAlreadyRedeemed(RedemptionOutcome::Pending)
+            ))
+        ));
+
+        challenger.record_outcome(challenge_id, RedemptionOutcome::Succeeded);
+        assert!(matches!(
+            challenger.get_challenge(challenge_id),
+            Err(Error::Challenge(
+                ChallengeErrorKind::ChallengeAlreadyRedeemed(RedemptionOutcome::Succeeded)
+            ))
+        ));
+
+        // A challenge can only be redeemed once.
+        assert!(challenger
+            .redeem_challenge(challenge_id, MEDIA_TYPE)
+            .is_err());
+    }
+
+    #[test]
+    fn media_type_mismatch() {
+        let challenger = Challenger::new(TTL);
+        let challenge_id = challenge(&challenger);
+
+        // The challenge is left in place for evidence of the right type.
+        assert!(matches!(
+            challenger.redeem_challenge(challenge_id, "application/psa-attestation-token"),
+            Err(Error::Challenge(ChallengeErrorKind::MediaTypeMismatch(_)))
+        ));
+        assert_eq!(challenger.pending().count, 1);
+        assert_eq!(
+            challenger
+                .redeem_challenge(challenge_id, MEDIA_TYPE)
+                .unwrap()
+                .challenge_id,
+            challenge_id
+        );
+    }
+
+    #[test]
+    fn cancelled_challenge() {
+        let challenger = Challenger::new(TTL);
+        let challenge_id = challenge(&challenger);
+        challenger.cancel_challenge(challenge_id).unwrap();
+
+        assert!(matches!(
+            challenger.redeem_challenge(challenge_id, MEDIA_TYPE),
+            Err(Error::Challenge(
+                ChallengeErrorKind::ChallengeAlreadyRedeemed(RedemptionOutcome::Cancelled)
+            ))
+        ));
+        assert!(challenger.cancel_challenge(challenge_id).is_err());
+    }
+
+    #[test]
+    fn concurrent_challenges() {
+        const THREADS: usize = 16;
+        const CHALLENGES: usize = 250;
+
+        let challenger = Challenger::new(TTL);
+        let issued: Vec<Vec<u32>> = std::thread::scope(|scope| {
+            let workers: Vec<_> = (0..THREADS)
+                .map(|thread| {
+                    let challenger = &challenger;
+                    scope.spawn(move || {
+                        (0..CHALLENGES)
+                            .map(|n| {
+                                // Half of the challenges are redeemed, the others cancelled.
+                                let challenge_id = challenge(challenger);
+                                if (thread + n) % 2 == 0 {
+                                    challenger
+                                        .redeem_challenge(challenge_id, MEDIA_TYPE)
+                                        .unwrap();
+                                    challenger
+                                        .record_outcome(challenge_id, RedemptionOutcome::Succeeded);
+                                } else {
+                                    challenger.cancel_challenge(challenge_id).unwrap();
+                                }
+                                challenge_id
+                            })
+                            .collect()
+                    })
+                })
+                .collect();
+            workers
+                .into_iter()
+                .map(|worker| worker.join().unwrap())
+                .collect()
+        });
+
+        // No identity was given twice, and no challenge was left behind.
+        let challenge_ids: std::collections::HashSet<u32> =
+            issued.iter().flatten().copied().collect();
+        assert_eq!(challenge_ids.len(), THREADS * CHALLENGES);
+        assert_eq!(
+            challenger.pending(),
+            PendingChallenges {
+                count: 0,
+                oldest_age_secs: None,
+                by_key: BTreeMap::new(),
+            }
+        );
+        for challenge_id in challenge_ids {
+            assert!(matches!(
+                challenger.get_challenge(challenge_id),
+                Err(Error::Challenge(
+                    ChallengeErrorKind::ChallengeAlreadyRedeemed(_)
+                ))
+            ));
+        }
+
+        // A challenge submitted for at once is only redeemed by one of the submissions.
+        let challenge_id = challenge(&challenger);
+        let redeemed = std::thread::scope(|scope| {
+            let submissions: Vec<_> = (0..THREADS)
+                .map(|_| {
+                    scope.spawn(|| {
+                        challenger
+                            .redeem_challenge(challenge_id, MEDIA_TYPE)
+                            .is_ok()
+                    })
+                })
+                .collect();
+            submissions
+                .into_iter()
+                .map(|submission| submission.join().unwrap())
+                .filter(|redeemed| *redeemed)
+                .count()
+        });
+        assert_eq!(redeemed, 1);
+    }
+
+    #[test]
+    fn correlation_id() {
+        let wrapping_key = PublicWrappingKey {
+            kty: "RSA".to_string(),
+            alg: "RSA1_5".to_string(),
+            n: Some("AQAB".to_string()),
+            e: Some("AQAB".to_string()),
+            ..Default::default()
+        };
+        let challenger = Challenger::new(TTL);
+        let challenge_id = challenger
+            .create_challenge(
+                "skywalker",
+                &wrapping_key,
+                vec![MEDIA_TYPE.to_string()],
+                64,
+                false,
+                false,
+                Some("job-42".to_string()),
+            )
+            .challenge_id;
+        assert_eq!(
+            challenger.correlation_id(challenge_id).as_deref(),
+            Some("job-42")
+        );
+
+        // The correlation identifier outlives the redemption, for the retried submissions.
+        challenger
+            .redeem_challenge(challenge_id, MEDIA_TYPE)
+            .unwrap();
+        assert_eq!(
+            challenger.correlation_id(challenge_id).as_deref(),
+            Some("job-42")
+        );
+        let challenge_id = challenge(&challenger);
+        assert_eq!(challenger.correlation_id(challenge_id), None);
+    }
+
+    #[test]
+    fn reinstated_challenge() {
+        let challenger = Challenger::new(TTL);
+        let challenge_id = challenge(&challenger);
+        let challenge = challenger.get_challenge(challenge_id).unwrap();
+        assert_eq!(challenge.attempts, 0);
+
+        // A pending challenge is not reinstated, it was not redeemed.
+        assert!(!challenger.reinstate_challenge(&challenge));
+
+        challenger
+            .redeem_challenge(challenge_id, MEDIA_TYPE)
+            .unwrap();
+        assert_eq!(challenger.pending().count, 0);
+        assert!(challenger.reinstate_challenge(&challenge));
+
+        // The challenge is back, with the same nonce, counting the failed attempt.
+        let reinstated = challenger.get_challenge(challenge_id).unwrap();
+        assert_eq!(reinstated.attempts, 1);
+        assert_eq!(reinstated.challenge_value, challenge.challenge_value);
+        assert_eq!(
+            challenger.pending().by_key,
+            BTreeMap::from([("skywalker".to_string(), 1)])
+        );
+
+        // It can be redeemed again, and the attempts add up.
+        challenger
+            .redeem_challenge(challenge_id, MEDIA_TYPE)
+            .unwrap();
+        assert!(challenger.reinstate_challenge(&reinstated));
+        assert_eq!(challenger.get_challenge(challenge_id).unwrap().attempts, 2);
+
+        // Once the outcome of the redemption is recorded, the challenge stays consumed.
+        challenger
+            .redeem_challenge(challenge_id, MEDIA_TYPE)
+            .unwrap();
+        challenger.record_outcome(challenge_id, RedemptionOutcome::Failed);
+        assert!(!challenger.reinstate_challenge(&reinstated));
+        assert!(matches!(
+            challenger.get_challenge(challenge_id),
+            Err(Error::Challenge(
+                ChallengeErrorKind::ChallengeAlreadyRedeemed(RedemptionOutcome::Failed)
+            ))
+        ));
+    }
+
+    #[test]
+    fn expired_challenge_not_reinstated() {
+        let challenger = Challenger::new(TTL);
+        let challenge_id = challenge(&challenger);
+        let challenge = challenger.get_challenge(challenge_id).unwrap();
+        challenger
+            .redeem_challenge(challenge_id, MEDIA_TYPE)
+            .unwrap();
+
+        assert!(!challenger.reinstate_challenge_at(&challenge, challenge.expires_at));
+        assert_eq!(challenger.pending().count, 0);
+        assert!(matches!(
+            challenger.get_challenge(challenge_id),
+            Err(Error::Challenge(
+                ChallengeErrorKind::ChallengeAlreadyRedeemed(RedemptionOutcome::Pending)
+            ))
+        ));
+    }
+
+    #[test]
+    fn tombstones_expire() {
+        let challenger = Challenger::new(TTL);
+        let challenge_id = challenge(&challenger);
+        challenger
+            .redeem_challenge(challenge_id, MEDIA_TYPE)
+            .unwrap();
+
+        challenger.prune_tombstones(Instant::now() + TOMBSTONE_LIFETIME);
+        assert!(matches!(
+            challenger.get_challenge(challenge_id),
+            Err(Error::Challenge(ChallengeErrorKind::ChallengeNotFound))
+        ));
+    }
+
+    #[test]
+    fn pending_challenges() {
+        let challenger = Challenger::new(TTL);
+        assert_eq!(
+            challenger.pending(),
+            PendingChallenges {
+                count: 0,
+                oldest_age_secs: None,
+                by_key: BTreeMap::new(),
+            }
+        );
+
+        let first = challenge(&challenger);
+        challenge(&challenger);
+        let deathstar = challenge_for(&challenger, "deathstar");
+        challenger.redeem_challenge(first, MEDIA_TYPE).unwrap();
+        challenger.redeem_challenge(deathstar, MEDIA_TYPE).unwrap();
+
+        let pending = challenger.pending();
+        assert_eq!(pending.count, 1);
+        assert_eq!(pending.oldest_age_secs, Some(0));
+        assert_eq!(
+            pending.by_key,
+            BTreeMap::from([("skywalker".to_string(), 1)])
+        );
+    }
+
+    #[test]
+    fn expiry() {
+        let challenger = Challenger::new(TTL);
+        let challenge_id = challenge(&challenger);
+        let expires_at = challenger.get_challenge(challenge_id).unwrap().expires_at;
+
+        // The challenge can be redeemed up to its expiry, exclusive.
+        assert!(challenger
+            .get_challenge_at(challenge_id, expires_at - Duration::from_millis(1))
+            .is_ok());
+        for now in [expires_at, expires_at + Duration::from_secs(1)] {
+            assert!(matches!(
+                challenger.get_challenge_at(challenge_id, now),
+                Err(Error::Challenge(ChallengeErrorKind::ChallengeExpired))
+            ));
+        }
+    }
+
+    #[test]
+    fn sweep() {
+        let challenger = Challenger::new(TTL);
+        let expiring = challenge(&challenger);
+        let redeemed = challenge(&challenger);
+        challenger.redeem_challenge(redeemed, MEDIA_TYPE).unwrap();
+        let expires_at = challenger.get_challenge(expiring).unwrap().expires_at;
+
+        // Nothing is swept before the expiry.
+        assert!(challenger
+            .sweep_expired_at(expires_at - Duration::from_millis(1))
+            .is_empty());
+        assert_eq!(challenger.pending().count, 1);
+
+        let swept = challenger.sweep_expired_at(expires_at);
+        assert_eq!(
+            swept
+                .iter()
+                .map(|challenge| challenge.challenge_id)
+                .collect::<Vec<_>>(),
+            vec![expiring]
+        );
+        assert_eq!(challenger.pending().count, 0);
+        assert!(challenger.pending().by_key.is_empty());
+
+        // A swept challenge is still reported as expired, rather than as unknown, and the
+        // redeemed one keeps its tombstone.
+        assert!(matches!(
+            challenger.get_challenge_at(expiring, expires_at),
+            Err(Error::Challenge(ChallengeErrorKind::ChallengeExpired))
+        ));
+        assert!(matches!(
+            challenger.get_challenge_at(redeemed, expires_at),
+            Err(Error::Challenge(
+                ChallengeErrorKind::ChallengeAlreadyRedeemed(RedemptionOutcome::Pending)
+            ))
+        ));
+        assert!(challenger.redeem_challenge(expiring, MEDIA_TYPE).is_err());
+
+        // Until the tombstone is gone too.
+        challenger.prune_tombstones(expires_at + TOMBSTONE_LIFETIME);
+        assert!(matches!(
+            challenger.get_challenge_at(expiring, expires_at + TOMBSTONE_LIFETIME),
+            Err(Error::Challenge(ChallengeErrorKind::ChallengeNotFound))
+        ));
+    }
 }
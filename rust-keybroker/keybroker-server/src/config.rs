@@ -0,0 +1,377 @@
+// Copyright 2024 Contributors to the Veraison project.
+// SPDX-License-Identifier: Apache-2.0
+
+//! The configuration file of the server, given with `--config`.
+//!
+//! The file is in TOML, and its keys are the long names of the command-line options, without their
+//! leading dashes:
+//!
+//! ```toml
+//! addr = "0.0.0.0"
+//! port = 8088
+//! verifier = "https://veraison.example:8443"
+//! reference-values = "/etc/keybroker/rims.json"
+//! cors-origin = ["https://demo.example"]
+//! mock-challenge = true
+//! ```
+//!
+//! The options given on the command line take precedence over those of the file, which take
+//! precedence over the defaults. The values of the file are turned into command-line arguments,
+//! so that they are validated exactly as the command line is: a flag is set with `true`, an option
+//! which can be repeated takes an array, and `verbosity` takes the number of `-v`.
+use crate::Args;
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+use clap::{Arg, ArgAction, ArgMatches, Command, CommandFactory, FromArgMatches};
+use std::ffi::OsString;
+use std::path::Path;
+
+/// The options which can't be set from the configuration file.
+const NOT_CONFIGURABLE: [&str; 4] = ["help", "version", "config", "print-config"];
+
+/// The options whose value is secret, and left out when the configuration is printed.
+const SECRETS: [&str; 1] = ["admin-token"];
+
+/// Parse the command-line arguments, merged with the configuration file if one is given.
+pub fn parse_args<I, T>(argv: I) -> Result<Args, clap::Error>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<OsString> + Clone,
+{
+    let mut command = Args::command();
+    let matches = merged_matches(&mut command, argv)?;
+    Args::from_arg_matches(&matches).map_err(|error| error.format(&mut command))
+}
+
+/// The effective configuration, merged from the command line, the configuration file and the
+/// defaults, as a TOML document which can be given back with `--config`. The secrets are left out,
+/// only named in a comment, and the defaults commented out.
+pub fn render_config<I, T>(argv: I) -> Result<String, clap::Error>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<OsString> + Clone,
+{
+    let mut command = Args::command();
+    let matches = merged_matches(&mut command, argv)?;
+
+    // The defaults are commented out, as some options require others, and can't be given alone,
+    // even with their default value.
+    let mut config = toml::Table::new();
+    let mut defaults = toml::Table::new();
+    let mut secrets = Vec::new();
+    for arg in command.get_arguments() {
+        let Some(key) = configurable(arg) else {
+            continue;
+        };
+        let id = arg.get_id().as_str();
+        if SECRETS.contains(&key) {
+            if matches.value_source(id).is_some() {
+                secrets.push(key);
+            }
+            continue;
+        }
+        let value = match arg.get_action() {
+            ArgAction::SetTrue => toml::Value::Boolean(matches.get_flag(id)),
+            ArgAction::Count => toml::Value::Integer(matches.get_count(id).into()),
+            action => {
+                let Some(values) = matches.get_raw(id) else {
+                    continue;
+                };
+                let mut values = values.map(|value| {
+                    let value = value.to_string_lossy();
+                    match value.parse() {
+                        Ok(number) => toml::Value::Integer(number),
+                        Err(_) => toml::Value::String(value.into_owned()),
+                    }
+                });
+                match action {
+                    ArgAction::Append => toml::Value::Array(values.collect()),
+                    _ => match values.next() {
+                        Some(value) => value,
+                        None => continue,
+                    },
+                }
+            }
+        };
+        match matches.value_source(id) {
+            Some(ValueSource::DefaultValue) => defaults.insert(key.to_string(), value),
+            _ => config.insert(key.to_string(), value),
+        };
+    }
+
+    let (mut rendered, defaults) = match (toml::to_string(&config), toml::to_string(&defaults)) {
+        (Ok(config), Ok(defaults)) => (config, defaults),
+        (Err(error), _) | (_, Err(error)) => return Err(command.error(ErrorKind::Io, error)),
+    };
+    if !secrets.is_empty() {
+        rendered.push_str("\n# The secrets, which are not printed, and have to be given again.\n");
+        for key in secrets {
+            rendered.push_str(&format!("# {key} = \"...\"\n"));
+        }
+    }
+    rendered.push_str("\n# The defaults of the other options.\n");
+    for line in defaults.lines() {
+        rendered.push_str(&format!("# {line}\n"));
+    }
+    Ok(rendered)
+}
+
+/// The key of an option in the configuration file, unless it can't be set from there.
+fn configurable(arg: &Arg) -> Option<&str> {
+    arg.get_long()
+        .filter(|long| !NOT_CONFIGURABLE.contains(long))
+}
+
+/// Parse the command-line arguments, preceded with those drawn from the configuration file, if one
+/// is given, for the options which are not on the command line.
+fn merged_matches<I, T>(command: &mut Command, argv: I) -> Result<ArgMatches, clap::Error>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<OsString> + Clone,
+{
+    let argv: Vec<OsString> = argv.into_iter().map(Into::into).collect();
+    // The command line alone may not be valid, for example when an option it has requires
+    // another one that is in the file.
+    let matches = command
+        .clone()
+        .ignore_errors(true)
+        .try_get_matches_from(&argv)?;
+    let Some(path) = matches.get_one::<std::path::PathBuf>("config") else {
+        return Ok(matches);
+    };
+
+    let mut merged: Vec<OsString> = argv.iter().take(1).cloned().collect();
+    for (key, value) in read_config(command, path)? {
+        let Some(arg) = command
+            .get_arguments()
+            .find(|arg| configurable(arg) == Some(key.as_str()))
+            .cloned()
+        else {
+            return Err(command.error(
+                ErrorKind::UnknownArgument,
+                format!(
+                    "unknown key '{key}' in the configuration file {}",
+                    path.display()
+                ),
+            ));
+        };
+        if matches.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine) {
+            continue;
+        }
+        merged.extend(config_arguments(command, &arg, &key, &value)?);
+    }
+    merged.extend(argv.into_iter().skip(1));
+
+    command.try_get_matches_from_mut(merged)
+}
+
+fn read_config(command: &mut Command, path: &Path) -> Result<toml::Table, clap::Error> {
+    let config = std::fs::read_to_string(path).map_err(|error| {
+        command.error(
+            ErrorKind::Io,
+            format!(
+                "failed to read the configuration file {}: {error}",
+                path.display()
+            ),
+        )
+    })?;
+    config.parse().map_err(|error| {
+        command.error(
+            ErrorKind::InvalidValue,
+            format!(
+                "failed to parse the configuration file {}: {error}",
+                path.display()
+            ),
+        )
+    })
+}
+
+/// The command-line arguments standing for the value of an option in the configuration file.
+fn config_arguments(
+    command: &mut Command,
+    arg: &Arg,
+    key: &str,
+    value: &toml::Value,
+) -> Result<Vec<OsString>, clap::Error> {
+    let option = format!("--{key}");
+    let scalar = |value: &toml::Value| match value {
+        toml::Value::String(value) => Some(value.clone()),
+        toml::Value::Integer(value) => Some(value.to_string()),
+        toml::Value::Float(value) => Some(value.to_string()),
+        _ => None,
+    };
+    let arguments: Option<Vec<String>> = match (arg.get_action(), value) {
+        (ArgAction::SetTrue, toml::Value::Boolean(set)) => {
+            Some(if *set { vec![option] } else { vec![] })
+        }
+        (ArgAction::Count, toml::Value::Integer(count)) => usize::try_from(*count)
+            .ok()
+            .map(|count| vec![option; count]),
+        (ArgAction::Append, toml::Value::Array(values)) => values
+            .iter()
+            .map(|value| scalar(value).map(|value| format!("{option}={value}")))
+            .collect(),
+        (ArgAction::Set | ArgAction::Append, value) => {
+            scalar(value).map(|value| vec![format!("{option}={value}")])
+        }
+        _ => None,
+    };
+
+    let Some(arguments) = arguments else {
+        let expected = match arg.get_action() {
+            ArgAction::SetTrue => "a boolean",
+            ArgAction::Count => "a non-negative integer",
+            ArgAction::Append => "a string, a number, or an array of them",
+            _ => "a string or a number",
+        };
+        return Err(command.error(
+            ErrorKind::InvalidValue,
+            format!("the key '{key}' of the configuration file expects {expected}"),
+        ));
+    };
+    Ok(arguments.into_iter().map(OsString::from).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn config_file(name: &str, config: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "keybroker-config-{name}-{}.toml",
+            std::process::id()
+        ));
+        std::fs::write(&path, config).unwrap();
+        path
+    }
+
+    fn args(config: &Path, extra_args: &[&str]) -> Result<Args, clap::Error> {
+        parse_args(
+            ["keybroker-server", "--config", config.to_str().unwrap()]
+                .iter()
+                .chain(extra_args),
+        )
+    }
+
+    #[test]
+    fn precedence() {
+        let config = config_file(
+            "precedence",
+            r#"
+            port = 9000
+            addr = "0.0.0.0"
+            cors-origin = ["https://a.example", "https://b.example"]
+            mock-challenge = true
+            verbosity = 2
+            "#,
+        );
+
+        // The file takes precedence over the defaults.
+        let from_file = args(&config, &[]).unwrap();
+        assert_eq!(from_file.port, 9000);
+        assert_eq!(from_file.addr, "0.0.0.0");
+        assert_eq!(
+            from_file.cors_origins,
+            vec!["https://a.example", "https://b.example"]
+        );
+        assert!(from_file.mock_challenge);
+        assert_eq!(from_file.verbosity, 2);
+        assert_eq!(from_file.request_deadline_secs, 60);
+
+        // The command line takes precedence over the file, even for the options which can be
+        // repeated, whose values are not merged.
+        let overridden = args(
+            &config,
+            &["--port", "9001", "--cors-origin", "https://c.example", "-v"],
+        )
+        .unwrap();
+        assert_eq!(overridden.port, 9001);
+        assert_eq!(overridden.addr, "0.0.0.0");
+        assert_eq!(overridden.cors_origins, vec!["https://c.example"]);
+        assert_eq!(overridden.verbosity, 1);
+        std::fs::remove_file(config).unwrap();
+
+        // The options of the command line may need those of the file.
+        let config = config_file("requires", "tls-key = \"server.key\"");
+        let combined = args(&config, &["--tls-cert", "server.crt"]).unwrap();
+        assert_eq!(combined.tls_cert, Some(PathBuf::from("server.crt")));
+        assert_eq!(combined.tls_key, Some(PathBuf::from("server.key")));
+
+        std::fs::remove_file(config).unwrap();
+    }
+
+    #[test]
+    fn invalid_config() {
+        for (name, config, message) in [
+            ("unknown", "prot = 9000", "unknown key 'prot'"),
+            (
+                "internal",
+                "print-config = true",
+                "unknown key 'print-config'",
+            ),
+            ("type", "mock-challenge = 1", "expects a boolean"),
+            ("value", "port = 100000", "100000"),
+            ("syntax", "port = ", "failed to parse"),
+        ] {
+            let config = config_file(name, config);
+            let error = args(&config, &[]).unwrap_err().to_string();
+            assert!(error.contains(message), "{name}: {error}");
+            std::fs::remove_file(config).unwrap();
+        }
+
+        assert!(args(Path::new("/nonexistent/keybroker.toml"), &[])
+            .unwrap_err()
+            .to_string()
+            .contains("failed to read the configuration file"));
+    }
+
+    #[test]
+    fn print_config() {
+        let config = config_file(
+            "print",
+            r#"
+            port = 9000
+            admin-token = "s3cret"
+            cors-origin = ["https://a.example"]
+            "#,
+        );
+        let argv = [
+            "keybroker-server",
+            "--config",
+            config.to_str().unwrap(),
+            "--mock-challenge",
+        ];
+        let rendered = render_config(argv).unwrap();
+        assert!(rendered.contains("port = 9000"), "{rendered}");
+        assert!(rendered.contains("mock-challenge = true"), "{rendered}");
+        assert!(
+            rendered.contains("\n# request-deadline-secs = 60\n"),
+            "{rendered}"
+        );
+        assert!(!rendered.contains("s3cret"), "{rendered}");
+        assert!(rendered.contains("\n# admin-token = "), "{rendered}");
+        assert!(!rendered.contains("print-config"), "{rendered}");
+
+        // The rendered configuration is a configuration file, which gives the same options, but
+        // for the secrets, which are left out.
+        let printed = config_file("printed", &rendered);
+        let args = args(&printed, &[]).unwrap();
+        assert_eq!(args.port, 9000);
+        assert!(args.mock_challenge);
+        assert_eq!(args.cors_origins, vec!["https://a.example"]);
+        assert_eq!(args.admin_token, None);
+        assert_eq!(args.request_deadline_secs, 60);
+
+        std::fs::remove_file(config).unwrap();
+        std::fs::remove_file(printed).unwrap();
+    }
+
+    // The example configures the verifier, whose options only exist with a remote verifier.
+    #[cfg(feature = "remote-verifier")]
+    #[test]
+    fn example_config() {
+        let example = Path::new(env!("CARGO_MANIFEST_DIR")).join("keybroker-server.example.toml");
+        args(&example, &[]).expect("The example configuration is valid.");
+    }
+}
@@ -0,0 +1,209 @@
+// Copyright 2024 Contributors to the Veraison project.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Cross-origin resource sharing, for the clients running in web pages served from another origin
+//! than the keybroker server.
+//!
+//! The requests from the allowed origins get the `Access-Control-Allow-Origin` header, and the
+//! `Location` header of the key requests is exposed to them, as it holds the URL where to submit
+//! the evidence. Their preflight requests are answered directly, without reaching the handlers.
+//! The requests from the other origins are served as usual, without the CORS headers, so the
+//! browsers withhold the responses from the pages.
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::http::Method;
+use actix_web::HttpResponse;
+use std::future::Future;
+
+/// The origins whose pages are allowed to call the keybroker server.
+#[derive(Debug, Clone, Default)]
+pub struct CorsPolicy {
+    origins: Vec<String>,
+}
+
+impl CorsPolicy {
+    /// Allow the given origins, `*` allowing any.
+    pub fn new(origins: Vec<String>) -> CorsPolicy {
+        CorsPolicy { origins }
+    }
+
+    /// The origin of the request, if it is an allowed one.
+    fn allowed_origin(&self, request: &ServiceRequest) -> Option<HeaderValue> {
+        let origin = request.headers().get(header::ORIGIN)?;
+        self.origins
+            .iter()
+            .any(|allowed| allowed == "*" || allowed.as_bytes() == origin.as_bytes())
+            .then(|| origin.clone())
+    }
+}
+
+/// Handle a request as per the policy: answer it if it is a preflight request from an allowed
+/// origin, or else pass it to the service, and add the CORS headers to the response.
+pub fn handle<S, B>(
+    policy: &CorsPolicy,
+    request: ServiceRequest,
+    service: &S,
+) -> impl Future<Output = Result<ServiceResponse<EitherBody<B>>, actix_web::Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody,
+{
+    let enabled = !policy.origins.is_empty();
+    let origin = policy.allowed_origin(&request);
+    let preflight = origin.is_some()
+        && request.method() == Method::OPTIONS
+        && request
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_REQUEST_METHOD);
+    let handling = if preflight {
+        Err(request)
+    } else {
+        Ok(service.call(request))
+    };
+
+    async move {
+        let mut response = match handling {
+            Ok(call) => call.await?.map_into_left_body(),
+            Err(request) => request
+                .into_response(
+                    HttpResponse::NoContent()
+                        .insert_header((header::ACCESS_CONTROL_ALLOW_METHODS, "GET, POST, DELETE"))
+                        .insert_header((
+                            header::ACCESS_CONTROL_ALLOW_HEADERS,
+                            "Content-Type, Content-Encoding",
+                        ))
+                        .insert_header((header::ACCESS_CONTROL_MAX_AGE, "600"))
+                        .finish(),
+                )
+                .map_into_right_body(),
+        };
+
+        let headers = response.headers_mut();
+        if enabled {
+            // The response depends on the origin, so caches must not mix them up.
+            headers.append(header::VARY, HeaderValue::from_static("Origin"));
+        }
+        if let Some(origin) = origin {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+            headers.insert(
+                header::ACCESS_CONTROL_EXPOSE_HEADERS,
+                HeaderValue::from_static("Location"),
+            );
+        }
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{web, App};
+
+    const PAGE_ORIGIN: &str = "http://localhost:8000";
+
+    async fn call(origins: &[&str], request: TestRequest) -> actix_web::dev::ServiceResponse {
+        let policy = CorsPolicy::new(origins.iter().map(|origin| origin.to_string()).collect());
+        let app = init_service(
+            App::new()
+                .wrap_fn(move |request, service| handle(&policy, request, service))
+                .route(
+                    "/keys/v1/key/skywalker",
+                    web::post().to(|| async {
+                        HttpResponse::Created()
+                            .insert_header((header::LOCATION, "/keys/v1/evidence/1"))
+                            .finish()
+                    }),
+                ),
+        )
+        .await;
+        call_service(&app, request.to_request())
+            .await
+            .map_into_boxed_body()
+    }
+
+    fn key_request() -> TestRequest {
+        TestRequest::post().uri("/keys/v1/key/skywalker")
+    }
+
+    fn preflight() -> TestRequest {
+        TestRequest::default()
+            .method(Method::OPTIONS)
+            .uri("/keys/v1/key/skywalker")
+            .insert_header((header::ACCESS_CONTROL_REQUEST_METHOD, "POST"))
+    }
+
+    #[actix_web::test]
+    async fn allowed_origin() {
+        for origins in [&[PAGE_ORIGIN][..], &["http://example.com", "*"]] {
+            let response = call(
+                origins,
+                key_request().insert_header((header::ORIGIN, PAGE_ORIGIN)),
+            )
+            .await;
+            assert_eq!(response.status(), StatusCode::CREATED);
+            assert_eq!(
+                response
+                    .headers()
+                    .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+                    .unwrap(),
+                PAGE_ORIGIN
+            );
+            assert_eq!(
+                response
+                    .headers()
+                    .get(header::ACCESS_CONTROL_EXPOSE_HEADERS)
+                    .unwrap(),
+                "Location"
+            );
+
+            let response = call(
+                origins,
+                preflight().insert_header((header::ORIGIN, PAGE_ORIGIN)),
+            )
+            .await;
+            assert_eq!(response.status(), StatusCode::NO_CONTENT);
+            assert_eq!(
+                response
+                    .headers()
+                    .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+                    .unwrap(),
+                PAGE_ORIGIN
+            );
+            assert!(response
+                .headers()
+                .contains_key(header::ACCESS_CONTROL_ALLOW_METHODS));
+        }
+    }
+
+    #[actix_web::test]
+    async fn other_origins() {
+        // Another origin, and the same without a policy, are served without the CORS headers.
+        for origins in [&["http://example.com"][..], &[]] {
+            let response = call(
+                origins,
+                key_request().insert_header((header::ORIGIN, PAGE_ORIGIN)),
+            )
+            .await;
+            assert_eq!(response.status(), StatusCode::CREATED);
+            assert!(!response
+                .headers()
+                .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+
+            let response = call(
+                origins,
+                preflight().insert_header((header::ORIGIN, PAGE_ORIGIN)),
+            )
+            .await;
+            assert_ne!(response.status(), StatusCode::NO_CONTENT);
+        }
+
+        let response = call(&[PAGE_ORIGIN], key_request()).await;
+        assert!(!response
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+        assert_eq!(response.headers().get(header::VARY).unwrap(), "Origin");
+    }
+}
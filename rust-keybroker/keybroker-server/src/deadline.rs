@@ -0,0 +1,137 @@
+// Copyright 2024 Contributors to the Veraison project.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Tracking of the progress of an evidence submission through its stages, so that a submission
+//! which runs out of time can tell where it was.
+//!
+//! The submission is abandoned at the deadline wherever it is, but the stage it reached decides
+//! what becomes of its challenge: before the appraisal starts, the challenge is still in place and
+//! the client can submit its evidence again; from then on, the challenge was consumed, and the
+//! timed-out submission counts as a failed attempt to redeem it.
+//!
+//! What the submission learned on the way, such as the media type of the evidence and the outcome
+//! of its appraisal, is recorded too, for the audit log.
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// The stages of the handling of an evidence submission, in order.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Stage {
+    /// The evidence is being received from the client.
+    #[default]
+    ReceivingEvidence,
+
+    /// The evidence is being decoded and checked.
+    DecodingEvidence,
+
+    /// The challenge is being looked up, and consumed.
+    RedeemingChallenge,
+
+    /// The evidence is being verified, and the attestation result appraised against the policy.
+    Appraising,
+
+    /// The key is being wrapped.
+    WrappingKey,
+}
+
+impl Stage {
+    /// Whether the challenge was consumed by the time this stage was reached.
+    pub fn challenge_consumed(self) -> bool {
+        self >= Stage::Appraising
+    }
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Stage::ReceivingEvidence => "receiving the evidence",
+            Stage::DecodingEvidence => "decoding the evidence",
+            Stage::RedeemingChallenge => "redeeming the challenge",
+            Stage::Appraising => "appraising the evidence",
+            Stage::WrappingKey => "wrapping the key",
+        })
+    }
+}
+
+/// The stage an evidence submission is at, shared between the handling of the submission and
+/// whoever enforces its deadline.
+#[derive(Debug, Default)]
+pub struct Progress {
+    stage: Cell<Stage>,
+    /// The key requested with the challenge, once it is redeemed.
+    key_id: RefCell<Option<String>>,
+    /// The media type of the evidence, once it is found acceptable.
+    media_type: RefCell<Option<String>>,
+    /// The status of each submodule of the attestation result, and whether it is in policy, once
+    /// it is appraised.
+    appraisal: RefCell<Option<(BTreeMap<String, String>, bool)>>,
+}
+
+impl Progress {
+    /// Record that the submission has reached a stage.
+    pub fn enter(&self, stage: Stage) {
+        self.stage.set(stage);
+    }
+
+    /// The stage the submission is at.
+    pub fn stage(&self) -> Stage {
+        self.stage.get()
+    }
+
+    /// Record the key requested with the redeemed challenge.
+    pub fn redeem(&self, key_id: &str) {
+        self.key_id.replace(Some(key_id.to_string()));
+    }
+
+    /// The key requested with the challenge, if it was redeemed.
+    pub fn key_id(&self) -> Option<String> {
+        self.key_id.borrow().clone()
+    }
+
+    /// Record the media type of the evidence.
+    pub fn accept_media_type(&self, media_type: &str) {
+        self.media_type.replace(Some(media_type.to_string()));
+    }
+
+    /// The media type of the evidence, if it was found acceptable.
+    pub fn media_type(&self) -> Option<String> {
+        self.media_type.borrow().clone()
+    }
+
+    /// Record the outcome of the appraisal of the attestation result.
+    pub fn appraised(&self, submod_statuses: &BTreeMap<String, String>, in_policy: bool) {
+        self.appraisal
+            .replace(Some((submod_statuses.clone(), in_policy)));
+    }
+
+    /// The status of each submodule of the attestation result, and whether it is in policy, if
+    /// it was appraised.
+    pub fn appraisal(&self) -> Option<(BTreeMap<String, String>, bool)> {
+        self.appraisal.borrow().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn challenge_consumption() {
+        let progress = Progress::default();
+        assert_eq!(progress.stage(), Stage::ReceivingEvidence);
+
+        for stage in [
+            Stage::ReceivingEvidence,
+            Stage::DecodingEvidence,
+            Stage::RedeemingChallenge,
+        ] {
+            progress.enter(stage);
+            assert!(!progress.stage().challenge_consumed(), "{stage}");
+        }
+        for stage in [Stage::Appraising, Stage::WrappingKey] {
+            progress.enter(stage);
+            assert!(progress.stage().challenge_consumed(), "{stage}");
+        }
+    }
+}
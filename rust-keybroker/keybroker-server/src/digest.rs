@@ -0,0 +1,125 @@
+// Copyright 2024 Contributors to the Veraison project.
+// SPDX-License-Identifier: Apache-2.0
+
+//! The encodings of the digests found in the reference values and the attestation results.
+//!
+//! Vendors publish digests in lowercase or uppercase hex, base64 or base64url, with or without
+//! padding. They are brought to a canonical form, padded standard base64, so that they can be
+//! compared as strings.
+use base64::engine::general_purpose::{STANDARD, STANDARD_NO_PAD};
+use base64::Engine as _;
+
+/// The sizes, in bytes, of the digests that can plausibly be used as reference values
+/// (SHA-256, SHA-384 and SHA-512).
+pub(crate) const DIGEST_SIZES: [usize; 3] = [32, 48, 64];
+
+/// Decode base64 or base64url, with or without padding.
+pub(crate) fn decode_base64(digest: &str) -> Option<Vec<u8>> {
+    let base64 = digest
+        .trim_end_matches('=')
+        .replace('-', "+")
+        .replace('_', "/");
+    STANDARD_NO_PAD.decode(base64).ok()
+}
+
+/// The bytes a digest can stand for: some digests are valid in both hex and base64.
+#[cfg(any(feature = "remote-verifier", test))]
+pub(crate) fn decode_digest(digest: &str) -> Vec<Vec<u8>> {
+    if digest.is_empty() {
+        return Vec::new();
+    }
+
+    hex::decode(digest)
+        .ok()
+        .into_iter()
+        .chain(decode_base64(digest))
+        .collect()
+}
+
+/// Encode the bytes of a digest in padded standard base64.
+pub(crate) fn encode_digest(bytes: &[u8]) -> String {
+    STANDARD.encode(bytes)
+}
+
+/// Bring a SHA-256, SHA-384 or SHA-512 digest, in any of the supported encodings, to padded
+/// standard base64.
+///
+/// A string of hex digits is read as hex if its letters are all of the same case, as hex digests
+/// are. Otherwise, a string which is a digest both in hex and in base64 is ambiguous, and rejected.
+pub(crate) fn canonical_digest(digest: &str) -> Result<String, String> {
+    let sized = |bytes: &Vec<u8>| DIGEST_SIZES.contains(&bytes.len());
+
+    let hex = hex::decode(digest).ok().filter(sized);
+    let single_case = !(digest.bytes().any(|c| c.is_ascii_lowercase())
+        && digest.bytes().any(|c| c.is_ascii_uppercase()));
+    let base64 = decode_base64(digest).filter(sized);
+
+    let bytes = match (hex, base64) {
+        (Some(bytes), None) | (None, Some(bytes)) => bytes,
+        (Some(bytes), Some(_)) if single_case => bytes,
+        (Some(hex), Some(base64)) => {
+            return Err(format!(
+                "{digest} is ambiguous: {} bytes in hex, but {} bytes in base64",
+                hex.len(),
+                base64.len()
+            ))
+        }
+        (None, None) => {
+            return Err(format!(
+                "{digest} is not a SHA-256, SHA-384 or SHA-512 digest in hex or base64"
+            ))
+        }
+    };
+
+    Ok(encode_digest(&bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn digests() {
+        let digest = [0x31, 0x13, 0x14, 0x6a];
+        for encoding in ["3113146a", "3113146A", "MRMUag==", "MRMUag"] {
+            assert!(
+                decode_digest(encoding).contains(&digest.to_vec()),
+                "{encoding}"
+            );
+        }
+
+        // Valid in both hex and base64.
+        assert_eq!(decode_digest("abcd").len(), 2);
+        assert!(decode_digest("").is_empty());
+        assert!(decode_digest("+1").is_empty());
+        assert!(decode_digest("not a digest").is_empty());
+    }
+
+    #[test]
+    fn canonical_digests() {
+        let canonical = "MRMUq3NiA1DPdYg0rlxl2ejC3H/r5ufZZUu+hk4wDUk=";
+        for encoding in [
+            canonical,
+            "MRMUq3NiA1DPdYg0rlxl2ejC3H/r5ufZZUu+hk4wDUk",
+            "MRMUq3NiA1DPdYg0rlxl2ejC3H_r5ufZZUu-hk4wDUk",
+            "311314ab73620350cf758834ae5c65d9e8c2dc7febe6e7d9654bbe864e300d49",
+            "311314AB73620350CF758834AE5C65D9E8C2DC7FEBE6E7D9654BBE864E300D49",
+        ] {
+            assert_eq!(
+                canonical_digest(encoding).as_deref(),
+                Ok(canonical),
+                "{encoding}"
+            );
+        }
+
+        // 64 hex digits are also 48 bytes of base64, so mixing the cases makes them ambiguous.
+        assert!(canonical_digest(
+            "311314ab73620350cf758834ae5c65d9e8c2dc7febe6e7d9654bbe864E300D49"
+        )
+        .is_err_and(|error| error.contains("ambiguous")));
+
+        for digest in ["", "AAAA", "not a digest", &STANDARD.encode([0; 33])] {
+            assert!(canonical_digest(digest).is_err(), "{digest}");
+        }
+    }
+}
@@ -0,0 +1,175 @@
+// Copyright 2024 Contributors to the Veraison project.
+// SPDX-License-Identifier: Apache-2.0
+
+//! The base URL at which the clients reach the server, which the evidence submission URLs given to
+//! them are built from, and which is written to the port file.
+//!
+//! It is the `--endpoint` if one is given, with the port the server listens on unless the URL has
+//! one already, as when the server is behind a proxy. Otherwise, it is built from the `--addr` and
+//! the port, IPv6 addresses being bracketed.
+use reqwest::Url;
+use std::fmt;
+use std::net::Ipv6Addr;
+
+/// The base URL of the server, without a trailing slash.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Endpoint(String);
+
+fn invalid(endpoint: &str, reason: &str) -> std::io::Error {
+    std::io::Error::other(format!("Invalid endpoint '{endpoint}': {reason}."))
+}
+
+/// Whether the authority of a URL has a port, which the parsed URL does not tell when it is the
+/// default port of the scheme.
+fn has_port(endpoint: &str) -> bool {
+    let authority = endpoint.split_once("://").map_or("", |(_, rest)| rest);
+    let authority = authority.split(['/', '?', '#']).next().unwrap_or_default();
+    let host_port = authority
+        .rsplit_once('@')
+        .map_or(authority, |(_, host_port)| host_port);
+    // The colons of an IPv6 address are followed by more of the address, or by its bracket.
+    host_port
+        .rsplit_once(':')
+        .is_some_and(|(_, port)| !port.is_empty() && port.bytes().all(|byte| byte.is_ascii_digit()))
+}
+
+impl Endpoint {
+    /// The endpoint of a server listening on `addr` and `port`, given the `--endpoint` if any, and
+    /// whether the server serves HTTPS.
+    pub fn new(endpoint: Option<&str>, addr: &str, port: u16, tls: bool) -> std::io::Result<Self> {
+        let scheme = if tls { "https" } else { "http" };
+
+        let url = match endpoint {
+            Some(endpoint) => {
+                let mut url =
+                    Url::parse(endpoint).map_err(|error| invalid(endpoint, &error.to_string()))?;
+                if !matches!(url.scheme(), "http" | "https") {
+                    return Err(invalid(endpoint, "it must be an http:// or https:// URL"));
+                }
+                if !url.has_host() {
+                    return Err(invalid(endpoint, "it has no host"));
+                }
+                if url.query().is_some() || url.fragment().is_some() {
+                    return Err(invalid(endpoint, "it can not have a query or a fragment"));
+                }
+                if url.scheme() != scheme {
+                    log::warn!(
+                        "The endpoint {endpoint} is {} while the server serves {scheme}, which is \
+                         only right behind a proxy.",
+                        url.scheme()
+                    );
+                }
+
+                if !has_port(endpoint) {
+                    // http and https URLs with a host can always have a port.
+                    let _ = url.set_port(Some(port));
+                }
+                url
+            }
+            None => {
+                let host = match addr.parse::<Ipv6Addr>() {
+                    Ok(_) => format!("[{addr}]"),
+                    Err(_) => addr.to_string(),
+                };
+                Url::parse(&format!("{scheme}://{host}:{port}"))
+                    .map_err(|error| invalid(addr, &error.to_string()))?
+            }
+        };
+
+        Ok(Endpoint(url.as_str().trim_end_matches('/').to_string()))
+    }
+
+    /// The URL of an absolute path of the server, such as `/keys/v1/info`.
+    pub fn join(&self, path: &str) -> String {
+        format!("{}{path}", self.0)
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn endpoint(endpoint: Option<&str>, addr: &str, tls: bool) -> String {
+        Endpoint::new(endpoint, addr, 8088, tls)
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn from_addr() {
+        assert_eq!(endpoint(None, "127.0.0.1", false), "http://127.0.0.1:8088");
+        assert_eq!(endpoint(None, "localhost", true), "https://localhost:8088");
+    }
+
+    #[test]
+    fn ipv6() {
+        assert_eq!(endpoint(None, "::1", false), "http://[::1]:8088");
+        assert_eq!(endpoint(None, "::", true), "https://[::]:8088");
+        assert_eq!(
+            endpoint(Some("http://[fd00::1]"), "::", false),
+            "http://[fd00::1]:8088"
+        );
+        assert_eq!(
+            endpoint(Some("http://[fd00::1]:9000"), "::", false),
+            "http://[fd00::1]:9000"
+        );
+    }
+
+    #[test]
+    fn fqdn() {
+        assert_eq!(
+            endpoint(Some("http://keybroker.example.com"), "0.0.0.0", false),
+            "http://keybroker.example.com:8088"
+        );
+        assert_eq!(
+            endpoint(Some("http://keybroker.example.com:9000"), "0.0.0.0", false),
+            "http://keybroker.example.com:9000"
+        );
+        // The default port of the scheme is kept, if implicit.
+        assert_eq!(
+            endpoint(Some("https://keybroker.example.com:443"), "0.0.0.0", true),
+            "https://keybroker.example.com"
+        );
+    }
+
+    #[test]
+    fn trailing_slash() {
+        assert_eq!(
+            endpoint(Some("http://keybroker.example.com/"), "0.0.0.0", false),
+            "http://keybroker.example.com:8088"
+        );
+        let behind_proxy = Endpoint::new(
+            Some("https://proxy.example.com:443/keybroker/"),
+            "0.0.0.0",
+            8088,
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            behind_proxy.join("/keys/v1/evidence/1234"),
+            "https://proxy.example.com/keybroker/keys/v1/evidence/1234"
+        );
+    }
+
+    #[test]
+    fn invalid_endpoints() {
+        for endpoint in [
+            "keybroker.example.com",
+            "localhost:8088",
+            "ftp://keybroker.example.com",
+            "http://keybroker.example.com/?version=1",
+            "http://",
+        ] {
+            assert!(
+                Endpoint::new(Some(endpoint), "0.0.0.0", 8088, false).is_err(),
+                "{endpoint}"
+            );
+        }
+    }
+}
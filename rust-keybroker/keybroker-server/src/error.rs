@@ -1,17 +1,20 @@
 // Copyright 2024 Contributors to the Veraison project.
 // SPDX-License-Identifier: Apache-2.0
 
+use keybroker_common::ErrorCode;
 use thiserror::Error;
 
 /// Top-level error type for the whole of the key broker service.
 #[derive(Error, Debug)]
 pub enum Error {
     /// Represents errors resulting from the Veraison API usage (when the keybroker calls out to Veraison to verify attestation tokens).
+    #[cfg(feature = "remote-verifier")]
     #[error(transparent)]
     VeraisonApi(#[from] veraison_apiclient::Error),
 
     /// Represents errors from the use of the attestation results library. These errors may occur when inspecting attestation
     /// results in order to implement an appraisal policy.
+    #[cfg(feature = "remote-verifier")]
     #[error(transparent)]
     Ear(#[from] ear::Error),
 
@@ -36,7 +39,11 @@ pub enum Error {
     /// Represents errors related to base64 decoding, which can occur when processing the various base64 strings
     /// that are transacted through the API between the client and the server, if the client provides faulty data.
     #[error(transparent)]
-    Base64Decode(#[from] base64::DecodeError),
+    Base64Decode(#[from] keybroker_common::base64::Base64FieldError),
+
+    /// Represents malformed inputs supplied by a client.
+    #[error(transparent)]
+    Input(#[from] InputErrorKind),
 
     /// Represents errors from the use of the policy evaluation library.
     #[error(transparent)]
@@ -45,11 +52,114 @@ pub enum Error {
     /// Represents errors from the use of the JSON serialisation and deserialisation library.
     #[error(transparent)]
     Json(#[from] serde_json::Error),
+
+    /// Represents errors from the HTTP client, used when talking to an authenticated verifier.
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+
+    /// Represents I/O errors, such as failures to read credentials from a file.
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    /// Represents errors loading the TLS certificate of the server, or its private key.
+    #[error("Invalid TLS certificate or key, {0}.")]
+    Tls(String),
+}
+
+impl Error {
+    /// The API error code reported to the client for this error.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            #[cfg(feature = "remote-verifier")]
+            Error::VeraisonApi(_) => ErrorCode::VerifierUnavailable,
+            Error::Http(_)
+            | Error::Verification(VerificationErrorKind::NoVerifier)
+            | Error::Verification(VerificationErrorKind::NoChallengeResponseEndpoint)
+            | Error::Verification(VerificationErrorKind::VerifierResponse(_))
+            | Error::Verification(VerificationErrorKind::VerifierStatus(..))
+            | Error::Verification(VerificationErrorKind::VerifierTimeout(_)) => {
+                ErrorCode::VerifierUnavailable
+            }
+            Error::Verification(VerificationErrorKind::VerifierCredentialsRejected) => {
+                ErrorCode::VerifierAuthenticationFailure
+            }
+            Error::Verification(VerificationErrorKind::InvalidReferenceValues(_)) => {
+                ErrorCode::InvalidReferenceValues
+            }
+            Error::Verification(VerificationErrorKind::PolicyEngineUnavailable(_)) => {
+                ErrorCode::PolicyEngineUnavailable
+            }
+            Error::KeyStore(KeyStoreErrorKind::KeyNotFound) => ErrorCode::KeyNotFound,
+            Error::KeyStore(KeyStoreErrorKind::UnsupportedWrappingKeyAlgorithm(..))
+            | Error::KeyStore(KeyStoreErrorKind::UnknownWrappingKeyAlgorithm(..)) => {
+                ErrorCode::UnsupportedWrappingKeyAlgorithm
+            }
+            Error::KeyStore(KeyStoreErrorKind::WeakWrappingKey(_)) => ErrorCode::WeakWrappingKey,
+            Error::KeyStore(KeyStoreErrorKind::ReadOnly)
+            | Error::KeyStore(KeyStoreErrorKind::DerivedKeys) => ErrorCode::KeyStoreReadOnly,
+            Error::KeyStore(KeyStoreErrorKind::KeyIdTaken(_)) => ErrorCode::KeyIdTaken,
+            Error::KeyStore(_) | Error::Rsa(_) => ErrorCode::KeyWrappingFailure,
+            Error::Challenge(ChallengeErrorKind::ChallengeNotFound) => ErrorCode::ChallengeNotFound,
+            Error::Challenge(ChallengeErrorKind::ChallengeExpired) => ErrorCode::ChallengeExpired,
+            Error::Challenge(ChallengeErrorKind::ChallengeAlreadyRedeemed(_)) => {
+                ErrorCode::ChallengeAlreadyRedeemed
+            }
+            Error::Challenge(ChallengeErrorKind::MediaTypeMismatch(_)) => {
+                ErrorCode::MediaTypeMismatch
+            }
+            Error::Input(InputErrorKind::InvalidEvidenceEncoding(_))
+            | Error::Input(InputErrorKind::InvalidCompression(_)) => {
+                ErrorCode::InvalidEvidenceEncoding
+            }
+            Error::Input(InputErrorKind::InvalidContentType(_)) => ErrorCode::InvalidContentType,
+            Error::Input(InputErrorKind::UnsupportedContentEncoding(_)) => {
+                ErrorCode::UnsupportedContentEncoding
+            }
+            Error::Input(InputErrorKind::EvidenceTooLarge(_)) => ErrorCode::EvidenceTooLarge,
+            Error::Input(InputErrorKind::MalformedEvidence(_)) => ErrorCode::MalformedEvidence,
+            Error::Input(InputErrorKind::InvalidKeyId(_)) => ErrorCode::InvalidKeyId,
+            Error::Input(InputErrorKind::UnsupportedMediaType(_)) => {
+                ErrorCode::UnsupportedMediaType
+            }
+            Error::Input(InputErrorKind::NotAcceptable(_)) => ErrorCode::NotAcceptable,
+            _ => ErrorCode::AttestationFailure,
+        }
+    }
+
+    /// Whether the error is a runtime failure of the verifier, or of the policy engine, rather
+    /// than a verdict on the evidence, so that the evidence may be appraised again: the verifier
+    /// could not be reached, timed out, or answered with a server error (5xx). A verifier that
+    /// answered with a client error (4xx), or with a malformed response, is not asked again.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            // The API client is only used for the discovery, before the evidence is submitted,
+            // and does not tell why it failed.
+            #[cfg(feature = "remote-verifier")]
+            Error::VeraisonApi(_) => true,
+            Error::Http(error) => {
+                error.is_connect()
+                    || error.is_timeout()
+                    || error
+                        .status()
+                        .is_some_and(|status| status.is_server_error())
+            }
+            Error::Verification(VerificationErrorKind::VerifierStatus(status, _)) => {
+                status.is_server_error()
+            }
+            Error::Verification(VerificationErrorKind::VerifierTimeout(_))
+            | Error::Verification(VerificationErrorKind::PolicyEngineUnavailable(_)) => true,
+            _ => false,
+        }
+    }
 }
 
 /// Errors happening within the verification process logic.
 #[derive(Error, Debug)]
 pub enum VerificationErrorKind {
+    /// The keybroker server was built without any verifier
+    #[error("This keybroker server was built without the remote-verifier feature, it can't appraise evidence.")]
+    NoVerifier,
+
     /// It was not possible to find the challenge-response newSession endpoint
     #[error("No newChallengeResponseSession endpoint was found on the Veraison server.")]
     NoChallengeResponseEndpoint,
@@ -58,10 +168,48 @@ pub enum VerificationErrorKind {
     #[error("No appraisal policy was found for the evidence type.")]
     PolicyNotFound,
 
+    /// The external policy engine could not be reached, or did not respond as expected
+    #[error("The policy engine is unavailable: {0}")]
+    PolicyEngineUnavailable(String),
+
     /// There are no known-good reference values
     #[error("No known-good reference values.")]
     NoReferenceValues,
 
+    /// The known-good reference values document is malformed
+    #[error("Invalid known-good reference values: {0}.")]
+    InvalidReferenceValues(String),
+
+    /// The verifier (or the token endpoint in front of it) did not accept our credentials
+    #[error("The verifier rejected our credentials.")]
+    VerifierCredentialsRejected,
+
+    /// The verifier returned an unexpected response
+    #[error("Unexpected response from the verifier: {0}")]
+    VerifierResponse(String),
+
+    /// The verifier answered a request with an error status
+    #[error("Unexpected response from the verifier: {0} {1}")]
+    VerifierStatus(reqwest::StatusCode, String),
+
+    /// The verifier session ended without attestation result, typically because the verifier
+    /// rejected the evidence
+    #[error("The verifier session ended without attestation result (status: {}).", .0.status.as_deref().unwrap_or("unknown"))]
+    #[cfg(feature = "remote-verifier")]
+    SessionFailed(Box<crate::verifier::SessionDiagnostics>),
+
+    /// The verifier did not produce an attestation result before the deadline
+    #[error("The verifier did not produce an attestation result within {0} seconds.")]
+    VerifierTimeout(u64),
+
+    /// The verification task ended before producing an outcome, such as when it panicked
+    #[error("The verification was aborted: {0}")]
+    VerificationAborted(String),
+
+    /// None of the published EAR verification keys could be used
+    #[error("No usable EAR verification key was published by the Veraison server.")]
+    NoVerificationKey,
+
     /// Represents errors in the CCA flavor of an EAR
     #[error("EAR/CCA error: {0}")]
     EARCCAError(String),
@@ -74,13 +222,93 @@ pub enum KeyStoreErrorKind {
     #[error("Requested key is not in the store.")]
     KeyNotFound,
 
-    /// The client provided a wrapping key that is not an RSA key.
-    #[error("The wrapping key type is not supported. Wrapping key must be an RSA key.")]
+    /// The client provided a wrapping key that is not an RSA, EC or OKP key.
+    #[error("The wrapping key type is not supported. Wrapping key must be an RSA, EC (P-256) or OKP (X25519) key.")]
     UnsupportedWrappingKeyType,
 
-    /// The client provided a wrapping key whose algorithm was not supported.
-    #[error("Thw wrapping key encryption algorithm is not supported.")]
-    UnsupportedWrappingKeyAlgorithm,
+    /// The client provided a wrapping key which is not a valid key of its type.
+    #[error("The wrapping key is not valid: {0}.")]
+    InvalidWrappingKey(String),
+
+    /// The client provided a wrapping key whose algorithm is not permitted for the requested key.
+    #[error("The wrapping key encryption algorithm {0} is not permitted for this key, permitted algorithms: [{1}].")]
+    UnsupportedWrappingKeyAlgorithm(String, String),
+
+    /// The client provided a wrapping key whose algorithm is not one the key store knows of.
+    #[error(
+        "The wrapping key encryption algorithm {0} is not supported, supported algorithms: [{1}]."
+    )]
+    UnknownWrappingKeyAlgorithm(String, String),
+
+    /// The key could not be encrypted with its content encryption key.
+    #[error("The key could not be encrypted with the content encryption key.")]
+    ContentEncryptionFailure,
+
+    /// The client provided a wrapping key which is too weak to protect the key.
+    #[error("The wrapping key is too weak: {0}.")]
+    WeakWrappingKey(String),
+
+    /// The key file is malformed.
+    #[error("Invalid key file: {0}.")]
+    InvalidKeyFile(String),
+
+    /// The SHA-256 digest of the key file is not the expected one.
+    #[error("The key file has the SHA-256 digest {1}, where {0} is expected.")]
+    KeyFileDigestMismatch(String, String),
+
+    /// Attempt to give a key or an alias an identifier which is already taken, by another key or
+    /// by an alias.
+    #[error("The key identifier {0} is already taken.")]
+    KeyIdTaken(String),
+
+    /// Attempt to change the keys of a read-only key store.
+    #[error("The key store is read-only, its keys can not be changed.")]
+    ReadOnly,
+
+    /// Attempt to change the keys of a key store which derives them from a master secret.
+    #[error("The keys are derived from the master secret, they can not be changed.")]
+    DerivedKeys,
+}
+
+/// Errors in the inputs supplied by the clients.
+#[derive(Error, Debug)]
+pub enum InputErrorKind {
+    /// The evidence is not correctly base64-encoded.
+    #[error("The evidence is not valid base64: {0}")]
+    InvalidEvidenceEncoding(String),
+
+    /// The Content-Type header contains characters that are not visible ASCII, or is not a media
+    /// type.
+    #[error("The Content-Type header is malformed: {0}.")]
+    InvalidContentType(String),
+
+    /// The evidence was submitted with a Content-Encoding other than gzip.
+    #[error("The content encoding '{0}' is not supported.")]
+    UnsupportedContentEncoding(String),
+
+    /// The gzip-compressed evidence could not be decompressed.
+    #[error("The evidence could not be decompressed: {0}")]
+    InvalidCompression(String),
+
+    /// The evidence, once decompressed, is larger than allowed.
+    #[error("The evidence exceeds the maximum size of {0} bytes.")]
+    EvidenceTooLarge(usize),
+
+    /// The evidence is not structurally valid for its media type.
+    #[error("The evidence is structurally invalid, {0}.")]
+    MalformedEvidence(String),
+
+    /// The key identifier does not comply with the key identifier policy.
+    #[error("Invalid key identifier: {0}.")]
+    InvalidKeyId(String),
+
+    /// The evidence media type is not supported by the keybroker.
+    #[error("The evidence media type '{0}' is not supported.")]
+    UnsupportedMediaType(String),
+
+    /// The client accepts none of the representations of the response.
+    #[error("None of the representations of the response is acceptable: {0}.")]
+    NotAcceptable(String),
 }
 
 /// Errors related to the management of challenges
@@ -89,6 +317,18 @@ pub enum ChallengeErrorKind {
     /// Attempt to lookup a challenge with an unknown ID.
     #[error("Reference to a challenge that does not exist.")]
     ChallengeNotFound,
+
+    /// Attempt to redeem a challenge past its time to live.
+    #[error("The challenge has expired.")]
+    ChallengeExpired,
+
+    /// Attempt to redeem a challenge that was already redeemed.
+    #[error("The challenge has already been redeemed, and {0}.")]
+    ChallengeAlreadyRedeemed(crate::challenge::RedemptionOutcome),
+
+    /// Attempt to redeem a challenge with evidence of a type it was not issued for.
+    #[error("The challenge was not issued for evidence of type '{0}'.")]
+    MediaTypeMismatch(String),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
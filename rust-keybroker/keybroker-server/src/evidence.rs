@@ -0,0 +1,278 @@
+// Copyright 2024 Contributors to the Veraison project.
+// SPDX-License-Identifier: Apache-2.0
+
+//! This module holds the registry of the evidence types supported by the keybroker.
+//!
+//! The media type of the submitted evidence (its Content-Type) selects everything that is specific to an
+//! evidence flavour: the appraisal policy and the rule to evaluate, the diagnostics to emit, the size of
+//! the challenge (nonce) that the evidence must incorporate, the structural check of the evidence, and
+//! the logging of its claims. Supporting a new evidence flavour is thus only a matter of adding an
+//! entry to the registry.
+use crate::error::{Error, InputErrorKind, Result};
+use crate::input;
+#[cfg(feature = "remote-verifier")]
+use crate::verifier::{CcaDiagnostics, DiagnosticsOptions, EmitDiagnostic};
+use phf::{phf_map, Map};
+#[cfg(feature = "remote-verifier")]
+use std::str::FromStr;
+
+/// Everything the keybroker needs to know to issue challenges for, and appraise, an evidence flavour.
+pub struct EvidenceType {
+    /// The appraisal policy, in Rego.
+    #[cfg(feature = "remote-verifier")]
+    pub policy: &'static str,
+
+    /// The rule of the appraisal policy that decides whether the EAR is acceptable.
+    #[cfg(feature = "remote-verifier")]
+    pub policy_rule: &'static str,
+
+    /// Build the diagnostics for this flavour of EAR, with the given options.
+    #[cfg(feature = "remote-verifier")]
+    pub diagnostics: fn(&DiagnosticsOptions) -> Box<dyn EmitDiagnostic>,
+
+    /// The size of the challenge (nonce), in bytes, that the evidence must incorporate.
+    pub nonce_size: usize,
+
+    /// A cheap and conservative check of the structure of the evidence, run before it is sent to
+    /// the verifier.
+    pub check_structure: fn(&[u8]) -> Result<()>,
+
+    /// Log the claims of the evidence submitted for a challenge, decoded locally before it is sent
+    /// to the verifier. This is only for diagnostics, and only available with some features.
+    pub log_claims: Option<fn(u32, &[u8])>,
+}
+
+#[cfg(feature = "remote-verifier")]
+fn cca_diagnostics(options: &DiagnosticsOptions) -> Box<dyn EmitDiagnostic> {
+    Box::new(CcaDiagnostics::new(options))
+}
+
+#[cfg(feature = "cca-token-diagnostics")]
+const CCA_LOG_CLAIMS: Option<fn(u32, &[u8])> = Some(crate::cca_token::log_claims);
+#[cfg(not(feature = "cca-token-diagnostics"))]
+const CCA_LOG_CLAIMS: Option<fn(u32, &[u8])> = None;
+
+/// The supported evidence types, keyed by media type, in the canonical form of
+/// [`input::canonical_media_type`].
+pub static EVIDENCE_TYPES: Map<&'static str, EvidenceType> = phf_map! {
+    r#"application/eat-collection; profile="http://arm.com/CCA-SSD/1.0.0""# => EvidenceType {
+        #[cfg(feature = "remote-verifier")]
+        policy: include_str!("arm-cca.rego"),
+        #[cfg(feature = "remote-verifier")]
+        policy_rule: "data.arm_cca.allow",
+        #[cfg(feature = "remote-verifier")]
+        diagnostics: cca_diagnostics,
+        nonce_size: 64,
+        check_structure: input::check_cbor_tagged,
+        log_claims: CCA_LOG_CLAIMS,
+    },
+    // Other, future evidence types
+};
+
+/// Look up the evidence type for a media type.
+pub fn evidence_type(media_type: &str) -> Result<&'static EvidenceType> {
+    EVIDENCE_TYPES
+        .get(media_type)
+        .ok_or_else(|| Error::Input(InputErrorKind::UnsupportedMediaType(media_type.to_string())))
+}
+
+/// The media types of all the supported evidence types.
+pub fn media_types() -> Vec<String> {
+    let mut media_types: Vec<String> = EVIDENCE_TYPES.keys().map(|k| k.to_string()).collect();
+    media_types.sort();
+    media_types
+}
+
+/// The size of the challenges to issue for a set of media types, which is that of the largest
+/// nonce they expect.
+pub fn nonce_size(media_types: &[String]) -> usize {
+    media_types
+        .iter()
+        .filter_map(|media_type| EVIDENCE_TYPES.get(media_type.as_str()))
+        .map(|evidence_type| evidence_type.nonce_size)
+        .max()
+        .unwrap_or(0)
+}
+
+/// Another name of a supported media type, under which the evidence of that type is submitted to
+/// the verifier, as specified on the command line with '<media type>=<verifier media type>'.
+#[cfg(feature = "remote-verifier")]
+#[derive(Clone, Debug, PartialEq)]
+pub struct MediaTypeAlias {
+    /// The supported media type, as submitted by the clients.
+    pub from: String,
+
+    /// The media type the verifier knows it as.
+    pub to: String,
+}
+
+#[cfg(feature = "remote-verifier")]
+impl FromStr for MediaTypeAlias {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        // Media types have '=' in their parameters, so the supported media type is matched as a
+        // whole. An alias for any other one would leave the evidence without an appraisal policy.
+        let Some(alias) = EVIDENCE_TYPES.keys().find_map(|from| {
+            let to = s.strip_prefix(from)?.strip_prefix('=')?;
+            Some(MediaTypeAlias {
+                from: from.to_string(),
+                to: to.to_string(),
+            })
+        }) else {
+            return Err(format!(
+                "expecting '<media type>=<verifier media type>', the media type being one of: {}",
+                media_types().join(", ")
+            ));
+        };
+
+        // The verifier media type ends up in a Content-Type header.
+        if alias.to.trim().is_empty()
+            || !alias
+                .to
+                .bytes()
+                .all(|c| c == b'\t' || (b' '..=b'~').contains(&c))
+        {
+            return Err(
+                "the verifier media type must be made of visible ASCII characters".to_string(),
+            );
+        }
+        Ok(alias)
+    }
+}
+
+/// The media type to submit the evidence to the verifier with: the alias of the media type of the
+/// evidence, if there is one, or else that media type.
+#[cfg(feature = "remote-verifier")]
+pub fn verifier_media_type<'a>(aliases: &'a [MediaTypeAlias], media_type: &'a str) -> &'a str {
+    aliases
+        .iter()
+        .find(|alias| alias.from == media_type)
+        .map_or(media_type, |alias| alias.to.as_str())
+}
+
+/// The media types to offer in the challenges: those of `supported` that the verifier lists in its
+/// discovery API, under their alias if they have one. The media types are compared in their
+/// canonical form, as the verifier may write them differently.
+#[cfg(feature = "remote-verifier")]
+pub fn offered_media_types(
+    supported: Vec<String>,
+    verifier_media_types: &[String],
+    aliases: &[MediaTypeAlias],
+) -> Vec<String> {
+    let canonical = |media_type: &str| {
+        input::canonical_media_type(media_type).unwrap_or_else(|_| media_type.to_string())
+    };
+    let verifier_media_types: Vec<String> = verifier_media_types
+        .iter()
+        .map(|media_type| canonical(media_type))
+        .collect();
+    supported
+        .into_iter()
+        .filter(|media_type| {
+            verifier_media_types.contains(&canonical(verifier_media_type(aliases, media_type)))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CCA_MEDIA_TYPE: &str =
+        r#"application/eat-collection; profile="http://arm.com/CCA-SSD/1.0.0""#;
+
+    #[test]
+    fn cca_is_registered() {
+        let cca = evidence_type(CCA_MEDIA_TYPE).expect("CCA is supported");
+        #[cfg(feature = "remote-verifier")]
+        assert_eq!(cca.policy_rule, "data.arm_cca.allow");
+        assert_eq!(cca.nonce_size, 64);
+        assert_eq!(media_types(), vec![CCA_MEDIA_TYPE.to_string()]);
+        assert_eq!(nonce_size(&media_types()), 64);
+    }
+
+    #[test]
+    fn media_types_are_canonical() {
+        // Otherwise, the evidence submitted with them could not be matched to their evidence type.
+        for media_type in media_types() {
+            assert_eq!(
+                input::canonical_media_type(&media_type).unwrap(),
+                media_type
+            );
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "remote-verifier")]
+    fn media_type_aliases() {
+        let older = r#"application/eat-collection; profile="http://arm.com/CCA-SSD/1.0.0-beta""#;
+        let alias: MediaTypeAlias = format!("{CCA_MEDIA_TYPE}={older}").parse().unwrap();
+        assert_eq!(
+            alias,
+            MediaTypeAlias {
+                from: CCA_MEDIA_TYPE.to_string(),
+                to: older.to_string(),
+            }
+        );
+
+        let aliases = [alias];
+        assert_eq!(verifier_media_type(&aliases, CCA_MEDIA_TYPE), older);
+        assert_eq!(
+            verifier_media_type(&aliases, "application/psa-attestation-token"),
+            "application/psa-attestation-token"
+        );
+        assert_eq!(verifier_media_type(&[], CCA_MEDIA_TYPE), CCA_MEDIA_TYPE);
+    }
+
+    #[test]
+    #[cfg(feature = "remote-verifier")]
+    fn invalid_media_type_aliases() {
+        for alias in [
+            format!("application/psa-attestation-token={CCA_MEDIA_TYPE}"),
+            CCA_MEDIA_TYPE.to_string(),
+            format!("{CCA_MEDIA_TYPE}="),
+            format!("{CCA_MEDIA_TYPE}=application/eat-collection\n"),
+        ] {
+            assert!(alias.parse::<MediaTypeAlias>().is_err(), "{alias:?}");
+        }
+    }
+
+    #[test]
+    fn unknown_media_type() {
+        assert!(matches!(
+            evidence_type("application/psa-attestation-token"),
+            Err(Error::Input(InputErrorKind::UnsupportedMediaType(_)))
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "remote-verifier")]
+    fn offered_media_types_from_discovery() {
+        const PSA: &str = "application/psa-attestation-token";
+        let older = r#"application/eat-collection; profile="http://arm.com/CCA-SSD/1.0.0-beta""#;
+        let supported = vec![CCA_MEDIA_TYPE.to_string(), PSA.to_string()];
+
+        // The verifier may write the media types differently.
+        let verifier = vec![
+            "application/eat-collection;profile=http://arm.com/CCA-SSD/1.0.0".to_string(),
+            "application/vnd.enacttrust.tpm-evidence".to_string(),
+        ];
+        assert_eq!(
+            offered_media_types(supported.clone(), &verifier, &[]),
+            vec![CCA_MEDIA_TYPE.to_string()]
+        );
+
+        // A media type the verifier knows under an alias is offered under its own name.
+        let alias: MediaTypeAlias = format!("{CCA_MEDIA_TYPE}={older}").parse().unwrap();
+        let verifier = vec![older.to_string(), PSA.to_string()];
+        assert_eq!(
+            offered_media_types(supported.clone(), &verifier, std::slice::from_ref(&alias)),
+            supported
+        );
+        assert_eq!(
+            offered_media_types(supported, &[CCA_MEDIA_TYPE.to_string()], &[alias]),
+            Vec::<String>::new()
+        );
+    }
+}
@@ -0,0 +1,671 @@
+// Copyright 2024 Contributors to the Veraison project.
+// SPDX-License-Identifier: Apache-2.0
+
+//! This module validates and decodes the inputs supplied by the clients of the keybroker.
+//!
+//! All of these inputs are attacker-controlled, so they are handled by pure functions which report
+//! malformed data as errors rather than panicking. These functions are also the entry points used by
+//! the fuzz targets, which is why they do not depend on the HTTP server.
+use crate::error::{Error, InputErrorKind, KeyStoreErrorKind, Result};
+use crate::keystore::{
+    CURVE_COORDINATE_SIZE, EC_KEY_TYPE, OKP_KEY_TYPE, P256_CURVE, RSA_KEY_TYPE, X25519_CURVE,
+};
+use flate2::read::GzDecoder;
+use keybroker_common::{base64, BackgroundCheckKeyRequest, PublicWrappingKey};
+use mime::Mime;
+use p256::elliptic_curve::sec1::FromEncodedPoint;
+use rsa::{BigUint, RsaPublicKey};
+use std::borrow::Cow;
+use std::io::Read;
+
+/// The media type assumed for evidence submitted without a Content-Type header.
+pub const DEFAULT_EVIDENCE_MEDIA_TYPE: &str = "text/plain";
+
+/// Decode the base64 body of an evidence submission.
+pub fn decode_evidence(evidence_base64: &[u8]) -> Result<Vec<u8>> {
+    let evidence_base64 = std::str::from_utf8(evidence_base64).map_err(|error| {
+        Error::Input(InputErrorKind::InvalidEvidenceEncoding(error.to_string()))
+    })?;
+    base64::decode("the evidence", evidence_base64)
+        .map_err(|error| Error::Input(InputErrorKind::InvalidEvidenceEncoding(error.to_string())))
+}
+
+/// Undo the Content-Encoding of the body of an evidence submission, if any.
+///
+/// Only gzip is supported. The decompression stops as soon as the output exceeds `max_size`, so
+/// that a small compressed body can't be used to exhaust the memory of the server.
+pub fn decode_content(
+    content_encoding: Option<&[u8]>,
+    body: Vec<u8>,
+    max_size: usize,
+) -> Result<Vec<u8>> {
+    let content_encoding = content_encoding.map(|encoding| encoding.trim_ascii());
+    match content_encoding {
+        None => {}
+        Some(encoding) if encoding.eq_ignore_ascii_case(b"identity") => {}
+        Some(encoding) if encoding.eq_ignore_ascii_case(b"gzip") => {
+            let mut decompressed = Vec::new();
+            // Read one byte more than allowed, to tell an oversized body from one of exactly max_size.
+            GzDecoder::new(body.as_slice())
+                .take(max_size as u64 + 1)
+                .read_to_end(&mut decompressed)
+                .map_err(|error| {
+                    Error::Input(InputErrorKind::InvalidCompression(error.to_string()))
+                })?;
+            if decompressed.len() > max_size {
+                return Err(Error::Input(InputErrorKind::EvidenceTooLarge(max_size)));
+            }
+            return Ok(decompressed);
+        }
+        Some(encoding) => {
+            return Err(Error::Input(InputErrorKind::UnsupportedContentEncoding(
+                String::from_utf8_lossy(encoding).into_owned(),
+            )))
+        }
+    }
+
+    if body.len() > max_size {
+        return Err(Error::Input(InputErrorKind::EvidenceTooLarge(max_size)));
+    }
+    Ok(body)
+}
+
+/// The maximum nesting depth of the CBOR data items accepted in an evidence.
+const MAX_CBOR_DEPTH: usize = 64;
+
+fn malformed_evidence(reason: &str) -> Error {
+    Error::Input(InputErrorKind::MalformedEvidence(reason.to_string()))
+}
+
+/// Check that an evidence is a single, well-formed, tagged CBOR data item.
+///
+/// This is a cheap and conservative check, meant to turn away obvious junk (random bytes, truncated
+/// uploads) before a verifier session is opened for it. Anything that passes it is left for the
+/// verifier to appraise.
+pub fn check_cbor_tagged(evidence: &[u8]) -> Result<()> {
+    if evidence.first().is_none_or(|&initial| initial >> 5 != 6) {
+        return Err(malformed_evidence("it is not a tagged CBOR data item"));
+    }
+
+    let mut rest = evidence;
+    ciborium::de::from_reader_with_recursion_limit::<ciborium::Value, _>(&mut rest, MAX_CBOR_DEPTH)
+        .map_err(|error| {
+            malformed_evidence(&match error {
+                ciborium::de::Error::Io(_) => "it is truncated".to_string(),
+                ciborium::de::Error::RecursionLimitExceeded => {
+                    "its CBOR items are nested too deeply".to_string()
+                }
+                error => format!("it is not well-formed CBOR ({error})"),
+            })
+        })?;
+
+    if !rest.is_empty() {
+        return Err(malformed_evidence(
+            "it has trailing data after the CBOR data item",
+        ));
+    }
+    Ok(())
+}
+
+fn invalid_content_type(reason: &str) -> Error {
+    Error::Input(InputErrorKind::InvalidContentType(reason.to_string()))
+}
+
+/// Quote the parameter values of a media type written without any quoted string, as the mime crate
+/// only accepts tokens as unquoted values, while the profiles of the evidence media types are URIs,
+/// which are commonly left unquoted. Without quoted strings, every semicolon separates parameters.
+fn quote_parameter_values(media_type: &str) -> Cow<'_, str> {
+    if media_type.contains('"') || !media_type.contains(';') {
+        return Cow::Borrowed(media_type);
+    }
+
+    let mut parts = media_type.split(';');
+    let mut quoted = parts.next().unwrap_or_default().trim_end().to_string();
+    // Tolerate a trailing semicolon.
+    for parameter in parts
+        .map(str::trim)
+        .filter(|parameter| !parameter.is_empty())
+    {
+        quoted.push_str("; ");
+        match parameter.split_once('=') {
+            Some((name, value)) => quoted.push_str(&format!("{name}=\"{value}\"")),
+            None => quoted.push_str(parameter),
+        }
+    }
+    Cow::Owned(quoted)
+}
+
+/// Bring a media type to the canonical form of the media types of the evidence types registry,
+/// so that its lookup does not depend on how the client wrote it: the type, subtype and parameter
+/// names are lowercased, the parameters are sorted by name, and their values are all quoted, e.g.
+/// `application/eat-collection; profile="http://arm.com/CCA-SSD/1.0.0"`.
+///
+/// The media type is parsed with the mime crate. Parameter values are case-sensitive, and kept as
+/// they are. As the profiles of the evidence media types are URIs, which are not tokens, an
+/// unquoted value is accepted whatever its characters, as long as no value is quoted.
+pub fn canonical_media_type(media_type: &str) -> Result<String> {
+    let mime: Mime = quote_parameter_values(media_type.trim()).parse().map_err(
+        |error: mime::FromStrError| {
+            invalid_content_type(&format!("it is not a media type ({error})"))
+        },
+    )?;
+    if mime.subtype().as_str().is_empty() {
+        return Err(invalid_content_type(
+            "the media type is not of the form type/subtype",
+        ));
+    }
+
+    let mut parameters: Vec<(&str, &str)> = Vec::new();
+    for (name, value) in mime.params() {
+        if parameters.iter().any(|(other, _)| *other == name.as_str()) {
+            return Err(invalid_content_type("a parameter is repeated"));
+        }
+        parameters.push((name.as_str(), value.as_str()));
+    }
+    parameters.sort();
+
+    Ok(parameters.into_iter().fold(
+        mime.essence_str().to_string(),
+        |media_type, (name, value)| format!("{media_type}; {name}=\"{value}\""),
+    ))
+}
+
+/// Validate the raw bytes of the Content-Type header of an evidence submission, and return
+/// the media type in its canonical form, as per [`canonical_media_type`].
+///
+/// Only visible ASCII characters, spaces and tabs are allowed in the header value.
+pub fn evidence_media_type(content_type: Option<&[u8]>) -> Result<String> {
+    let Some(content_type) = content_type else {
+        return Ok(DEFAULT_EVIDENCE_MEDIA_TYPE.to_string());
+    };
+
+    if !content_type
+        .iter()
+        .all(|&c| c == b'\t' || (b' '..=b'~').contains(&c))
+    {
+        return Err(invalid_content_type(
+            "it contains characters that are not visible ASCII",
+        ));
+    }
+
+    // Can't fail now, as the header is plain ASCII.
+    canonical_media_type(&String::from_utf8_lossy(content_type))
+}
+
+/// Parse the JSON body of a key request.
+pub fn parse_key_request(body: &[u8]) -> Result<BackgroundCheckKeyRequest> {
+    Ok(serde_json::from_slice(body)?)
+}
+
+/// The smallest public exponent of the wrapping keys.
+pub const MIN_WRAPPING_KEY_EXPONENT: u32 = 65537;
+
+fn invalid_wrapping_key(reason: &str) -> Error {
+    Error::KeyStore(KeyStoreErrorKind::InvalidWrappingKey(reason.to_string()))
+}
+
+/// Decode a base64 member of a wrapping key, which its key type requires.
+fn wrapping_key_member(value: &Option<String>, member: &str, description: &str) -> Result<Vec<u8>> {
+    let value = value
+        .as_deref()
+        .ok_or_else(|| invalid_wrapping_key(&format!("its '{member}' member is missing")))?;
+    Ok(base64::decode(description, value)?)
+}
+
+/// Check that a wrapping key is strong enough to protect the keys wrapped to it: the modulus of an
+/// RSA key must be at least `min_bits` long, and its public exponent odd and at least 65537.
+///
+/// The elliptic-curve keys are all of the same strength, whatever `min_bits`: they are only checked
+/// to be valid public keys.
+pub fn check_wrapping_key_strength(
+    wrapping_key: &PublicWrappingKey,
+    min_bits: usize,
+) -> Result<()> {
+    if wrapping_key.kty != RSA_KEY_TYPE {
+        return wrapping_public_key(wrapping_key).map(|_| ());
+    }
+
+    let weak = |reason: String| Err(Error::KeyStore(KeyStoreErrorKind::WeakWrappingKey(reason)));
+
+    let k_mod = wrapping_key_member(&wrapping_key.n, "n", "the wrapping key modulus")?;
+    let bits = BigUint::from_bytes_be(&k_mod).bits();
+    if bits < min_bits {
+        return weak(format!(
+            "its modulus is {bits} bits long, where at least {min_bits} bits are required"
+        ));
+    }
+
+    let k_exp = wrapping_key_member(&wrapping_key.e, "e", "the wrapping key exponent")?;
+    let e = BigUint::from_bytes_be(&k_exp);
+    if e < BigUint::from(MIN_WRAPPING_KEY_EXPONENT) {
+        return weak(format!(
+            "its public exponent is {e}, where at least {MIN_WRAPPING_KEY_EXPONENT} is required"
+        ));
+    }
+    // The exponent is big-endian, its parity is that of its last byte.
+    if k_exp.last().is_some_and(|byte| byte % 2 == 0) {
+        return weak(format!("its public exponent {e} is even"));
+    }
+
+    Ok(())
+}
+
+/// The public key described by a client-supplied wrapping key.
+#[derive(Debug, Clone)]
+pub enum WrappingPublicKey {
+    /// An RSA key, for the RSA wrapping algorithms.
+    Rsa(RsaPublicKey),
+
+    /// A P-256 key, for the ECDH-ES+A256KW wrapping algorithm.
+    P256(p256::PublicKey),
+
+    /// An X25519 key, for the ECDH-ES+A256KW wrapping algorithm.
+    X25519(x25519_dalek::PublicKey),
+}
+
+/// Check that the curve of an elliptic-curve wrapping key is the one of its key type.
+fn check_curve(wrapping_key: &PublicWrappingKey, curve: &str) -> Result<()> {
+    match wrapping_key.crv.as_deref() {
+        Some(crv) if crv == curve => Ok(()),
+        Some(crv) => Err(invalid_wrapping_key(&format!(
+            "its curve {crv} is not supported, {} keys must be on {curve}",
+            wrapping_key.kty
+        ))),
+        None => Err(invalid_wrapping_key("its 'crv' member is missing")),
+    }
+}
+
+/// Build the public key described by a client-supplied wrapping key.
+pub fn wrapping_public_key(wrapping_key: &PublicWrappingKey) -> Result<WrappingPublicKey> {
+    match wrapping_key.kty.as_str() {
+        RSA_KEY_TYPE => {
+            let k_mod = wrapping_key_member(&wrapping_key.n, "n", "the wrapping key modulus")?;
+            let n = BigUint::from_bytes_be(&k_mod);
+            let k_exp = wrapping_key_member(&wrapping_key.e, "e", "the wrapping key exponent")?;
+            let e = BigUint::from_bytes_be(&k_exp);
+
+            Ok(WrappingPublicKey::Rsa(RsaPublicKey::new(n, e)?))
+        }
+        EC_KEY_TYPE => {
+            check_curve(wrapping_key, P256_CURVE)?;
+            let x = wrapping_key_member(&wrapping_key.x, "x", "the wrapping key x coordinate")?;
+            let y = wrapping_key_member(&wrapping_key.y, "y", "the wrapping key y coordinate")?;
+            if x.len() != CURVE_COORDINATE_SIZE || y.len() != CURVE_COORDINATE_SIZE {
+                return Err(invalid_wrapping_key(&format!(
+                    "its coordinates must be {CURVE_COORDINATE_SIZE} bytes long"
+                )));
+            }
+
+            let point = p256::EncodedPoint::from_affine_coordinates(
+                p256::FieldBytes::from_slice(&x),
+                p256::FieldBytes::from_slice(&y),
+                false,
+            );
+            Option::<p256::PublicKey>::from(p256::PublicKey::from_encoded_point(&point))
+                .map(WrappingPublicKey::P256)
+                .ok_or_else(|| invalid_wrapping_key("its point is not on the P-256 curve"))
+        }
+        OKP_KEY_TYPE => {
+            check_curve(wrapping_key, X25519_CURVE)?;
+            let x: [u8; CURVE_COORDINATE_SIZE] =
+                wrapping_key_member(&wrapping_key.x, "x", "the wrapping public key")?
+                    .try_into()
+                    .map_err(|_| {
+                        invalid_wrapping_key(&format!(
+                            "its public key must be {CURVE_COORDINATE_SIZE} bytes long"
+                        ))
+                    })?;
+
+            // The shared secrets of the points of small order are all zero, whatever the private
+            // key, which the clamping of the scalars makes a multiple of their order.
+            if x25519_dalek::x25519([0x5a; CURVE_COORDINATE_SIZE], x) == [0; CURVE_COORDINATE_SIZE]
+            {
+                return Err(invalid_wrapping_key("its public key is of small order"));
+            }
+            Ok(WrappingPublicKey::X25519(x25519_dalek::PublicKey::from(x)))
+        }
+        _ => Err(Error::KeyStore(
+            KeyStoreErrorKind::UnsupportedWrappingKeyType,
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{write::GzEncoder, Compression};
+    use std::io::Write;
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn evidence_round_trip() {
+        let evidence =
+            decode_evidence(base64::encode(b"evidence").as_bytes()).expect("valid base64");
+        assert_eq!(evidence, b"evidence");
+    }
+
+    #[test]
+    fn evidence_not_utf8() {
+        assert!(matches!(
+            decode_evidence(b"ZXZp\xff"),
+            Err(Error::Input(InputErrorKind::InvalidEvidenceEncoding(_)))
+        ));
+    }
+
+    // Tag 399 (CMW collection), with a map of two byte strings, as in the CCA evidence.
+    const TAGGED_CBOR: &[u8] = &[
+        0xd9, 0x01, 0x8f, 0xa2, 0x19, 0xac, 0xca, 0x42, 0x00, 0x01, 0x19, 0xac, 0xd1, 0x41, 0x02,
+    ];
+
+    #[test]
+    fn cbor_tagged() {
+        check_cbor_tagged(TAGGED_CBOR).expect("well-formed tagged CBOR");
+
+        // Indefinite-length maps and strings are fine too.
+        check_cbor_tagged(&[0xc1, 0xbf, 0x01, 0x5f, 0x41, 0x00, 0xff, 0xff]).unwrap();
+    }
+
+    #[test]
+    fn cbor_malformed() {
+        for evidence in [
+            &b""[..],
+            b"evidence",
+            // Truncated upload.
+            &TAGGED_CBOR[..TAGGED_CBOR.len() - 1],
+            // Trailing data.
+            &[TAGGED_CBOR, &[0x00]].concat(),
+            // Not tagged.
+            &TAGGED_CBOR[3..],
+            // Absurd map length.
+            &[0xc1, 0xbb, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+            // Reserved encoding.
+            &[0xc1, 0x1c],
+            // Stray break.
+            &[0xc1, 0xff],
+            // Indefinite-length map with a missing value.
+            &[0xc1, 0xbf, 0x01, 0xff],
+            // Indefinite-length byte string with a text chunk.
+            &[0xc1, 0x5f, 0x61, 0x00, 0xff],
+        ] {
+            assert!(
+                matches!(
+                    check_cbor_tagged(evidence),
+                    Err(Error::Input(InputErrorKind::MalformedEvidence(_)))
+                ),
+                "{evidence:02x?} accepted"
+            );
+        }
+    }
+
+    #[test]
+    fn cbor_nested_too_deeply() {
+        let mut evidence = vec![0xc1; MAX_CBOR_DEPTH + 1];
+        evidence.push(0x00);
+        assert!(check_cbor_tagged(&evidence).is_err());
+
+        let mut evidence = vec![0xc1; MAX_CBOR_DEPTH];
+        evidence.push(0x00);
+        check_cbor_tagged(&evidence).unwrap();
+    }
+
+    #[test]
+    fn content_identity() {
+        assert_eq!(decode_content(None, b"ZXZp".to_vec(), 4).unwrap(), b"ZXZp");
+        assert_eq!(
+            decode_content(Some(b"identity"), b"ZXZp".to_vec(), 4).unwrap(),
+            b"ZXZp"
+        );
+        assert!(matches!(
+            decode_content(None, b"ZXZp".to_vec(), 3),
+            Err(Error::Input(InputErrorKind::EvidenceTooLarge(3)))
+        ));
+    }
+
+    #[test]
+    fn content_gzip() {
+        let body = gzip(b"ZXZpZGVuY2U");
+        assert_eq!(
+            decode_content(Some(b"gzip"), body.clone(), 11).unwrap(),
+            b"ZXZpZGVuY2U"
+        );
+        assert_eq!(
+            decode_content(Some(b" GZIP "), body, 11).unwrap(),
+            b"ZXZpZGVuY2U"
+        );
+    }
+
+    #[test]
+    fn content_gzip_bomb() {
+        // 64 MiB of zeroes compress to about 64 KiB.
+        let body = gzip(&vec![0; 64 * 1024 * 1024]);
+        assert!(body.len() < 1024 * 1024);
+        assert!(matches!(
+            decode_content(Some(b"gzip"), body, 1024 * 1024),
+            Err(Error::Input(InputErrorKind::EvidenceTooLarge(_)))
+        ));
+    }
+
+    #[test]
+    fn content_invalid() {
+        assert!(matches!(
+            decode_content(Some(b"gzip"), b"not gzip".to_vec(), 1024),
+            Err(Error::Input(InputErrorKind::InvalidCompression(_)))
+        ));
+        assert!(matches!(
+            decode_content(Some(b"br"), b"ZXZp".to_vec(), 1024),
+            Err(Error::Input(InputErrorKind::UnsupportedContentEncoding(encoding))) if encoding == "br"
+        ));
+    }
+
+    #[test]
+    fn evidence_invalid_base64() {
+        assert!(matches!(
+            decode_evidence(b"not base64!"),
+            Err(Error::Input(InputErrorKind::InvalidEvidenceEncoding(_)))
+        ));
+    }
+
+    #[test]
+    fn media_type_defaults() {
+        assert_eq!(
+            evidence_media_type(None).expect("default media type"),
+            DEFAULT_EVIDENCE_MEDIA_TYPE
+        );
+    }
+
+    #[test]
+    fn media_type_non_ascii() {
+        assert!(matches!(
+            evidence_media_type(Some(b"application/eat-collection\xff")),
+            Err(Error::Input(InputErrorKind::InvalidContentType(_)))
+        ));
+    }
+
+    #[test]
+    fn media_type_canonical() {
+        const CCA: &str = r#"application/eat-collection; profile="http://arm.com/CCA-SSD/1.0.0""#;
+        for media_type in [
+            CCA,
+            r#"application/eat-collection;profile="http://arm.com/CCA-SSD/1.0.0""#,
+            r#"Application/EAT-Collection; PROFILE="http://arm.com/CCA-SSD/1.0.0";"#,
+            "application/eat-collection; profile=http://arm.com/CCA-SSD/1.0.0",
+            "application/eat-collection ;profile=http://arm.com/CCA-SSD/1.0.0;",
+        ] {
+            assert_eq!(
+                evidence_media_type(Some(media_type.as_bytes())).unwrap(),
+                CCA,
+                "{media_type}"
+            );
+        }
+
+        // The parameters are sorted, and their values are case-sensitive.
+        assert_eq!(
+            canonical_media_type(r#"a/b; z=1; y="A;B""#).unwrap(),
+            r#"a/b; y="A;B"; z="1""#
+        );
+        assert_eq!(
+            canonical_media_type("a/b; z=1; y=A").unwrap(),
+            r#"a/b; y="A"; z="1""#
+        );
+    }
+
+    #[test]
+    fn media_type_malformed() {
+        for media_type in [
+            "",
+            "application",
+            "application/",
+            "/eat-collection",
+            "application/eat collection",
+            "application/eat-collection; profile",
+            r#"application/eat-collection; profile="http://arm.com"#,
+            r#"application/eat-collection; profile=http://"arm.com""#,
+            "application/eat-collection; a=1; A=2",
+            r#"application/eat-collection; profile = "http://arm.com/CCA-SSD/1.0.0""#,
+            r#"application/eat-collection; a="1"; profile=http://arm.com/CCA-SSD/1.0.0"#,
+        ] {
+            assert!(
+                matches!(
+                    canonical_media_type(media_type),
+                    Err(Error::Input(InputErrorKind::InvalidContentType(_)))
+                ),
+                "{media_type}"
+            );
+        }
+    }
+
+    #[test]
+    fn wrapping_key_garbage() {
+        let request = parse_key_request(
+            br#"{"pubkey": {"kty": "RSA", "alg": "RSA1_5", "n": "AA", "e": "!!"}}"#,
+        )
+        .expect("valid JSON");
+        assert!(wrapping_public_key(&request.pubkey).is_err());
+    }
+
+    #[test]
+    fn wrapping_key_strength() {
+        let wrapping_key = |bits: usize, e: &[u8]| PublicWrappingKey {
+            kty: "RSA".to_string(),
+            alg: "RSA-OAEP-256".to_string(),
+            n: Some(base64::encode(vec![0xc5; bits / 8])),
+            e: Some(base64::encode(e)),
+            ..Default::default()
+        };
+        let weak = |bits, e| {
+            matches!(
+                check_wrapping_key_strength(&wrapping_key(bits, e), 2048),
+                Err(Error::KeyStore(KeyStoreErrorKind::WeakWrappingKey(_)))
+            )
+        };
+
+        assert!(check_wrapping_key_strength(&wrapping_key(2048, &[1, 0, 1]), 2048).is_ok());
+        assert!(check_wrapping_key_strength(&wrapping_key(4096, &[1, 0, 1]), 2048).is_ok());
+        assert!(check_wrapping_key_strength(&wrapping_key(1024, &[1, 0, 1]), 1024).is_ok());
+
+        // Too short a modulus.
+        assert!(weak(1024, &[1, 0, 1]));
+        // Too small an exponent.
+        assert!(weak(2048, &[3]));
+        assert!(weak(2048, &[1]));
+        assert!(weak(2048, &[]));
+        assert!(weak(2048, &[1, 0, 0]));
+        // An even exponent, large enough.
+        assert!(weak(2048, &[1, 0, 2]));
+        // Leading zeros don't count.
+        assert!(weak(2048, &[0, 0, 3]));
+        assert!(check_wrapping_key_strength(&wrapping_key(2048, &[0, 1, 0, 1]), 2048).is_ok());
+    }
+
+    #[test]
+    fn elliptic_curve_wrapping_keys() {
+        // The EC public key of RFC 7517, appendix A.1, and the X25519 public key of Bob in
+        // RFC 8037, appendix A.6.
+        let ec_key = || PublicWrappingKey {
+            kty: "EC".to_string(),
+            alg: "ECDH-ES+A256KW".to_string(),
+            crv: Some("P-256".to_string()),
+            x: Some("MKBCTNIcKUSDii11ySs3526iDZ8AiTo7Tu6KPAqv7D4".to_string()),
+            y: Some("4Etl6SRW2YiLUrN5vfvVHuhp7x8PxltmWWlbbM4IFyM".to_string()),
+            ..Default::default()
+        };
+        let okp_key = || PublicWrappingKey {
+            kty: "OKP".to_string(),
+            alg: "ECDH-ES+A256KW".to_string(),
+            crv: Some("X25519".to_string()),
+            x: Some("3p7bfXt9wbTTW2HC7OQ1Nz-DQ8hbeGdNrfx-FG-IK08".to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            wrapping_public_key(&ec_key()),
+            Ok(WrappingPublicKey::P256(_))
+        ));
+        assert!(matches!(
+            wrapping_public_key(&okp_key()),
+            Ok(WrappingPublicKey::X25519(_))
+        ));
+        // They are not subject to the minimum size of the RSA keys.
+        assert!(check_wrapping_key_strength(&ec_key(), 4096).is_ok());
+        assert!(check_wrapping_key_strength(&okp_key(), 4096).is_ok());
+
+        let invalid = |wrapping_key: PublicWrappingKey| {
+            matches!(
+                check_wrapping_key_strength(&wrapping_key, 2048),
+                Err(Error::KeyStore(KeyStoreErrorKind::InvalidWrappingKey(_)))
+            )
+        };
+        // A point off the curve.
+        assert!(invalid(PublicWrappingKey {
+            y: ec_key().x,
+            ..ec_key()
+        }));
+        assert!(invalid(PublicWrappingKey {
+            y: None,
+            ..ec_key()
+        }));
+        assert!(invalid(PublicWrappingKey {
+            crv: Some("P-384".to_string()),
+            ..ec_key()
+        }));
+        assert!(invalid(PublicWrappingKey {
+            crv: Some("P-256".to_string()),
+            ..okp_key()
+        }));
+        assert!(invalid(PublicWrappingKey {
+            x: Some(base64::encode([0x2a; 31])),
+            ..okp_key()
+        }));
+        // Points of small order, such as 0 and 1.
+        for point in [[0; 32], {
+            let mut one = [0; 32];
+            one[0] = 1;
+            one
+        }] {
+            assert!(invalid(PublicWrappingKey {
+                x: Some(base64::encode(point)),
+                ..okp_key()
+            }));
+        }
+
+        // The other key types are still refused as such.
+        for kty in ["oct", "ec", ""] {
+            assert!(matches!(
+                check_wrapping_key_strength(
+                    &PublicWrappingKey {
+                        kty: kty.to_string(),
+                        ..okp_key()
+                    },
+                    2048
+                ),
+                Err(Error::KeyStore(
+                    KeyStoreErrorKind::UnsupportedWrappingKeyType
+                ))
+            ));
+        }
+    }
+}
@@ -0,0 +1,588 @@
+// Copyright 2024 Contributors to the Veraison project.
+// SPDX-License-Identifier: Apache-2.0
+
+//! This module reads the keys held by the key store from a JSON key file:
+//!
+//! ```json
+//! {
+//!   "keys": {
+//!     "disk-unlock": { "value": "May the force be with you.", "aliases": [ "skywalker" ] },
+//!     "database": {
+//!       "value": { "user": "admin", "password": "1234" },
+//!       "tags": [ "production" ],
+//!       "metadata": { "owner": "rebels" },
+//!       "allowed-wrapping-algs": [ "RSA-OAEP" ],
+//!       "release-rate": { "releases": 1, "window-secs": 60 }
+//!     }
+//!   }
+//! }
+//! ```
+//!
+//! A string value is released as its UTF-8 bytes. An object value is a structured secret
+//! document, released in its canonical serialisation: compact, with the members of every object
+//! sorted by name, so that the bytes released for a given document do not depend on how the key
+//! file is laid out.
+//!
+//! A key can be restricted to some of the wrapping algorithms supported by the server, with
+//! `allowed-wrapping-algs`, and it can be released at most `releases` times per `window-secs`
+//! seconds with `release-rate`.
+//!
+//! A key can also be restricted to the workloads whose realm initial measurement is one of its
+//! `realm-initial-measurements`, given as the digests, prefix rules and groups of the reference
+//! values. The appraisal policies find them as `data.key_constraints`, and the default policy then
+//! requires the measurement to match both the reference values and the constraints of the key.
+//!
+//! A key can also be requested under the identifiers given in `aliases`, such as its former
+//! identifiers. An alias can't be the identifier of another key, nor an alias of another key.
+//!
+//! The key file is either a local file, or a document fetched from an `https://` URL, optionally
+//! with a bearer token and a custom root certificate. Either way, its SHA-256 digest can be checked
+//! before it is trusted.
+use crate::error::{Error, KeyStoreErrorKind, Result};
+use crate::key_id::KeyIdPolicy;
+use crate::keystore::{canonical_wrapping_algorithm, KeyAttributes, KeyConstraints};
+use crate::reference_values::MeasurementEntry;
+use crate::release_rate::ReleaseRate;
+use reqwest::Url;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// The time allowed to fetch a key file from its URL.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Where the key file is read from.
+#[derive(Clone, Debug, PartialEq)]
+pub enum KeyFileSource {
+    /// A local file.
+    Path(PathBuf),
+
+    /// A document fetched from an `https://` URL.
+    Url(Url),
+}
+
+impl FromStr for KeyFileSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        // The keys are never fetched in the clear.
+        if s.starts_with("http://") {
+            Err(format!("the key file URL {s} is not https"))
+        } else if s.starts_with("https://") {
+            Url::parse(s)
+                .map(KeyFileSource::Url)
+                .map_err(|error| format!("invalid key file URL: {error}"))
+        } else {
+            Ok(KeyFileSource::Path(PathBuf::from(s)))
+        }
+    }
+}
+
+impl fmt::Display for KeyFileSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyFileSource::Path(path) => write!(f, "{}", path.display()),
+            KeyFileSource::Url(url) => write!(f, "{url}"),
+        }
+    }
+}
+
+/// How a key file is read.
+#[derive(Debug, Default)]
+pub struct KeyFileOptions<'a> {
+    /// The root certificate trusted on top of the built-in ones, for a key file fetched from a URL.
+    pub root_certificate: Option<&'a Path>,
+
+    /// The file holding the bearer token presented to fetch a key file from a URL.
+    pub token_file: Option<&'a Path>,
+
+    /// The SHA-256 digest expected of the key file, if it is checked.
+    pub digest: Option<[u8; 32]>,
+}
+
+/// Parse the SHA-256 digest expected of the key file, given in hex.
+pub fn parse_digest(s: &str) -> std::result::Result<[u8; 32], String> {
+    hex::decode(s)
+        .ok()
+        .and_then(|digest| digest.try_into().ok())
+        .ok_or_else(|| format!("expected the 64 hex digits of a SHA-256 digest, not '{s}'"))
+}
+
+/// The value of a key, as given in the key file.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum KeyValue {
+    Text(String),
+    Document(Map<String, Value>),
+}
+
+/// A key, as given in the key file.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct KeyEntry {
+    value: KeyValue,
+    #[serde(default)]
+    aliases: Vec<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    metadata: BTreeMap<String, String>,
+    #[serde(default)]
+    allowed_wrapping_algs: Option<Vec<String>>,
+    #[serde(default)]
+    release_rate: Option<ReleaseRate>,
+    #[serde(default)]
+    realm_initial_measurements: Option<Vec<MeasurementEntry>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KeyFile {
+    keys: BTreeMap<String, KeyEntry>,
+}
+
+/// A key read from the key file, ready to be put in the key store.
+#[derive(Debug, PartialEq)]
+pub struct LoadedKey {
+    pub key_id: String,
+    pub aliases: Vec<String>,
+    pub data: Vec<u8>,
+    pub attributes: KeyAttributes,
+}
+
+// Rebuild a JSON value with the members of its objects inserted in name order, which is the
+// order they get serialised in, whether or not serde_json preserves the insertion order.
+fn canonicalise(value: Value) -> Value {
+    match value {
+        Value::Object(members) => {
+            let sorted: BTreeMap<String, Value> = members.into_iter().collect();
+            Value::Object(
+                sorted
+                    .into_iter()
+                    .map(|(name, value)| (name, canonicalise(value)))
+                    .collect(),
+            )
+        }
+        Value::Array(items) => Value::Array(items.into_iter().map(canonicalise).collect()),
+        value => value,
+    }
+}
+
+/// Serialise a structured secret document canonically.
+pub fn canonical_document(document: Map<String, Value>) -> Result<Vec<u8>> {
+    Ok(serde_json::to_vec(&canonicalise(Value::Object(document)))?)
+}
+
+/// Parse a key file, normalising the key identifiers with the given policy.
+pub fn parse_key_file(document: &str, key_id_policy: &KeyIdPolicy) -> Result<Vec<LoadedKey>> {
+    let key_file: KeyFile = serde_json::from_str(document)
+        .map_err(|error| Error::KeyStore(KeyStoreErrorKind::InvalidKeyFile(error.to_string())))?;
+
+    let mut keys = Vec::with_capacity(key_file.keys.len());
+    for (key_id, mut entry) in key_file.keys {
+        let normalised = key_id_policy.normalise(&key_id)?;
+        if keys.iter().any(|key: &LoadedKey| key.key_id == normalised) {
+            return Err(Error::KeyStore(KeyStoreErrorKind::InvalidKeyFile(format!(
+                "key {key_id} is given more than once"
+            ))));
+        }
+
+        // The wrapping algorithms are held under their standard names, even if the key file uses
+        // legacy ones.
+        if let Some(algs) = &mut entry.allowed_wrapping_algs {
+            if algs.is_empty() {
+                return Err(Error::KeyStore(KeyStoreErrorKind::InvalidKeyFile(format!(
+                    "key {key_id} allows no wrapping algorithm"
+                ))));
+            }
+            for alg in algs.iter_mut() {
+                match canonical_wrapping_algorithm(alg) {
+                    Some(canonical) => *alg = canonical.to_string(),
+                    None => {
+                        return Err(Error::KeyStore(KeyStoreErrorKind::InvalidKeyFile(format!(
+                            "key {key_id} allows the unknown wrapping algorithm {alg}"
+                        ))))
+                    }
+                }
+            }
+        }
+
+        if let Some(rate) = &entry.release_rate {
+            if rate.releases == 0 || rate.window_secs == 0 {
+                return Err(Error::KeyStore(KeyStoreErrorKind::InvalidKeyFile(format!(
+                    "key {key_id} has a release rate without releases or without window"
+                ))));
+            }
+        }
+
+        let constraints = match entry.realm_initial_measurements {
+            Some(mut measurements) => {
+                if measurements.is_empty() {
+                    return Err(Error::KeyStore(KeyStoreErrorKind::InvalidKeyFile(format!(
+                        "key {key_id} allows no realm initial measurement"
+                    ))));
+                }
+                for measurement in &mut measurements {
+                    measurement.normalise(false).map_err(|error| {
+                        Error::KeyStore(KeyStoreErrorKind::InvalidKeyFile(format!(
+                            "key {key_id} has invalid realm initial measurements: {error}"
+                        )))
+                    })?;
+                }
+                Some(KeyConstraints {
+                    realm_initial_measurements: measurements,
+                })
+            }
+            None => None,
+        };
+
+        let mut aliases = Vec::with_capacity(entry.aliases.len());
+        for alias in &entry.aliases {
+            aliases.push(key_id_policy.normalise(alias)?);
+        }
+
+        let data = match entry.value {
+            KeyValue::Text(text) => text.into_bytes(),
+            KeyValue::Document(document) => canonical_document(document)?,
+        };
+
+        keys.push(LoadedKey {
+            key_id: normalised,
+            aliases,
+            data,
+            attributes: KeyAttributes {
+                tags: entry.tags,
+                metadata: entry.metadata,
+                allowed_wrapping_algs: entry.allowed_wrapping_algs,
+                release_rate: entry.release_rate,
+                constraints,
+            },
+        });
+    }
+
+    // The aliases are checked once all the key identifiers are known.
+    let mut taken: Vec<&str> = keys.iter().map(|key| key.key_id.as_str()).collect();
+    for key in &keys {
+        for alias in &key.aliases {
+            if taken.contains(&alias.as_str()) {
+                return Err(Error::KeyStore(KeyStoreErrorKind::InvalidKeyFile(format!(
+                    "the alias {alias} of key {} is already the identifier of a key or an alias",
+                    key.key_id
+                ))));
+            }
+            taken.push(alias);
+        }
+    }
+
+    Ok(keys)
+}
+
+/// Fetch a key file from its URL.
+fn fetch_key_file(url: &Url, options: &KeyFileOptions) -> Result<Vec<u8>> {
+    let mut client = reqwest::blocking::Client::builder().timeout(FETCH_TIMEOUT);
+    if let Some(root_certificate) = options.root_certificate {
+        let pem = std::fs::read(root_certificate)?;
+        client = client.add_root_certificate(reqwest::Certificate::from_pem(&pem)?);
+    }
+
+    let mut request = client.build()?.get(url.clone());
+    if let Some(token_file) = options.token_file {
+        request = request.bearer_auth(std::fs::read_to_string(token_file)?.trim());
+    }
+    Ok(request.send()?.error_for_status()?.bytes()?.to_vec())
+}
+
+/// Read a key file, from its URL or from the disk, and check its digest.
+fn read_key_file(source: &KeyFileSource, options: &KeyFileOptions) -> Result<String> {
+    let document = match source {
+        KeyFileSource::Path(path) => std::fs::read(path)?,
+        // The blocking HTTP client can't be used from within the async runtime that the server is
+        // built in, so the key file is fetched on a thread of its own.
+        KeyFileSource::Url(url) => std::thread::scope(|scope| {
+            scope
+                .spawn(|| fetch_key_file(url, options))
+                .join()
+                .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+        })?,
+    };
+
+    if let Some(expected) = &options.digest {
+        let actual = Sha256::digest(&document);
+        if actual[..] != expected[..] {
+            return Err(Error::KeyStore(KeyStoreErrorKind::KeyFileDigestMismatch(
+                hex::encode(expected),
+                hex::encode(actual),
+            )));
+        }
+    }
+
+    String::from_utf8(document).map_err(|_| {
+        Error::KeyStore(KeyStoreErrorKind::InvalidKeyFile(
+            "it is not UTF-8".to_string(),
+        ))
+    })
+}
+
+/// Read and parse a key file.
+pub fn load_key_file(
+    source: &KeyFileSource,
+    options: &KeyFileOptions,
+    key_id_policy: &KeyIdPolicy,
+) -> Result<Vec<LoadedKey>> {
+    parse_key_file(&read_key_file(source, options)?, key_id_policy)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POLICY: KeyIdPolicy = KeyIdPolicy { fold_case: false };
+
+    #[test]
+    fn text_and_document_values() {
+        let keys = parse_key_file(
+            r#"{
+                "keys": {
+                    "skywalker": { "value": "May the force be with you." },
+                    "database": {
+                        "value": { "user": "admin", "options": { "tls": true, "port": 5432 } },
+                        "tags": [ "production" ],
+                        "metadata": { "owner": "rebels" },
+                        "allowed-wrapping-algs": [ "RSA-OAEP" ],
+                        "release-rate": { "releases": 1, "window-secs": 60 }
+                    }
+                }
+            }"#,
+            &POLICY,
+        )
+        .expect("valid key file");
+
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys[0].key_id, "database");
+        assert_eq!(
+            keys[0].data,
+            br#"{"options":{"port":5432,"tls":true},"user":"admin"}"#
+        );
+        assert_eq!(keys[0].attributes.tags, vec!["production".to_string()]);
+        assert_eq!(keys[0].attributes.metadata["owner"], "rebels");
+        assert_eq!(
+            keys[0].attributes.allowed_wrapping_algs,
+            Some(vec!["RSA-OAEP".to_string()])
+        );
+        assert_eq!(
+            keys[0].attributes.release_rate,
+            Some(ReleaseRate {
+                releases: 1,
+                window_secs: 60
+            })
+        );
+        assert_eq!(keys[1].key_id, "skywalker");
+        assert_eq!(keys[1].data, b"May the force be with you.");
+        assert_eq!(keys[1].attributes, KeyAttributes::default());
+    }
+
+    #[test]
+    fn aliases() {
+        let keys = parse_key_file(
+            r#"{
+                "keys": {
+                    "disk-unlock": { "value": "a", "aliases": [ "Skywalker", "luke" ] },
+                    "database": { "value": "b" }
+                }
+            }"#,
+            &KeyIdPolicy { fold_case: true },
+        )
+        .expect("valid key file");
+        assert_eq!(keys[1].key_id, "disk-unlock");
+        assert_eq!(keys[1].aliases, vec!["skywalker", "luke"]);
+        assert!(keys[0].aliases.is_empty());
+
+        for document in [
+            r#"{ "keys": { "a": { "value": "a", "aliases": [ "b" ] }, "b": { "value": "b" } } }"#,
+            r#"{ "keys": { "a": { "value": "a", "aliases": [ "a" ] } } }"#,
+            r#"{ "keys": { "a": { "value": "a", "aliases": [ "c", "c" ] } } }"#,
+            r#"{ "keys": { "a": { "value": "a", "aliases": [ "c" ] }, "b": { "value": "b", "aliases": [ "c" ] } } }"#,
+        ] {
+            assert!(
+                matches!(
+                    parse_key_file(document, &POLICY),
+                    Err(Error::KeyStore(KeyStoreErrorKind::InvalidKeyFile(_)))
+                ),
+                "{document}"
+            );
+        }
+        assert!(matches!(
+            parse_key_file(
+                r#"{ "keys": { "a": { "value": "a", "aliases": [ "c/d" ] } } }"#,
+                &POLICY
+            ),
+            Err(Error::Input(_))
+        ));
+    }
+
+    #[test]
+    fn realm_initial_measurements() {
+        let keys = parse_key_file(
+            r#"{
+                "keys": {
+                    "skywalker": {
+                        "value": "a",
+                        "realm-initial-measurements": [
+                            "311314ab73620350cf758834ae5c65d9e8c2dc7febe6e7d9654bbe864e300d49",
+                            { "prefix": "q3N_r5ufZZUu" }
+                        ]
+                    },
+                    "vader": { "value": "b" }
+                }
+            }"#,
+            &POLICY,
+        )
+        .expect("valid key file");
+
+        // The measurements are normalised as the reference values are.
+        assert_eq!(
+            keys[0].attributes.constraints,
+            Some(KeyConstraints {
+                realm_initial_measurements: vec![
+                    MeasurementEntry::Digest(
+                        "MRMUq3NiA1DPdYg0rlxl2ejC3H/r5ufZZUu+hk4wDUk=".to_string()
+                    ),
+                    MeasurementEntry::Prefix(crate::reference_values::PrefixRule {
+                        prefix: "q3N/r5ufZZUu".to_string(),
+                        min_length: None,
+                    }),
+                ]
+            })
+        );
+        assert_eq!(keys[1].attributes.constraints, None);
+
+        for document in [
+            r#"{ "keys": { "a": { "value": "a", "realm-initial-measurements": [] } } }"#,
+            r#"{ "keys": { "a": { "value": "a", "realm-initial-measurements": [ "not a digest" ] } } }"#,
+            r#"{ "keys": { "a": { "value": "a", "realm-initial-measurements": "MRMUq3NiA1DPdYg0rlxl2ejC3H/r5ufZZUu+hk4wDUk=" } } }"#,
+        ] {
+            assert!(
+                matches!(
+                    parse_key_file(document, &POLICY),
+                    Err(Error::KeyStore(KeyStoreErrorKind::InvalidKeyFile(_)))
+                ),
+                "{document}"
+            );
+        }
+    }
+
+    #[test]
+    fn canonical_whatever_the_layout() {
+        let first: Map<String, Value> =
+            serde_json::from_str(r#"{ "b": [ { "y": 1, "x": 2 } ], "a": null }"#).unwrap();
+        let second: Map<String, Value> =
+            serde_json::from_str(r#"{"a":null,"b":[{"x":2,"y":1}]}"#).unwrap();
+
+        assert_eq!(
+            canonical_document(first).unwrap(),
+            canonical_document(second).unwrap()
+        );
+    }
+
+    #[test]
+    fn legacy_wrapping_algorithm_names() {
+        let keys = parse_key_file(
+            r#"{ "keys": { "skywalker": { "value": "a", "allowed-wrapping-algs": [ "OAEP", "RSA-OAEP" ] } } }"#,
+            &POLICY,
+        )
+        .expect("valid key file");
+
+        assert_eq!(
+            keys[0].attributes.allowed_wrapping_algs,
+            Some(vec!["RSA-OAEP-256".to_string(), "RSA-OAEP".to_string()])
+        );
+    }
+
+    #[test]
+    fn invalid_key_files() {
+        for document in [
+            "not json",
+            r#"{ "skywalker": "May the force be with you." }"#,
+            r#"{ "keys": { "skywalker": { "value": 42 } } }"#,
+            r#"{ "keys": { "skywalker": { "value": [ "a", "b" ] } } }"#,
+            r#"{ "keys": { "skywalker": { "value": "a", "colour": "blue" } } }"#,
+            r#"{ "keys": { "skywalker": { "value": "a", "allowed-wrapping-algs": [] } } }"#,
+            r#"{ "keys": { "skywalker": { "value": "a", "allowed-wrapping-algs": [ "A256KW" ] } } }"#,
+            r#"{ "keys": { "skywalker": { "value": "a", "release-rate": { "releases": 0, "window-secs": 60 } } } }"#,
+            r#"{ "keys": { "skywalker": { "value": "a", "release-rate": { "releases": 1, "window-secs": 0 } } } }"#,
+            r#"{ "keys": { "skywalker": { "value": "a", "release-rate": { "releases": 1 } } } }"#,
+        ] {
+            assert!(
+                matches!(
+                    parse_key_file(document, &POLICY),
+                    Err(Error::KeyStore(KeyStoreErrorKind::InvalidKeyFile(_)))
+                ),
+                "{document}"
+            );
+        }
+    }
+
+    #[test]
+    fn digests() {
+        let path = std::env::temp_dir().join(format!("keybroker-keys-{}.json", std::process::id()));
+        std::fs::write(&path, r#"{ "keys": { "skywalker": { "value": "a" } } }"#).unwrap();
+        let source = KeyFileSource::Path(path.clone());
+        let digest =
+            parse_digest(&hex::encode(Sha256::digest(std::fs::read(&path).unwrap()))).unwrap();
+
+        let options = KeyFileOptions {
+            digest: Some(digest),
+            ..Default::default()
+        };
+        assert_eq!(load_key_file(&source, &options, &POLICY).unwrap().len(), 1);
+
+        let options = KeyFileOptions {
+            digest: Some([0; 32]),
+            ..Default::default()
+        };
+        assert!(matches!(
+            load_key_file(&source, &options, &POLICY),
+            Err(Error::KeyStore(KeyStoreErrorKind::KeyFileDigestMismatch(expected, actual)))
+                if expected == "0".repeat(64) && actual == hex::encode(digest)
+        ));
+        std::fs::remove_file(path).unwrap();
+
+        assert!(parse_digest(&"A".repeat(64)).is_ok());
+        for invalid in ["", "00", &"g".repeat(64), &"0".repeat(66)] {
+            assert!(parse_digest(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn sources() {
+        assert_eq!(
+            "keys.json".parse(),
+            Ok(KeyFileSource::Path(PathBuf::from("keys.json")))
+        );
+        assert!(matches!(
+            "https://provisioning.example/keys.json".parse(),
+            Ok(KeyFileSource::Url(url)) if url.host_str() == Some("provisioning.example")
+        ));
+        assert!("https://".parse::<KeyFileSource>().is_err());
+        assert!("http://provisioning.example/keys.json"
+            .parse::<KeyFileSource>()
+            .is_err());
+    }
+
+    #[test]
+    fn duplicate_normalised_key_ids() {
+        let document =
+            r#"{ "keys": { "Skywalker": { "value": "a" }, "skywalker": { "value": "b" } } }"#;
+
+        assert!(parse_key_file(document, &POLICY).is_ok());
+        assert!(matches!(
+            parse_key_file(document, &KeyIdPolicy { fold_case: true }),
+            Err(Error::KeyStore(KeyStoreErrorKind::InvalidKeyFile(_)))
+        ));
+    }
+}
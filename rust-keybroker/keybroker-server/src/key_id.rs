@@ -0,0 +1,171 @@
+// Copyright 2024 Contributors to the Veraison project.
+// SPDX-License-Identifier: Apache-2.0
+
+//! This module defines what a valid key identifier is.
+//!
+//! Key identifiers come from a URL path segment, so they are attacker-controlled. Without rules, the same
+//! logical name could exist under several encodings, and arbitrarily long or exotic names would be accepted
+//! into the key store. The policy enforced here is:
+//!
+//! - the raw path segment is percent-decoded exactly once,
+//! - the decoded identifier must be between 1 and [`MAX_KEY_ID_LENGTH`] characters long,
+//! - it can only contain ASCII letters and digits, '-', '_' and '.', and can not be "." or "..",
+//! - optionally, it is folded to lower case, so that "Skywalker" and "skywalker" are the same key.
+//!
+//! As all the allowed characters are unreserved in URLs, an accepted identifier appears unchanged in the
+//! URLs built by the clients.
+use crate::error::{Error, InputErrorKind, Result};
+use percent_encoding::percent_decode_str;
+
+/// The maximum length of a key identifier.
+pub const MAX_KEY_ID_LENGTH: usize = 128;
+
+/// The rules applied to the key identifiers, whether they come from a client or from the key store
+/// configuration.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct KeyIdPolicy {
+    /// Fold the key identifiers to lower case.
+    pub fold_case: bool,
+}
+
+fn invalid(reason: String) -> Error {
+    Error::Input(InputErrorKind::InvalidKeyId(reason))
+}
+
+impl KeyIdPolicy {
+    /// Validate and normalise a key identifier, as found in a raw (not yet percent-decoded) URL
+    /// path segment.
+    pub fn parse_path_segment(&self, segment: &str) -> Result<String> {
+        let key_id = percent_decode_str(segment)
+            .decode_utf8()
+            .map_err(|_| invalid("it is not valid UTF-8 once percent-decoded".to_string()))?;
+
+        self.normalise(&key_id)
+    }
+
+    /// Validate and normalise a key identifier.
+    pub fn normalise(&self, key_id: &str) -> Result<String> {
+        if key_id.is_empty() {
+            return Err(invalid("it is empty".to_string()));
+        }
+
+        if key_id.len() > MAX_KEY_ID_LENGTH {
+            return Err(invalid(format!(
+                "it is {} bytes long, the maximum is {MAX_KEY_ID_LENGTH}",
+                key_id.len()
+            )));
+        }
+
+        if let Some(c) = key_id
+            .chars()
+            .find(|c| !(c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.')))
+        {
+            return Err(invalid(format!(
+                "it contains the character {c:?}, only ASCII letters, digits, '-', '_' and '.' are allowed"
+            )));
+        }
+
+        if key_id == "." || key_id == ".." {
+            return Err(invalid(format!("'{key_id}' is a reserved path segment")));
+        }
+
+        Ok(if self.fold_case {
+            key_id.to_ascii_lowercase()
+        } else {
+            key_id.to_string()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    const POLICY: KeyIdPolicy = KeyIdPolicy { fold_case: false };
+
+    #[test]
+    fn default_key_is_valid() {
+        assert_eq!(POLICY.normalise("skywalker").unwrap(), "skywalker");
+        assert_eq!(POLICY.parse_path_segment("skywalker").unwrap(), "skywalker");
+    }
+
+    #[test]
+    fn invalid_key_ids() {
+        for segment in [
+            "",
+            ".",
+            "..",
+            "a%2Fb",
+            "a/b",
+            "%2E%2E",
+            "sky%20walker",
+            "sky%2541",
+            "%ff",
+            "skywalk\u{e9}r",
+            &"a".repeat(MAX_KEY_ID_LENGTH + 1),
+        ] {
+            assert!(
+                matches!(
+                    POLICY.parse_path_segment(segment),
+                    Err(Error::Input(InputErrorKind::InvalidKeyId(_)))
+                ),
+                "{segment:?} should be rejected"
+            );
+        }
+    }
+
+    #[test]
+    fn percent_decoding_is_applied_once() {
+        assert_eq!(
+            POLICY.parse_path_segment("sky%77alker").unwrap(),
+            "skywalker"
+        );
+    }
+
+    #[test]
+    fn case_folding() {
+        let policy = KeyIdPolicy { fold_case: true };
+        assert_eq!(policy.normalise("SkyWalker").unwrap(), "skywalker");
+        assert_eq!(POLICY.normalise("SkyWalker").unwrap(), "SkyWalker");
+    }
+
+    // Property: any accepted key identifier round-trips unchanged through the URL built by a client.
+    #[test]
+    fn accepted_key_ids_round_trip_through_urls() {
+        let mut rng = StdRng::seed_from_u64(0x6b6579);
+        let allowed: Vec<char> = ('a'..='z')
+            .chain('A'..='Z')
+            .chain('0'..='9')
+            .chain(['-', '_', '.'])
+            .collect();
+        let mut accepted = 0;
+
+        for _ in 0..10000 {
+            // Mostly allowed characters, with the occasional arbitrary one.
+            let len = rng.gen_range(0..=MAX_KEY_ID_LENGTH + 8);
+            let candidate: String = (0..len)
+                .map(|_| {
+                    if rng.gen_ratio(199, 200) {
+                        allowed[rng.gen_range(0..allowed.len())]
+                    } else {
+                        rng.gen::<char>()
+                    }
+                })
+                .collect();
+
+            let Ok(key_id) = POLICY.normalise(&candidate) else {
+                continue;
+            };
+            accepted += 1;
+
+            let url = reqwest::Url::parse(&format!("http://127.0.0.1:8088/keys/v1/key/{key_id}"))
+                .unwrap();
+            let segment = url.path_segments().unwrap().next_back().unwrap();
+            assert_eq!(segment, key_id);
+            assert_eq!(POLICY.parse_path_segment(segment).unwrap(), key_id);
+        }
+
+        assert!(accepted > 1000);
+    }
+}
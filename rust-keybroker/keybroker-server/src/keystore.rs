@@ -1,18 +1,328 @@
 // Copyright 2024 Contributors to the Veraison project.
 // SPDX-License-Identifier: Apache-2.0
 
-use base64::engine::general_purpose::URL_SAFE_NO_PAD;
-use base64::prelude::*;
-use keybroker_common::{PublicWrappingKey, WrappedKeyData};
-use rsa::{BigUint, Oaep, Pkcs1v15Encrypt, RsaPublicKey};
+use aes_gcm::aead::generic_array::GenericArray;
+use aes_kw::KekAes256;
+use keybroker_common::{base64, EphemeralPublicKey, PublicWrappingKey, WrappedKeyData};
+use p256::elliptic_curve::sec1::ToEncodedPoint;
+use rsa::rand_core::CryptoRngCore;
+use rsa::traits::PublicKeyParts;
+use rsa::{Oaep, Pkcs1v15Encrypt, RsaPublicKey};
+use sha1::Sha1;
 use sha2::Sha256;
+use zeroize::Zeroizing;
 
 use crate::error::Result;
-use std::collections::HashMap;
+use crate::input::{check_wrapping_key_strength, wrapping_public_key, WrappingPublicKey};
+use crate::reference_values::MeasurementEntry;
+use crate::release_rate::ReleaseRate;
+use hkdf::Hkdf;
+use keybroker_common::jwk::{
+    self, ecdh_es_key_wrapping_key, CEK_SIZE, IV_SIZE, KEY_WRAPPING_KEY_SIZE,
+};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
 
-const RSA_KEY_TYPE: &str = "RSA";
-const RSA_PKCS15_ALGORITHM: &str = "RSA1_5";
-const RSA_OAEP_ALGORITHM: &str = "RSA-OAEP";
+// The wrapping key types and algorithms supported by the key store are those of the JWKs.
+pub(crate) use keybroker_common::jwk::{
+    algorithm_fits_key_type, canonical_wrapping_algorithm, A256GCM_ENCRYPTION,
+    CURVE_COORDINATE_SIZE, EC_KEY_TYPE, OKP_KEY_TYPE, P256_CURVE, RSA_KEY_TYPE,
+    RSA_OAEP_256_ALGORITHM, RSA_OAEP_ALGORITHM, RSA_PKCS15_ALGORITHM, WRAPPING_ALGORITHMS,
+    X25519_CURVE,
+};
+
+/// The attributes of a key, which the appraisal policies can use to decide whether the key can be released.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct KeyAttributes {
+    /// Free-form tags, such as "production".
+    pub tags: Vec<String>,
+
+    /// Free-form metadata, as name-value pairs.
+    pub metadata: BTreeMap<String, String>,
+
+    /// The wrapping algorithms the key can be released under, if it is restricted to some of them.
+    /// This is not given to the appraisal policies.
+    #[serde(skip)]
+    pub allowed_wrapping_algs: Option<Vec<String>>,
+
+    /// How often the key can be released, if it is limited. This is not given to the appraisal
+    /// policies.
+    #[serde(skip)]
+    pub release_rate: Option<ReleaseRate>,
+
+    /// The workloads the key can be released to, if it is restricted to some of them. This is
+    /// given to the appraisal policies as `data.key_constraints`, rather than with the key.
+    #[serde(skip)]
+    pub constraints: Option<KeyConstraints>,
+}
+
+/// The constraints a key puts on the workloads it is released to, on top of the reference values.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct KeyConstraints {
+    /// The realm initial measurements of the workloads, as digests, prefix rules and groups, in the
+    /// same form as the reference values.
+    pub realm_initial_measurements: Vec<MeasurementEntry>,
+}
+
+/// A key of the store, as listed by the admin API.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct KeyListing {
+    /// The canonical identifier of the key.
+    pub id: String,
+
+    /// The other identifiers the key can be requested under.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub aliases: Vec<String>,
+}
+
+/// A key held in the store, along with its attributes.
+struct StoredKey {
+    data: Vec<u8>,
+    attributes: KeyAttributes,
+}
+
+/// The maximum length, in bytes, of a value derived from the master secret, which is the maximum
+/// output length of HKDF-SHA256.
+pub(crate) const MAX_DERIVED_KEY_LENGTH: usize = 255 * 32;
+
+/// Parse the length, in bytes, of a value derived from the master secret.
+pub(crate) fn parse_derived_key_length(length: &str) -> std::result::Result<usize, String> {
+    match length.parse::<usize>() {
+        Ok(length) if (1..=MAX_DERIVED_KEY_LENGTH).contains(&length) => Ok(length),
+        _ => Err(format!(
+            "the length must be a number of bytes between 1 and {MAX_DERIVED_KEY_LENGTH}"
+        )),
+    }
+}
+
+/// The default minimum size of the wrapping keys, in bits.
+pub const DEFAULT_MIN_WRAPPING_KEY_BITS: usize = 2048;
+
+/// The smallest minimum size of the wrapping keys which can be configured, in bits.
+const MIN_MIN_WRAPPING_KEY_BITS: usize = 1024;
+
+/// Parse the minimum size of the wrapping keys given on the command line.
+pub(crate) fn parse_min_wrapping_key_bits(bits: &str) -> std::result::Result<usize, String> {
+    match bits.parse::<usize>() {
+        Ok(bits) if (MIN_MIN_WRAPPING_KEY_BITS..=RsaPublicKey::MAX_SIZE).contains(&bits) => {
+            Ok(bits)
+        }
+        _ => Err(format!(
+            "the size must be a number of bits between {MIN_MIN_WRAPPING_KEY_BITS} and {}",
+            RsaPublicKey::MAX_SIZE
+        )),
+    }
+}
+
+/// The RSA encryption schemes of the wrapping algorithms.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RsaPadding {
+    Pkcs1v15,
+    OaepSha1,
+    OaepSha256,
+}
+
+impl RsaPadding {
+    /// The encryption scheme of a wrapping algorithm, given under its standard or legacy name.
+    fn of(alg: &str) -> Option<RsaPadding> {
+        match canonical_wrapping_algorithm(alg)? {
+            RSA_PKCS15_ALGORITHM => Some(RsaPadding::Pkcs1v15),
+            RSA_OAEP_ALGORITHM => Some(RsaPadding::OaepSha1),
+            RSA_OAEP_256_ALGORITHM => Some(RsaPadding::OaepSha256),
+            _ => None,
+        }
+    }
+
+    /// The size of the largest data which can be encrypted directly with a key of `key_size`
+    /// bytes.
+    fn capacity(self, key_size: usize) -> usize {
+        let overhead = match self {
+            RsaPadding::Pkcs1v15 => 11,
+            RsaPadding::OaepSha1 => 2 * 20 + 2,
+            RsaPadding::OaepSha256 => 2 * 32 + 2,
+        };
+        key_size.saturating_sub(overhead)
+    }
+
+    fn encrypt<R: CryptoRngCore>(
+        self,
+        rng: &mut R,
+        key: &RsaPublicKey,
+        data: &[u8],
+    ) -> rsa::Result<Vec<u8>> {
+        match self {
+            RsaPadding::Pkcs1v15 => key.encrypt(rng, Pkcs1v15Encrypt, data),
+            RsaPadding::OaepSha1 => key.encrypt(rng, Oaep::new::<Sha1>(), data),
+            RsaPadding::OaepSha256 => key.encrypt(rng, Oaep::new::<Sha256>(), data),
+        }
+    }
+}
+
+/// Encrypt a key with a random AES-256-GCM content encryption key, bound to the `key-id` of the
+/// wrapped key data, filling the content encryption members of the wrapped key data but the
+/// wrapped content encryption key, which is returned.
+fn encrypt_content<R: CryptoRngCore>(
+    rng: &mut R,
+    data: &[u8],
+    wrapped_data: &mut WrappedKeyData,
+) -> Result<Zeroizing<[u8; CEK_SIZE]>> {
+    let mut cek = Zeroizing::new([0; CEK_SIZE]);
+    rng.fill_bytes(cek.as_mut());
+    let mut iv = [0; IV_SIZE];
+    rng.fill_bytes(&mut iv);
+
+    let key_id = wrapped_data.key_id.as_deref().unwrap_or_default();
+    let ciphertext = jwk::encrypt_content(&cek, &iv, key_id, data).map_err(|_| {
+        crate::error::Error::KeyStore(crate::error::KeyStoreErrorKind::ContentEncryptionFailure)
+    })?;
+    wrapped_data.data = base64::encode(ciphertext);
+    wrapped_data.iv = Some(base64::encode(iv));
+    wrapped_data.enc = Some(A256GCM_ENCRYPTION.to_string());
+    Ok(cek)
+}
+
+/// Fill the key agreement members of the wrapped key data.
+fn fill_key_agreement(
+    wrapped_data: &mut WrappedKeyData,
+    epk: EphemeralPublicKey,
+    apu: &[u8],
+    apv: &[u8],
+) {
+    wrapped_data.epk = Some(epk);
+    wrapped_data.apu = Some(base64::encode(apu));
+    wrapped_data.apv = Some(base64::encode(apv));
+}
+
+/// Agree on an ECDH-ES+A256KW key wrapping key with an elliptic-curve wrapping key, through an
+/// ephemeral key-pair on the same curve, filling the public key of the ephemeral key-pair and the
+/// `apu` and `apv` the key wrapping key is derived with in the wrapped key data.
+fn ecdh_key_wrapping_key<R: CryptoRngCore>(
+    rng: &mut R,
+    wrapping_key: &WrappingPublicKey,
+    wrapped_data: &mut WrappedKeyData,
+) -> Result<Zeroizing<[u8; KEY_WRAPPING_KEY_SIZE]>> {
+    match wrapping_key {
+        WrappingPublicKey::P256(public_key) => {
+            let secret = p256::ecdh::EphemeralSecret::random(rng);
+            let point = secret.public_key().to_encoded_point(false);
+            let coordinate = |coordinate: Option<&p256::FieldBytes>| {
+                base64::encode(coordinate.expect("An uncompressed point has both coordinates."))
+            };
+            let epk = EphemeralPublicKey {
+                kty: EC_KEY_TYPE.to_string(),
+                crv: P256_CURVE.to_string(),
+                x: coordinate(point.x()),
+                y: Some(coordinate(point.y())),
+            };
+            // The x and y coordinates, without the SEC1 tag of the uncompressed points.
+            let apu = point.as_bytes()[1..].to_vec();
+            let apv = public_key.to_encoded_point(false).as_bytes()[1..].to_vec();
+            let shared_secret = secret.diffie_hellman(public_key);
+            let kek = ecdh_es_key_wrapping_key(shared_secret.raw_secret_bytes(), &apu, &apv);
+            fill_key_agreement(wrapped_data, epk, &apu, &apv);
+            Ok(kek)
+        }
+        WrappingPublicKey::X25519(public_key) => {
+            let secret = x25519_dalek::EphemeralSecret::random_from_rng(&mut *rng);
+            let ephemeral_public_key = x25519_dalek::PublicKey::from(&secret);
+            let epk = EphemeralPublicKey {
+                kty: OKP_KEY_TYPE.to_string(),
+                crv: X25519_CURVE.to_string(),
+                x: base64::encode(ephemeral_public_key.as_bytes()),
+                y: None,
+            };
+            let apu = ephemeral_public_key.as_bytes().to_vec();
+            let apv = public_key.as_bytes().to_vec();
+            let shared_secret = secret.diffie_hellman(public_key);
+            let kek = ecdh_es_key_wrapping_key(shared_secret.as_bytes(), &apu, &apv);
+            fill_key_agreement(wrapped_data, epk, &apu, &apv);
+            Ok(kek)
+        }
+        WrappingPublicKey::Rsa(_) => Err(crate::error::Error::KeyStore(
+            crate::error::KeyStoreErrorKind::UnsupportedWrappingKeyType,
+        )),
+    }
+}
+
+/// A key whose value is derived from the master secret, as specified on the command line with
+/// '<key-id>[:<length>]'.
+#[derive(Clone, Debug)]
+pub struct DerivedKey {
+    pub key_id: String,
+
+    /// The length of the derived value, in bytes, if it is not the default one.
+    pub length: Option<usize>,
+}
+
+impl FromStr for DerivedKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        // Key identifiers can't contain ':', so the last one separates the length.
+        match s.rsplit_once(':') {
+            Some((key_id, length)) => Ok(DerivedKey {
+                key_id: key_id.to_string(),
+                length: Some(parse_derived_key_length(length)?),
+            }),
+            None => Ok(DerivedKey {
+                key_id: s.to_string(),
+                length: None,
+            }),
+        }
+    }
+}
+
+/// Derives the value of the keys from a master secret, as HKDF-SHA256(master secret, info = key id),
+/// rather than storing them.
+///
+/// The same master secret always yields the same values, so the keys are the same across restarts.
+pub struct KeyDerivation {
+    master_secret: Zeroizing<Vec<u8>>,
+
+    /// The declared key identifiers, with the length of their value.
+    key_lengths: HashMap<String, usize>,
+
+    /// The length of the value of the keys that were not declared. They can only be derived when it
+    /// is set.
+    any_key_length: Option<usize>,
+}
+
+impl KeyDerivation {
+    /// Derive the keys from `master_secret`. No key can be derived until some are declared.
+    pub fn new(master_secret: Zeroizing<Vec<u8>>) -> KeyDerivation {
+        KeyDerivation {
+            master_secret,
+            key_lengths: HashMap::new(),
+            any_key_length: None,
+        }
+    }
+
+    /// Declare a key, whose value is `length` bytes long.
+    pub fn declare_key(&mut self, key_id: &str, length: usize) {
+        self.key_lengths.insert(key_id.to_owned(), length);
+    }
+
+    /// Derive a value of `length` bytes for any key that was not declared.
+    pub fn derive_any_key(&mut self, length: usize) {
+        self.any_key_length = Some(length);
+    }
+
+    fn derive(&self, key_id: &str) -> Option<Zeroizing<Vec<u8>>> {
+        let length = self
+            .key_lengths
+            .get(key_id)
+            .copied()
+            .or(self.any_key_length)?;
+
+        let mut data = Zeroizing::new(vec![0; length]);
+        Hkdf::<Sha256>::new(None, &self.master_secret)
+            .expand(key_id.as_bytes(), &mut data)
+            .ok()?;
+        Some(data)
+    }
+}
 
 /// A minimally simple key-value store where the lookup keys are strings and the values
 /// are byte arrays (octet vectors).
@@ -21,16 +331,30 @@ const RSA_OAEP_ALGORITHM: &str = "RSA-OAEP";
 /// structure is called a "key store", so take care not to confuse the lookup keys
 /// (which are strings) with the values, since the term "key" can be ambiguous.
 ///
-/// The byte arrays do not necessarily need to be encryption keys. They could be other small
-/// secret data blobs. However, whatever they are, they should be small, because they
-/// are treated using asymmetric encryption methods. Do not store very large data
-/// blobs in this store. It is only intended to demonstrate the retrieval of secrets
-/// in confidential computing contexts.
+/// The byte arrays do not necessarily need to be encryption keys. They could be other
+/// secret data blobs. Those too large to be encrypted with the wrapping key directly are
+/// encrypted with a random AES-256-GCM key, which is then the one encrypted with the
+/// wrapping key. Do not store very large data blobs in this store anyway. It is only
+/// intended to demonstrate the retrieval of secrets in confidential computing contexts.
 ///
 /// Data is never revealed in plaintext - only the `wrap()` function is used, which
 /// encrypts data with a given public key.
+///
+/// Alternatively, the values can be derived from a master secret rather than stored, see
+/// [`KeyDerivation`].
+///
+/// A stored key can also be requested under aliases, so that the clients written against an older
+/// identifier keep working. Everywhere else, such as in the policy input, the key is known by its
+/// canonical identifier.
 pub struct KeyStore {
-    keys: HashMap<String, Vec<u8>>,
+    keys: HashMap<String, StoredKey>,
+
+    /// The canonical identifier of each alias.
+    aliases: HashMap<String, String>,
+    derivation: Option<KeyDerivation>,
+    wrapping_algorithms: Vec<&'static str>,
+    min_wrapping_key_bits: usize,
+    read_only: bool,
 }
 
 impl KeyStore {
@@ -38,6 +362,24 @@ impl KeyStore {
     pub fn new() -> KeyStore {
         KeyStore {
             keys: HashMap::new(),
+            aliases: HashMap::new(),
+            derivation: None,
+            wrapping_algorithms: WRAPPING_ALGORITHMS.to_vec(),
+            min_wrapping_key_bits: DEFAULT_MIN_WRAPPING_KEY_BITS,
+            read_only: false,
+        }
+    }
+
+    /// Create a key store which derives the value of the keys from a master secret, instead of
+    /// looking up stored values.
+    pub fn with_derivation(derivation: KeyDerivation) -> KeyStore {
+        KeyStore {
+            keys: HashMap::new(),
+            aliases: HashMap::new(),
+            derivation: Some(derivation),
+            wrapping_algorithms: WRAPPING_ALGORITHMS.to_vec(),
+            min_wrapping_key_bits: DEFAULT_MIN_WRAPPING_KEY_BITS,
+            read_only: false,
         }
     }
 
@@ -47,47 +389,356 @@ impl KeyStore {
     /// function that is only used by the internals of the key broker to build the contents
     /// of the store from trusted internal sources, such as command-line arguments or a local
     /// configuration file.
-    pub fn store_key(&mut self, key_id: &str, data: Vec<u8>) {
-        self.keys.insert(key_id.to_owned(), data.clone());
+    ///
+    /// Fails once the store is read-only.
+    pub fn store_key(&mut self, key_id: &str, data: Vec<u8>) -> Result<()> {
+        self.store_key_with_attributes(key_id, data, KeyAttributes::default())
+    }
+
+    /// Store a new key in the key store, along with its attributes.
+    ///
+    /// Fails once the store is read-only, or if the identifier is an alias.
+    pub fn store_key_with_attributes(
+        &mut self,
+        key_id: &str,
+        data: Vec<u8>,
+        attributes: KeyAttributes,
+    ) -> Result<()> {
+        if self.read_only {
+            return Err(crate::error::Error::KeyStore(
+                crate::error::KeyStoreErrorKind::ReadOnly,
+            ));
+        }
+        if self.aliases.contains_key(key_id) {
+            return Err(crate::error::Error::KeyStore(
+                crate::error::KeyStoreErrorKind::KeyIdTaken(key_id.to_string()),
+            ));
+        }
+
+        self.keys
+            .insert(key_id.to_owned(), StoredKey { data, attributes });
+        Ok(())
+    }
+
+    /// Let a stored key be requested under another identifier, which must not be that of a key or
+    /// of another alias.
+    ///
+    /// Fails once the store is read-only.
+    pub fn add_alias(&mut self, alias: &str, key_id: &str) -> Result<()> {
+        if self.read_only {
+            return Err(crate::error::Error::KeyStore(
+                crate::error::KeyStoreErrorKind::ReadOnly,
+            ));
+        }
+        if !self.keys.contains_key(key_id) {
+            return Err(crate::error::Error::KeyStore(
+                crate::error::KeyStoreErrorKind::KeyNotFound,
+            ));
+        }
+        if self.keys.contains_key(alias) || self.aliases.contains_key(alias) {
+            return Err(crate::error::Error::KeyStore(
+                crate::error::KeyStoreErrorKind::KeyIdTaken(alias.to_string()),
+            ));
+        }
+
+        self.aliases.insert(alias.to_owned(), key_id.to_owned());
+        Ok(())
+    }
+
+    /// Store a key given through the admin API. A new key is stored without attributes, while an
+    /// existing key only has its value replaced, keeping its attributes and its aliases. Tells
+    /// whether a key was replaced.
+    ///
+    /// Fails once the store is read-only, when the keys are derived, or if the identifier is an
+    /// alias.
+    pub fn put_key(&mut self, key_id: &str, data: Vec<u8>) -> Result<bool> {
+        self.check_changeable()?;
+        if let Some(key) = self.keys.get_mut(key_id) {
+            key.data = data;
+            return Ok(true);
+        }
+
+        self.store_key(key_id, data)?;
+        Ok(false)
+    }
+
+    /// Remove a key along with its aliases, or only an alias if the identifier is one.
+    ///
+    /// Fails once the store is read-only, or when the keys are derived.
+    pub fn delete_key(&mut self, key_id: &str) -> Result<()> {
+        self.check_changeable()?;
+        if self.aliases.remove(key_id).is_some() {
+            return Ok(());
+        }
+        if self.keys.remove(key_id).is_none() {
+            return Err(crate::error::Error::KeyStore(
+                crate::error::KeyStoreErrorKind::KeyNotFound,
+            ));
+        }
+
+        self.aliases.retain(|_, canonical| canonical != key_id);
+        Ok(())
+    }
+
+    /// Fails if the keys can't be changed, as the store is read-only, or the keys are derived
+    /// rather than stored.
+    fn check_changeable(&self) -> Result<()> {
+        if self.read_only {
+            return Err(crate::error::Error::KeyStore(
+                crate::error::KeyStoreErrorKind::ReadOnly,
+            ));
+        }
+        if self.derivation.is_some() {
+            return Err(crate::error::Error::KeyStore(
+                crate::error::KeyStoreErrorKind::DerivedKeys,
+            ));
+        }
+        Ok(())
+    }
+
+    /// The canonical identifier of a key, which is the identifier itself unless it is an alias.
+    pub fn canonical_key_id<'a>(&'a self, key_id: &'a str) -> &'a str {
+        self.aliases.get(key_id).map_or(key_id, String::as_str)
+    }
+
+    /// Whether a key is in the store, under its identifier or one of its aliases. The derived keys
+    /// are not in the store.
+    pub fn contains_key(&self, key_id: &str) -> bool {
+        self.keys.contains_key(self.canonical_key_id(key_id))
+    }
+
+    /// The keys of the store, with their aliases, by identifier. When the keys are derived, these
+    /// are the declared keys.
+    pub fn list_keys(&self) -> Vec<KeyListing> {
+        let mut listing: Vec<KeyListing> = match &self.derivation {
+            Some(derivation) => derivation
+                .key_lengths
+                .keys()
+                .map(|key_id| KeyListing {
+                    id: key_id.clone(),
+                    aliases: Vec::new(),
+                })
+                .collect(),
+            None => self
+                .keys
+                .keys()
+                .map(|key_id| {
+                    let mut aliases: Vec<String> = self
+                        .aliases
+                        .iter()
+                        .filter(|(_, canonical)| *canonical == key_id)
+                        .map(|(alias, _)| alias.clone())
+                        .collect();
+                    aliases.sort();
+                    KeyListing {
+                        id: key_id.clone(),
+                        aliases,
+                    }
+                })
+                .collect(),
+        };
+        listing.sort_by(|a, b| a.id.cmp(&b.id));
+
+        listing
+    }
+
+    /// Make the store read-only: from then on, the keys can no longer be changed. This is meant
+    /// for the stores whose keys are all provisioned at startup, from a reviewed source.
+    pub fn make_read_only(&mut self) {
+        self.read_only = true;
+    }
+
+    /// Get the attributes of a key, if it is in the store.
+    pub fn key_attributes(&self, key_id: &str) -> Option<&KeyAttributes> {
+        self.keys
+            .get(self.canonical_key_id(key_id))
+            .map(|key| &key.attributes)
+    }
+
+    /// The number of keys in the store, or of declared keys when they are derived.
+    pub fn key_count(&self) -> usize {
+        match &self.derivation {
+            Some(derivation) => derivation.key_lengths.len(),
+            None => self.keys.len(),
+        }
+    }
+
+    /// The keys whose releases are limited, with their release rate.
+    pub fn release_rates(&self) -> impl Iterator<Item = (&str, &ReleaseRate)> {
+        self.keys.iter().filter_map(|(key_id, key)| {
+            key.attributes
+                .release_rate
+                .as_ref()
+                .map(|rate| (key_id.as_str(), rate))
+        })
+    }
+
+    /// Stop releasing any key under the RSA1_5 wrapping algorithm, whatever the key allows.
+    pub fn forbid_rsa1_5(&mut self) {
+        self.wrapping_algorithms
+            .retain(|alg| *alg != RSA_PKCS15_ALGORITHM);
+    }
+
+    /// Set the minimum size of the modulus of the wrapping keys, in bits.
+    pub fn set_min_wrapping_key_bits(&mut self, bits: usize) {
+        self.min_wrapping_key_bits = bits;
+    }
+
+    /// Check that a wrapping key is strong enough for the keys to be wrapped to it, and that its
+    /// wrapping algorithm, if it is a supported one, is one for its key type.
+    pub fn check_wrapping_key(&self, wrapping_key: &PublicWrappingKey) -> Result<()> {
+        check_wrapping_key_strength(wrapping_key, self.min_wrapping_key_bits)?;
+
+        if canonical_wrapping_algorithm(&wrapping_key.alg).is_some()
+            && !algorithm_fits_key_type(&wrapping_key.alg, &wrapping_key.kty)
+        {
+            let fitting: Vec<_> = self
+                .wrapping_algorithms
+                .iter()
+                .filter(|alg| algorithm_fits_key_type(alg, &wrapping_key.kty))
+                .copied()
+                .collect();
+            return Err(crate::error::Error::KeyStore(
+                crate::error::KeyStoreErrorKind::UnsupportedWrappingKeyAlgorithm(
+                    wrapping_key.alg.clone(),
+                    fitting.join(", "),
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    /// The wrapping algorithms the keys can be released under, unless a key is further restricted.
+    pub fn wrapping_algorithms(&self) -> &[&'static str] {
+        &self.wrapping_algorithms
+    }
+
+    /// The wrapping algorithms a key can be released under: those permitted both by the store
+    /// and by the key itself.
+    pub fn permitted_wrapping_algorithms(&self, key_id: &str) -> Vec<&'static str> {
+        let allowed = self
+            .key_attributes(key_id)
+            .and_then(|attributes| attributes.allowed_wrapping_algs.as_ref());
+
+        self.wrapping_algorithms
+            .iter()
+            .filter(|alg| allowed.is_none_or(|allowed| allowed.iter().any(|a| a == *alg)))
+            .copied()
+            .collect()
+    }
+
+    /// Check that a key can be released under the given wrapping algorithm, which can be given
+    /// under a legacy name.
+    pub fn check_wrapping_algorithm(&self, key_id: &str, alg: &str) -> Result<()> {
+        let Some(canonical) = canonical_wrapping_algorithm(alg) else {
+            return Err(crate::error::Error::KeyStore(
+                crate::error::KeyStoreErrorKind::UnknownWrappingKeyAlgorithm(
+                    alg.to_string(),
+                    self.wrapping_algorithms.join(", "),
+                ),
+            ));
+        };
+        let permitted = self.permitted_wrapping_algorithms(key_id);
+
+        if permitted.contains(&canonical) {
+            Ok(())
+        } else {
+            Err(crate::error::Error::KeyStore(
+                crate::error::KeyStoreErrorKind::UnsupportedWrappingKeyAlgorithm(
+                    alg.to_string(),
+                    permitted.join(", "),
+                ),
+            ))
+        }
+    }
+
+    /// Get the value of a key, either derived or stored, in a buffer wiped once dropped.
+    fn key_data(&self, key_id: &str) -> Option<Zeroizing<Vec<u8>>> {
+        match &self.derivation {
+            Some(derivation) => derivation.derive(key_id),
+            None => self
+                .keys
+                .get(self.canonical_key_id(key_id))
+                .map(|key| Zeroizing::new(key.data.clone())),
+        }
     }
 
     /// Obtain a wrapped (encrypted) data item from the store.
+    ///
+    /// With an RSA wrapping key, the data is encrypted with the wrapping key directly if it is
+    /// small enough, and with a random AES-256-GCM content encryption key otherwise, which is then
+    /// wrapped instead. With an elliptic-curve wrapping key, the data is always encrypted with a
+    /// content encryption key, which is wrapped with ECDH-ES+A256KW.
     pub fn wrap_key(
         &self,
-        key_id: &String,
+        key_id: &str,
         wrapping_key: &PublicWrappingKey,
     ) -> Result<WrappedKeyData> {
-        if wrapping_key.kty != *RSA_KEY_TYPE {
-            return Err(crate::error::Error::KeyStore(
-                crate::error::KeyStoreErrorKind::UnsupportedWrappingKeyType,
-            ));
-        }
+        self.wrap_key_with_rng(key_id, wrapping_key, &mut rand::thread_rng())
+    }
 
-        let k_mod = URL_SAFE_NO_PAD.decode(&wrapping_key.n)?;
-        let n = BigUint::from_bytes_be(&k_mod);
-        let k_exp = URL_SAFE_NO_PAD.decode(&wrapping_key.e)?;
-        let e = BigUint::from_bytes_be(&k_exp);
+    /// Obtain a wrapped (encrypted) data item from the store, using the given source of
+    /// randomness for the encryption padding.
+    ///
+    /// This is only exposed separately from `wrap_key()` so that the ciphertexts can be made
+    /// reproducible, which is needed to produce the published test vectors.
+    fn wrap_key_with_rng<R: CryptoRngCore>(
+        &self,
+        key_id: &str,
+        wrapping_key: &PublicWrappingKey,
+        rng: &mut R,
+    ) -> Result<WrappedKeyData> {
+        self.check_wrapping_key(wrapping_key)?;
+        let public_key = wrapping_public_key(wrapping_key)?;
 
-        let mut rng = rand::thread_rng();
+        if let Some(data) = self.key_data(key_id) {
+            self.check_wrapping_algorithm(key_id, &wrapping_key.alg)?;
 
-        let rsa_pub_key = RsaPublicKey::new(n, e)?;
-
-        if let Some(entry) = self.keys.get_key_value(key_id) {
-            let (_k, data) = entry;
-            let wrapped_data = {
-                if wrapping_key.alg == *RSA_PKCS15_ALGORITHM {
-                    rsa_pub_key.encrypt(&mut rng, Pkcs1v15Encrypt, data)
-                } else if wrapping_key.alg == *RSA_OAEP_ALGORITHM {
-                    let padding = Oaep::new::<Sha256>();
-                    rsa_pub_key.encrypt(&mut rng, padding, data)
-                } else {
+            let mut retobj = WrappedKeyData {
+                data: String::new(),
+                wrapped_cek: None,
+                iv: None,
+                enc: None,
+                epk: None,
+                apu: None,
+                apv: None,
+                key_id: Some(self.canonical_key_id(key_id).to_string()),
+                attestation_result: None,
+            };
+            match (&public_key, RsaPadding::of(&wrapping_key.alg)) {
+                (WrappingPublicKey::Rsa(rsa_pub_key), Some(padding)) => {
+                    if data.len() <= padding.capacity(rsa_pub_key.size()) {
+                        retobj.data = base64::encode(padding.encrypt(rng, rsa_pub_key, &data)?);
+                    } else {
+                        let cek = encrypt_content(rng, &data, &mut retobj)?;
+                        retobj.wrapped_cek = Some(base64::encode(padding.encrypt(
+                            rng,
+                            rsa_pub_key,
+                            cek.as_ref(),
+                        )?));
+                    }
+                }
+                (WrappingPublicKey::P256(_) | WrappingPublicKey::X25519(_), None) => {
+                    let kek = ecdh_key_wrapping_key(rng, &public_key, &mut retobj)?;
+                    let cek = encrypt_content(rng, &data, &mut retobj)?;
+                    let wrapped_cek = KekAes256::new(GenericArray::from_slice(kek.as_ref()))
+                        .wrap_vec(cek.as_ref())
+                        .map_err(|_| {
+                            crate::error::Error::KeyStore(
+                                crate::error::KeyStoreErrorKind::ContentEncryptionFailure,
+                            )
+                        })?;
+                    retobj.wrapped_cek = Some(base64::encode(wrapped_cek));
+                }
+                _ => {
                     return Err(crate::error::Error::KeyStore(
-                        crate::error::KeyStoreErrorKind::UnsupportedWrappingKeyAlgorithm,
-                    ));
+                        crate::error::KeyStoreErrorKind::UnsupportedWrappingKeyAlgorithm(
+                            wrapping_key.alg.clone(),
+                            self.permitted_wrapping_algorithms(key_id).join(", "),
+                        ),
+                    ))
                 }
-            }?;
-            let data_base64 = URL_SAFE_NO_PAD.encode(wrapped_data);
-            let retobj = WrappedKeyData { data: data_base64 };
+            }
             Ok(retobj)
         } else {
             Err(crate::error::Error::KeyStore(
@@ -100,74 +751,795 @@ impl KeyStore {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use rsa::{traits::PublicKeyParts, RsaPrivateKey};
+    use keybroker_common::jwk::{ECDH_ES_A256KW_ALGORITHM, LEGACY_OAEP_ALGORITHM};
+    use keybroker_common::BackgroundCheckKeyRequest;
+    use rand::{rngs::StdRng, SeedableRng};
+    use rsa::pkcs8::{DecodePrivateKey, EncodePrivateKey, LineEnding};
+    use rsa::RsaPrivateKey;
+
+    /// The secret of the round trips, which is small enough to be wrapped directly.
+    const SMALL_KEY: &[u8] = b"May the force be with you.";
+
+    /// A secret too large to be wrapped directly, with any wrapping algorithm.
+    fn large_key() -> Vec<u8> {
+        (0..4096).map(|i| (i % 251) as u8).collect()
+    }
 
-    fn key_store_round_trip(kty: &str, alg: &str) {
+    fn key_store_round_trip(kty: &str, alg: &str, key_content: &[u8]) {
         let mut store = KeyStore::new();
 
         // Put a key into the store
         let key_id = "skywalker";
-        let key_content = "May the force be with you.";
-        store.store_key(key_id, key_content.as_bytes().to_vec());
+        store.store_key(key_id, key_content.to_vec()).unwrap();
 
         // Create an ephemeral wrapping key-pair
         let mut rng = rand::thread_rng();
-        let bits = 1024;
+        let bits = 2048;
         let priv_key =
             RsaPrivateKey::new(&mut rng, bits).expect("Failed to generate ephemeral wrapping key.");
 
-        // Get the public key and deconstruct into modulus and exponent
+        // Turn the public key into API-level input, with the key type and algorithm under test.
         let pub_key = RsaPublicKey::from(&priv_key);
-        let k_mod = pub_key.n();
-        let k_exp = pub_key.e();
-
-        // Create base64 strings for n and e
-        let k_mod_base64 = URL_SAFE_NO_PAD.encode(BigUint::to_bytes_be(k_mod));
-        let k_exp_base64 = URL_SAFE_NO_PAD.encode(BigUint::to_bytes_be(k_exp));
-
-        // Turn this into API-level input
         let wrapping_key = PublicWrappingKey {
             kty: kty.to_string(),
             alg: alg.to_string(),
-            n: k_mod_base64,
-            e: k_exp_base64,
+            ..PublicWrappingKey::try_from(&pub_key).expect("Failed to convert the wrapping key.")
         };
 
         // Make the API call
         let wrapped_data = store
-            .wrap_key(&key_id.to_string(), &wrapping_key)
+            .wrap_key(key_id, &wrapping_key)
             .expect("Key store did not return the wrapped key.");
 
         // Decode and decrypt with the private key.
-        let ciphertext = URL_SAFE_NO_PAD
-            .decode(wrapped_data.data)
-            .expect("Failed to base64-decode the wrapped data from the key store.");
-        let plaintext = {
-            if alg == RSA_PKCS15_ALGORITHM {
-                priv_key
-                    .decrypt(Pkcs1v15Encrypt, &ciphertext)
-                    .expect("Failed to decrypt wrapped data from the key store.")
-            } else if alg == RSA_OAEP_ALGORITHM {
-                let padding = Oaep::new::<Sha256>();
-                priv_key
-                    .decrypt(padding, &ciphertext)
-                    .expect("Failed to decrypt wrapped data from the key store.")
-            } else {
-                vec![]
+        let decode = |data: &str| {
+            base64::decode("the wrapped data", data)
+                .expect("Failed to base64-decode the wrapped data from the key store.")
+        };
+        let rsa_decrypt = |ciphertext: &[u8]| {
+            match canonical_wrapping_algorithm(alg) {
+                Some(RSA_PKCS15_ALGORITHM) => priv_key.decrypt(Pkcs1v15Encrypt, ciphertext),
+                Some(RSA_OAEP_ALGORITHM) => {
+                    // The hash is part of the wire format: the other one can't decrypt.
+                    assert!(priv_key.decrypt(Oaep::new::<Sha256>(), ciphertext).is_err());
+                    priv_key.decrypt(Oaep::new::<Sha1>(), ciphertext)
+                }
+                Some(RSA_OAEP_256_ALGORITHM) => {
+                    assert!(priv_key.decrypt(Oaep::new::<Sha1>(), ciphertext).is_err());
+                    priv_key.decrypt(Oaep::new::<Sha256>(), ciphertext)
+                }
+                _ => panic!("unexpected wrapping algorithm {alg}"),
+            }
+            .expect("Failed to decrypt wrapped data from the key store.")
+        };
+
+        // Only the keys too large to be wrapped directly have a content encryption key.
+        assert_eq!(wrapped_data.wrapped_cek.is_some(), key_content.len() > 256);
+        let plaintext = match &wrapped_data.wrapped_cek {
+            None => rsa_decrypt(&decode(&wrapped_data.data)),
+            Some(wrapped_cek) => {
+                assert_eq!(wrapped_data.enc.as_deref(), Some(A256GCM_ENCRYPTION));
+                let cek = rsa_decrypt(&decode(wrapped_cek));
+                let iv = decode(wrapped_data.iv.as_deref().expect("No IV with the CEK."));
+                jwk::decrypt_content(&cek, &iv, key_id, &decode(&wrapped_data.data))
+                    .expect("Failed to decrypt the content from the key store.")
+                    .to_vec()
             }
         };
 
         // Check we got it back
-        assert_eq!(key_content.as_bytes(), &plaintext);
+        assert_eq!(key_content, &plaintext);
     }
 
     #[test]
     fn round_trip_rsa_pkcs15() {
-        key_store_round_trip(RSA_KEY_TYPE, RSA_PKCS15_ALGORITHM)
+        key_store_round_trip(RSA_KEY_TYPE, RSA_PKCS15_ALGORITHM, SMALL_KEY);
+        key_store_round_trip(RSA_KEY_TYPE, RSA_PKCS15_ALGORITHM, &large_key());
     }
 
     #[test]
     fn round_trip_rsa_oaep() {
-        key_store_round_trip(RSA_KEY_TYPE, RSA_OAEP_ALGORITHM)
+        key_store_round_trip(RSA_KEY_TYPE, RSA_OAEP_ALGORITHM, SMALL_KEY);
+        key_store_round_trip(RSA_KEY_TYPE, RSA_OAEP_ALGORITHM, &large_key());
+    }
+
+    #[test]
+    fn round_trip_rsa_oaep_256() {
+        key_store_round_trip(RSA_KEY_TYPE, RSA_OAEP_256_ALGORITHM, SMALL_KEY);
+        key_store_round_trip(RSA_KEY_TYPE, RSA_OAEP_256_ALGORITHM, &large_key());
+    }
+
+    #[test]
+    fn round_trip_legacy_oaep() {
+        key_store_round_trip(RSA_KEY_TYPE, LEGACY_OAEP_ALGORITHM, SMALL_KEY);
+        key_store_round_trip(RSA_KEY_TYPE, LEGACY_OAEP_ALGORITHM, &large_key());
+    }
+
+    /// Unwrap a key wrapped with ECDH-ES+A256KW to `wrapping_key`, given the ECDH shared secret of
+    /// its private part and of the ephemeral public key.
+    fn ecdh_unwrap(
+        wrapped_data: &WrappedKeyData,
+        wrapping_key: &PublicWrappingKey,
+        shared_secret: &[u8],
+    ) -> Vec<u8> {
+        let decode = |data: &str| {
+            base64::decode("the wrapped data", data)
+                .expect("Failed to base64-decode the wrapped data from the key store.")
+        };
+        assert_eq!(wrapped_data.enc.as_deref(), Some(A256GCM_ENCRYPTION));
+
+        // The key derivation is bound to the coordinates of both public keys.
+        let party_info = |x: &str, y: Option<&String>| {
+            let mut info = decode(x);
+            info.extend(y.map(|y| decode(y)).unwrap_or_default());
+            info
+        };
+        let epk = wrapped_data.epk.as_ref().expect("No ephemeral public key.");
+        let apu = party_info(&epk.x, epk.y.as_ref());
+        let apv = party_info(wrapping_key.x.as_deref().unwrap(), wrapping_key.y.as_ref());
+        assert_eq!(wrapped_data.apu, Some(base64::encode(&apu)));
+        assert_eq!(wrapped_data.apv, Some(base64::encode(&apv)));
+
+        let kek = ecdh_es_key_wrapping_key(shared_secret, &apu, &apv);
+        let cek = KekAes256::new(GenericArray::from_slice(kek.as_ref()))
+            .unwrap_vec(&decode(
+                wrapped_data.wrapped_cek.as_deref().expect("No CEK."),
+            ))
+            .expect("Failed to unwrap the CEK.");
+        let iv = decode(wrapped_data.iv.as_deref().expect("No IV with the CEK."));
+        let key_id = wrapped_data.key_id.as_deref().expect("No key identifier.");
+        jwk::decrypt_content(&cek, &iv, key_id, &decode(&wrapped_data.data))
+            .expect("Failed to decrypt the content from the key store.")
+            .to_vec()
+    }
+
+    #[test]
+    fn round_trip_ecdh_p256() {
+        let secret = p256::SecretKey::random(&mut rand::thread_rng());
+        let point = secret.public_key().to_encoded_point(false);
+        let wrapping_key = PublicWrappingKey {
+            kty: EC_KEY_TYPE.to_string(),
+            alg: ECDH_ES_A256KW_ALGORITHM.to_string(),
+            crv: Some(P256_CURVE.to_string()),
+            x: point.x().map(base64::encode),
+            y: point.y().map(base64::encode),
+            ..Default::default()
+        };
+
+        for key_content in [SMALL_KEY, &large_key()] {
+            let mut store = KeyStore::new();
+            store.store_key("skywalker", key_content.to_vec()).unwrap();
+            let wrapped_data = store.wrap_key("skywalker", &wrapping_key).unwrap();
+
+            let epk = wrapped_data.epk.as_ref().expect("No ephemeral public key.");
+            assert_eq!((epk.kty.as_str(), epk.crv.as_str()), ("EC", "P-256"));
+            let mut sec1 = vec![0x04];
+            for coordinate in [Some(&epk.x), epk.y.as_ref()] {
+                sec1.extend(base64::decode("epk", coordinate.expect("No y coordinate.")).unwrap());
+            }
+            let epk = p256::PublicKey::from_sec1_bytes(&sec1).expect("Invalid ephemeral key.");
+
+            let shared_secret =
+                p256::ecdh::diffie_hellman(secret.to_nonzero_scalar(), epk.as_affine());
+            assert_eq!(
+                ecdh_unwrap(
+                    &wrapped_data,
+                    &wrapping_key,
+                    shared_secret.raw_secret_bytes()
+                ),
+                key_content
+            );
+        }
+    }
+
+    #[test]
+    fn round_trip_ecdh_x25519() {
+        let secret = x25519_dalek::StaticSecret::random_from_rng(rand::thread_rng());
+        let wrapping_key = PublicWrappingKey {
+            kty: OKP_KEY_TYPE.to_string(),
+            alg: ECDH_ES_A256KW_ALGORITHM.to_string(),
+            crv: Some(X25519_CURVE.to_string()),
+            x: Some(base64::encode(
+                x25519_dalek::PublicKey::from(&secret).as_bytes(),
+            )),
+            ..Default::default()
+        };
+
+        for key_content in [SMALL_KEY, &large_key()] {
+            let mut store = KeyStore::new();
+            store.store_key("skywalker", key_content.to_vec()).unwrap();
+            let wrapped_data = store.wrap_key("skywalker", &wrapping_key).unwrap();
+
+            let epk = wrapped_data.epk.as_ref().expect("No ephemeral public key.");
+            assert_eq!((epk.kty.as_str(), epk.crv.as_str()), ("OKP", "X25519"));
+            assert!(epk.y.is_none());
+            let epk: [u8; 32] = base64::decode("epk", &epk.x).unwrap().try_into().unwrap();
+
+            let shared_secret = secret.diffie_hellman(&x25519_dalek::PublicKey::from(epk));
+            assert_eq!(
+                ecdh_unwrap(&wrapped_data, &wrapping_key, shared_secret.as_bytes()),
+                key_content
+            );
+        }
+    }
+
+    #[test]
+    fn wrapping_algorithm_of_the_key_type() {
+        let mut store = KeyStore::new();
+        store.store_key("skywalker", b"secret".to_vec()).unwrap();
+        let unsupported = |wrapping_key: &PublicWrappingKey| {
+            matches!(
+                store.wrap_key("skywalker", wrapping_key),
+                Err(crate::error::Error::KeyStore(
+                    crate::error::KeyStoreErrorKind::UnsupportedWrappingKeyAlgorithm(..)
+                ))
+            )
+        };
+
+        // The RSA algorithms are not for the X25519 keys, and ECDH-ES not for the RSA keys.
+        let secret = x25519_dalek::StaticSecret::random_from_rng(rand::thread_rng());
+        let x25519_key = PublicWrappingKey {
+            kty: OKP_KEY_TYPE.to_string(),
+            alg: RSA_OAEP_256_ALGORITHM.to_string(),
+            crv: Some(X25519_CURVE.to_string()),
+            x: Some(base64::encode(
+                x25519_dalek::PublicKey::from(&secret).as_bytes(),
+            )),
+            ..Default::default()
+        };
+        assert!(unsupported(&x25519_key));
+        assert!(unsupported(&wrapping_key(ECDH_ES_A256KW_ALGORITHM)));
+        match store.check_wrapping_key(&x25519_key) {
+            Err(crate::error::Error::KeyStore(
+                crate::error::KeyStoreErrorKind::UnsupportedWrappingKeyAlgorithm(_, fitting),
+            )) => assert_eq!(fitting, ECDH_ES_A256KW_ALGORITHM),
+            other => panic!("Unexpected outcome: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn direct_wrapping_capacity() {
+        // The largest keys wrapped directly with a 2048-bit wrapping key, as per RFC 8017.
+        assert_eq!(RsaPadding::Pkcs1v15.capacity(256), 245);
+        assert_eq!(RsaPadding::OaepSha1.capacity(256), 214);
+        assert_eq!(RsaPadding::OaepSha256.capacity(256), 190);
+
+        let mut store = KeyStore::new();
+        store.store_key("fits", vec![0x5a; 190]).unwrap();
+        store.store_key("too-large", vec![0x5a; 191]).unwrap();
+        let priv_key = RsaPrivateKey::new(&mut rand::thread_rng(), 2048).unwrap();
+        let wrapping_key = PublicWrappingKey::try_from(&RsaPublicKey::from(&priv_key))
+            .and_then(|key| key.with_algorithm(RSA_OAEP_256_ALGORITHM))
+            .unwrap();
+
+        let wrapped = store.wrap_key("fits", &wrapping_key).unwrap();
+        assert!(wrapped.wrapped_cek.is_none() && wrapped.iv.is_none() && wrapped.enc.is_none());
+        let wrapped = store.wrap_key("too-large", &wrapping_key).unwrap();
+        assert!(wrapped.wrapped_cek.is_some() && wrapped.iv.is_some() && wrapped.enc.is_some());
+    }
+
+    #[test]
+    fn unknown_wrapping_algorithm() {
+        let mut store = KeyStore::new();
+        store.store_key("skywalker", b"secret".to_vec()).unwrap();
+        let priv_key = RsaPrivateKey::new(&mut rand::thread_rng(), 2048).unwrap();
+        let wrapping_key = PublicWrappingKey {
+            alg: "RSA-OAEP-384".to_string(),
+            ..PublicWrappingKey::try_from(&RsaPublicKey::from(&priv_key)).unwrap()
+        };
+        match store.wrap_key("skywalker", &wrapping_key) {
+            Err(crate::error::Error::KeyStore(
+                crate::error::KeyStoreErrorKind::UnknownWrappingKeyAlgorithm(alg, supported),
+            )) => {
+                assert_eq!(alg, "RSA-OAEP-384");
+                assert_eq!(supported, store.wrapping_algorithms().join(", "));
+            }
+            other => panic!("Unexpected outcome: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn weak_wrapping_keys() {
+        let mut store = KeyStore::new();
+        store.store_key("skywalker", b"secret".to_vec()).unwrap();
+        let priv_key = RsaPrivateKey::new(&mut rand::thread_rng(), 1024).unwrap();
+        let wrapping_key = PublicWrappingKey::try_from(&RsaPublicKey::from(&priv_key)).unwrap();
+
+        let weak = |store: &KeyStore, wrapping_key: &PublicWrappingKey| {
+            matches!(
+                store.wrap_key("skywalker", wrapping_key),
+                Err(crate::error::Error::KeyStore(
+                    crate::error::KeyStoreErrorKind::WeakWrappingKey(_)
+                ))
+            )
+        };
+
+        // A 1024-bit key is only accepted once the minimum size is lowered.
+        assert!(weak(&store, &wrapping_key));
+        store.set_min_wrapping_key_bits(1024);
+        assert!(store.wrap_key("skywalker", &wrapping_key).is_ok());
+
+        // Small exponents are refused, whatever the size of the modulus.
+        for e in ["Aw", "AQ"] {
+            let wrapping_key = PublicWrappingKey {
+                e: Some(e.to_string()),
+                ..wrapping_key.clone()
+            };
+            assert!(weak(&store, &wrapping_key), "{e}");
+        }
+    }
+
+    #[test]
+    fn min_wrapping_key_bits_option() {
+        assert_eq!(parse_min_wrapping_key_bits("2048"), Ok(2048));
+        assert_eq!(parse_min_wrapping_key_bits("1024"), Ok(1024));
+        for bits in ["512", "8192", "-1", "many"] {
+            assert!(parse_min_wrapping_key_bits(bits).is_err(), "{bits}");
+        }
+    }
+
+    /// The published interoperability test vectors, in testdata/wrapping-vectors.json.
+    #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+    #[serde(rename_all = "kebab-case")]
+    struct WrappingVectors {
+        description: Vec<String>,
+        vectors: Vec<WrappingVector>,
+    }
+
+    #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+    #[serde(rename_all = "kebab-case")]
+    struct WrappingVector {
+        name: String,
+        private_key_pem: String,
+        key_id: String,
+        plaintext: String,
+        request: String,
+        response: String,
+    }
+
+    const WRAPPING_VECTORS_DESCRIPTION: &[&str] = &[
+        "Test vectors for the wrapping of keys by the keybroker server.",
+        "'request' is the exact body of the key request, containing the public part of 'private-key-pem'.",
+        "'response' is the exact body returned on successful attestation, wrapping 'plaintext'.",
+        "All base64 strings in the bodies use the URL-safe alphabet, without padding.",
+        "The modulus 'n' and exponent 'e' are encoded as big-endian unsigned integers.",
+        "RSA-OAEP-256 uses SHA-256 for both the label hash and MGF1.",
+        "'rsa-oaep-256-2048-hybrid' wraps a key too large for the wrapping key: the response has the A256GCM content encryption key in 'wrapped-cek', and its 'key-id' is the additional authenticated data.",
+        "These vectors are regenerated with: KEYBROKER_REGENERATE_VECTORS=1 cargo test wrapping_vectors",
+    ];
+
+    /// Checks the published wrapping test vectors, or regenerates them (including the RSA key pairs)
+    /// when KEYBROKER_REGENERATE_VECTORS is set in the environment.
+    #[test]
+    fn wrapping_vectors() {
+        let mut path = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        path.push("../../testdata/wrapping-vectors.json");
+
+        let regenerate = std::env::var_os("KEYBROKER_REGENERATE_VECTORS").is_some();
+        let published: Option<WrappingVectors> = if regenerate {
+            None
+        } else {
+            let vectors = std::fs::read_to_string(&path).expect("Failed to read the test vectors.");
+            Some(serde_json::from_str(&vectors).expect("Failed to parse the test vectors."))
+        };
+
+        // The last key is too large to be wrapped directly.
+        let keys = [
+            ("skywalker", "May the force be with you.".to_string()),
+            ("yoda", "Do. Or do not. There is no try. ".repeat(8)),
+        ];
+        let mut store = KeyStore::new();
+        for (key_id, plaintext) in &keys {
+            store
+                .store_key(key_id, plaintext.as_bytes().to_vec())
+                .unwrap();
+        }
+
+        let mut key_rng = StdRng::seed_from_u64(0x6b65_7962_726f_6b65);
+        let mut vectors = vec![];
+        for (i, (name, alg, (key_id, plaintext))) in [
+            ("rsa1_5-2048", RSA_PKCS15_ALGORITHM, &keys[0]),
+            ("rsa-oaep-256-2048", RSA_OAEP_256_ALGORITHM, &keys[0]),
+            ("rsa-oaep-256-2048-hybrid", RSA_OAEP_256_ALGORITHM, &keys[1]),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            // The key pairs are only generated when regenerating the vectors, as key generation
+            // is slow and not guaranteed to be stable across versions of the rsa crate.
+            let priv_key = match &published {
+                Some(published) => {
+                    RsaPrivateKey::from_pkcs8_pem(&published.vectors[i].private_key_pem)
+                        .expect("Failed to parse the test vector private key.")
+                }
+                None => RsaPrivateKey::new(&mut key_rng, 2048)
+                    .expect("Failed to generate the test vector key pair."),
+            };
+            let pub_key = RsaPublicKey::from(&priv_key);
+
+            let request = BackgroundCheckKeyRequest {
+                pubkey: PublicWrappingKey::try_from(&pub_key)
+                    .and_then(|pubkey| pubkey.with_algorithm(alg))
+                    .expect("Failed to convert the test vector wrapping key."),
+                return_attestation_result: None,
+                correlation_id: None,
+            };
+
+            let wrapped_data = store
+                .wrap_key_with_rng(
+                    key_id,
+                    &request.pubkey,
+                    &mut StdRng::seed_from_u64(i as u64),
+                )
+                .expect("Key store did not return the wrapped key.");
+
+            vectors.push(WrappingVector {
+                name: name.to_string(),
+                private_key_pem: priv_key
+                    .to_pkcs8_pem(LineEnding::LF)
+                    .expect("Failed to encode the test vector private key.")
+                    .to_string(),
+                key_id: key_id.to_string(),
+                plaintext: plaintext.to_string(),
+                request: serde_json::to_string(&request).unwrap(),
+                response: serde_json::to_string(&wrapped_data).unwrap(),
+            });
+        }
+
+        let generated = WrappingVectors {
+            description: WRAPPING_VECTORS_DESCRIPTION
+                .iter()
+                .map(|s| s.to_string())
+                .collect(),
+            vectors,
+        };
+
+        match published {
+            Some(published) => assert_eq!(generated, published),
+            None => std::fs::write(
+                &path,
+                serde_json::to_string_pretty(&generated).unwrap() + "\n",
+            )
+            .expect("Failed to write the test vectors."),
+        }
+    }
+
+    #[test]
+    fn derivation_rfc5869_vector() {
+        // RFC 5869, test case 3: no salt, and no info (that is, an empty key identifier).
+        let mut derivation = KeyDerivation::new(Zeroizing::new(vec![0x0b; 22]));
+        derivation.declare_key("", 42);
+
+        assert_eq!(
+            *derivation.derive("").unwrap(),
+            [
+                0x8d, 0xa4, 0xe7, 0x75, 0xa5, 0x63, 0xc1, 0x8f, 0x71, 0x5f, 0x80, 0x2a, 0x06, 0x3c,
+                0x5a, 0x31, 0xb8, 0xa1, 0x1f, 0x5c, 0x5e, 0xe1, 0x87, 0x9e, 0xc3, 0x45, 0x4e, 0x5f,
+                0x3c, 0x73, 0x8d, 0x2d, 0x9d, 0x20, 0x13, 0x95, 0xfa, 0xa4, 0xb6, 0x1a, 0x96, 0xc8,
+            ]
+        );
+    }
+
+    #[test]
+    fn derivation_is_reproducible() {
+        let derivation = || {
+            let mut derivation = KeyDerivation::new(Zeroizing::new(b"master secret".to_vec()));
+            derivation.declare_key("skywalker", 32);
+            derivation.declare_key("deathstar", 64);
+            derivation
+        };
+
+        let skywalker = derivation().derive("skywalker").unwrap();
+        assert_eq!(skywalker.len(), 32);
+        assert_eq!(derivation().derive("skywalker").unwrap(), skywalker);
+
+        let deathstar = derivation().derive("deathstar").unwrap();
+        assert_eq!(deathstar.len(), 64);
+        assert_ne!(deathstar[..32], *skywalker);
+
+        let mut other = KeyDerivation::new(Zeroizing::new(b"other master secret".to_vec()));
+        other.declare_key("skywalker", 32);
+        assert_ne!(other.derive("skywalker").unwrap(), skywalker);
+    }
+
+    #[test]
+    fn derivation_of_undeclared_keys() {
+        let mut derivation = KeyDerivation::new(Zeroizing::new(b"master secret".to_vec()));
+        derivation.declare_key("skywalker", 32);
+        assert!(derivation.derive("vader").is_none());
+
+        derivation.derive_any_key(16);
+        assert_eq!(derivation.derive("vader").unwrap().len(), 16);
+        assert_eq!(derivation.derive("skywalker").unwrap().len(), 32);
+    }
+
+    #[test]
+    fn derived_key_not_found() {
+        let mut derivation = KeyDerivation::new(Zeroizing::new(b"master secret".to_vec()));
+        derivation.declare_key("skywalker", 32);
+        let store = KeyStore::with_derivation(derivation);
+
+        let wrapping_key = PublicWrappingKey {
+            kty: RSA_KEY_TYPE.to_string(),
+            alg: RSA_PKCS15_ALGORITHM.to_string(),
+            n: Some(base64::encode([0xc5; 256])),
+            e: Some(base64::encode([0x01, 0x00, 0x01])),
+            ..Default::default()
+        };
+        assert!(matches!(
+            store.wrap_key("vader", &wrapping_key),
+            Err(crate::error::Error::KeyStore(
+                crate::error::KeyStoreErrorKind::KeyNotFound
+            ))
+        ));
+        assert!(store.wrap_key("skywalker", &wrapping_key).is_ok());
+    }
+
+    fn wrapping_key(alg: &str) -> PublicWrappingKey {
+        PublicWrappingKey {
+            kty: RSA_KEY_TYPE.to_string(),
+            alg: alg.to_string(),
+            n: Some(base64::encode([0xc5; 256])),
+            e: Some(base64::encode([0x01, 0x00, 0x01])),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn key_restricted_to_oaep() {
+        let mut store = KeyStore::new();
+        store
+            .store_key("skywalker", b"May the force be with you.".to_vec())
+            .unwrap();
+        store
+            .store_key_with_attributes(
+                "deathstar",
+                b"Plans".to_vec(),
+                KeyAttributes {
+                    allowed_wrapping_algs: Some(vec![RSA_OAEP_ALGORITHM.to_string()]),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+        match store.wrap_key("deathstar", &wrapping_key(RSA_PKCS15_ALGORITHM)) {
+            Err(crate::error::Error::KeyStore(
+                crate::error::KeyStoreErrorKind::UnsupportedWrappingKeyAlgorithm(alg, permitted),
+            )) => {
+                assert_eq!(alg, RSA_PKCS15_ALGORITHM);
+                assert_eq!(permitted, RSA_OAEP_ALGORITHM);
+            }
+            other => panic!("Unexpected outcome: {other:?}"),
+        }
+        assert!(store
+            .wrap_key("deathstar", &wrapping_key(RSA_OAEP_ALGORITHM))
+            .is_ok());
+        assert!(store
+            .wrap_key("skywalker", &wrapping_key(RSA_PKCS15_ALGORITHM))
+            .is_ok());
+    }
+
+    #[test]
+    fn aliases() {
+        let mut store = KeyStore::new();
+        store
+            .store_key("skywalker", b"May the force be with you.".to_vec())
+            .unwrap();
+        store
+            .store_key("vader", b"I am your father.".to_vec())
+            .unwrap();
+        store.add_alias("disk-unlock", "skywalker").unwrap();
+        store.add_alias("luke", "skywalker").unwrap();
+
+        assert_eq!(store.canonical_key_id("disk-unlock"), "skywalker");
+        assert_eq!(store.canonical_key_id("vader"), "vader");
+        assert!(store.contains_key("luke"));
+        assert_eq!(
+            store.key_data("disk-unlock").unwrap().as_slice(),
+            b"May the force be with you."
+        );
+        let wrapped = store
+            .wrap_key("luke", &wrapping_key(RSA_OAEP_ALGORITHM))
+            .unwrap();
+        assert_eq!(wrapped.key_id.as_deref(), Some("skywalker"));
+        assert_eq!(
+            store.list_keys(),
+            vec![
+                KeyListing {
+                    id: "skywalker".to_string(),
+                    aliases: vec!["disk-unlock".to_string(), "luke".to_string()],
+                },
+                KeyListing {
+                    id: "vader".to_string(),
+                    aliases: Vec::new(),
+                },
+            ]
+        );
+
+        // An alias can't shadow a key or another alias, nor be shadowed by a new key.
+        for attempt in [
+            store.add_alias("vader", "skywalker"),
+            store.add_alias("luke", "vader"),
+            store.store_key("disk-unlock", b"Plans".to_vec()),
+        ] {
+            assert!(matches!(
+                attempt,
+                Err(crate::error::Error::KeyStore(
+                    crate::error::KeyStoreErrorKind::KeyIdTaken(_)
+                ))
+            ));
+        }
+        assert!(matches!(
+            store.add_alias("leia", "organa"),
+            Err(crate::error::Error::KeyStore(
+                crate::error::KeyStoreErrorKind::KeyNotFound
+            ))
+        ));
+    }
+
+    #[test]
+    fn put_and_delete() {
+        let mut store = KeyStore::new();
+        let attributes = KeyAttributes {
+            tags: vec!["production".to_string()],
+            ..Default::default()
+        };
+        store
+            .store_key_with_attributes(
+                "skywalker",
+                b"May the force be with you.".to_vec(),
+                attributes,
+            )
+            .unwrap();
+        store.add_alias("disk-unlock", "skywalker").unwrap();
+
+        // A new key is stored, while an existing one only has its value replaced.
+        assert!(!store
+            .put_key("vader", b"I am your father.".to_vec())
+            .unwrap());
+        assert!(store
+            .put_key("skywalker", b"Use the force.".to_vec())
+            .unwrap());
+        assert_eq!(
+            store.key_data("disk-unlock").unwrap().as_slice(),
+            b"Use the force."
+        );
+        assert_eq!(
+            store.key_attributes("skywalker").unwrap().tags,
+            vec!["production"]
+        );
+        assert!(matches!(
+            store.put_key("disk-unlock", b"Plans".to_vec()),
+            Err(crate::error::Error::KeyStore(
+                crate::error::KeyStoreErrorKind::KeyIdTaken(_)
+            ))
+        ));
+
+        // Deleting an alias leaves its key, while deleting a key takes its aliases along.
+        store.add_alias("luke", "skywalker").unwrap();
+        store.delete_key("disk-unlock").unwrap();
+        assert!(store.contains_key("skywalker"));
+        assert!(!store.contains_key("disk-unlock"));
+        store.delete_key("skywalker").unwrap();
+        assert!(!store.contains_key("luke"));
+        assert_eq!(store.key_count(), 1);
+        assert!(matches!(
+            store.delete_key("skywalker"),
+            Err(crate::error::Error::KeyStore(
+                crate::error::KeyStoreErrorKind::KeyNotFound
+            ))
+        ));
+
+        // The derived keys can't be changed.
+        let mut store = KeyStore::with_derivation(KeyDerivation::new(Zeroizing::new(vec![7; 32])));
+        assert!(matches!(
+            store.put_key("vader", b"I am your father.".to_vec()),
+            Err(crate::error::Error::KeyStore(
+                crate::error::KeyStoreErrorKind::DerivedKeys
+            ))
+        ));
+    }
+
+    #[test]
+    fn read_only_store() {
+        let mut store = KeyStore::new();
+        store
+            .store_key("skywalker", b"May the force be with you.".to_vec())
+            .unwrap();
+        store.make_read_only();
+
+        let attempts = [
+            store.store_key("vader", b"I am your father.".to_vec()),
+            store.store_key("skywalker", b"Use the force.".to_vec()),
+            store.store_key_with_attributes(
+                "deathstar",
+                b"Plans".to_vec(),
+                KeyAttributes::default(),
+            ),
+        ];
+        for attempt in attempts {
+            assert!(matches!(
+                attempt,
+                Err(crate::error::Error::KeyStore(
+                    crate::error::KeyStoreErrorKind::ReadOnly
+                ))
+            ));
+        }
+
+        assert!(matches!(
+            store.put_key("skywalker", b"Use the force.".to_vec()),
+            Err(crate::error::Error::KeyStore(
+                crate::error::KeyStoreErrorKind::ReadOnly
+            ))
+        ));
+        assert!(matches!(
+            store.delete_key("skywalker"),
+            Err(crate::error::Error::KeyStore(
+                crate::error::KeyStoreErrorKind::ReadOnly
+            ))
+        ));
+
+        // The keys are still released, unchanged.
+        assert!(store.key_attributes("vader").is_none());
+        assert!(store.key_attributes("deathstar").is_none());
+        assert_eq!(
+            store.key_data("skywalker").unwrap().as_slice(),
+            b"May the force be with you."
+        );
+    }
+
+    #[test]
+    fn rsa1_5_forbidden() {
+        let mut store = KeyStore::new();
+        store
+            .store_key("skywalker", b"May the force be with you.".to_vec())
+            .unwrap();
+        store
+            .store_key_with_attributes(
+                "deathstar",
+                b"Plans".to_vec(),
+                KeyAttributes {
+                    allowed_wrapping_algs: Some(vec![RSA_PKCS15_ALGORITHM.to_string()]),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        store.forbid_rsa1_5();
+
+        assert_eq!(
+            store.wrapping_algorithms(),
+            [
+                RSA_OAEP_ALGORITHM,
+                RSA_OAEP_256_ALGORITHM,
+                ECDH_ES_A256KW_ALGORITHM
+            ]
+        );
+        assert!(store
+            .check_wrapping_algorithm("skywalker", RSA_PKCS15_ALGORITHM)
+            .is_err());
+        assert!(store
+            .check_wrapping_algorithm("skywalker", RSA_OAEP_ALGORITHM)
+            .is_ok());
+
+        // The most restrictive wins: the key can not be released at all anymore.
+        assert!(store.permitted_wrapping_algorithms("deathstar").is_empty());
+        assert!(store
+            .check_wrapping_algorithm("deathstar", RSA_PKCS15_ALGORITHM)
+            .is_err());
+    }
+
+    #[test]
+    fn derived_key_option() {
+        let key: DerivedKey = "skywalker".parse().unwrap();
+        assert_eq!(key.key_id, "skywalker");
+        assert_eq!(key.length, None);
+
+        let key: DerivedKey = "deathstar:64".parse().unwrap();
+        assert_eq!(key.key_id, "deathstar");
+        assert_eq!(key.length, Some(64));
+
+        assert!("deathstar:0".parse::<DerivedKey>().is_err());
+        assert!("deathstar:8161".parse::<DerivedKey>().is_err());
+        assert!("deathstar:many".parse::<DerivedKey>().is_err());
     }
 }
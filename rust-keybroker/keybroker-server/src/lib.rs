@@ -0,0 +1,2617 @@
+// Copyright 2024 Contributors to the Veraison project.
+// SPDX-License-Identifier: Apache-2.0
+
+//! A simple web service that can provide keys and secrets in exchange for verifiable attestation tokens.
+//!
+//! The service is provided as a library so that it can be embedded and driven in-process, which is
+//! what the `keybroker-server` executable and the integration tests do.
+//!
+//! The evidence is appraised by a remote Veraison instance, unless the `remote-verifier` feature
+//! (enabled by default) is disabled. The server then has no verifier: the verifier and appraisal
+//! policy options are not available, and all the evidence submissions fail with a
+//! `VerifierUnavailable` error.
+
+use std::sync::Mutex;
+
+use actix_web::dev::{Server, ServerHandle};
+use actix_web::error::InternalError;
+use actix_web::{
+    delete, get, http, post, put,
+    rt::{task, time},
+    web, App, HttpRequest, HttpResponse, HttpResponseBuilder, HttpServer, Responder, ResponseError,
+};
+use audit::{AuditLog, AuditRecord};
+use challenge::{Challenge, Challenger, PendingChallenges, RedemptionOutcome};
+#[cfg(feature = "remote-verifier")]
+use chrono::{DateTime, SecondsFormat, Utc};
+use clap::Parser;
+use cors::CorsPolicy;
+use deadline::{Progress, Stage};
+#[cfg(feature = "remote-verifier")]
+use digest::encode_digest;
+use endpoint::Endpoint;
+use evidence::EvidenceType;
+#[cfg(feature = "remote-verifier")]
+use evidence::MediaTypeAlias;
+use key_file::{KeyFileOptions, KeyFileSource};
+use key_id::KeyIdPolicy;
+use keybroker_common::{
+    base64, sanitise_correlation_id, AttestationChallenge, BackgroundCheckKeyRequest, ErrorCode,
+    ErrorInformation, ServerInfo, StrictDeserialize, UnknownFields, VerifierInfo,
+    ATTESTATION_CHALLENGE_ENCODINGS_MEDIA_TYPE, PROBLEM_JSON_MEDIA_TYPE,
+};
+use keystore::{DerivedKey, KeyDerivation, KeyStore};
+use lifecycle::{correlation_suffix, EventBus, Outcome, Transition};
+use log::Level;
+use logging::{log_request, LogFormat, RequestFields};
+#[cfg(feature = "remote-verifier")]
+use opa::OpaEngine;
+#[cfg(feature = "remote-verifier")]
+use policy::{
+    ChallengeContext, EmbeddedEngine, KeyContext, PolicyConfig, PolicyContext, PolicyEngine,
+    PolicyEngineKind,
+};
+use reference_values::{ReferenceValuesSource, ReferenceValuesStore, ReferenceValuesUpdate};
+use release_rate::{ReleaseStats, ReleaseThrottle};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::net::{IpAddr, TcpListener};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tls::CertificateResolver;
+#[cfg(feature = "remote-verifier")]
+use tokio::sync::Semaphore;
+#[cfg(feature = "remote-verifier")]
+use verifier::{DiagnosticsOptions, DiscoveryCache, SessionPolling, Verifier};
+#[cfg(feature = "remote-verifier")]
+use verifier_auth::{VerifierAuth, VerifierAuthenticator};
+use zeroize::Zeroizing;
+mod audit;
+#[cfg(feature = "cca-token-diagnostics")]
+mod cca_token;
+mod challenge;
+pub mod config;
+mod cors;
+mod deadline;
+mod digest;
+mod endpoint;
+pub mod error;
+mod evidence;
+pub mod input;
+mod key_file;
+mod key_id;
+mod keystore;
+mod lifecycle;
+pub mod logging;
+mod negotiation;
+#[cfg(feature = "remote-verifier")]
+mod opa;
+#[cfg(feature = "remote-verifier")]
+pub mod policy;
+mod reference_values;
+mod release_rate;
+mod tls;
+#[cfg(feature = "remote-verifier")]
+mod verifier;
+#[cfg(feature = "remote-verifier")]
+mod verifier_auth;
+
+/// Respond with the error information, as a problem details document. Its title, status and
+/// instance are filled in, and the instance, unique to the response, is logged, so that an error
+/// reported by a client can be traced in the logs.
+fn problem(response: &mut HttpResponseBuilder, mut error_info: ErrorInformation) -> HttpResponse {
+    let mut response = response.content_type(PROBLEM_JSON_MEDIA_TYPE).finish();
+    // Kept with the response, for the audit log.
+    response.extensions_mut().insert(error_info.r#type.clone());
+
+    let instance = uuid::Uuid::new_v4().urn().to_string();
+    log::info!(
+        "Error {instance}: {} ({}){}",
+        error_info.r#type,
+        response.status(),
+        correlation_suffix(error_info.correlation_id.as_deref())
+    );
+
+    error_info.title = Some(error_info.r#type.title());
+    error_info.status = Some(response.status().as_u16());
+    error_info.instance = Some(instance);
+    let body = serde_json::to_string(&error_info).expect("The error information is serialisable.");
+    response.set_body(body).map_into_boxed_body()
+}
+
+/// Turn away the JSON bodies that can't be read with a problem details document of type `code`,
+/// as any other error, rather than with the plain text response of actix-web.
+fn json_config(code: ErrorCode) -> web::JsonConfig {
+    web::JsonConfig::default().error_handler(move |error, request| {
+        let error_info = ErrorInformation::new(code.clone(), error.to_string(), None);
+
+        log_request!(
+            Level::Info,
+            RequestFields::new(&peer_address(request)),
+            "Invalid JSON body for {}: {error}",
+            request.path()
+        );
+        let response = problem(&mut HttpResponse::build(error.status_code()), error_info);
+        InternalError::from_response(error, response).into()
+    })
+}
+
+/// The IP address of the client of a request, for the logs and the audit log.
+fn peer_address(request: &HttpRequest) -> String {
+    request
+        .peer_addr()
+        .map(|peer| peer.ip().to_string())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+#[post("/key/{keyid}")]
+async fn request_key(
+    data: web::Data<ServerState>,
+    request: HttpRequest,
+    key_request: web::Json<serde_json::Value>,
+) -> impl Responder {
+    // The key identifier is taken from the raw path, rather than from the (already percent-decoded)
+    // path parameters, so that it is percent-decoded exactly once.
+    let raw_key_id = request.uri().path().rsplit('/').next().unwrap_or_default();
+    let peer = peer_address(&request);
+    let fields = RequestFields::new(&peer);
+    let key_id = match data.key_id_policy.parse_path_segment(raw_key_id) {
+        Ok(key_id) => key_id,
+        Err(error) => {
+            let error_info =
+                ErrorInformation::new(ErrorCode::InvalidKeyId, error.to_string(), None);
+
+            log_request!(
+                Level::Info,
+                fields,
+                "Key requested with identifier '{raw_key_id}': {error}"
+            );
+            return problem(&mut HttpResponse::BadRequest(), error_info);
+        }
+    };
+
+    let unknown_fields = if data.args.deny_unknown_fields {
+        UnknownFields::Deny
+    } else {
+        UnknownFields::Ignore
+    };
+    let key_request = match BackgroundCheckKeyRequest::from_json_value(
+        key_request.into_inner(),
+        unknown_fields,
+    ) {
+        Ok(key_request) => key_request,
+        Err(error) => {
+            let error_info = ErrorInformation::new(
+                ErrorCode::InvalidKeyRequest,
+                format!("Invalid key request: {error}"),
+                None,
+            );
+
+            log_request!(
+                Level::Info,
+                fields.key(&key_id),
+                "Key '{key_id}' requested: invalid key request, {error}"
+            );
+            return problem(&mut HttpResponse::BadRequest(), error_info);
+        }
+    };
+    let correlation_id = key_request
+        .correlation_id
+        .as_deref()
+        .and_then(sanitise_correlation_id);
+
+    // Pick the representation of the challenge before creating it, so that a client which can't
+    // take any of them doesn't leave an unredeemable challenge behind.
+    let representation = match negotiation::negotiate(
+        &request,
+        &[
+            negotiation::JSON_MEDIA_TYPE,
+            negotiation::CBOR_MEDIA_TYPE,
+            ATTESTATION_CHALLENGE_ENCODINGS_MEDIA_TYPE,
+        ],
+    ) {
+        Ok(representation) => representation,
+        Err(error) => {
+            let error_info =
+                ErrorInformation::new(error.code(), error.to_string(), correlation_id.clone());
+
+            log_request!(
+                Level::Info,
+                fields.key(&key_id),
+                "Key '{key_id}' requested{}: {error}",
+                correlation_suffix(correlation_id.as_deref())
+            );
+            return problem(&mut HttpResponse::NotAcceptable(), error_info);
+        }
+    };
+
+    // Resolve an alias to the key it stands for, which is the key the challenge is for, and turn
+    // away a wrapping key that key can't be released under, or a weak one, before any attestation.
+    let (key_id, permitted) = {
+        let keystore = data.keystore.lock().expect("Poisoned keystore lock.");
+        let canonical_key_id = keystore.canonical_key_id(&key_id);
+        if canonical_key_id != key_id {
+            log_request!(
+                Level::Debug,
+                fields.key(canonical_key_id),
+                "Key '{canonical_key_id}' requested under its alias '{key_id}'."
+            );
+        }
+        let key_id = canonical_key_id.to_string();
+        let permitted = keystore
+            .check_wrapping_algorithm(&key_id, &key_request.pubkey.alg)
+            .and_then(|()| keystore.check_wrapping_key(&key_request.pubkey));
+        (key_id, permitted)
+    };
+    if let Err(error) = permitted {
+        let error_info =
+            ErrorInformation::new(error.code(), error.to_string(), correlation_id.clone());
+
+        log_request!(
+            Level::Info,
+            fields.key(&key_id),
+            "Key '{key_id}' requested{}: {error}",
+            correlation_suffix(correlation_id.as_deref())
+        );
+        return problem(&mut HttpResponse::BadRequest(), error_info);
+    }
+
+    // Get a new challenge from the challenger, for the supported evidence types that the verifier
+    // supports too.
+    let media_types = offered_media_types(&data).await;
+    let nonce_size = evidence::nonce_size(&media_types);
+    let challenge = data.challenger.create_challenge(
+        &key_id,
+        &key_request.pubkey,
+        media_types,
+        nonce_size,
+        data.args.mock_challenge,
+        key_request.return_attestation_result.unwrap_or(false),
+        correlation_id,
+    );
+
+    let attestation_challenge = AttestationChallenge {
+        challenge: base64::encode(&challenge.challenge_value),
+        accept: challenge.media_types.clone(),
+        correlation_id: challenge.correlation_id.clone(),
+    };
+
+    data.events.publish(
+        Transition::ChallengeCreated,
+        challenge.challenge_id,
+        &challenge.key_id,
+        challenge.correlation_id.as_deref(),
+        Outcome::Ok,
+    );
+
+    let location = data
+        .endpoint
+        .join(&format!("/keys/v1/evidence/{}", challenge.challenge_id));
+
+    log_request!(
+        Level::Info,
+        fields
+            .challenge(challenge.challenge_id)
+            .key(&challenge.key_id),
+        "Created attestation challenge at {}:\n\
+          - challenge_id: {}\n\
+          - key_id: {}\n\
+          - correlation_id: {}\n\
+          - challenge value ({} bytes): {:02x?}",
+        location,
+        challenge.challenge_id,
+        challenge.key_id,
+        challenge.correlation_id.as_deref().unwrap_or("none"),
+        challenge.challenge_value.len(),
+        challenge.challenge_value
+    );
+
+    let mut response = HttpResponse::Created();
+    response
+        .append_header((http::header::LOCATION, location))
+        .append_header((http::header::VARY, "Accept"));
+    match representation {
+        negotiation::CBOR_MEDIA_TYPE => {
+            response
+                .content_type(negotiation::CBOR_MEDIA_TYPE)
+                .body(negotiation::challenge_cbor(
+                    &challenge.challenge_value,
+                    &challenge.media_types,
+                    challenge.correlation_id.as_deref(),
+                ))
+        }
+        ATTESTATION_CHALLENGE_ENCODINGS_MEDIA_TYPE => {
+            let encodings =
+                negotiation::challenge_encodings(attestation_challenge, &challenge.challenge_value);
+            response
+                .content_type(ATTESTATION_CHALLENGE_ENCODINGS_MEDIA_TYPE)
+                .body(serde_json::to_vec(&encodings).expect("Failed to serialise a challenge."))
+        }
+        _ => response.json(attestation_challenge),
+    }
+}
+
+#[get("/info")]
+async fn server_info(data: web::Data<ServerState>) -> impl Responder {
+    // Strip any credentials or query from the verifier URL, only its location is of interest.
+    #[cfg(feature = "remote-verifier")]
+    let verifier = VerifierInfo {
+        mode: "remote".to_string(),
+        url: reqwest::Url::parse(&data.args.verifier)
+            .map(|mut url| {
+                let _ = url.set_username("");
+                let _ = url.set_password(None);
+                url.set_query(None);
+                url.set_fragment(None);
+                url.to_string()
+            })
+            .ok(),
+    };
+    #[cfg(not(feature = "remote-verifier"))]
+    let verifier = VerifierInfo {
+        mode: "none".to_string(),
+        url: None,
+    };
+
+    HttpResponse::Ok().json(ServerInfo {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        evidence_media_types: evidence::media_types(),
+        wrapping_algorithms: data
+            .keystore
+            .lock()
+            .expect("Poisoned keystore lock.")
+            .wrapping_algorithms()
+            .iter()
+            .map(|alg| alg.to_string())
+            .collect(),
+        mock_challenge: data.args.mock_challenge,
+        verifier,
+    })
+}
+
+/// Whether the server is fit to serve the key requests.
+#[derive(serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+enum HealthStatus {
+    Ok,
+
+    /// The server is up, but a key request would fail, as the verifier can't be reached.
+    Degraded,
+}
+
+/// The health of the server, for the liveness and readiness probes. It tells the number of keys,
+/// but nothing of the keys themselves.
+#[derive(serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct Health {
+    status: HealthStatus,
+    version: &'static str,
+    uptime_secs: u64,
+    /// The number of keys in the key store.
+    keys: usize,
+    /// Whether the verifier answered its discovery API, if it was checked.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    verifier_reachable: Option<bool>,
+}
+
+/// The time allowed to the verifier to answer a discovery call, for a health check or for the
+/// media types to offer in the challenges.
+#[cfg(feature = "remote-verifier")]
+const VERIFIER_DISCOVERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long the media types offered in the challenges are kept, once derived from the discovery API
+/// of the verifier.
+#[cfg(feature = "remote-verifier")]
+const OFFERED_MEDIA_TYPES_LIFETIME: Duration = Duration::from_secs(300);
+
+/// How long the server falls back to offering all its media types, when the verifier could not tell
+/// which it supports, before asking it again.
+#[cfg(feature = "remote-verifier")]
+const OFFERED_MEDIA_TYPES_RETRY: Duration = Duration::from_secs(30);
+
+/// How long the outcome of a health check of the verifier is kept, so that frequent probes do not
+/// each make a discovery call.
+#[cfg(feature = "remote-verifier")]
+const VERIFIER_REACHABILITY_LIFETIME: Duration = Duration::from_secs(10);
+
+/// The media types to offer in the challenges: the supported ones that the verifier supports too,
+/// as per its discovery API, which is only queried again once the previous answer is stale. When
+/// the verifier can't tell, all the supported media types are offered.
+#[cfg(feature = "remote-verifier")]
+async fn offered_media_types(data: &ServerState) -> Vec<String> {
+    if let Some((expires_at, media_types)) = &*data
+        .offered_media_types
+        .lock()
+        .expect("Poisoned offered media types lock.")
+    {
+        if Instant::now() < *expires_at {
+            return media_types.clone();
+        }
+    }
+
+    let verifier = data.verifier();
+    let discovery = task::spawn_blocking(move || verifier::media_types(&verifier));
+    let (lifetime, media_types) = match time::timeout(VERIFIER_DISCOVERY_TIMEOUT, discovery).await {
+        Ok(Ok(Ok(verifier_media_types))) => {
+            let media_types = evidence::offered_media_types(
+                evidence::media_types(),
+                &verifier_media_types,
+                &data.args.media_type_aliases,
+            );
+            if media_types.is_empty() {
+                log::warn!(
+                    "The verifier supports none of the evidence media types of the server, only: {}. Offering them all in the challenges.",
+                    verifier_media_types.join(", ")
+                );
+                (OFFERED_MEDIA_TYPES_RETRY, evidence::media_types())
+            } else {
+                log::debug!(
+                    "Offering the evidence media types {} in the challenges.",
+                    media_types.join(", ")
+                );
+                (OFFERED_MEDIA_TYPES_LIFETIME, media_types)
+            }
+        }
+        Ok(Ok(Err(error))) => {
+            log::warn!("The verifier can't tell which evidence media types it supports, offering them all in the challenges. {error}");
+            (OFFERED_MEDIA_TYPES_RETRY, evidence::media_types())
+        }
+        Ok(Err(_)) | Err(_) => {
+            log::warn!("The verifier did not tell in time which evidence media types it supports, offering them all in the challenges.");
+            (OFFERED_MEDIA_TYPES_RETRY, evidence::media_types())
+        }
+    };
+
+    *data
+        .offered_media_types
+        .lock()
+        .expect("Poisoned offered media types lock.") =
+        Some((Instant::now() + lifetime, media_types.clone()));
+    media_types
+}
+
+/// The media types to offer in the challenges: all the supported ones, without a verifier to ask.
+#[cfg(not(feature = "remote-verifier"))]
+async fn offered_media_types(_data: &ServerState) -> Vec<String> {
+    evidence::media_types()
+}
+
+/// Whether the verifier answers its discovery API, as last checked unless that is stale.
+#[cfg(feature = "remote-verifier")]
+async fn verifier_reachable(data: &ServerState) -> bool {
+    if let Some((expires_at, reachable)) = *data
+        .verifier_reachability
+        .lock()
+        .expect("Poisoned verifier reachability lock.")
+    {
+        if Instant::now() < expires_at {
+            return reachable;
+        }
+    }
+
+    let verifier = data.verifier();
+    let check = task::spawn_blocking(move || verifier::check_reachable(&verifier));
+    let reachable = match time::timeout(VERIFIER_DISCOVERY_TIMEOUT, check).await {
+        Ok(Ok(Ok(()))) => true,
+        Ok(Ok(Err(error))) => {
+            log::warn!("Health check: the verifier can't be reached. {error}");
+            false
+        }
+        Ok(Err(_)) | Err(_) => {
+            log::warn!("Health check: the verifier did not answer in time.");
+            false
+        }
+    };
+
+    *data
+        .verifier_reachability
+        .lock()
+        .expect("Poisoned verifier reachability lock.") =
+        Some((Instant::now() + VERIFIER_REACHABILITY_LIFETIME, reachable));
+    reachable
+}
+
+/// The health of the server, checking the verifier if it is configured to.
+async fn current_health(data: &ServerState) -> Health {
+    // The key store is only locked to count the keys.
+    let keys = data
+        .keystore
+        .lock()
+        .expect("Poisoned keystore lock.")
+        .key_count();
+
+    #[cfg(feature = "remote-verifier")]
+    let verifier_reachable = if data.args.health_check_verifier {
+        Some(verifier_reachable(data).await)
+    } else {
+        None
+    };
+    #[cfg(not(feature = "remote-verifier"))]
+    let verifier_reachable = None;
+
+    Health {
+        status: if verifier_reachable == Some(false) {
+            HealthStatus::Degraded
+        } else {
+            HealthStatus::Ok
+        },
+        version: env!("CARGO_PKG_VERSION"),
+        uptime_secs: data.started.elapsed().as_secs(),
+        keys,
+        verifier_reachable,
+    }
+}
+
+/// The liveness of the server: it answers, even when degraded, as restarting it would not bring
+/// the verifier back.
+#[get("/health")]
+async fn health(data: web::Data<ServerState>) -> impl Responder {
+    HttpResponse::Ok().json(current_health(&data).await)
+}
+
+/// The readiness of the server: it is not ready to serve the key requests when degraded.
+#[get("/health/ready")]
+async fn readiness(data: web::Data<ServerState>) -> impl Responder {
+    let report = current_health(&data).await;
+    match report.status {
+        HealthStatus::Ok => HttpResponse::Ok().json(report),
+        HealthStatus::Degraded => HttpResponse::ServiceUnavailable().json(report),
+    }
+}
+
+#[post("/evidence/{challengeid}")]
+async fn submit_evidence(
+    path: web::Path<u32>,
+    data: web::Data<ServerState>,
+    request: HttpRequest,
+    body: web::Payload,
+) -> impl Responder {
+    let challenge_id = path.into_inner();
+    let correlation_id = data.challenger.correlation_id(challenge_id);
+    let correlation_id = correlation_id.as_deref();
+    let peer = peer_address(&request);
+
+    // The whole handling of the submission has a single budget, the response aside.
+    let progress = Progress::default();
+    let deadline = Duration::from_secs(data.args.request_deadline_secs);
+    let handling = handle_evidence(
+        challenge_id,
+        correlation_id,
+        &data,
+        &request,
+        body,
+        &progress,
+    );
+    let response = match time::timeout(deadline, handling).await {
+        Ok(response) => response,
+        Err(_) => {
+            let stage = progress.stage();
+
+            // A submission that timed out once its challenge was consumed is a failed attempt to
+            // redeem it, which a retried submission is told about. Before that, the challenge is
+            // left in place for the retry.
+            let challenge = if stage.challenge_consumed() {
+                data.verifications.timed_out.fetch_add(1, Ordering::Relaxed);
+                data.challenger
+                    .record_outcome(challenge_id, RedemptionOutcome::TimedOut);
+                data.events.publish(
+                    Transition::ChallengeCancelled,
+                    challenge_id,
+                    &progress.key_id().unwrap_or_default(),
+                    correlation_id,
+                    Outcome::Error(ErrorCode::DeadlineExceeded),
+                );
+                "The challenge was consumed."
+            } else {
+                "The challenge can still be redeemed."
+            };
+            let error_info = ErrorInformation::new(
+                ErrorCode::DeadlineExceeded,
+                format!(
+                    "The evidence submission was not handled within {} seconds. {challenge}",
+                    deadline.as_secs()
+                ),
+                correlation_id.map(str::to_string),
+            );
+
+            log_request!(
+                Level::Warn, RequestFields::new(&peer).challenge(challenge_id),
+                "Evidence submitted for challenge {challenge_id}{}: the deadline expired while {stage}.",
+                correlation_suffix(correlation_id)
+            );
+            problem(&mut HttpResponse::GatewayTimeout(), error_info)
+        }
+    };
+
+    let appraisal = progress.appraisal();
+    data.audit.record(AuditRecord {
+        key_id: progress.key_id(),
+        correlation_id: correlation_id.map(str::to_string),
+        peer: Some(peer),
+        media_type: progress.media_type(),
+        submods: appraisal
+            .as_ref()
+            .map(|(submods, _)| submods.clone())
+            .unwrap_or_default(),
+        decision: appraisal.map(|(_, in_policy)| {
+            if in_policy {
+                Outcome::Allowed
+            } else {
+                Outcome::Denied
+            }
+        }),
+        status: response.status().as_u16(),
+        error: response.extensions().get::<ErrorCode>().cloned(),
+        ..AuditRecord::new(challenge_id)
+    });
+    response
+}
+
+/// Cancel a challenge for which no evidence will be submitted, such as when the attester failed to
+/// produce it, so that the server does not hold it any longer.
+#[delete("/evidence/{challengeid}")]
+async fn cancel_challenge(path: web::Path<u32>, data: web::Data<ServerState>) -> impl Responder {
+    let challenge_id = path.into_inner();
+
+    let (key_id, correlation_id) = {
+        let challenger = &data.challenger;
+        match challenger.cancel_challenge(challenge_id) {
+            Ok(challenge) => (challenge.key_id, challenge.correlation_id),
+            Err(error::Error::Challenge(
+                error @ error::ChallengeErrorKind::ChallengeAlreadyRedeemed(_),
+            )) => {
+                let error_info = ErrorInformation::new(
+                    ErrorCode::ChallengeAlreadyRedeemed,
+                    error.to_string(),
+                    challenger.correlation_id(challenge_id),
+                );
+
+                log::info!(
+                    challenge_id = challenge_id;
+                    "Cancellation of challenge {challenge_id}: {error}"
+                );
+                return problem(&mut HttpResponse::Conflict(), error_info);
+            }
+            Err(error::Error::Challenge(error @ error::ChallengeErrorKind::ChallengeExpired)) => {
+                let error_info = ErrorInformation::new(
+                    ErrorCode::ChallengeExpired,
+                    error.to_string(),
+                    challenger.correlation_id(challenge_id),
+                );
+
+                log::info!(
+                    challenge_id = challenge_id;
+                    "Cancellation of challenge {challenge_id}: {error}"
+                );
+                // As for a submission of evidence for it.
+                return problem(&mut HttpResponse::Forbidden(), error_info);
+            }
+            Err(_) => {
+                let error_info = ErrorInformation::new(
+                    ErrorCode::ChallengeNotFound,
+                    "The challenge identifier did not match any issued challenge.".to_string(),
+                    None,
+                );
+
+                log::info!(
+                    challenge_id = challenge_id;
+                    "Cancellation of challenge {challenge_id}: it does not match any issued challenge."
+                );
+                return problem(&mut HttpResponse::NotFound(), error_info);
+            }
+        }
+    };
+
+    data.events.publish(
+        Transition::ChallengeCancelled,
+        challenge_id,
+        &key_id,
+        correlation_id.as_deref(),
+        Outcome::Ok,
+    );
+    HttpResponse::NoContent().finish()
+}
+
+/// Handle an evidence submission, recording the stages it goes through in `progress`.
+async fn handle_evidence(
+    challenge_id: u32,
+    correlation_id: Option<&str>,
+    data: &ServerState,
+    request: &HttpRequest,
+    body: web::Payload,
+    progress: &Progress,
+) -> HttpResponse {
+    let peer = peer_address(request);
+    let fields = RequestFields::new(&peer).challenge(challenge_id);
+    let error_information =
+        |code, detail| ErrorInformation::new(code, detail, correlation_id.map(str::to_string));
+    let flow = format!(
+        "challenge {challenge_id}{}",
+        correlation_suffix(correlation_id)
+    );
+
+    // Validate the inputs before the challenge is consumed, so that the client can retry
+    // with well-formed inputs.
+    let content_type = match input::evidence_media_type(
+        request
+            .headers()
+            .get(http::header::CONTENT_TYPE)
+            .map(|value| value.as_bytes()),
+    ) {
+        Ok(content_type) => content_type,
+        Err(error) => {
+            let error_info = error_information(ErrorCode::InvalidContentType, error.to_string());
+
+            log_request!(
+                Level::Info,
+                fields,
+                "Evidence submitted for {flow}: {error}"
+            );
+            return problem(&mut HttpResponse::BadRequest(), error_info);
+        }
+    };
+
+    let evidence_type = match evidence::evidence_type(&content_type) {
+        Ok(evidence_type) => evidence_type,
+        Err(error) => {
+            let error_info = error_information(ErrorCode::UnsupportedMediaType, error.to_string());
+
+            log_request!(
+                Level::Info,
+                fields,
+                "Evidence submitted for {flow}: {error}"
+            );
+            return problem(&mut HttpResponse::UnsupportedMediaType(), error_info);
+        }
+    };
+    progress.accept_media_type(&content_type);
+
+    // The body is capped at the maximum evidence size both as received and, if it is compressed,
+    // once decompressed. An oversized evidence is turned away before the challenge is looked up,
+    // so that it can still be redeemed.
+    let max_evidence_size = data.args.max_evidence_size;
+    let evidence_base64 = match body.to_bytes_limited(max_evidence_size).await {
+        Ok(Ok(body)) => input::decode_content(
+            request
+                .headers()
+                .get(http::header::CONTENT_ENCODING)
+                .map(|value| value.as_bytes()),
+            body.to_vec(),
+            max_evidence_size,
+        ),
+        Ok(Err(error)) => {
+            let error_info = error_information(
+                ErrorCode::MalformedEvidence,
+                format!("The evidence could not be read. {error}"),
+            );
+
+            log_request!(
+                Level::Info,
+                fields,
+                "Evidence submitted for {flow}: {error}"
+            );
+            let status = error.as_response_error().status_code();
+            return problem(&mut HttpResponse::build(status), error_info);
+        }
+        Err(_) => Err(error::Error::Input(
+            error::InputErrorKind::EvidenceTooLarge(max_evidence_size),
+        )),
+    };
+
+    progress.enter(Stage::DecodingEvidence);
+    let evidence_base64 = match evidence_base64 {
+        Ok(evidence_base64) => evidence_base64,
+        Err(error) => {
+            let mut detail = error.to_string();
+            let mut response = match error {
+                error::Error::Input(error::InputErrorKind::EvidenceTooLarge(_)) => {
+                    detail.push_str(" The challenge can still be redeemed.");
+                    HttpResponse::PayloadTooLarge()
+                }
+                error::Error::Input(error::InputErrorKind::UnsupportedContentEncoding(_)) => {
+                    HttpResponse::UnsupportedMediaType()
+                }
+                _ => HttpResponse::BadRequest(),
+            };
+            let error_info = error_information(error.code(), detail);
+
+            log_request!(
+                Level::Info,
+                fields,
+                "Evidence submitted for {flow}: {error}"
+            );
+            return problem(&mut response, error_info);
+        }
+    };
+
+    let evidence_bytes = match input::decode_evidence(&evidence_base64) {
+        Ok(evidence_bytes) => evidence_bytes,
+        Err(error) => {
+            let error_info =
+                error_information(ErrorCode::InvalidEvidenceEncoding, error.to_string());
+
+            log_request!(
+                Level::Info,
+                fields,
+                "Evidence submitted for {flow}: {error}"
+            );
+            return problem(&mut HttpResponse::BadRequest(), error_info);
+        }
+    };
+
+    // Whatever the encodings of the submission, the evidence itself is capped too.
+    if evidence_bytes.len() > max_evidence_size {
+        let error = error::InputErrorKind::EvidenceTooLarge(max_evidence_size);
+        let error_info = error_information(
+            ErrorCode::EvidenceTooLarge,
+            format!("{error} The challenge can still be redeemed."),
+        );
+
+        log_request!(
+            Level::Info,
+            fields,
+            "Evidence submitted for {flow}: {error}"
+        );
+        return problem(&mut HttpResponse::PayloadTooLarge(), error_info);
+    }
+
+    // Turn away obvious junk before the challenge is consumed and a verifier session is opened.
+    if let Err(error) = (evidence_type.check_structure)(&evidence_bytes) {
+        let error_info = error_information(error.code(), error.to_string());
+
+        log_request!(
+            Level::Info,
+            fields,
+            "Evidence submitted for {flow}: {error}"
+        );
+        return problem(&mut HttpResponse::BadRequest(), error_info);
+    }
+    if let Some(log_claims) = evidence_type.log_claims {
+        log_claims(challenge_id, &evidence_bytes);
+    }
+
+    progress.enter(Stage::RedeemingChallenge);
+    let challenge = {
+        // Once the evidence is submitted, delete the challenge. It can't be used again.
+        let challenge = match data
+            .challenger
+            .redeem_challenge(challenge_id, &content_type)
+        {
+            Ok(challenge) => challenge,
+            // A retried submission is told that the challenge was used, rather than that it never existed.
+            Err(error::Error::Challenge(
+                error @ error::ChallengeErrorKind::ChallengeAlreadyRedeemed(_),
+            )) => {
+                let error_info =
+                    error_information(ErrorCode::ChallengeAlreadyRedeemed, error.to_string());
+
+                log_request!(
+                    Level::Info,
+                    fields,
+                    "Evidence submitted for {flow}: {error}"
+                );
+                return problem(&mut HttpResponse::Conflict(), error_info);
+            }
+            // The challenge is left in place, so that the client can retry with evidence of the right type.
+            Err(error::Error::Challenge(
+                error @ error::ChallengeErrorKind::MediaTypeMismatch(_),
+            )) => {
+                let error_info = error_information(ErrorCode::MediaTypeMismatch, error.to_string());
+
+                log_request!(
+                    Level::Info,
+                    fields,
+                    "Evidence submitted for {flow}: {error}"
+                );
+                return problem(&mut HttpResponse::BadRequest(), error_info);
+            }
+            Err(error::Error::Challenge(error @ error::ChallengeErrorKind::ChallengeExpired)) => {
+                let error_info = error_information(ErrorCode::ChallengeExpired, error.to_string());
+
+                log_request!(
+                    Level::Info,
+                    fields,
+                    "Evidence submitted for {flow}: {error}"
+                );
+                return problem(&mut HttpResponse::Forbidden(), error_info);
+            }
+            Err(_) => {
+                let error_info = error_information(
+                    ErrorCode::ChallengeNotFound,
+                    "The challenge identifier did not match any issued challenge.".to_string(),
+                );
+
+                log_request!(
+                    Level::Info,
+                    fields,
+                    "Evidence submitted for {flow}: it does not match any issued challenge."
+                );
+                return problem(&mut HttpResponse::Forbidden(), error_info);
+            }
+        };
+
+        progress.redeem(&challenge.key_id);
+        progress.enter(Stage::Appraising);
+
+        challenge
+    };
+    let fields = fields.key(&challenge.key_id);
+    data.events.publish(
+        Transition::EvidenceReceived,
+        challenge_id,
+        &challenge.key_id,
+        correlation_id,
+        Outcome::Ok,
+    );
+
+    // Optionally dump the evidence to file.
+    // This can be useful for debugging or for educational purpose for example.
+    if data.args.dump_evidence_cbor {
+        let filename = format!("evidence-{challenge_id}.cbor");
+        match std::fs::write(&filename, &evidence_bytes) {
+            Ok(()) => {
+                log_request!(
+                    Level::Info,
+                    fields,
+                    "Evidence for {flow} dumped to file {filename}"
+                )
+            }
+            Err(e) => {
+                log_request!(
+                    Level::Error,
+                    fields,
+                    "Failed to dump evidence for {flow} to file {filename}: {e}"
+                )
+            }
+        }
+    }
+
+    data.events.publish(
+        Transition::VerificationStarted,
+        challenge_id,
+        &challenge.key_id,
+        correlation_id,
+        Outcome::Ok,
+    );
+    let result = appraise(
+        data,
+        content_type,
+        evidence_type,
+        &challenge,
+        evidence_bytes,
+    )
+    .await;
+
+    // The verifier and the policy are run together, their transitions are published once both
+    // are done.
+    match &result {
+        Ok(appraisal) => {
+            progress.appraised(&appraisal.submod_statuses, appraisal.in_policy);
+            data.events.publish(
+                Transition::VerificationFinished,
+                challenge_id,
+                &challenge.key_id,
+                correlation_id,
+                Outcome::Ok,
+            );
+            data.events.publish(
+                Transition::PolicyDecision,
+                challenge_id,
+                &challenge.key_id,
+                correlation_id,
+                if appraisal.in_policy {
+                    Outcome::Allowed
+                } else {
+                    Outcome::Denied
+                },
+            );
+        }
+        Err(error) => data.events.publish(
+            Transition::VerificationFinished,
+            challenge_id,
+            &challenge.key_id,
+            correlation_id,
+            Outcome::Error(error.code()),
+        ),
+    }
+
+    let in_policy = matches!(&result, Ok(appraisal) if appraisal.in_policy);
+
+    // A challenge whose evidence could not be appraised for a runtime error of the verifier is put
+    // back, within its --challenge-attempts, so that the client can submit its evidence again. Any
+    // other outcome consumes it.
+    let reinstated = match &result {
+        Err(error)
+            if error.is_transient() && challenge.attempts + 1 < data.args.challenge_attempts =>
+        {
+            data.challenger.reinstate_challenge(&challenge)
+        }
+        _ => false,
+    };
+    // The outcome of an appraisal in policy is only known once the key is wrapped, and released.
+    if !in_policy {
+        data.verifications.failed.fetch_add(1, Ordering::Relaxed);
+        if !reinstated {
+            data.challenger
+                .record_outcome(challenge_id, RedemptionOutcome::Failed);
+        }
+    }
+
+    match result {
+        Ok(appraisal) => {
+            // Switch on whether the evidence was successfully verified or not.
+            if appraisal.in_policy {
+                progress.enter(Stage::WrappingKey);
+                let keystore = data.keystore.lock().expect("Poisoned keystore lock.");
+                let wrapped_key = keystore.wrap_key(&challenge.key_id, &challenge.wrapping_key);
+
+                // A key with a release rate is only released if it was not released too often. The
+                // release is only counted once the key is wrapped, as a key that could not be
+                // wrapped was not released.
+                let release_rate = keystore
+                    .key_attributes(&challenge.key_id)
+                    .and_then(|attributes| attributes.release_rate)
+                    .filter(|_| wrapped_key.is_ok());
+                if let Some(rate) = release_rate {
+                    let release = data
+                        .release_throttle
+                        .lock()
+                        .expect("Poisoned release throttle lock.")
+                        .try_release(&challenge.key_id, &rate, Instant::now());
+                    if let Err(retry_after) = release {
+                        data.verifications.failed.fetch_add(1, Ordering::Relaxed);
+                        // The challenge is put back, within its --challenge-attempts, for the
+                        // client to submit its evidence again once the wait is over, if the
+                        // challenge does not expire in the meantime.
+                        let reinstated = challenge.attempts + 1 < data.args.challenge_attempts
+                            && Instant::now() + retry_after < challenge.expires_at
+                            && data.challenger.reinstate_challenge(&challenge);
+                        if !reinstated {
+                            data.challenger
+                                .record_outcome(challenge_id, RedemptionOutcome::Failed);
+                        }
+                        data.events.publish(
+                            Transition::KeyWrapped,
+                            challenge_id,
+                            &challenge.key_id,
+                            correlation_id,
+                            Outcome::Error(ErrorCode::ReleaseRateExceeded),
+                        );
+                        let retry_after =
+                            retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+                        let mut detail = format!(
+                            "The key '{}' was released too often, it can be released again in {} seconds.",
+                            challenge.key_id, retry_after
+                        );
+                        if reinstated {
+                            detail.push_str(&format!(
+                                " The evidence can then be submitted again for this challenge ({} of {} attempts used).",
+                                challenge.attempts + 1,
+                                data.args.challenge_attempts
+                            ));
+                        } else {
+                            detail.push_str(" A new challenge must then be requested.");
+                        }
+                        let error_info = error_information(ErrorCode::ReleaseRateExceeded, detail);
+
+                        log_request!(
+                            Level::Info, fields,
+                            "Evidence submitted for {}: verification succeeded, but key '{}' was released too often.",
+                            flow,
+                            challenge.key_id
+                        );
+                        return problem(
+                            HttpResponse::TooManyRequests().append_header((
+                                http::header::RETRY_AFTER,
+                                retry_after.to_string(),
+                            )),
+                            error_info,
+                        );
+                    }
+                }
+
+                let (count, outcome) = match &wrapped_key {
+                    Ok(_) => (&data.verifications.succeeded, RedemptionOutcome::Succeeded),
+                    Err(_) => (&data.verifications.failed, RedemptionOutcome::Failed),
+                };
+                count.fetch_add(1, Ordering::Relaxed);
+                data.challenger.record_outcome(challenge_id, outcome);
+                data.events.publish(
+                    Transition::KeyWrapped,
+                    challenge_id,
+                    &challenge.key_id,
+                    correlation_id,
+                    match &wrapped_key {
+                        Ok(_) => Outcome::Ok,
+                        Err(error) => Outcome::Error(error.code()),
+                    },
+                );
+                match wrapped_key {
+                    Ok(mut wrapped_key) => {
+                        log_request!(
+                            Level::Info,
+                            fields,
+                            "Evidence submitted for {}: verification succeeded !",
+                            flow
+                        );
+                        // Only return the attestation result when it was explicitly requested.
+                        if challenge.return_attestation_result {
+                            wrapped_key.attestation_result = Some(appraisal.attestation_result);
+                        }
+                        HttpResponse::Ok().json(wrapped_key)
+                    }
+                    Err(error::Error::KeyStore(error::KeyStoreErrorKind::KeyNotFound)) => {
+                        let error_info = error_information(
+                            ErrorCode::KeyNotFound,
+                            format!("The key '{}' is not in the store.", challenge.key_id),
+                        );
+
+                        log_request!(
+                            Level::Info, fields,
+                            "Evidence submitted for {}: verification succeeded, but key '{}' is not in the store.",
+                            flow,
+                            challenge.key_id
+                        );
+                        problem(&mut HttpResponse::NotFound(), error_info)
+                    }
+                    Err(error) => {
+                        let error_info = error_information(
+                            error.code(),
+                            format!("The key could not be wrapped. {}", error),
+                        );
+
+                        log_request!(
+                            Level::Error, fields,
+                            "Evidence submitted for {}: verification succeeded, but the key could not be wrapped. {}",
+                            flow,
+                            error
+                        );
+                        problem(&mut HttpResponse::BadRequest(), error_info)
+                    }
+                }
+            } else {
+                let mut detail = "The attestation result is not in policy.".to_string();
+                let reasons = appraisal.deny_reasons.join("; ");
+
+                // The reasons tell about the policy, so they are only reported to the client on
+                // request.
+                if !reasons.is_empty() && data.args.verbose_failures {
+                    detail.push_str(&format!(" Denied: {reasons}."));
+                }
+                let error_info = error_information(ErrorCode::PolicyRejected, detail);
+
+                log_request!(
+                    Level::Info,
+                    fields,
+                    "Evidence submitted for {}: the attestation result is not in policy.{}",
+                    flow,
+                    if reasons.is_empty() {
+                        String::new()
+                    } else {
+                        format!(" Denied: {reasons}.")
+                    }
+                );
+                problem(&mut HttpResponse::Forbidden(), error_info)
+            }
+        }
+        Err(error::Error::Verification(
+            error::VerificationErrorKind::VerifierCredentialsRejected,
+        )) => {
+            let error_info = error_information(
+                ErrorCode::VerifierAuthenticationFailure,
+                "The verifier rejected our credentials.".to_string(),
+            );
+
+            log_request!(
+                Level::Error,
+                fields,
+                "Evidence submitted for {}: the verifier rejected our credentials.",
+                flow
+            );
+            problem(&mut HttpResponse::BadGateway(), error_info)
+        }
+        Err(error) => {
+            let mut detail = format!("No attestation result was obtained. {}", error);
+            if reinstated {
+                detail.push_str(&format!(
+                    " The challenge can still be redeemed ({} of {} attempts used).",
+                    challenge.attempts + 1,
+                    data.args.challenge_attempts
+                ));
+            }
+
+            // The diagnostics of a failed verifier session are always logged, but only reported to
+            // the client on request, as they tell about the verifier deployment.
+            #[cfg(feature = "remote-verifier")]
+            if let error::Error::Verification(error::VerificationErrorKind::SessionFailed(
+                diagnostics,
+            )) = &error
+            {
+                log_request!(
+                    Level::Info,
+                    fields,
+                    "Evidence submitted for {}: verifier {}.",
+                    flow,
+                    diagnostics
+                );
+                if data.args.verbose_failures {
+                    detail.push_str(&format!(" Verifier {}.", diagnostics));
+                }
+            }
+
+            let error_info = error_information(error.code(), detail);
+
+            log_request!(
+                Level::Info,
+                fields,
+                "Evidence submitted for {}: no attestation result was obtained. {}{}",
+                flow,
+                error,
+                if reinstated {
+                    format!(
+                        " The challenge was put back ({} of {} attempts used).",
+                        challenge.attempts + 1,
+                        data.args.challenge_attempts
+                    )
+                } else {
+                    String::new()
+                }
+            );
+            problem(&mut HttpResponse::Forbidden(), error_info)
+        }
+    }
+}
+
+/// The outcome of the appraisal of an evidence by the verifier.
+pub(crate) struct Appraisal {
+    /// Whether the attestation result is in policy.
+    pub in_policy: bool,
+
+    /// Why the attestation result is not in policy, when the policy tells.
+    pub deny_reasons: Vec<String>,
+
+    /// The attestation result (EAR) from the verifier, as a signed JWT.
+    pub attestation_result: String,
+
+    /// The status of each submodule of the attestation result, by submodule name.
+    pub submod_statuses: BTreeMap<String, String>,
+}
+
+/// Appraise the evidence with the remote verifier.
+#[cfg(feature = "remote-verifier")]
+async fn appraise(
+    data: &ServerState,
+    content_type: String,
+    evidence_type: &'static EvidenceType,
+    challenge: &Challenge,
+    evidence_bytes: Vec<u8>,
+) -> error::Result<Appraisal> {
+    // Tell the appraisal policy which key is requested, and about the challenge.
+    let key_attributes = data
+        .keystore
+        .lock()
+        .expect("Poisoned keystore lock.")
+        .key_attributes(&challenge.key_id)
+        .cloned()
+        .unwrap_or_default();
+    let policy_context = PolicyContext {
+        key: KeyContext {
+            id: challenge.key_id.clone(),
+            attributes: key_attributes,
+        },
+        challenge: ChallengeContext {
+            id: challenge.challenge_id,
+            created_at: DateTime::<Utc>::from(challenge.created_at)
+                .to_rfc3339_opts(SecondsFormat::Secs, true),
+            media_type: content_type.clone(),
+            wrapping_key_binding: data
+                .args
+                .bind_wrapping_key
+                .then(|| encode_digest(&challenge.wrapping_key.binding_measurement())),
+        },
+        config: PolicyConfig {
+            all_submods_affirming: data.args.all_submods_affirming,
+        },
+    };
+
+    let challenge_value = challenge.challenge_value.clone();
+    let verifier = data.verifier();
+    let reference_values = data.reference_values.get();
+    let diagnostics_options = DiagnosticsOptions {
+        verbosity: data.args.verbosity,
+        suggested_reference_values_out: data.args.suggested_reference_values_out.clone(),
+    };
+
+    // The verifier may know the media type under another name.
+    let verifier_media_type =
+        evidence::verifier_media_type(&data.args.media_type_aliases, &content_type);
+    if verifier_media_type != content_type {
+        log::debug!(
+            challenge_id = policy_context.challenge.id, key_id = policy_context.key.id.as_str();
+            "Evidence submitted for challenge {}: submitted to the verifier as '{verifier_media_type}' rather than '{content_type}'.",
+            policy_context.challenge.id
+        );
+    }
+    let content_type = verifier_media_type.to_string();
+
+    // The verifier client, and the policy engines, are synchronous, so the verification runs as a
+    // blocking task, once one of the --max-concurrent-verifications permits is available. The task
+    // holds its permit until it is done, even if the submission is abandoned at its deadline.
+    let permit = match data.verification_permits.clone().try_acquire_owned() {
+        Ok(permit) => permit,
+        Err(_) => {
+            log::debug!(
+                challenge_id = policy_context.challenge.id, key_id = policy_context.key.id.as_str();
+                "Evidence submitted for challenge {}: waiting for one of the {} verifications in \
+                 progress to complete.",
+                policy_context.challenge.id,
+                data.args.max_concurrent_verifications
+            );
+            data.verification_permits
+                .clone()
+                .acquire_owned()
+                .await
+                .expect("The verification permits are never closed.")
+        }
+    };
+    task::spawn_blocking(move || {
+        let _permit = permit;
+        verifier::verify_with_veraison_instance(
+            &verifier,
+            &content_type,
+            evidence_type,
+            &policy_context,
+            &challenge_value,
+            &evidence_bytes,
+            &reference_values,
+            &diagnostics_options,
+        )
+    })
+    .await
+    .unwrap_or_else(|error| {
+        Err(error::Error::Verification(
+            error::VerificationErrorKind::VerificationAborted(error.to_string()),
+        ))
+    })
+}
+
+/// Without a verifier, no evidence can be appraised.
+#[cfg(not(feature = "remote-verifier"))]
+async fn appraise(
+    _data: &ServerState,
+    _content_type: String,
+    _evidence_type: &'static EvidenceType,
+    _challenge: &Challenge,
+    _evidence_bytes: Vec<u8>,
+) -> error::Result<Appraisal> {
+    Err(error::Error::Verification(
+        error::VerificationErrorKind::NoVerifier,
+    ))
+}
+
+#[post("/reference-values/reload")]
+async fn reload_reference_values(
+    data: web::Data<ServerState>,
+    request: HttpRequest,
+) -> impl Responder {
+    if let Some(refusal) = admin_refusal(&data.args, &request) {
+        return refusal;
+    }
+
+    let reference_values = data.reference_values.clone();
+    let reloaded = admin_task("reload of the reference values", move || {
+        reference_values.reload()
+    });
+    let reloaded = match reloaded.await {
+        Ok(reloaded) => reloaded,
+        Err(response) => return response,
+    };
+
+    match reloaded {
+        Ok(outcome) => HttpResponse::Ok().json(outcome),
+        Err(error::Error::Verification(error::VerificationErrorKind::NoReferenceValues)) => {
+            let error_info = ErrorInformation::new(
+                ErrorCode::NoReferenceValuesSource,
+                "The server was started without --reference-values, there is nothing to reload."
+                    .to_string(),
+                None,
+            );
+            problem(&mut HttpResponse::Conflict(), error_info)
+        }
+        Err(error) => {
+            let error_info = ErrorInformation::new(
+                ErrorCode::InvalidReferenceValues,
+                format!(
+                    "The reference values could not be reloaded, the previous ones are kept. {}",
+                    error
+                ),
+                None,
+            );
+            problem(&mut HttpResponse::BadRequest(), error_info)
+        }
+    }
+}
+
+#[post("/reference-values")]
+async fn append_reference_values(
+    data: web::Data<ServerState>,
+    request: HttpRequest,
+    update: web::Json<ReferenceValuesUpdate>,
+) -> impl Responder {
+    if let Some(refusal) = admin_refusal(&data.args, &request) {
+        return refusal;
+    }
+
+    let reference_values = data.reference_values.clone();
+    let persist = data.args.persist_reference_values;
+    let appended = admin_task("append of the reference values", move || {
+        reference_values.append(&update, persist)
+    });
+    let appended = match appended.await {
+        Ok(appended) => appended,
+        Err(response) => return response,
+    };
+
+    match appended {
+        Ok(outcome) => HttpResponse::Ok().json(outcome),
+        Err(error @ error::Error::Verification(_)) => {
+            let error_info = ErrorInformation::new(
+                ErrorCode::InvalidReferenceValues,
+                format!("The reference values were not appended. {}", error),
+                None,
+            );
+            problem(&mut HttpResponse::BadRequest(), error_info)
+        }
+        Err(error) => {
+            let error_info = ErrorInformation::new(
+                ErrorCode::ReferenceValuesPersistenceFailure,
+                format!("The reference values could not be persisted. {}", error),
+                None,
+            );
+
+            log::error!("Failed to persist the reference values: {error}");
+            problem(&mut HttpResponse::InternalServerError(), error_info)
+        }
+    }
+}
+
+/// Run an admin operation as a blocking task. Should the task panic, or be cancelled as the server
+/// shuts down, the answer is a problem document rather than a panic of the worker.
+async fn admin_task<T: Send + 'static>(
+    operation: &'static str,
+    run: impl FnOnce() -> error::Result<T> + Send + 'static,
+) -> Result<error::Result<T>, HttpResponse> {
+    task::spawn_blocking(run).await.map_err(|error| {
+        let error_info = ErrorInformation::new(
+            ErrorCode::AdminOperationAborted,
+            format!("The {operation} was aborted before it completed. {error}"),
+            None,
+        );
+
+        log::error!("The {operation} was aborted: {error}");
+        problem(&mut HttpResponse::InternalServerError(), error_info)
+    })
+}
+
+/// The answer to an admin request which does not present the --admin-token, if it does not.
+fn admin_refusal(args: &Args, request: &HttpRequest) -> Option<HttpResponse> {
+    let Some(admin_token) = &args.admin_token else {
+        let error_info = ErrorInformation::new(
+            ErrorCode::AdminAuthenticationFailure,
+            "The server was started without --admin-token, this operation is disabled.".to_string(),
+            None,
+        );
+        return Some(problem(&mut HttpResponse::Forbidden(), error_info));
+    };
+
+    let presented = request
+        .headers()
+        .get(http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    // Compare the digests, so that the time the comparison takes tells nothing of the token.
+    if presented.is_some_and(|presented| Sha256::digest(presented) == Sha256::digest(admin_token)) {
+        return None;
+    }
+
+    let error_info = ErrorInformation::new(
+        ErrorCode::AdminAuthenticationFailure,
+        "The admin token is missing or wrong.".to_string(),
+        None,
+    );
+    Some(problem(
+        HttpResponse::Unauthorized().insert_header((http::header::WWW_AUTHENTICATE, "Bearer")),
+        error_info,
+    ))
+}
+
+/// The keys of the store, with their aliases, but never their value.
+#[get("/keys")]
+async fn list_keys(data: web::Data<ServerState>, request: HttpRequest) -> impl Responder {
+    if let Some(refusal) = admin_refusal(&data.args, &request) {
+        return refusal;
+    }
+
+    let keys = data
+        .keystore
+        .lock()
+        .expect("Poisoned keystore lock.")
+        .list_keys();
+    HttpResponse::Ok().json(serde_json::json!({ "keys": keys }))
+}
+
+/// The identifier of the key an admin request is about, as the last segment of its path.
+fn admin_key_id(data: &ServerState, request: &HttpRequest) -> Result<String, Box<HttpResponse>> {
+    let raw_key_id = request.uri().path().rsplit('/').next().unwrap_or_default();
+    data.key_id_policy
+        .parse_path_segment(raw_key_id)
+        .map_err(|error| {
+            let error_info =
+                ErrorInformation::new(ErrorCode::InvalidKeyId, error.to_string(), None);
+            Box::new(problem(&mut HttpResponse::BadRequest(), error_info))
+        })
+}
+
+/// The answer to an admin request whose change of the keys failed.
+fn key_change_failure(error: error::Error) -> HttpResponse {
+    let mut response = match error {
+        error::Error::KeyStore(error::KeyStoreErrorKind::KeyNotFound) => HttpResponse::NotFound(),
+        error::Error::KeyStore(error::KeyStoreErrorKind::ReadOnly)
+        | error::Error::KeyStore(error::KeyStoreErrorKind::DerivedKeys) => {
+            HttpResponse::Forbidden()
+        }
+        _ => HttpResponse::Conflict(),
+    };
+    let error_info = ErrorInformation::new(error.code(), error.to_string(), None);
+    problem(&mut response, error_info)
+}
+
+/// Store a key, whose value is the base64-encoded body, or replace the value of an existing key.
+#[put("/keys/{keyid}")]
+async fn put_key(
+    data: web::Data<ServerState>,
+    request: HttpRequest,
+    body: web::Bytes,
+) -> impl Responder {
+    if let Some(refusal) = admin_refusal(&data.args, &request) {
+        return refusal;
+    }
+    let key_id = match admin_key_id(&data, &request) {
+        Ok(key_id) => key_id,
+        Err(response) => return *response,
+    };
+
+    let value = match std::str::from_utf8(&body)
+        .map_err(|error| error.to_string())
+        .and_then(|body| {
+            base64::decode("key value", body.trim()).map_err(|error| error.to_string())
+        }) {
+        Ok(value) if !value.is_empty() => value,
+        Ok(_) | Err(_) => {
+            let error_info = ErrorInformation::new(
+                ErrorCode::InvalidKeyValue,
+                "The key value must be given as non-empty base64 in the body.",
+                None,
+            );
+            return problem(&mut HttpResponse::BadRequest(), error_info);
+        }
+    };
+
+    let replaced = data
+        .keystore
+        .lock()
+        .expect("Poisoned keystore lock.")
+        .put_key(&key_id, value);
+    match replaced {
+        Ok(true) => {
+            log::info!("Key '{key_id}' replaced through the admin API.");
+            HttpResponse::NoContent().finish()
+        }
+        Ok(false) => {
+            log::info!("Key '{key_id}' stored through the admin API.");
+            HttpResponse::Created().finish()
+        }
+        Err(error) => {
+            log::info!("Key '{key_id}' not stored through the admin API: {error}");
+            key_change_failure(error)
+        }
+    }
+}
+
+/// Remove a key along with its aliases, or only an alias.
+#[delete("/keys/{keyid}")]
+async fn delete_key(data: web::Data<ServerState>, request: HttpRequest) -> impl Responder {
+    if let Some(refusal) = admin_refusal(&data.args, &request) {
+        return refusal;
+    }
+    let key_id = match admin_key_id(&data, &request) {
+        Ok(key_id) => key_id,
+        Err(response) => return *response,
+    };
+
+    let deleted = data
+        .keystore
+        .lock()
+        .expect("Poisoned keystore lock.")
+        .delete_key(&key_id);
+    match deleted {
+        Ok(()) => {
+            log::info!("Key '{key_id}' deleted through the admin API.");
+            HttpResponse::NoContent().finish()
+        }
+        Err(error) => {
+            log::info!("Key '{key_id}' not deleted through the admin API: {error}");
+            key_change_failure(error)
+        }
+    }
+}
+
+/// The lifecycle events of the challenges, streamed as server-sent events as they occur.
+#[get("/events")]
+async fn events_stream(data: web::Data<ServerState>, request: HttpRequest) -> impl Responder {
+    if let Some(refusal) = admin_refusal(&data.args, &request) {
+        return refusal;
+    }
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header((http::header::CACHE_CONTROL, "no-cache"))
+        .streaming(data.events.subscribe())
+}
+
+/// The number of evidence submissions whose appraisal completed or was abandoned, since the server
+/// started.
+#[derive(Debug, Default, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct VerificationCounts {
+    /// The submissions whose key was released.
+    succeeded: AtomicU64,
+    /// The submissions whose evidence was not in policy, or whose key was not released, as it could
+    /// not be wrapped or it was released too often.
+    failed: AtomicU64,
+    /// The submissions abandoned at their deadline, once the appraisal had started.
+    timed_out: AtomicU64,
+}
+
+/// The statistics of the server, as returned by the admin API. They tell what the server holds,
+/// but never any nonce, key or wrapping key.
+#[derive(serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct Stats<'a> {
+    uptime_secs: u64,
+    pending_challenges: PendingChallenges,
+    /// The number of keys in the key store.
+    keys: usize,
+    verifications: &'a VerificationCounts,
+    /// The number of connections accepted, which tells whether the clients reuse theirs.
+    connections: u64,
+    /// The releases of the keys with a release rate, by key identifier.
+    release_rates: BTreeMap<String, ReleaseStats>,
+    /// How long ago the verification API description in use was discovered, if it was.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    verifier_discovery_age_secs: Option<u64>,
+}
+
+#[get("/stats")]
+async fn stats(data: web::Data<ServerState>, request: HttpRequest) -> impl Responder {
+    if let Some(refusal) = admin_refusal(&data.args, &request) {
+        return refusal;
+    }
+
+    let pending_challenges = data.challenger.pending();
+    let keystore = data.keystore.lock().expect("Poisoned keystore lock.");
+    let release_rates = data
+        .release_throttle
+        .lock()
+        .expect("Poisoned release throttle lock.")
+        .stats(keystore.release_rates(), Instant::now());
+    #[cfg(feature = "remote-verifier")]
+    let verifier_discovery_age_secs = data.verifier.discovery.age().map(|age| age.as_secs());
+    #[cfg(not(feature = "remote-verifier"))]
+    let verifier_discovery_age_secs = None;
+
+    HttpResponse::Ok().json(Stats {
+        uptime_secs: data.started.elapsed().as_secs(),
+        pending_challenges,
+        keys: keystore.key_count(),
+        verifications: &data.verifications,
+        connections: data.connections.load(Ordering::Relaxed),
+        release_rates,
+        verifier_discovery_age_secs,
+    })
+}
+
+/// Structure for parsing and storing the command-line arguments
+#[derive(Clone, Parser, Debug)]
+#[command(version, about, long_about = None)]
+pub struct Args {
+    /// The interface on which this server will listen (use 0.0.0.0 to listen on all interfaces)
+    #[arg(short, long, default_value = "127.0.0.1")]
+    addr: String,
+
+    /// The port on which this server will listen, or 0 to let the system choose a free one
+    #[arg(short, long, default_value_t = 8088)]
+    port: u16,
+
+    /// Write the base URL of the server, with the port it listens on, to this file once it is
+    /// listening. This is mostly useful with '--port 0'
+    #[arg(long, default_value = None)]
+    port_file: Option<PathBuf>,
+
+    /// The URL at which this server can be reached to request a key or submit an evidence.
+    /// It will be set by default to 'http://{addr}' ('https://{addr}' with --tls-cert), but this value can be overridden with
+    /// an http:// or https:// URL with an FQDN for {addr}, in order to use name resolution for example.
+    /// The port the server listens on is added, unless the URL has a port.
+    #[arg(short, long, default_value = None)]
+    endpoint: Option<String>,
+
+    /// Serve HTTPS, with the certificate chain in this PEM file. The certificate is loaded again,
+    /// with its key, on SIGHUP
+    #[arg(long, default_value = None, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// The PEM file holding the private key of the --tls-cert certificate
+    #[arg(long, default_value = None, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// Serve HTTPS with an ephemeral self-signed certificate, generated at startup for the host of
+    /// the --endpoint, or else for the --addr. Meant for demonstrations only
+    #[arg(long, default_value_t = false, conflicts_with_all = ["tls_cert", "tls_key"])]
+    tls_self_signed: bool,
+
+    /// Where to write the self-signed certificate, in PEM, for the clients to trust it
+    #[arg(
+        long,
+        default_value = "keybroker-server-cert.pem",
+        requires = "tls_self_signed"
+    )]
+    tls_cert_out: PathBuf,
+
+    /// Allow the cross-origin requests of the web pages from this origin (such as
+    /// 'http://localhost:8000'), or from any origin with '*'. Can be repeated
+    #[arg(long = "cors-origin")]
+    cors_origins: Vec<String>,
+
+    /// The URL where the verifier can be reached
+    #[cfg(feature = "remote-verifier")]
+    #[arg(long, default_value = "https://veraison.test.linaro.org:8443")]
+    verifier: String,
+
+    /// Optional verifier's custom root certificate to use for the TLS connection
+    #[cfg(feature = "remote-verifier")]
+    #[arg(long, default_value = None)]
+    verifier_root_certificate: Option<PathBuf>,
+
+    /// Credentials to present to the verifier, either 'bearer:<token-file>' for a static
+    /// bearer token, or 'oauth2:<client-id>,<secret-file>,<token-url>' for the OAuth2
+    /// client-credentials grant
+    #[cfg(feature = "remote-verifier")]
+    #[arg(long, default_value = None)]
+    verifier_auth: Option<VerifierAuth>,
+
+    /// The maximum number of evidence submissions appraised at the same time, the others waiting
+    /// for their turn, so that a burst of submissions does not open as many connections to the
+    /// verifier
+    #[cfg(feature = "remote-verifier")]
+    #[arg(long, default_value_t = 64, value_parser = clap::value_parser!(u32).range(1..))]
+    max_concurrent_verifications: u32,
+
+    /// How long the verification API description, obtained from the discovery API of the
+    /// verifier, is used for the evidence submissions before it is discovered again, in seconds
+    #[cfg(feature = "remote-verifier")]
+    #[arg(long, default_value_t = 300)]
+    discovery_ttl_secs: u64,
+
+    /// Also check that the verifier can be reached, with a discovery call made at most every 10
+    /// seconds, in the health checks, which report the server as degraded when it can't
+    #[cfg(feature = "remote-verifier")]
+    #[arg(long, default_value_t = false)]
+    health_check_verifier: bool,
+
+    /// The overall time allowed to the verifier to appraise an evidence, in seconds, including
+    /// the time spent polling sessions that the verifier is still processing
+    #[cfg(feature = "remote-verifier")]
+    #[arg(long, default_value_t = 30)]
+    verification_deadline_secs: u64,
+
+    /// The interval between two polls of a verifier session that is still processing, in milliseconds
+    #[cfg(feature = "remote-verifier")]
+    #[arg(long, default_value_t = 500)]
+    verification_poll_interval_ms: u64,
+
+    /// The engine evaluating the appraisal policies: 'embedded' to evaluate them in process, or
+    /// 'opa:<url>' to query the OPA server at that URL
+    #[cfg(feature = "remote-verifier")]
+    #[arg(long, default_value = "embedded")]
+    policy_engine: PolicyEngineKind,
+
+    /// The time allowed to the OPA server to answer a query, in milliseconds. The evidence
+    /// submission fails if it does not
+    #[cfg(feature = "remote-verifier")]
+    #[arg(long, default_value_t = 2000)]
+    policy_engine_timeout_ms: u64,
+
+    /// Submit the evidence of a supported media type to the verifier under another media type, as
+    /// '<media type>=<verifier media type>', for a verifier which registered it differently. The
+    /// clients still use the supported media type. Can be repeated
+    #[cfg(feature = "remote-verifier")]
+    #[arg(long = "media-type-alias")]
+    media_type_aliases: Vec<MediaTypeAlias>,
+
+    /// The maximum size, in bytes, of the base64-encoded evidence accepted in a submission. For a
+    /// gzip-compressed submission, this applies to the evidence once decompressed. The evidence is
+    /// capped once base64-decoded too
+    #[arg(long, default_value_t = 64 * 1024)]
+    max_evidence_size: usize,
+
+    /// Reject the key requests with members this server does not know of, with a '400 Bad Request'
+    /// and an 'InvalidKeyRequest' error, rather than ignoring them
+    #[arg(long, default_value_t = false)]
+    deny_unknown_fields: bool,
+
+    /// Use the static CCA example token nonce instead of a randomly generated one
+    #[arg(short, long, default_value_t = false)]
+    mock_challenge: bool,
+
+    /// Only release the keys if the evidence binds the wrapping key, with a realm extensible
+    /// measurement extended once with the RFC 7638 thumbprint of the wrapping key
+    #[cfg(feature = "remote-verifier")]
+    #[arg(long, default_value_t = false)]
+    bind_wrapping_key: bool,
+
+    /// Only release the keys if every submodule of the attestation result is affirming, rather than
+    /// appraising the status of each submodule as the policy of the evidence type does
+    #[cfg(feature = "remote-verifier")]
+    #[arg(long, default_value_t = false)]
+    all_submods_affirming: bool,
+
+    /// A file holding a master secret, from which the value of the keys is derived with HKDF-SHA256
+    /// (the key identifier being the info), instead of being stored
+    #[arg(long, default_value = None)]
+    master_secret_file: Option<PathBuf>,
+
+    /// A key whose value is derived from the master secret, as '<key-id>[:<length>]', with the
+    /// length in bytes. Can be repeated
+    #[arg(long = "derived-key", requires = "master_secret_file")]
+    derived_keys: Vec<DerivedKey>,
+
+    /// Derive a value from the master secret for any key identifier, not only for the --derived-key ones
+    #[arg(long, default_value_t = false, requires = "master_secret_file")]
+    derive_any_key: bool,
+
+    /// The length, in bytes, of the values derived from the master secret, unless a --derived-key
+    /// specifies another one
+    #[arg(long, default_value_t = 32, value_parser = keystore::parse_derived_key_length)]
+    derived_key_length: usize,
+
+    /// A JSON file holding the keys, with their values and attributes, or the https:// URL where
+    /// to fetch it. A value is either a string or a JSON object, released in its canonical
+    /// serialisation. The keys are loaded again, and fetched again, on SIGHUP
+    #[arg(long, default_value = None, conflicts_with = "master_secret_file")]
+    key_file: Option<KeyFileSource>,
+
+    /// A custom root certificate, in PEM, to trust for fetching the --key-file from its URL
+    #[arg(long, default_value = None, requires = "key_file")]
+    key_file_ca: Option<PathBuf>,
+
+    /// A file holding the bearer token to present when fetching the --key-file from its URL
+    #[arg(long, default_value = None, requires = "key_file")]
+    key_file_token_file: Option<PathBuf>,
+
+    /// The SHA-256 digest, in hex, that the --key-file must have to be trusted
+    #[arg(long, default_value = None, requires = "key_file", value_parser = key_file::parse_digest)]
+    key_file_digest: Option<[u8; 32]>,
+
+    /// Do not store the built-in 'skywalker' key, so that only the keys of the --key-file are held
+    #[arg(long, default_value_t = false)]
+    no_default_keys: bool,
+
+    /// Seal the key store once its keys are loaded at startup, so that nothing can change them at
+    /// runtime. Incompatible with --derive-any-key, which makes up keys on demand
+    #[arg(long, default_value_t = false, conflicts_with = "derive_any_key")]
+    keystore_read_only: bool,
+
+    /// Never release a key under the RSA1_5 wrapping algorithm, even if the key allows it
+    #[arg(long = "forbid-rsa1_5", default_value_t = false)]
+    forbid_rsa1_5: bool,
+
+    /// The minimum size of the modulus of the wrapping keys, in bits. Weaker keys are refused
+    #[arg(long, default_value_t = keystore::DEFAULT_MIN_WRAPPING_KEY_BITS, value_parser = keystore::parse_min_wrapping_key_bits)]
+    min_wrapping_key_bits: usize,
+
+    /// Treat the key identifiers as case-insensitive, by folding them to lower case
+    #[arg(long, default_value_t = false)]
+    case_insensitive_key_ids: bool,
+
+    /// Include the diagnostics of a failed verifier session (its URL, status and error messages) in
+    /// the error reported to the client, rather than only in the log. The evidence is never included
+    #[arg(long, default_value_t = false)]
+    verbose_failures: bool,
+
+    /// The overall time allowed to handle an evidence submission, in seconds, from the receipt of
+    /// the evidence to the wrapping of the key. A submission still in progress at the deadline is
+    /// answered with '504 Gateway Timeout', and counts as a failed attempt to redeem its challenge
+    /// if the appraisal of the evidence had started
+    #[arg(long, default_value_t = 60, value_parser = clap::value_parser!(u64).range(1..))]
+    request_deadline_secs: u64,
+
+    /// How long the requests in progress, such as the evidence submissions waiting for the verifier,
+    /// are given to complete when the server is stopped with SIGTERM or SIGINT, in seconds. The
+    /// server stops accepting connections straight away
+    #[arg(long, default_value_t = 30)]
+    shutdown_grace: u64,
+
+    /// How long a challenge can be redeemed for, in seconds. The evidence submitted past that is
+    /// rejected with 'ChallengeExpired', and the expired challenges are swept periodically
+    #[arg(long, default_value_t = 300, value_parser = clap::value_parser!(u64).range(1..))]
+    challenge_ttl: u64,
+
+    /// How many times the evidence can be submitted for a challenge, when the submissions fail for
+    /// a runtime error of the verifier (unreachable, answering with a server error, timing out), or
+    /// because the key was released too often, rather than for a verdict on the evidence. A failed
+    /// attestation, or a released key, always consumes the challenge
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    challenge_attempts: u32,
+
+    /// Dump evidence to file 'evidence-{challenge_id}.cbor'
+    #[arg(long, default_value_t = false)]
+    dump_evidence_cbor: bool,
+
+    /// Append the lifecycle events of the challenges to this file, as JSON lines, on top of
+    /// logging them
+    #[arg(long, default_value = None)]
+    event_log: Option<PathBuf>,
+
+    /// Append a record of each evidence submission, telling whether the key was released and why,
+    /// to this file, as JSON lines. The file is opened again on SIGHUP, so that it can be rotated
+    #[arg(long, default_value = None)]
+    audit_log: Option<PathBuf>,
+
+    /// Enable the admin operations which need authentication, such as the events stream and the
+    /// changes of the keys, for the requests presenting this token as
+    /// 'Authorization: Bearer <token>'. They are disabled without it
+    #[arg(long, default_value = None)]
+    admin_token: Option<String>,
+
+    /// A file holding the --admin-token, so that it does not show in the process list
+    #[arg(long, default_value = None, conflicts_with = "admin_token")]
+    admin_token_file: Option<PathBuf>,
+
+    /// Increase verbosity
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    pub verbosity: u8,
+
+    /// The format of the log lines: 'text', or 'json' for JSON lines carrying the challenge
+    /// identifier, key identifier and peer address of the key requests as fields of their own
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
+
+    /// Silence all output
+    #[arg(short, long, default_value_t = false)]
+    pub quiet: bool,
+
+    /// Load the keys, the reference values and the TLS certificate, report on them, and exit
+    /// without serving
+    #[arg(long, default_value_t = false)]
+    pub check: bool,
+
+    /// Read the options from this TOML file, whose keys are the long names of the options (e.g.
+    /// 'port = 8088'). The options given on the command line take precedence over those of the file
+    #[arg(long, default_value = None)]
+    config: Option<PathBuf>,
+
+    /// Print the effective configuration, merged from the command line, the configuration file
+    /// and the defaults, as a configuration file with the secrets left out, and exit
+    #[arg(long, default_value_t = false)]
+    pub print_config: bool,
+
+    /// Known-good reference values: either a file containing a JSON document with an array of
+    /// base64-encoded realm initial measurements and optionally the allowed platform
+    /// configurations and lifecycle states, or the JSON document itself
+    /// (e.g. '{ "realm-initial-measurements": [ ... ] }'), optionally prefixed with 'inline:'
+    #[arg(long, default_value = None)]
+    reference_values: Option<ReferenceValuesSource>,
+
+    /// Write the reference values appended through the admin API back to the --reference-values
+    /// file, so that they survive a restart
+    #[arg(long, default_value_t = false)]
+    persist_reference_values: bool,
+
+    /// When evidence is rejected for want of reference values, merge its realm initial measurement
+    /// into a reference values file at this path, ready to be given to --reference-values
+    #[arg(long, default_value = None)]
+    suggested_reference_values_out: Option<PathBuf>,
+}
+
+struct ServerState {
+    args: Args,
+    started: Instant,
+    endpoint: Endpoint,
+    keystore: Mutex<KeyStore>,
+    key_id_policy: KeyIdPolicy,
+    /// Sharded, so that the key requests and the evidence submissions do not wait on each other.
+    challenger: Challenger,
+    /// Kept apart from the key store, so that it is not reset when the keys are loaded again.
+    release_throttle: Mutex<ReleaseThrottle>,
+    verifications: VerificationCounts,
+    connections: AtomicU64,
+    events: EventBus,
+    audit: AuditLog,
+    /// The verifier, with its verification API description once discovered.
+    #[cfg(feature = "remote-verifier")]
+    verifier: Arc<Verifier>,
+    /// The permits of the verifications which can run at the same time.
+    #[cfg(feature = "remote-verifier")]
+    verification_permits: Arc<Semaphore>,
+    /// The media types offered in the challenges, as last derived from the discovery API of the
+    /// verifier, with when they go stale.
+    #[cfg(feature = "remote-verifier")]
+    offered_media_types: Mutex<Option<(Instant, Vec<String>)>>,
+    /// Whether the verifier answered the last health check, with when that goes stale.
+    #[cfg(feature = "remote-verifier")]
+    verifier_reachability: Mutex<Option<(Instant, bool)>>,
+    reference_values: Arc<ReferenceValuesStore>,
+}
+
+#[cfg(feature = "remote-verifier")]
+impl ServerState {
+    /// The verifier, as configured on the command line.
+    fn verifier(&self) -> Arc<Verifier> {
+        self.verifier.clone()
+    }
+}
+
+/// How often the expired challenges are swept.
+const CHALLENGE_SWEEP_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Build the keybroker server from the command-line arguments.
+///
+/// The returned server is not started: it needs to be awaited (or spawned) to start serving requests.
+/// It stops on SIGTERM, SIGINT and SIGQUIT as any actix-web server does, the requests in progress
+/// being given the shutdown grace period on SIGTERM only.
+pub fn build_server(args: Args) -> std::io::Result<Server> {
+    build(args, true).map(|(server, _)| server)
+}
+
+/// Build the keybroker server from the command-line arguments, as [`build_server`] does, but
+/// without any handling of the termination signals: the server is stopped with the returned
+/// [`Shutdown`], which lets the requests in progress complete.
+pub fn build_server_with_shutdown(args: Args) -> std::io::Result<(Server, Shutdown)> {
+    build(args, false)
+}
+
+/// The graceful shutdown of a server built with [`build_server_with_shutdown`].
+#[derive(Clone)]
+pub struct Shutdown {
+    data: web::Data<ServerState>,
+    handle: ServerHandle,
+}
+
+impl Shutdown {
+    /// Stop the server, for the given `reason`, letting the requests in progress complete within
+    /// the shutdown grace period. The outstanding challenges are logged, as their clients will
+    /// have to request their key again.
+    pub async fn stop(&self, reason: &str) {
+        let outstanding = self.data.challenger.pending().count;
+        log::warn!(
+            "{reason}, shutting down once the requests in progress are handled, within {} \
+             seconds. {outstanding} challenges are outstanding, their clients will have to request \
+             their key again.",
+            self.data.args.shutdown_grace
+        );
+        self.handle.stop(true).await;
+        log::info!("Shut down.");
+    }
+}
+
+/// Build the keybroker server, with the default handling of the termination signals or not.
+fn build(mut args: Args, handle_signals: bool) -> std::io::Result<(Server, Shutdown)> {
+    if let Some(admin_token_file) = &args.admin_token_file {
+        args.admin_token = Some(read_admin_token(admin_token_file)?);
+    }
+
+    let challenger = Challenger::new(Duration::from_secs(args.challenge_ttl));
+
+    let key_id_policy = KeyIdPolicy {
+        fold_case: args.case_insensitive_key_ids,
+    };
+
+    let (keystore, _) = build_keystore(&args, &key_id_policy)?;
+
+    #[cfg(feature = "remote-verifier")]
+    let policy_engine: Arc<dyn PolicyEngine> = match &args.policy_engine {
+        PolicyEngineKind::Embedded => Arc::new(EmbeddedEngine),
+        PolicyEngineKind::Opa(url) => {
+            log::info!("The appraisal policies are evaluated by the OPA server at {url}");
+            Arc::new(OpaEngine::new(
+                url.clone(),
+                Duration::from_millis(args.policy_engine_timeout_ms),
+            ))
+        }
+    };
+
+    #[cfg(feature = "remote-verifier")]
+    let verifier_client = Arc::new(
+        VerifierAuthenticator::new(args.verifier_auth.as_ref(), &args.verifier_root_certificate)
+            .map_err(std::io::Error::other)?,
+    );
+
+    #[cfg(feature = "remote-verifier")]
+    let verifier = Arc::new(Verifier {
+        base_url: args.verifier.clone(),
+        root_certificate: args.verifier_root_certificate.clone(),
+        client: verifier_client,
+        polling: SessionPolling {
+            interval: Duration::from_millis(args.verification_poll_interval_ms),
+            deadline: Duration::from_secs(args.verification_deadline_secs),
+        },
+        policy_engine,
+        discovery: DiscoveryCache::new(Duration::from_secs(args.discovery_ttl_secs)),
+    });
+
+    #[cfg(feature = "remote-verifier")]
+    for (index, alias) in args.media_type_aliases.iter().enumerate() {
+        if args.media_type_aliases[..index]
+            .iter()
+            .any(|other| other.from == alias.from)
+        {
+            return Err(std::io::Error::other(format!(
+                "The media type '{}' has more than one --media-type-alias.",
+                alias.from
+            )));
+        }
+    }
+
+    #[cfg(feature = "remote-verifier")]
+    if args.request_deadline_secs <= args.verification_deadline_secs {
+        log::warn!(
+            "The request deadline ({}s) is not longer than the verification deadline ({}s): slow \
+             verifications will be cut short by the request deadline.",
+            args.request_deadline_secs,
+            args.verification_deadline_secs
+        );
+    }
+
+    let events = match &args.event_log {
+        Some(event_log) => EventBus::with_event_log(event_log).map_err(|error| {
+            std::io::Error::other(format!(
+                "Failed to open the event log {}: {error}",
+                event_log.display()
+            ))
+        })?,
+        None => EventBus::default(),
+    };
+
+    let audit = match &args.audit_log {
+        Some(audit_log) => AuditLog::open(audit_log).map_err(|error| {
+            std::io::Error::other(format!(
+                "Failed to open the audit log {}: {error}",
+                audit_log.display()
+            ))
+        })?,
+        None => AuditLog::default(),
+    };
+
+    let reference_values = Arc::new(
+        ReferenceValuesStore::new(args.reference_values.clone()).map_err(std::io::Error::other)?,
+    );
+
+    let certificate_resolver = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => Some(Arc::new(
+            CertificateResolver::load(cert, key).map_err(std::io::Error::other)?,
+        )),
+        _ if args.tls_self_signed => Some(Arc::new(self_signed_certificate(&args)?)),
+        _ => None,
+    };
+
+    // Bind before building the endpoint, as the port is only known once bound with '--port 0'.
+    let listener = TcpListener::bind((args.addr.as_str(), args.port))?;
+    let local_addr = listener.local_addr()?;
+    let scheme = if certificate_resolver.is_some() {
+        "https"
+    } else {
+        "http"
+    };
+    let endpoint = Endpoint::new(
+        args.endpoint.as_deref(),
+        &args.addr,
+        local_addr.port(),
+        certificate_resolver.is_some(),
+    )?;
+    log::info!("listening on {scheme}://{local_addr}");
+    if args.tls_self_signed {
+        log::info!(
+            "The clients must trust the self-signed certificate, for instance: \
+             keybroker-app --endpoint {endpoint} --ca-cert {} <key-name>",
+            args.tls_cert_out.display()
+        );
+    }
+
+    if let Some(port_file) = &args.port_file {
+        write_port_file(port_file, &endpoint).map_err(|error| {
+            std::io::Error::other(format!(
+                "Failed to write the port file {}: {error}",
+                port_file.display()
+            ))
+        })?;
+    }
+
+    let server_state = ServerState {
+        args: args.clone(),
+        started: Instant::now(),
+        endpoint,
+        keystore: Mutex::new(keystore),
+        key_id_policy,
+        challenger,
+        release_throttle: Mutex::new(ReleaseThrottle::new()),
+        verifications: VerificationCounts::default(),
+        connections: AtomicU64::new(0),
+        events,
+        audit,
+        #[cfg(feature = "remote-verifier")]
+        verifier,
+        #[cfg(feature = "remote-verifier")]
+        verification_permits: Arc::new(Semaphore::new(args.max_concurrent_verifications as usize)),
+        #[cfg(feature = "remote-verifier")]
+        offered_media_types: Mutex::new(None),
+        #[cfg(feature = "remote-verifier")]
+        verifier_reachability: Mutex::new(None),
+        reference_values,
+    };
+
+    let app_data = web::Data::new(server_state);
+
+    // Reload the reference values, the keys and the TLS certificate on SIGHUP, as is customary for
+    // configuration files, and open the audit log again, as is customary for log files.
+    #[cfg(unix)]
+    {
+        use actix_web::rt::signal::unix::{signal, SignalKind};
+
+        let mut hangup = signal(SignalKind::hangup())?;
+        let data = app_data.clone();
+        let certificate_resolver = certificate_resolver.clone();
+        actix_web::rt::spawn(async move {
+            while hangup.recv().await.is_some() {
+                log::info!("SIGHUP received, reloading the configuration files.");
+                data.audit.reopen();
+                // Failures are logged by the store, and the previous values are kept.
+                let _ = data.reference_values.reload();
+                if data.args.key_file.is_some() {
+                    // The key file may have to be fetched again.
+                    let data = data.clone();
+                    let _ = task::spawn_blocking(move || reload_keys(&data)).await;
+                }
+                if let Some(certificate_resolver) = &certificate_resolver {
+                    // Likewise, the previous certificate is kept on failure.
+                    let _ = certificate_resolver.reload();
+                }
+            }
+        });
+    }
+
+    // Sweep the expired challenges, which the clients will never come back for.
+    let data = app_data.clone();
+    actix_web::rt::spawn(async move {
+        let mut sweep = time::interval(CHALLENGE_SWEEP_INTERVAL);
+        loop {
+            sweep.tick().await;
+            let expired = data.challenger.sweep_expired();
+            for challenge in expired {
+                data.events.publish(
+                    Transition::ChallengeExpired,
+                    challenge.challenge_id,
+                    &challenge.key_id,
+                    challenge.correlation_id.as_deref(),
+                    Outcome::Error(ErrorCode::ChallengeExpired),
+                );
+            }
+        }
+    });
+
+    let cors_policy = CorsPolicy::new(args.cors_origins.clone());
+    let connection_data = app_data.clone();
+    let shutdown_data = app_data.clone();
+
+    let server = HttpServer::new(move || {
+        // Only the key broker API is for the pages of other origins: the admin API is not.
+        let cors_policy = cors_policy.clone();
+        let scope = web::scope("/keys/v1")
+            .wrap_fn(move |request, service| cors::handle(&cors_policy, request, service))
+            .app_data(json_config(ErrorCode::InvalidKeyRequest))
+            .service(request_key)
+            .service(submit_evidence)
+            .service(cancel_challenge)
+            .service(server_info)
+            .service(health)
+            .service(readiness);
+        let admin_scope = web::scope("/admin/v1")
+            .app_data(json_config(ErrorCode::InvalidReferenceValues))
+            .service(reload_reference_values)
+            .service(append_reference_values)
+            .service(stats)
+            .service(list_keys)
+            .service(put_key)
+            .service(delete_key)
+            .service(events_stream);
+        App::new()
+            .app_data(app_data.clone())
+            .service(scope)
+            .service(admin_scope)
+    })
+    .on_connect(move |_, _| {
+        connection_data.connections.fetch_add(1, Ordering::Relaxed);
+    });
+
+    let server = match certificate_resolver {
+        Some(certificate_resolver) => {
+            server.listen_rustls_0_23(listener, certificate_resolver.server_config())?
+        }
+        None => server.listen(listener)?,
+    };
+
+    // The requests in progress, such as the evidence submissions waiting for the verifier, are
+    // given some time to complete when the server is stopped.
+    let server = server.shutdown_timeout(args.shutdown_grace);
+    let server = if handle_signals {
+        server.run()
+    } else {
+        server.disable_signals().run()
+    };
+    let shutdown = Shutdown {
+        data: shutdown_data,
+        handle: server.handle(),
+    };
+
+    Ok((server, shutdown))
+}
+
+/// Check the configuration given by the command-line arguments, loading what the server loads at
+/// startup, and report on it.
+pub fn check_configuration(args: &Args) -> std::io::Result<String> {
+    let key_id_policy = KeyIdPolicy {
+        fold_case: args.case_insensitive_key_ids,
+    };
+    let (keystore, default_keys) = build_keystore(args, &key_id_policy)?;
+
+    let mut report = String::new();
+    let keys: Vec<String> = keystore
+        .list_keys()
+        .into_iter()
+        .map(|listing| listing.id)
+        .collect();
+    if keys.is_empty() {
+        report.push_str("Keys: none\n");
+    } else {
+        report.push_str(&format!("Keys: {} ({})\n", keys.len(), keys.join(", ")));
+    }
+    report.push_str(match (default_keys, args.no_default_keys) {
+        (true, _) => "Default keys: active (skywalker)\n",
+        (false, true) => "Default keys: disabled (--no-default-keys)\n",
+        (false, false) if args.master_secret_file.is_some() => {
+            "Default keys: not used with --master-secret-file\n"
+        }
+        (false, false) => "Default keys: replaced by the key file\n",
+    });
+
+    match &args.reference_values {
+        Some(source) => {
+            source.load().map_err(std::io::Error::other)?;
+            report.push_str(&format!("Reference values: loaded from {source}\n"));
+        }
+        None => report.push_str("Reference values: none\n"),
+    }
+
+    if let (Some(cert), Some(key)) = (&args.tls_cert, &args.tls_key) {
+        CertificateResolver::load(cert, key).map_err(std::io::Error::other)?;
+        report.push_str(&format!(
+            "TLS certificate: loaded from {}\n",
+            cert.display()
+        ));
+    }
+
+    Ok(report)
+}
+
+/// Load the keys again from the key file, fetching it again if it comes from a URL. The previous
+/// keys are kept on failure. The key file is the source of truth: a read-only key store is reloaded
+/// as well, and the keys stored or removed through the admin API since are not kept.
+fn reload_keys(data: &ServerState) {
+    match build_keystore(&data.args, &data.key_id_policy) {
+        Ok((keystore, _)) => {
+            let count = keystore.key_count();
+            *data.keystore.lock().expect("Poisoned keystore lock.") = keystore;
+            log::info!("Reloaded the keys, {count} of them.");
+        }
+        Err(error) => {
+            log::error!("The keys could not be reloaded, the previous ones are kept. {error}")
+        }
+    }
+}
+
+/// The admin token held by a file, without the trailing newline.
+fn read_admin_token(admin_token_file: &Path) -> std::io::Result<String> {
+    let admin_token = std::fs::read_to_string(admin_token_file).map_err(|error| {
+        std::io::Error::other(format!(
+            "Failed to read the admin token from {}: {error}",
+            admin_token_file.display()
+        ))
+    })?;
+    let admin_token = admin_token.trim();
+    if admin_token.is_empty() {
+        return Err(std::io::Error::other(format!(
+            "The admin token file {} is empty.",
+            admin_token_file.display()
+        )));
+    }
+    Ok(admin_token.to_string())
+}
+
+/// Build the key store from the command-line arguments, telling whether the built-in key was
+/// stored.
+fn build_keystore(args: &Args, key_id_policy: &KeyIdPolicy) -> std::io::Result<(KeyStore, bool)> {
+    let mut default_keys = false;
+    let mut keystore = match &args.master_secret_file {
+        Some(master_secret_file) => {
+            let master_secret = std::fs::read(master_secret_file)
+                .map(Zeroizing::new)
+                .map_err(|error| {
+                    std::io::Error::other(format!(
+                        "Failed to read the master secret from {}: {error}",
+                        master_secret_file.display()
+                    ))
+                })?;
+            if master_secret.is_empty() {
+                return Err(std::io::Error::other(format!(
+                    "The master secret file {} is empty.",
+                    master_secret_file.display()
+                )));
+            }
+
+            let mut derivation = KeyDerivation::new(master_secret);
+            for derived_key in &args.derived_keys {
+                derivation.declare_key(
+                    &key_id_policy
+                        .normalise(&derived_key.key_id)
+                        .map_err(std::io::Error::other)?,
+                    derived_key.length.unwrap_or(args.derived_key_length),
+                );
+            }
+            if args.derive_any_key {
+                derivation.derive_any_key(args.derived_key_length);
+            }
+            KeyStore::with_derivation(derivation)
+        }
+        None => {
+            let mut keystore = KeyStore::new();
+            if let Some(key_file) = &args.key_file {
+                let options = KeyFileOptions {
+                    root_certificate: args.key_file_ca.as_deref(),
+                    token_file: args.key_file_token_file.as_deref(),
+                    digest: args.key_file_digest,
+                };
+                let keys = key_file::load_key_file(key_file, &options, key_id_policy).map_err(
+                    |error| {
+                        std::io::Error::other(format!(
+                            "Failed to load the keys from {key_file}: {error}"
+                        ))
+                    },
+                )?;
+                for key in keys {
+                    keystore
+                        .store_key_with_attributes(&key.key_id, key.data, key.attributes)
+                        .map_err(std::io::Error::other)?;
+                    for alias in key.aliases {
+                        keystore.add_alias(&alias, &key.key_id).map_err(|error| {
+                            std::io::Error::other(format!(
+                                "Failed to load the keys from {key_file}: {error}"
+                            ))
+                        })?;
+                    }
+                }
+            }
+
+            // The keys from the key file come on top of the built-in one, which they can replace,
+            // either with a key or with an alias.
+            let builtin_key_id = key_id_policy
+                .normalise("skywalker")
+                .map_err(std::io::Error::other)?;
+            if !args.no_default_keys && !keystore.contains_key(&builtin_key_id) {
+                keystore
+                    .store_key(
+                        &builtin_key_id,
+                        "May the force be with you.".as_bytes().to_vec(),
+                    )
+                    .map_err(std::io::Error::other)?;
+                default_keys = true;
+            }
+            if keystore.key_count() == 0 {
+                log::warn!(
+                    "The key store is empty: the built-in key is disabled by --no-default-keys, \
+                     and no key was loaded from a key file. All the key requests will fail."
+                );
+            }
+            keystore
+        }
+    };
+
+    if args.forbid_rsa1_5 {
+        keystore.forbid_rsa1_5();
+    }
+    keystore.set_min_wrapping_key_bits(args.min_wrapping_key_bits);
+    if args.keystore_read_only {
+        keystore.make_read_only();
+    }
+
+    Ok((keystore, default_keys))
+}
+
+/// Generate the self-signed certificate of the server, and write it out for the clients.
+fn self_signed_certificate(args: &Args) -> std::io::Result<CertificateResolver> {
+    // The certificate is for the host the clients are told to connect to.
+    let endpoint_host = args
+        .endpoint
+        .as_deref()
+        .and_then(|endpoint| reqwest::Url::parse(endpoint).ok())
+        .and_then(|url| url.host_str().map(str::to_string));
+    let host = match endpoint_host {
+        Some(host) => host.trim_matches(['[', ']']).to_string(),
+        None => match args.addr.parse::<IpAddr>() {
+            Ok(ip) if ip.is_unspecified() => "localhost".to_string(),
+            _ => args.addr.clone(),
+        },
+    };
+
+    let (resolver, pem) =
+        CertificateResolver::self_signed(vec![host.clone()]).map_err(std::io::Error::other)?;
+    std::fs::write(&args.tls_cert_out, pem).map_err(|error| {
+        std::io::Error::other(format!(
+            "Failed to write the self-signed certificate to {}: {error}",
+            args.tls_cert_out.display()
+        ))
+    })?;
+    log::info!(
+        "Generated a self-signed certificate for {host}, written to {}.",
+        args.tls_cert_out.display()
+    );
+
+    Ok(resolver)
+}
+
+/// Write the base URL of the server to the port file, atomically so that a harness polling for the
+/// file never reads it half-written.
+fn write_port_file(port_file: &Path, endpoint: &Endpoint) -> std::io::Result<()> {
+    let mut temporary = port_file.as_os_str().to_owned();
+    temporary.push(".tmp");
+    std::fs::write(&temporary, format!("{endpoint}\n"))?;
+    std::fs::rename(&temporary, port_file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_web::test]
+    async fn admin_task_aborted() {
+        let response = admin_task("reload of the reference values", || -> error::Result<()> {
+            panic!("The reload panicked.")
+        })
+        .await
+        .expect_err("The aborted task was not reported.");
+        assert_eq!(response.status(), http::StatusCode::INTERNAL_SERVER_ERROR);
+
+        let body = actix_web::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        let error_info: ErrorInformation = serde_json::from_slice(&body).unwrap();
+        assert_eq!(error_info.r#type, ErrorCode::AdminOperationAborted);
+        assert!(error_info.detail.contains("reload of the reference values"));
+    }
+
+    #[actix_web::test]
+    async fn admin_task_completed() {
+        let outcome = admin_task("reload of the reference values", || Ok(42)).await;
+        assert!(matches!(outcome, Ok(Ok(42))));
+    }
+}
@@ -0,0 +1,334 @@
+// Copyright 2024 Contributors to the Veraison project.
+// SPDX-License-Identifier: Apache-2.0
+
+//! The lifecycle events of the challenges, one for each transition, so that the protocol can be
+//! followed, for example to visualise it for teaching.
+//!
+//! The events are logged, and written as JSON lines to the file given with `--event-log`:
+//!
+//! ```json
+//! {"timestamp":"2024-11-06T10:20:34.512Z","event":"challenge-created","challenge-id":1234,"key-id":"skywalker","outcome":"ok"}
+//! ```
+//!
+//! The events of a key request for which the client gave a correlation identifier also have a
+//! `correlation-id` member.
+//!
+//! The handlers publish the events to a channel, which a writer thread drains, so that they never
+//! wait for the file.
+//!
+//! The events are also streamed to the subscribers of `GET /admin/v1/events`, as server-sent
+//! events. Each subscriber has a bounded buffer, and is disconnected when it falls behind, so that
+//! a stuck dashboard never holds the broker back.
+use actix_web::web::Bytes;
+use chrono::{SecondsFormat, Utc};
+use keybroker_common::ErrorCode;
+use serde::{Serialize, Serializer};
+use std::convert::Infallible;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Mutex;
+
+/// The number of events buffered for each subscriber of the events stream. A subscriber this far
+/// behind is disconnected.
+const SUBSCRIBER_BUFFER: usize = 64;
+
+/// A chunk of the events stream, as the body of a streaming response takes it.
+pub type EventChunk = Result<Bytes, Infallible>;
+
+/// The transitions in the lifecycle of a challenge, in the order a successful key request goes
+/// through them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Transition {
+    /// The challenge was issued for a key request.
+    ChallengeCreated,
+
+    /// Evidence was received for the challenge, which is consumed.
+    EvidenceReceived,
+
+    /// The evidence was handed to the verifier.
+    VerificationStarted,
+
+    /// The verifier returned an attestation result, or failed to.
+    VerificationFinished,
+
+    /// The attestation result was appraised against the policy.
+    PolicyDecision,
+
+    /// The key was wrapped, or could not be released.
+    KeyWrapped,
+
+    /// The client cancelled the challenge, or the submission was abandoned at its deadline after
+    /// the challenge was consumed.
+    ChallengeCancelled,
+
+    /// The challenge expired before any evidence was submitted for it, and was swept.
+    ChallengeExpired,
+}
+
+impl fmt::Display for Transition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Transition::ChallengeCreated => "challenge created",
+            Transition::EvidenceReceived => "evidence received",
+            Transition::VerificationStarted => "verification started",
+            Transition::VerificationFinished => "verification finished",
+            Transition::PolicyDecision => "policy decision",
+            Transition::KeyWrapped => "key wrapped",
+            Transition::ChallengeCancelled => "challenge cancelled",
+            Transition::ChallengeExpired => "challenge expired",
+        })
+    }
+}
+
+/// The outcome of a transition, serialised as `ok`, `allowed`, `denied` or the code of the error.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Ok,
+
+    /// The attestation result is in policy.
+    Allowed,
+
+    /// The attestation result is not in policy.
+    Denied,
+
+    /// The transition failed with this error.
+    Error(ErrorCode),
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Outcome::Ok => "ok",
+            Outcome::Allowed => "allowed",
+            Outcome::Denied => "denied",
+            Outcome::Error(code) => code.as_str(),
+        })
+    }
+}
+
+impl Serialize for Outcome {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// A transition of a challenge.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct LifecycleEvent {
+    /// When the transition happened, in RFC 3339 with milliseconds.
+    pub timestamp: String,
+    pub event: Transition,
+    pub challenge_id: u32,
+    pub key_id: String,
+
+    /// The correlation identifier of the key request, if the client gave one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+    pub outcome: Outcome,
+}
+
+/// The suffix which tells the correlation identifier of a key request in the log lines, if the
+/// client gave one.
+pub fn correlation_suffix(correlation_id: Option<&str>) -> String {
+    correlation_id
+        .map(|correlation_id| format!(" (correlation ID {correlation_id})"))
+        .unwrap_or_default()
+}
+
+/// Where the lifecycle events are published.
+#[derive(Debug, Default)]
+pub struct EventBus {
+    /// The channel to the writer of the event log, if there is one.
+    event_log: Option<Sender<LifecycleEvent>>,
+
+    /// The channels to the subscribers of the events stream.
+    subscribers: Mutex<Vec<futures_channel::mpsc::Sender<EventChunk>>>,
+}
+
+impl EventBus {
+    /// Also write the events to a file, which is created or appended to.
+    pub fn with_event_log(path: &Path) -> std::io::Result<EventBus> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let (sender, receiver) = mpsc::channel();
+        std::thread::Builder::new()
+            .name("event-log".to_string())
+            .spawn(move || write_events(receiver, file))?;
+
+        Ok(EventBus {
+            event_log: Some(sender),
+            subscribers: Mutex::default(),
+        })
+    }
+
+    /// Subscribe to the events stream, as server-sent events. The stream ends when the subscriber
+    /// falls behind.
+    pub fn subscribe(&self) -> futures_channel::mpsc::Receiver<EventChunk> {
+        let (mut sender, receiver) = futures_channel::mpsc::channel(SUBSCRIBER_BUFFER);
+        // Start with a comment, so that the subscriber knows it is subscribed before any event.
+        let _ = sender.try_send(Ok(Bytes::from_static(b": subscribed\n\n")));
+        self.subscribers
+            .lock()
+            .expect("Poisoned subscribers lock.")
+            .push(sender);
+
+        receiver
+    }
+
+    /// Publish a transition of a challenge, with the correlation identifier of its key request.
+    pub fn publish(
+        &self,
+        event: Transition,
+        challenge_id: u32,
+        key_id: &str,
+        correlation_id: Option<&str>,
+        outcome: Outcome,
+    ) {
+        log::info!(
+            "Challenge {challenge_id} for key '{key_id}'{}: {event} ({outcome}).",
+            correlation_suffix(correlation_id)
+        );
+
+        let event = LifecycleEvent {
+            timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            event,
+            challenge_id,
+            key_id: key_id.to_string(),
+            correlation_id: correlation_id.map(str::to_string),
+            outcome,
+        };
+
+        let mut subscribers = self.subscribers.lock().expect("Poisoned subscribers lock.");
+        if !subscribers.is_empty() {
+            let chunk = Bytes::from(format!(
+                "data: {}\n\n",
+                serde_json::to_string(&event).expect("Failed to serialise a lifecycle event.")
+            ));
+            // Drop the subscribers which went away, and those which fell behind.
+            subscribers.retain_mut(|subscriber| match subscriber.try_send(Ok(chunk.clone())) {
+                Ok(()) => true,
+                Err(error) => {
+                    if error.is_full() {
+                        log::warn!(
+                            "Disconnected a subscriber of the events stream, \
+                             {SUBSCRIBER_BUFFER} events behind."
+                        );
+                    }
+                    false
+                }
+            });
+        }
+        drop(subscribers);
+
+        if let Some(event_log) = &self.event_log {
+            // The writer only goes away with the bus.
+            let _ = event_log.send(event);
+        }
+    }
+}
+
+/// Write the events, one JSON document per line, until the bus goes away.
+fn write_events(events: Receiver<LifecycleEvent>, mut out: impl Write) {
+    for event in events {
+        let mut line = serde_json::to_vec(&event).expect("Failed to serialise a lifecycle event.");
+        line.push(b'\n');
+
+        // The event log is only informative: failing to write it must not stop the server.
+        if let Err(error) = out.write_all(&line).and_then(|_| out.flush()) {
+            log::warn!("Failed to write a lifecycle event: {error}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn event_lines() {
+        let (sender, receiver) = mpsc::channel();
+        let bus = EventBus {
+            event_log: Some(sender),
+            subscribers: Mutex::default(),
+        };
+        bus.publish(
+            Transition::ChallengeCreated,
+            1234,
+            "skywalker",
+            None,
+            Outcome::Ok,
+        );
+        bus.publish(
+            Transition::PolicyDecision,
+            1234,
+            "skywalker",
+            Some("job-42"),
+            Outcome::Denied,
+        );
+        bus.publish(
+            Transition::KeyWrapped,
+            1234,
+            "skywalker",
+            None,
+            Outcome::Error(ErrorCode::ReleaseRateExceeded),
+        );
+        drop(bus);
+
+        let mut out = Vec::new();
+        write_events(receiver, &mut out);
+        let events: Vec<serde_json::Value> = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[0]["event"], "challenge-created");
+        assert_eq!(events[0]["challenge-id"], 1234);
+        assert_eq!(events[0]["key-id"], "skywalker");
+        assert_eq!(events[0]["outcome"], "ok");
+        assert!(events[0].get("correlation-id").is_none());
+        assert_eq!(events[1]["outcome"], "denied");
+        assert_eq!(events[1]["correlation-id"], "job-42");
+        assert_eq!(events[2]["event"], "key-wrapped");
+        assert_eq!(events[2]["outcome"], "ReleaseRateExceeded");
+        assert!(
+            chrono::DateTime::parse_from_rfc3339(events[0]["timestamp"].as_str().unwrap()).is_ok()
+        );
+    }
+
+    #[test]
+    fn slow_subscriber() {
+        let bus = EventBus::default();
+        let mut slow = bus.subscribe();
+        let mut gone = bus.subscribe();
+        gone.close();
+
+        for challenge_id in 0..=SUBSCRIBER_BUFFER as u32 {
+            bus.publish(
+                Transition::ChallengeCreated,
+                challenge_id,
+                "skywalker",
+                None,
+                Outcome::Ok,
+            );
+        }
+        assert!(bus.subscribers.lock().unwrap().is_empty());
+
+        // The subscriber gets the events it had room for, then the end of the stream.
+        let mut chunks = Vec::new();
+        while let Ok(Ok(chunk)) = slow.try_recv() {
+            chunks.push(chunk);
+        }
+        assert_eq!(&chunks[0][..], b": subscribed\n\n");
+        let first: serde_json::Value =
+            serde_json::from_slice(chunks[1].strip_prefix(b"data: ").unwrap()).unwrap();
+        assert_eq!(first["event"], "challenge-created");
+        assert_eq!(first["challenge-id"], 0);
+        assert!(chunks.len() > SUBSCRIBER_BUFFER / 2);
+    }
+}
@@ -0,0 +1,265 @@
+// Copyright 2024 Contributors to the Veraison project.
+// SPDX-License-Identifier: Apache-2.0
+
+//! The logging of the server, to standard error, either as text for a terminal, or as JSON lines
+//! for a log collector, with `--log-format json`:
+//!
+//! ```json
+//! {"timestamp":"2024-11-06T10:20:35.104Z","level":"INFO","module":"keybroker_server","message":"Evidence submitted for challenge 1923965078: verification succeeded !","challenge-id":1923965078,"key-id":"skywalker","peer":"192.0.2.7"}
+//! ```
+//!
+//! The log lines about a key request carry its challenge identifier, key identifier and peer
+//! address as structured fields, which the JSON lines have as members of their own, named as in
+//! the lifecycle events and the audit log. The text lines only have the message, as they always
+//! did. These fields are gathered once per request in a `RequestFields`, which the log lines are
+//! written with by `log_request!`.
+use chrono::{SecondsFormat, Utc};
+use clap::ValueEnum;
+use log::kv::{Key, Source, Value, VisitSource};
+use log::{LevelFilter, Log, Metadata, Record};
+use serde_json::Map;
+use std::io::Write;
+
+/// The format of the log lines.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    /// Plain text, for a terminal.
+    #[default]
+    Text,
+
+    /// JSON lines, for a log collector.
+    Json,
+}
+
+/// Log to standard error in the given format, at the level given by the number of `-v`: the
+/// warnings and errors only by default, and nothing when quiet.
+pub fn init(format: LogFormat, verbosity: u8, quiet: bool) {
+    match format {
+        LogFormat::Text => stderrlog::new()
+            .quiet(quiet)
+            .verbosity(1 + usize::from(verbosity))
+            .init()
+            .unwrap(),
+        LogFormat::Json => {
+            let level = if quiet {
+                LevelFilter::Off
+            } else {
+                match verbosity {
+                    0 => LevelFilter::Warn,
+                    1 => LevelFilter::Info,
+                    2 => LevelFilter::Debug,
+                    _ => LevelFilter::Trace,
+                }
+            };
+            log::set_boxed_logger(Box::new(JsonLogger { level })).unwrap();
+            log::set_max_level(level);
+        }
+    }
+}
+
+/// A logger writing JSON lines to standard error.
+struct JsonLogger {
+    level: LevelFilter,
+}
+
+impl Log for JsonLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            let mut line = json_line(record);
+            line.push('\n');
+            // There is nowhere to report the failure to log.
+            let _ = std::io::stderr().lock().write_all(line.as_bytes());
+        }
+    }
+
+    fn flush(&self) {
+        let _ = std::io::stderr().flush();
+    }
+}
+
+/// The structured fields of the log lines about a key request, those not known yet being left out.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RequestFields<'a> {
+    challenge_id: Option<u32>,
+    key_id: Option<&'a str>,
+    peer: &'a str,
+}
+
+impl<'a> RequestFields<'a> {
+    /// The fields of a request from `peer`.
+    pub(crate) fn new(peer: &'a str) -> Self {
+        RequestFields {
+            challenge_id: None,
+            key_id: None,
+            peer,
+        }
+    }
+
+    /// The fields, with the challenge the request is about.
+    pub(crate) fn challenge(self, challenge_id: u32) -> Self {
+        RequestFields {
+            challenge_id: Some(challenge_id),
+            ..self
+        }
+    }
+
+    /// The fields, with the key the request is about.
+    pub(crate) fn key(self, key_id: &'a str) -> Self {
+        RequestFields {
+            key_id: Some(key_id),
+            ..self
+        }
+    }
+}
+
+impl Source for RequestFields<'_> {
+    fn visit<'kvs>(&'kvs self, visitor: &mut dyn VisitSource<'kvs>) -> Result<(), log::kv::Error> {
+        if let Some(challenge_id) = self.challenge_id {
+            visitor.visit_pair(Key::from("challenge_id"), Value::from(challenge_id))?;
+        }
+        if let Some(key_id) = self.key_id {
+            visitor.visit_pair(Key::from("key_id"), Value::from(key_id))?;
+        }
+        visitor.visit_pair(Key::from("peer"), Value::from(self.peer))
+    }
+
+    fn count(&self) -> usize {
+        usize::from(self.challenge_id.is_some()) + usize::from(self.key_id.is_some()) + 1
+    }
+}
+
+/// Log a message about a key request at the given level, with its [`RequestFields`], as the `log`
+/// macros do.
+macro_rules! log_request {
+    ($level:expr, $fields:expr, $($arg:tt)+) => {{
+        let level: log::Level = $level;
+        if level <= log::max_level() {
+            log::logger().log(
+                &log::Record::builder()
+                    .level(level)
+                    .target(module_path!())
+                    .module_path_static(Some(module_path!()))
+                    .file_static(Some(file!()))
+                    .line(Some(line!()))
+                    .key_values(&$fields)
+                    .args(format_args!($($arg)+))
+                    .build(),
+            );
+        }
+    }};
+}
+pub(crate) use log_request;
+
+/// The fields of a log record, as members of its JSON line.
+struct Fields<'a>(&'a mut Map<String, serde_json::Value>);
+
+impl<'kvs> VisitSource<'kvs> for Fields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), log::kv::Error> {
+        let value = if let Some(number) = value.to_u64() {
+            number.into()
+        } else if let Some(number) = value.to_i64() {
+            number.into()
+        } else if let Some(boolean) = value.to_bool() {
+            boolean.into()
+        } else {
+            value.to_string().into()
+        };
+        self.0.insert(key.as_str().replace('_', "-"), value);
+        Ok(())
+    }
+}
+
+/// The JSON line of a log record.
+fn json_line(record: &Record) -> String {
+    let mut line = Map::new();
+    line.insert(
+        "timestamp".to_string(),
+        Utc::now()
+            .to_rfc3339_opts(SecondsFormat::Millis, true)
+            .into(),
+    );
+    line.insert("level".to_string(), record.level().as_str().into());
+    line.insert(
+        "module".to_string(),
+        record.module_path().unwrap_or(record.target()).into(),
+    );
+    line.insert("message".to_string(), record.args().to_string().into());
+    // A field which can't be visited is left out, the message still tells about it.
+    let _ = record.key_values().visit(&mut Fields(&mut line));
+
+    serde_json::Value::Object(line).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_lines() {
+        let fields = [
+            ("challenge_id", Value::from(1234u32)),
+            ("key_id", Value::from("skywalker")),
+            ("peer", Value::from("192.0.2.7")),
+        ];
+        let line = json_line(
+            &Record::builder()
+                .level(log::Level::Info)
+                .module_path(Some("keybroker_server"))
+                .args(format_args!("Evidence submitted for challenge {}.", 1234))
+                .key_values(&fields)
+                .build(),
+        );
+        let line: serde_json::Value = serde_json::from_str(&line).unwrap();
+
+        assert_eq!(line["level"], "INFO");
+        assert_eq!(line["module"], "keybroker_server");
+        assert_eq!(line["message"], "Evidence submitted for challenge 1234.");
+        assert_eq!(line["challenge-id"], 1234);
+        assert_eq!(line["key-id"], "skywalker");
+        assert_eq!(line["peer"], "192.0.2.7");
+        assert!(chrono::DateTime::parse_from_rfc3339(line["timestamp"].as_str().unwrap()).is_ok());
+
+        // Without fields, the line only has the message.
+        let line = json_line(
+            &Record::builder()
+                .level(log::Level::Warn)
+                .target("keybroker")
+                .args(format_args!("Nothing to tell."))
+                .build(),
+        );
+        let line: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(line["level"], "WARN");
+        assert_eq!(line["module"], "keybroker");
+        assert_eq!(line.as_object().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn request_fields() {
+        let line = |fields: RequestFields| {
+            let line = json_line(
+                &Record::builder()
+                    .level(log::Level::Info)
+                    .args(format_args!("Key requested."))
+                    .key_values(&fields)
+                    .build(),
+            );
+            serde_json::from_str::<serde_json::Value>(&line).unwrap()
+        };
+
+        // The fields not known yet are left out.
+        let fields = RequestFields::new("192.0.2.7");
+        let peer_only = line(fields);
+        assert_eq!(peer_only["peer"], "192.0.2.7");
+        assert!(peer_only.get("challenge-id").is_none());
+        assert!(peer_only.get("key-id").is_none());
+
+        let all = line(fields.key("skywalker").challenge(1234));
+        assert_eq!(all["challenge-id"], 1234);
+        assert_eq!(all["key-id"], "skywalker");
+        assert_eq!(all["peer"], "192.0.2.7");
+    }
+}
@@ -0,0 +1,231 @@
+// Copyright 2024 Contributors to the Veraison project.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Content negotiation of the representation of the responses, from the `Accept` header of the
+//! requests.
+//!
+//! A handler offers its representations in its order of preference, the first one being the
+//! default, which is used when the request has no `Accept` header. The representation the client
+//! prefers is picked among them, as per the quality values of the media ranges matching them (the
+//! most specific range wins, so that `application/*;q=0.5, application/cbor` prefers CBOR). Ties are
+//! resolved in the order of preference of the handler.
+//!
+//! This module also holds the encoders of the representations which are not JSON.
+use crate::error::{Error, InputErrorKind, Result};
+use actix_web::http::header::{Accept, Header, Quality, QualityItem};
+use actix_web::mime::{Mime, STAR};
+use actix_web::HttpRequest;
+use base64::engine::general_purpose::STANDARD;
+use base64::prelude::*;
+use ciborium::Value;
+use keybroker_common::{AttestationChallenge, AttestationChallengeEncodings};
+
+/// The media type of the JSON representations.
+pub const JSON_MEDIA_TYPE: &str = "application/json";
+
+/// The media type of the CBOR representations.
+pub const CBOR_MEDIA_TYPE: &str = "application/cbor";
+
+// How specifically a media range matches a media type, if it does: `*/*`, `type/*` or `type/subtype`.
+fn specificity(range: &Mime, media_type: &Mime) -> Option<u8> {
+    if range.type_() == STAR {
+        Some(0)
+    } else if range.type_() != media_type.type_() {
+        None
+    } else if range.subtype() == STAR {
+        Some(1)
+    } else if range.essence_str() == media_type.essence_str() {
+        Some(2)
+    } else {
+        None
+    }
+}
+
+// The quality the client gives to a media type, as per its most specific matching range.
+fn quality(ranges: &[QualityItem<Mime>], media_type: &str) -> Quality {
+    let Ok(media_type) = media_type.parse::<Mime>() else {
+        return Quality::ZERO;
+    };
+
+    ranges
+        .iter()
+        .filter_map(|range| {
+            specificity(&range.item, &media_type).map(|specificity| (specificity, range.quality))
+        })
+        .max_by_key(|(specificity, _)| *specificity)
+        .map_or(Quality::ZERO, |(_, quality)| quality)
+}
+
+/// Pick the representation of a response among the `offered` media types, in the order of preference
+/// of the server, as per the `Accept` header of the request.
+///
+/// Fails with [`InputErrorKind::NotAcceptable`] if the client accepts none of them.
+pub fn negotiate(request: &HttpRequest, offered: &[&'static str]) -> Result<&'static str> {
+    // An Accept header that can't be parsed at all is ignored, as if it was missing.
+    let ranges = Accept::parse(request)
+        .map(|accept| accept.0)
+        .unwrap_or_default();
+    if ranges.is_empty() {
+        return Ok(offered[0]);
+    }
+
+    let mut chosen = None;
+    let mut best = Quality::ZERO;
+    for media_type in offered {
+        let quality = quality(&ranges, media_type);
+        if quality > best {
+            chosen = Some(*media_type);
+            best = quality;
+        }
+    }
+
+    chosen.ok_or_else(|| Error::Input(InputErrorKind::NotAcceptable(offered.join(", "))))
+}
+
+/// Encode an attestation challenge in CBOR, as a map with the challenge value (nonce) as a byte
+/// string under `challenge`, the accepted evidence media types as an array of text strings under
+/// `accept`, and the correlation identifier of the key request, if any, under `correlation-id`.
+pub fn challenge_cbor(
+    challenge: &[u8],
+    accept: &[String],
+    correlation_id: Option<&str>,
+) -> Vec<u8> {
+    let mut members = vec![
+        (
+            Value::Text("challenge".to_string()),
+            Value::Bytes(challenge.to_vec()),
+        ),
+        (
+            Value::Text("accept".to_string()),
+            Value::Array(accept.iter().cloned().map(Value::Text).collect()),
+        ),
+    ];
+    if let Some(correlation_id) = correlation_id {
+        members.push((
+            Value::Text("correlation-id".to_string()),
+            Value::Text(correlation_id.to_string()),
+        ));
+    }
+
+    let mut out = Vec::new();
+    ciborium::into_writer(&Value::Map(members), &mut out).expect("Encoding in memory can't fail.");
+    out
+}
+
+/// Add the alternative encodings of the challenge value to an attestation challenge, for the
+/// vendor representation.
+pub fn challenge_encodings(
+    challenge: AttestationChallenge,
+    challenge_value: &[u8],
+) -> AttestationChallengeEncodings {
+    AttestationChallengeEncodings {
+        challenge,
+        challenge_hex: hex::encode(challenge_value),
+        challenge_base64: STANDARD.encode(challenge_value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+    use keybroker_common::ATTESTATION_CHALLENGE_ENCODINGS_MEDIA_TYPE;
+
+    const OFFERED: [&str; 3] = [
+        JSON_MEDIA_TYPE,
+        CBOR_MEDIA_TYPE,
+        ATTESTATION_CHALLENGE_ENCODINGS_MEDIA_TYPE,
+    ];
+
+    fn negotiate_with(accept: Option<&str>) -> Result<&'static str> {
+        let mut request = TestRequest::default();
+        if let Some(accept) = accept {
+            request = request.insert_header(("Accept", accept));
+        }
+        negotiate(&request.to_http_request(), &OFFERED)
+    }
+
+    #[test]
+    fn default_representation() {
+        assert_eq!(negotiate_with(None).unwrap(), JSON_MEDIA_TYPE);
+        assert_eq!(negotiate_with(Some("*/*")).unwrap(), JSON_MEDIA_TYPE);
+        assert_eq!(
+            negotiate_with(Some("application/*")).unwrap(),
+            JSON_MEDIA_TYPE
+        );
+    }
+
+    #[test]
+    fn client_preferences() {
+        assert_eq!(
+            negotiate_with(Some("application/cbor")).unwrap(),
+            CBOR_MEDIA_TYPE
+        );
+        assert_eq!(
+            negotiate_with(Some("application/json;q=0.5, application/cbor")).unwrap(),
+            CBOR_MEDIA_TYPE
+        );
+        assert_eq!(
+            negotiate_with(Some("application/*;q=0.5, application/cbor")).unwrap(),
+            CBOR_MEDIA_TYPE
+        );
+        assert_eq!(
+            negotiate_with(Some("application/json;q=0, */*")).unwrap(),
+            CBOR_MEDIA_TYPE
+        );
+        assert_eq!(
+            negotiate_with(Some(ATTESTATION_CHALLENGE_ENCODINGS_MEDIA_TYPE)).unwrap(),
+            ATTESTATION_CHALLENGE_ENCODINGS_MEDIA_TYPE
+        );
+    }
+
+    #[test]
+    fn not_acceptable() {
+        for accept in ["text/html", "application/xml, text/*", "*/*;q=0"] {
+            match negotiate_with(Some(accept)) {
+                Err(Error::Input(InputErrorKind::NotAcceptable(offered))) => {
+                    assert_eq!(offered, OFFERED.join(", "))
+                }
+                result => panic!("unexpected result for {accept}: {result:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn challenge_in_cbor() {
+        let cbor = challenge_cbor(&[1, 2, 3], &["a".to_string()], None);
+
+        let mut expected = vec![0xa2, 0x69];
+        expected.extend(b"challenge");
+        expected.extend([0x43, 1, 2, 3, 0x66]);
+        expected.extend(b"accept");
+        expected.extend([0x81, 0x61, b'a']);
+        assert_eq!(cbor, expected);
+
+        // A 64-byte nonce takes a one-byte length.
+        assert_eq!(&challenge_cbor(&[0; 64], &[], None)[11..13], [0x58, 64]);
+
+        // The correlation identifier is a third member.
+        let cbor = challenge_cbor(&[1, 2, 3], &[], Some("job-42"));
+        assert_eq!(cbor[0], 0xa3);
+        let mut correlation_id = vec![0x6e];
+        correlation_id.extend(b"correlation-id");
+        correlation_id.push(0x66);
+        correlation_id.extend(b"job-42");
+        assert!(cbor.ends_with(&correlation_id));
+    }
+
+    #[test]
+    fn alternative_encodings() {
+        let challenge = AttestationChallenge {
+            challenge: keybroker_common::base64::encode([0xfb, 0xff]),
+            accept: vec![],
+            correlation_id: None,
+        };
+        let encodings = challenge_encodings(challenge, &[0xfb, 0xff]);
+
+        assert_eq!(encodings.challenge.challenge, "-_8");
+        assert_eq!(encodings.challenge_hex, "fbff");
+        assert_eq!(encodings.challenge_base64, "+/8=");
+    }
+}
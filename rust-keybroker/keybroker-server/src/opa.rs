@@ -0,0 +1,324 @@
+// Copyright 2024 Contributors to the Veraison project.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Evaluation of the appraisal policies by an external OPA server, for the deployments where the
+//! policies are managed centrally, selected with `--policy-engine opa:<url>`.
+//!
+//! The input document is posted to the OPA Data API, with the known-good reference values as its
+//! `reference-values` member, since the data of the OPA server is not the keybroker's to set:
+//!
+//! ```json
+//! {
+//!   "input": {
+//!     "ear": { ... the EAR claims-set ... },
+//!     "key": { "id": "skywalker", "tags": [ ... ], "metadata": { ... } },
+//!     "challenge": { "id": 1234, "created-at": "2024-11-06T10:20:34Z", "media-type": "..." },
+//!     "config": { "all-submods-affirming": false },
+//!     "reference-values": { "realm-initial-measurements": [ ... ], ... },
+//!     "key-constraints": { "realm-initial-measurements": [ ... ] }
+//!   }
+//! }
+//! ```
+//!
+//! The `key-constraints` member, which the embedded engine gives as `data.key_constraints`, is only
+//! there when the requested key has constraints.
+//!
+//! If the URL has no path, the document of the policy rule of the evidence type is queried, for
+//! instance `/v1/data/arm_cca/allow` for `data.arm_cca.allow`. Otherwise, the URL is used as it is.
+//! The OPA server must also hold the shared policy (`submods.rego`) if its policies use it.
+//!
+//! The result is either a boolean, or an object with a boolean `allow` member and an optional
+//! `deny` array of reasons. Anything else, including an undefined result, is not in policy. The
+//! evaluation fails, and so does the evidence submission, when the server can't be reached in
+//! time or answers with an error: the keys are never released without a decision of the policy.
+use crate::error::{Error, Result, VerificationErrorKind};
+use crate::policy::{policy_input, PolicyContext, PolicyDecision, PolicyEngine};
+use reqwest::blocking::Client;
+use reqwest::Url;
+use serde::Deserialize;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+/// The response of the OPA Data API.
+#[derive(Deserialize)]
+struct DataResponse {
+    /// Missing when the document is undefined.
+    result: Option<serde_json::Value>,
+}
+
+/// Evaluates the appraisal policies with an OPA server.
+pub(crate) struct OpaEngine {
+    url: Url,
+    timeout: Duration,
+    /// Built on first use, as the blocking HTTP client can't be built from the async runtime of
+    /// the server, but only from the blocking tasks the policies are evaluated in.
+    client: OnceLock<Client>,
+}
+
+impl OpaEngine {
+    /// Query the OPA server at `url`, giving up on each query after `timeout`.
+    pub fn new(url: Url, timeout: Duration) -> OpaEngine {
+        OpaEngine {
+            url,
+            timeout,
+            client: OnceLock::new(),
+        }
+    }
+
+    fn client(&self) -> Result<&Client> {
+        if let Some(client) = self.client.get() {
+            return Ok(client);
+        }
+        let client = Client::builder().timeout(self.timeout).build()?;
+        Ok(self.client.get_or_init(|| client))
+    }
+
+    /// The URL of the document of `policy_rule`.
+    fn document_url(&self, policy_rule: &str) -> Result<Url> {
+        if self.url.path() != "/" {
+            return Ok(self.url.clone());
+        }
+
+        let path = policy_rule.strip_prefix("data.").unwrap_or(policy_rule);
+        self.url
+            .join(&format!("v1/data/{}", path.replace('.', "/")))
+            .map_err(|error| unavailable(error.to_string()))
+    }
+}
+
+fn unavailable(reason: String) -> Error {
+    Error::Verification(VerificationErrorKind::PolicyEngineUnavailable(reason))
+}
+
+/// Interpret the result of a policy, failing closed.
+fn decision(result: Option<serde_json::Value>) -> PolicyDecision {
+    match result {
+        Some(serde_json::Value::Bool(allowed)) => PolicyDecision {
+            allowed,
+            deny_reasons: Vec::new(),
+        },
+        Some(serde_json::Value::Object(result)) => PolicyDecision {
+            allowed: result.get("allow") == Some(&serde_json::Value::Bool(true)),
+            deny_reasons: result
+                .get("deny")
+                .and_then(serde_json::Value::as_array)
+                .into_iter()
+                .flatten()
+                .map(|reason| match reason {
+                    serde_json::Value::String(reason) => reason.clone(),
+                    reason => reason.to_string(),
+                })
+                .collect(),
+        },
+        Some(result) => {
+            log::warn!("Unexpected OPA policy result, taken as not in policy: {result}");
+            PolicyDecision::default()
+        }
+        None => PolicyDecision {
+            allowed: false,
+            deny_reasons: vec!["the policy decision is undefined".to_string()],
+        },
+    }
+}
+
+impl PolicyEngine for OpaEngine {
+    fn evaluate(
+        &self,
+        _policy: &str,
+        policy_rule: &str,
+        reference_values: &str,
+        ear_claims: &str,
+        context: &PolicyContext,
+    ) -> Result<PolicyDecision> {
+        let url = self.document_url(policy_rule)?;
+        let mut input = policy_input(ear_claims, context)?;
+        input["reference-values"] = serde_json::from_str(reference_values)?;
+        if let Some(constraints) = &context.key.attributes.constraints {
+            input["key-constraints"] = serde_json::to_value(constraints)?;
+        }
+
+        let response = self
+            .client()?
+            .post(url.clone())
+            .json(&serde_json::json!({ "input": input }))
+            .send()
+            .map_err(|error| unavailable(format!("{url}: {error}")))?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(unavailable(format!("{url} answered {status}")));
+        }
+        let response: DataResponse = response
+            .json()
+            .map_err(|error| unavailable(format!("{url}: invalid response, {error}")))?;
+
+        Ok(decision(response.result))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keystore::KeyAttributes;
+    use crate::policy::{ChallengeContext, KeyContext, PolicyConfig};
+    use actix_web::rt::task;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn context() -> PolicyContext {
+        PolicyContext {
+            key: KeyContext {
+                id: "skywalker".to_string(),
+                attributes: KeyAttributes::default(),
+            },
+            challenge: ChallengeContext {
+                id: 1234,
+                created_at: "2024-11-06T10:20:34Z".to_string(),
+                media_type: "application/eat-collection".to_string(),
+                wrapping_key_binding: None,
+            },
+            config: PolicyConfig::default(),
+        }
+    }
+
+    /// Evaluate the default policy rule with the OPA server at `url`.
+    async fn evaluate(url: &str, timeout: Duration) -> Result<PolicyDecision> {
+        let engine = OpaEngine::new(url.parse().unwrap(), timeout);
+        task::spawn_blocking(move || {
+            engine.evaluate(
+                "",
+                "data.arm_cca.allow",
+                r#"{ "realm-initial-measurements": [] }"#,
+                include_str!("../../../testdata/ear-claims-ok.json"),
+                &context(),
+            )
+        })
+        .await
+        .unwrap()
+    }
+
+    #[test]
+    fn results() {
+        let decision = |result: serde_json::Value| decision(Some(result));
+
+        assert!(decision(serde_json::json!(true)).allowed);
+        assert!(!decision(serde_json::json!(false)).allowed);
+        assert!(decision(serde_json::json!({ "allow": true })).allowed);
+        assert!(!decision(serde_json::json!({ "allow": "true" })).allowed);
+        assert!(!decision(serde_json::json!("true")).allowed);
+        assert!(!decision(serde_json::json!([true])).allowed);
+
+        let denied = decision(serde_json::json!({
+            "allow": false,
+            "deny": ["unknown realm initial measurement", 42]
+        }));
+        assert!(!denied.allowed);
+        assert_eq!(
+            denied.deny_reasons,
+            vec!["unknown realm initial measurement", "42"]
+        );
+
+        // An undefined document is not in policy.
+        assert!(!super::decision(None).allowed);
+    }
+
+    #[test]
+    fn document_urls() {
+        let engine = |url: &str| OpaEngine::new(url.parse().unwrap(), Duration::from_secs(1));
+
+        assert_eq!(
+            engine("http://opa:8181")
+                .document_url("data.arm_cca.allow")
+                .unwrap()
+                .as_str(),
+            "http://opa:8181/v1/data/arm_cca/allow"
+        );
+        assert_eq!(
+            engine("http://opa:8181/v1/data/keybroker/decision")
+                .document_url("data.arm_cca.allow")
+                .unwrap()
+                .as_str(),
+            "http://opa:8181/v1/data/keybroker/decision"
+        );
+    }
+
+    #[actix_web::test]
+    async fn input_and_deny_reasons() {
+        let opa = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/data/arm_cca/allow"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "result": { "allow": false, "deny": ["unknown realm initial measurement"] }
+            })))
+            .expect(1)
+            .mount(&opa)
+            .await;
+
+        let decision = evaluate(&opa.uri(), Duration::from_secs(2))
+            .await
+            .expect("successful evaluation");
+        assert!(!decision.allowed);
+        assert_eq!(
+            decision.deny_reasons,
+            vec!["unknown realm initial measurement"]
+        );
+
+        let requests = opa.received_requests().await.unwrap();
+        let body: serde_json::Value = requests[0].body_json().unwrap();
+        assert_eq!(body["input"]["key"]["id"], "skywalker");
+        assert_eq!(body["input"]["challenge"]["id"], 1234);
+        assert_eq!(
+            body["input"]["ear"]["eat_profile"],
+            "tag:github.com,2023:veraison/ear"
+        );
+        assert_eq!(
+            body["input"]["reference-values"],
+            serde_json::json!({ "realm-initial-measurements": [] })
+        );
+        assert!(body["input"].get("key-constraints").is_none());
+    }
+
+    #[actix_web::test]
+    async fn fail_closed() {
+        // An error of the server.
+        let opa = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(500))
+            .mount(&opa)
+            .await;
+        assert!(matches!(
+            evaluate(&opa.uri(), Duration::from_secs(2)).await,
+            Err(Error::Verification(
+                VerificationErrorKind::PolicyEngineUnavailable(_)
+            ))
+        ));
+
+        // A server too slow to answer.
+        let opa = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(serde_json::json!({ "result": true }))
+                    .set_delay(Duration::from_secs(2)),
+            )
+            .mount(&opa)
+            .await;
+        assert!(matches!(
+            evaluate(&opa.uri(), Duration::from_millis(100)).await,
+            Err(Error::Verification(
+                VerificationErrorKind::PolicyEngineUnavailable(_)
+            ))
+        ));
+
+        // No server at all.
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .unwrap()
+            .port();
+        assert!(matches!(
+            evaluate(&format!("http://127.0.0.1:{port}"), Duration::from_secs(2)).await,
+            Err(Error::Verification(
+                VerificationErrorKind::PolicyEngineUnavailable(_)
+            ))
+        ));
+    }
+}
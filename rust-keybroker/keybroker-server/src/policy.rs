@@ -1,84 +1,1004 @@
 // Copyright 2024 Contributors to the Veraison project.
 // SPDX-License-Identifier: Apache-2.0
 
+//! Appraisal of the attestation results against the appraisal policies.
+//!
+//! The policies are given a structured input, so that they can take the requested key and the
+//! challenge into account, besides the EAR claims-set:
+//!
+//! ```json
+//! {
+//!   "ear": { ... the EAR claims-set ... },
+//!   "key": { "id": "skywalker", "tags": [ ... ], "metadata": { ... } },
+//!   "challenge": { "id": 1234, "created-at": "2024-11-06T10:20:34Z", "media-type": "..." },
+//!   "config": { "all-submods-affirming": false }
+//! }
+//! ```
+//!
+//! The EAR claims-set has all the submodules of the attestation result. With
+//! `--all-submods-affirming`, the policies are told to require every one of them to be affirming,
+//! which they can do with the `data.keybroker.submods.all_affirming` rule of the shared policy
+//! (`submods.rego`), evaluated along with the policy of the evidence type.
+//!
+//! A key may restrict the workloads it is released to, on top of the reference values: its
+//! constraints, such as the realm initial measurements of the workloads, are then given to the
+//! policies as `data.key_constraints`, which the default Arm CCA policy checks. The keys without
+//! constraints leave `data.key_constraints` undefined. The key broker checks the realm initial
+//! measurement against them again once the policy has allowed the attestation result, so that
+//! they hold whatever the policy, custom policies included.
+//!
+//! With `--bind-wrapping-key`, the challenge also has a `wrapping-key-binding`: the value that one
+//! of the realm extensible measurements must have for the evidence to bind the wrapping key of the
+//! key request, which the default Arm CCA policy then checks.
+//!
+//! Besides the Rego builtins, the policies can call the keybroker builtins:
+//!
+//! - `keybroker.digest_equal(a, b)`, whether two digests are the same bytes, each being encoded
+//!   in hex, base64 or base64url, with or without padding;
+//! - `keybroker.digest_has_prefix(digest, prefix, min_length)`, whether a digest starts with the
+//!   bytes of `prefix` and is at least `min_length` bytes long, both being encoded as for
+//!   `keybroker.digest_equal`, an empty prefix matching no digest;
+//! - `keybroker.semver_gte(a, b)`, whether the version `a` is the same as or later than `b`, as
+//!   per semantic versioning, an optional `v` prefix and missing minor or patch numbers being
+//!   allowed.
+use crate::digest::{canonical_digest, decode_digest};
 use crate::error::Result;
-use phf::{phf_map, Map};
+use crate::keystore::KeyAttributes;
+use crate::reference_values::MeasurementEntry;
 use regorus::{self, Value};
+use serde::Serialize;
+use std::str::FromStr;
 
-pub static MEDIATYPES_TO_POLICY: Map<&'static str, (&'static str, &'static str)> = phf_map! {
-    r#"application/eat-collection; profile="http://arm.com/CCA-SSD/1.0.0""# => ( include_str!("arm-cca.rego"), "data.arm_cca.allow" ),
-    // Other, future mappings
-};
+/// What the appraisal policies are told about the requested key.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct KeyContext {
+    pub id: String,
+    #[serde(flatten)]
+    pub attributes: KeyAttributes,
+}
+
+/// What the appraisal policies are told about the challenge the evidence was produced for.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct ChallengeContext {
+    pub id: u32,
+    /// When the challenge was issued, in RFC 3339 format.
+    pub created_at: String,
+    /// The media type of the submitted evidence.
+    pub media_type: String,
+    /// The measurement binding the wrapping key, if the evidence must bind it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub wrapping_key_binding: Option<String>,
+}
+
+/// The configuration of the server the appraisal policies are told about.
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct PolicyConfig {
+    /// Whether every submodule of the attestation result must be affirming.
+    pub all_submods_affirming: bool,
+}
+
+/// The key request details given to the appraisal policies, alongside the EAR claims-set.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct PolicyContext {
+    pub key: KeyContext,
+    pub challenge: ChallengeContext,
+    pub config: PolicyConfig,
+}
+
+/// The policies shared by all the evidence types, as (name, text).
+const SHARED_POLICIES: [(&str, &str); 1] = [("submods.rego", include_str!("submods.rego"))];
+
+/// The maximum number of bytes of `print()` output kept from a policy evaluation.
+const MAX_PRINTS_SIZE: usize = 16 * 1024;
+
+/// The outcome of an appraisal policy evaluation.
+#[derive(Debug)]
+pub(crate) struct Evaluation {
+    /// The value of the evaluated rule.
+    pub result: Value,
+    /// The output of the policy `print()` statements, truncated to `MAX_PRINTS_SIZE` bytes.
+    pub prints: Vec<String>,
+}
+
+/// The decision of a policy engine about an attestation result.
+#[derive(Debug, Default)]
+pub(crate) struct PolicyDecision {
+    /// Whether the attestation result is in policy.
+    pub allowed: bool,
+    /// Why the attestation result is not in policy, when the policy tells.
+    pub deny_reasons: Vec<String>,
+}
+
+/// Evaluates the appraisal policies.
+///
+/// The embedded engine, which runs the policy of the evidence type locally, is the default. The
+/// policies can also be evaluated by an external OPA server, see [`crate::opa`].
+pub(crate) trait PolicyEngine: Send + Sync {
+    /// Evaluate `policy_rule` for an EAR claims-set, along with the key request details and the
+    /// known-good reference values. `policy` is the text of the policy of the evidence type, which
+    /// an external engine may already hold under the path of `policy_rule`.
+    fn evaluate(
+        &self,
+        policy: &str,
+        policy_rule: &str,
+        reference_values: &str,
+        ear_claims: &str,
+        context: &PolicyContext,
+    ) -> Result<PolicyDecision>;
+}
+
+/// Evaluates the appraisal policies in process, with regorus.
+pub(crate) struct EmbeddedEngine;
+
+impl PolicyEngine for EmbeddedEngine {
+    fn evaluate(
+        &self,
+        policy: &str,
+        policy_rule: &str,
+        reference_values: &str,
+        ear_claims: &str,
+        context: &PolicyContext,
+    ) -> Result<PolicyDecision> {
+        let evaluation = rego_eval(policy, policy_rule, reference_values, ear_claims, context)?;
+        log_prints(context.challenge.id, &evaluation.prints);
+
+        Ok(PolicyDecision {
+            allowed: evaluation.result.to_string() == "true",
+            deny_reasons: Vec::new(),
+        })
+    }
+}
+
+/// The policy engine selected on the command line.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PolicyEngineKind {
+    /// The embedded regorus engine.
+    Embedded,
+    /// An OPA server, reached at the given URL.
+    Opa(reqwest::Url),
+}
+
+impl FromStr for PolicyEngineKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if s == "embedded" {
+            return Ok(PolicyEngineKind::Embedded);
+        }
+        let Some(url) = s.strip_prefix("opa:") else {
+            return Err(format!(
+                "unknown policy engine '{s}', expected 'embedded' or 'opa:<url>'"
+            ));
+        };
+        match reqwest::Url::parse(url) {
+            Ok(url) if matches!(url.scheme(), "http" | "https") => Ok(PolicyEngineKind::Opa(url)),
+            Ok(url) => Err(format!(
+                "unsupported OPA URL scheme '{}', expected http or https",
+                url.scheme()
+            )),
+            Err(error) => Err(format!("invalid OPA URL '{url}': {error}")),
+        }
+    }
+}
+
+/// The input of the appraisal policies.
+#[derive(Serialize)]
+struct PolicyInput<'a> {
+    ear: serde_json::Value,
+    #[serde(flatten)]
+    context: &'a PolicyContext,
+}
+
+// Log the policy prints of the evaluation for a challenge.
+fn log_prints(challenge_id: u32, prints: &[String]) {
+    for line in prints {
+        log::debug!("Policy print for challenge {challenge_id}: {line}");
+    }
+}
+
+// Keep the policy prints within MAX_PRINTS_SIZE bytes, noting how many lines were dropped.
+fn truncate_prints(prints: Vec<String>) -> Vec<String> {
+    let mut size = 0;
+    let mut kept = Vec::new();
+    let total = prints.len();
+
+    for line in prints {
+        size += line.len();
+        if size > MAX_PRINTS_SIZE {
+            break;
+        }
+        kept.push(line);
+    }
+
+    if kept.len() < total {
+        kept.push(format!(
+            "... {} more print() lines truncated",
+            total - kept.len()
+        ));
+    }
+    kept
+}
+
+/// Build the input of the appraisal policies, from the EAR claims-set and the key request details.
+pub(crate) fn policy_input(ear_claims: &str, context: &PolicyContext) -> Result<serde_json::Value> {
+    let mut ear = serde_json::from_str(ear_claims)?;
+    normalise_digests(&mut ear);
+    Ok(serde_json::to_value(PolicyInput { ear, context })?)
+}
+
+/// The annotated evidence claims holding digests, which are brought to the encoding of the
+/// reference values.
+const DIGEST_CLAIMS: [&str; 1] = ["cca-realm-initial-measurement"];
+
+/// Bring the digests of the annotated evidence of an EAR claims-set to padded standard base64, as
+/// the reference values are, so that the policies can compare them as strings. The values which
+/// are not digests are left as they are, for the policies to reject.
+fn normalise_digests(ear: &mut serde_json::Value) {
+    let Some(submods) = ear
+        .get_mut("submods")
+        .and_then(serde_json::Value::as_object_mut)
+    else {
+        return;
+    };
+    for submod in submods.values_mut() {
+        let Some(claims) = submod
+            .get_mut("ear.veraison.annotated-evidence")
+            .and_then(serde_json::Value::as_object_mut)
+        else {
+            continue;
+        };
+        for claim in DIGEST_CLAIMS {
+            let Some(digest) = claims.get(claim).and_then(|digest| digest.as_str()) else {
+                continue;
+            };
+            if let Ok(digest) = canonical_digest(digest) {
+                claims.insert(claim.to_string(), digest.into());
+            }
+        }
+    }
+}
+
+/// Get a string argument of a keybroker builtin.
+fn string_arg<'a>(builtin: &str, args: &'a [Value], index: usize) -> anyhow::Result<&'a str> {
+    match args.get(index).map(Value::as_string) {
+        Some(Ok(arg)) => Ok(arg.as_ref()),
+        _ => anyhow::bail!("{builtin}: argument {} must be a string", index + 1),
+    }
+}
+
+/// keybroker.digest_equal(a, b)
+fn digest_equal(args: Vec<Value>) -> anyhow::Result<Value> {
+    let a = decode_digest(string_arg("keybroker.digest_equal", &args, 0)?);
+    let b = decode_digest(string_arg("keybroker.digest_equal", &args, 1)?);
+    Ok(Value::from(a.iter().any(|bytes| b.contains(bytes))))
+}
+
+/// Whether some of the bytes a digest can stand for start with some of those of a prefix, and are
+/// at least `min_length` bytes long.
+fn has_prefix(digest: &[Vec<u8>], prefix: &[Vec<u8>], min_length: u64) -> bool {
+    digest.iter().any(|digest| {
+        digest.len() as u64 >= min_length
+            && prefix
+                .iter()
+                .any(|prefix| !prefix.is_empty() && digest.starts_with(prefix))
+    })
+}
+
+/// keybroker.digest_has_prefix(digest, prefix, min_length)
+fn digest_has_prefix(args: Vec<Value>) -> anyhow::Result<Value> {
+    let digest = decode_digest(string_arg("keybroker.digest_has_prefix", &args, 0)?);
+    let prefix = decode_digest(string_arg("keybroker.digest_has_prefix", &args, 1)?);
+    let min_length = match args.get(2).map(Value::as_u64) {
+        Some(Ok(min_length)) => min_length,
+        _ => anyhow::bail!("keybroker.digest_has_prefix: argument 3 must be a length"),
+    };
+
+    Ok(Value::from(has_prefix(&digest, &prefix, min_length)))
+}
+
+/// Whether a measurement, as the bytes it can stand for, is one of the digests, prefix rules and
+/// groups of `entries`, as the default Arm CCA policy matches them.
+fn measurement_in_entries(measurement: &[Vec<u8>], entries: &[MeasurementEntry]) -> bool {
+    entries.iter().any(|entry| match entry {
+        MeasurementEntry::Digest(digest) => decode_digest(digest)
+            .iter()
+            .any(|bytes| measurement.contains(bytes)),
+        MeasurementEntry::Prefix(rule) => has_prefix(
+            measurement,
+            &decode_digest(&rule.prefix),
+            rule.min_length.unwrap_or(0) as u64,
+        ),
+        MeasurementEntry::Group(group) => measurement_in_entries(measurement, &group.values),
+    })
+}
+
+/// Deny an attestation result that the policy allowed, if its realm initial measurement does not
+/// meet the constraints of the requested key.
+///
+/// The policies are given the constraints, but nothing makes them check them: a custom policy may
+/// well not. The constraints are then checked here, whatever the policy, and an attestation
+/// result without a realm initial measurement does not meet them.
+pub(crate) fn enforce_key_constraints(
+    mut decision: PolicyDecision,
+    ear_claims: &str,
+    context: &PolicyContext,
+) -> Result<PolicyDecision> {
+    let Some(constraints) = &context.key.attributes.constraints else {
+        return Ok(decision);
+    };
+    if !decision.allowed {
+        return Ok(decision);
+    }
+
+    let ear: serde_json::Value = serde_json::from_str(ear_claims)?;
+    let rim = ear
+        .pointer("/submods/CCA_REALM/ear.veraison.annotated-evidence/cca-realm-initial-measurement")
+        .and_then(serde_json::Value::as_str)
+        .map(decode_digest)
+        .unwrap_or_default();
+    if !measurement_in_entries(&rim, &constraints.realm_initial_measurements) {
+        decision.allowed = false;
+        decision.deny_reasons.push(format!(
+            "the realm initial measurement is not one of those the key '{}' is released to",
+            context.key.id
+        ));
+    }
+
+    Ok(decision)
+}
+
+/// Parse a version, leniently as firmware versions are often not quite semantic versions.
+fn parse_version(version: &str) -> anyhow::Result<semver::Version> {
+    let version = version.strip_prefix('v').unwrap_or(version);
+
+    // Complete the missing minor and patch numbers.
+    let end = version.find(['-', '+']).unwrap_or(version.len());
+    let missing = 2usize.saturating_sub(version[..end].matches('.').count());
+    let version = format!(
+        "{}{}{}",
+        &version[..end],
+        ".0".repeat(missing),
+        &version[end..]
+    );
+    Ok(semver::Version::parse(&version)?)
+}
 
-// Evaluate an EAR claims-set against the appraisal policy and known-good reference values
+/// keybroker.semver_gte(a, b)
+fn semver_gte(args: Vec<Value>) -> anyhow::Result<Value> {
+    let a = parse_version(string_arg("keybroker.semver_gte", &args, 0)?)?;
+    let b = parse_version(string_arg("keybroker.semver_gte", &args, 1)?)?;
+    // The build metadata does not take part in the precedence.
+    Ok(Value::from(a.cmp_precedence(&b).is_ge()))
+}
+
+/// Register the keybroker builtins on a policy engine.
+fn add_builtins(engine: &mut regorus::Engine) -> Result<()> {
+    engine.add_extension(
+        "keybroker.digest_equal".to_string(),
+        2,
+        Box::new(digest_equal),
+    )?;
+    engine.add_extension(
+        "keybroker.digest_has_prefix".to_string(),
+        3,
+        Box::new(digest_has_prefix),
+    )?;
+    engine.add_extension("keybroker.semver_gte".to_string(), 2, Box::new(semver_gte))?;
+    Ok(())
+}
+
+// Evaluate an EAR claims-set, along with the key request details, against the appraisal policy and
+// known-good reference values
 pub(crate) fn rego_eval(
     policy: &str,
     policy_rule: &str,
     reference_values: &str,
     ear_claims: &str,
-) -> Result<Value> {
+    context: &PolicyContext,
+) -> Result<Evaluation> {
     // Create engine.
     let mut engine = regorus::Engine::new();
 
     engine.set_rego_v1(true);
     engine.set_strict_builtin_errors(false);
 
-    // Add the appraisal policy
+    // Keep the output of the print() statements, which are the usual policy debugging tool,
+    // rather than letting it go to the server's stderr.
+    engine.set_gather_prints(true);
+
+    add_builtins(&mut engine)?;
+
+    // Add the appraisal policy, and the rules it can use
     engine.add_policy(String::from("policy.rego"), String::from(policy))?;
+    for (name, shared_policy) in SHARED_POLICIES {
+        engine.add_policy(name.to_string(), shared_policy.to_string())?;
+    }
+
+    // Load the configured known-good reference values, and the constraints of the requested key
+    engine.add_data(Value::from_json_str(reference_values)?)?;
+    if let Some(constraints) = &context.key.attributes.constraints {
+        let data = serde_json::json!({ "key_constraints": constraints });
+        engine.add_data(Value::from_json_str(&data.to_string())?)?;
+    }
 
-    // Load the configured known-good reference values
-    engine.add_data(Value::from_json_file(reference_values)?)?;
+    // Set the EAR claims-set to be appraised, along with the key request details
+    let input = policy_input(ear_claims, context)?;
+    engine.set_input(Value::from_json_str(&input.to_string())?);
 
-    // Set the EAR claims-set to be appraised
-    engine.set_input(Value::from_json_str(ear_claims)?);
+    let result = engine.eval_rule(policy_rule.to_string());
 
-    let results = engine.eval_rule(policy_rule.to_string())?;
+    // The prints are taken before an evaluation error is propagated, as they are most useful then.
+    let prints = truncate_prints(engine.take_prints()?);
+    let result = match result {
+        Ok(result) => result,
+        Err(error) => {
+            log_prints(context.challenge.id, &prints);
+            return Err(error.into());
+        }
+    };
 
-    Ok(results)
+    Ok(Evaluation { result, prints })
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::keystore::KeyConstraints;
+    use crate::opa::OpaEngine;
+    use crate::reference_values::{
+        parse_reference_values, MeasurementEntry, MeasurementGroup, PrefixRule,
+    };
+    use keybroker_common::{base64, PublicWrappingKey};
+    use std::time::Duration;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn context(tags: &[&str]) -> PolicyContext {
+        PolicyContext {
+            key: KeyContext {
+                id: "skywalker".to_string(),
+                attributes: KeyAttributes {
+                    tags: tags.iter().map(|tag| tag.to_string()).collect(),
+                    metadata: [("owner".to_string(), "rebels".to_string())].into(),
+                    ..Default::default()
+                },
+            },
+            challenge: ChallengeContext {
+                id: 1234,
+                created_at: "2024-11-06T10:20:34Z".to_string(),
+                media_type: "application/eat-collection".to_string(),
+                wrapping_key_binding: None,
+            },
+            config: PolicyConfig::default(),
+        }
+    }
+
+    /// The data given to the appraisal policies for a reference values document.
+    fn policy_data(document: &str) -> String {
+        parse_reference_values(document)
+            .expect("valid reference values")
+            .data()
+    }
 
     #[test]
     fn rego_eval_ear_default_policy_ok() {
         let ear_claims = include_str!("../../../testdata/ear-claims-ok.json");
-        let reference_values = stringify_testdata_path("rims-matching.json");
+        let reference_values = policy_data(include_str!("../../../testdata/rims-matching.json"));
 
         let results = rego_eval(
             include_str!("arm-cca.rego"),
             "data.arm_cca.allow",
             &reference_values,
             ear_claims,
+            &context(&[]),
         )
         .expect("successful eval");
 
-        assert_eq!(results.to_string(), "true");
+        assert_eq!(results.result.to_string(), "true");
+    }
+
+    #[test]
+    fn rego_eval_default_policy_prefix_rules() {
+        let ear_claims = include_str!("../../../testdata/ear-claims-ok.json");
+        for (reference_values, allowed) in [
+            (
+                include_str!("../../../testdata/rims-prefix-matching.json"),
+                "true",
+            ),
+            (
+                include_str!("../../../testdata/rims-prefix-near-miss.json"),
+                "false",
+            ),
+        ] {
+            let results = rego_eval(
+                include_str!("arm-cca.rego"),
+                "data.arm_cca.allow",
+                &policy_data(reference_values),
+                ear_claims,
+                &context(&[]),
+            )
+            .expect("successful eval");
+
+            assert_eq!(results.result.to_string(), allowed, "{reference_values}");
+        }
+    }
+
+    #[test]
+    fn rego_eval_default_policy_wrapping_key_binding() {
+        let wrapping_key = PublicWrappingKey {
+            kty: "RSA".to_string(),
+            alg: "RSA-OAEP".to_string(),
+            n: Some("xcXF".to_string()),
+            e: Some("AQAB".to_string()),
+            ..Default::default()
+        };
+        let mut context = context(&[]);
+        context.challenge.wrapping_key_binding = Some(crate::digest::encode_digest(
+            &wrapping_key.binding_measurement(),
+        ));
+        let reference_values = policy_data(include_str!("../../../testdata/rims-matching.json"));
+
+        let mut ear: serde_json::Value =
+            serde_json::from_str(include_str!("../../../testdata/ear-claims-ok.json")).unwrap();
+        let unbound = ear.to_string();
+        // The client may have extended any of the measurements, and the encoding does not matter.
+        ear["submods"]["CCA_REALM"]["ear.veraison.annotated-evidence"]
+            ["cca-realm-extensible-measurements"][2] =
+            base64::encode(wrapping_key.binding_measurement()).into();
+        let bound = ear.to_string();
+
+        for (ear_claims, allowed) in [(bound, "true"), (unbound, "false")] {
+            let results = rego_eval(
+                include_str!("arm-cca.rego"),
+                "data.arm_cca.allow",
+                &reference_values,
+                &ear_claims,
+                &context,
+            )
+            .expect("successful eval");
+            assert_eq!(results.result.to_string(), allowed);
+        }
+    }
+
+    #[test]
+    fn rego_eval_all_affirming() {
+        let reference_values = policy_data(include_str!("../../../testdata/rims-matching.json"));
+        let all_affirming = |ear_claims: &serde_json::Value| {
+            rego_eval(
+                include_str!("arm-cca.rego"),
+                "data.keybroker.submods.all_affirming",
+                &reference_values,
+                &ear_claims.to_string(),
+                &context(&[]),
+            )
+            .expect("successful eval")
+            .result
+            .to_string()
+        };
+
+        // One affirming submodule, and one in warning.
+        let mut ear: serde_json::Value = serde_json::from_str(include_str!(
+            "../../../testdata/ear-claims-multi-attester.json"
+        ))
+        .unwrap();
+        assert_eq!(all_affirming(&ear), "false");
+
+        ear["submods"]["attester-2"]["ear.status"] = "affirming".into();
+        assert_eq!(all_affirming(&ear), "true");
+
+        ear["submods"] = serde_json::json!({});
+        assert_eq!(all_affirming(&ear), "false");
+    }
+
+    #[test]
+    fn rego_eval_default_policy_all_submods_affirming() {
+        let reference_values = policy_data(include_str!("../../../testdata/rims-matching.json"));
+        let mut context = context(&[]);
+        context.config.all_submods_affirming = true;
+        let allow = |ear_claims: &serde_json::Value| {
+            rego_eval(
+                include_str!("arm-cca.rego"),
+                "data.arm_cca.allow",
+                &reference_values,
+                &ear_claims.to_string(),
+                &context,
+            )
+            .expect("successful eval")
+            .result
+            .to_string()
+        };
+
+        // The realm of the example token is in warning, which the default mode accepts.
+        let mut ear: serde_json::Value =
+            serde_json::from_str(include_str!("../../../testdata/ear-claims-ok.json")).unwrap();
+        assert_eq!(allow(&ear), "false");
+
+        ear["submods"]["CCA_REALM"]["ear.status"] = "affirming".into();
+        assert_eq!(allow(&ear), "true");
+    }
+
+    #[test]
+    fn rego_eval_default_policy_key_constraints() {
+        let ear_claims = include_str!("../../../testdata/ear-claims-ok.json");
+        let reference_values = policy_data(include_str!("../../../testdata/rims-matching.json"));
+        let allow = |constraints: Option<Vec<MeasurementEntry>>| {
+            let mut context = context(&[]);
+            context.key.attributes.constraints =
+                constraints.map(|realm_initial_measurements| KeyConstraints {
+                    realm_initial_measurements,
+                });
+            rego_eval(
+                include_str!("arm-cca.rego"),
+                "data.arm_cca.allow",
+                &reference_values,
+                ear_claims,
+                &context,
+            )
+            .expect("successful eval")
+            .result
+            .to_string()
+        };
+
+        // Both RIMs are known-good, but the keys are each released to one workload only.
+        let skywalker = vec![MeasurementEntry::Digest(
+            "MRMUq3NiA1DPdYg0rlxl2ejC3H/r5ufZZUu+hk4wDUk=".to_string(),
+        )];
+        let vader = vec![MeasurementEntry::Digest(
+            "q3N/r5ufZZUu+iAg0rlxl2ejC3HMRMUhk4wDUk1DPdY=".to_string(),
+        )];
+        assert_eq!(allow(Some(skywalker)), "true");
+        assert_eq!(allow(Some(vader)), "false");
+        assert_eq!(allow(None), "true");
+
+        // The constraints can be prefix rules and groups as well.
+        let group = vec![MeasurementEntry::Group(MeasurementGroup {
+            group: "rebels".to_string(),
+            values: vec![MeasurementEntry::Prefix(PrefixRule {
+                prefix: "MRMUq3NiA1DPdYg0".to_string(),
+                min_length: Some(32),
+            })],
+        })];
+        assert_eq!(allow(Some(group)), "true");
+    }
+
+    #[test]
+    fn key_constraints_enforced_whatever_the_policy() {
+        let ear_claims = include_str!("../../../testdata/ear-claims-ok.json");
+        let reference_values = policy_data(include_str!("../../../testdata/rims-matching.json"));
+        let decide = |constraints: Option<Vec<MeasurementEntry>>| {
+            let mut context = context(&[]);
+            context.key.attributes.constraints =
+                constraints.map(|realm_initial_measurements| KeyConstraints {
+                    realm_initial_measurements,
+                });
+
+            // A custom policy which allows anything, and so ignores the constraints of the key.
+            let decision = EmbeddedEngine
+                .evaluate(
+                    "package arm_cca\n\nallow := true\n",
+                    "data.arm_cca.allow",
+                    &reference_values,
+                    ear_claims,
+                    &context,
+                )
+                .expect("successful evaluation");
+            assert!(decision.allowed);
+            enforce_key_constraints(decision, ear_claims, &context).expect("valid EAR claims")
+        };
+
+        let skywalker = vec![MeasurementEntry::Prefix(PrefixRule {
+            prefix: "MRMUq3NiA1DPdYg0".to_string(),
+            min_length: Some(32),
+        })];
+        assert!(decide(Some(skywalker)).allowed);
+        assert!(decide(None).allowed);
+
+        let vader = vec![MeasurementEntry::Group(MeasurementGroup {
+            group: "empire".to_string(),
+            values: vec![MeasurementEntry::Digest(
+                "q3N/r5ufZZUu+iAg0rlxl2ejC3HMRMUhk4wDUk1DPdY=".to_string(),
+            )],
+        })];
+        let decision = decide(Some(vader));
+        assert!(!decision.allowed);
+        assert_eq!(
+            decision.deny_reasons,
+            ["the realm initial measurement is not one of those the key 'skywalker' is released to"]
+        );
     }
 
     #[test]
     fn rego_eval_default_policy_unmatched_rim() {
         let ear_claims = include_str!("../../../testdata/ear-claims-ok.json");
-        let reference_values = stringify_testdata_path("rims-not-matching.json");
+        let reference_values =
+            policy_data(include_str!("../../../testdata/rims-not-matching.json"));
 
         let results = rego_eval(
             include_str!("arm-cca.rego"),
             "data.arm_cca.allow",
             &reference_values,
             ear_claims,
+            &context(&[]),
+        )
+        .expect("successful eval");
+
+        assert_eq!(results.result.to_string(), "false");
+    }
+
+    /// Check that an engine decides as the default Arm CCA policy.
+    fn check_engine(engine: &dyn PolicyEngine) {
+        let ear_claims = include_str!("../../../testdata/ear-claims-ok.json");
+        for (reference_values, allowed) in [
+            (include_str!("../../../testdata/rims-matching.json"), true),
+            (
+                include_str!("../../../testdata/rims-not-matching.json"),
+                false,
+            ),
+        ] {
+            let decision = engine
+                .evaluate(
+                    include_str!("arm-cca.rego"),
+                    "data.arm_cca.allow",
+                    &policy_data(reference_values),
+                    ear_claims,
+                    &context(&[]),
+                )
+                .expect("successful evaluation");
+            assert_eq!(decision.allowed, allowed, "{reference_values}");
+        }
+    }
+
+    #[test]
+    fn embedded_engine() {
+        check_engine(&EmbeddedEngine);
+    }
+
+    #[actix_web::test]
+    async fn opa_engine() {
+        // A stand-in for an OPA server holding the RIM check of the default policy.
+        let opa = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/data/arm_cca/allow"))
+            .respond_with(|request: &wiremock::Request| {
+                let body: serde_json::Value = request.body_json().unwrap();
+                let input = &body["input"];
+                let rim = &input["ear"]["submods"]["CCA_REALM"]["ear.veraison.annotated-evidence"]
+                    ["cca-realm-initial-measurement"];
+                let known = input["reference-values"]["realm-initial-measurements"]
+                    .as_array()
+                    .unwrap()
+                    .contains(rim);
+                ResponseTemplate::new(200).set_body_json(serde_json::json!({ "result": known }))
+            })
+            .mount(&opa)
+            .await;
+
+        let url = opa.uri().parse().unwrap();
+        actix_web::rt::task::spawn_blocking(move || {
+            check_engine(&OpaEngine::new(url, Duration::from_secs(2)))
+        })
+        .await
+        .unwrap();
+    }
+
+    #[test]
+    fn rego_eval_default_policy_platform() {
+        let ear_claims = include_str!("../../../testdata/ear-claims-ok.json");
+        let platform = include_str!("../../../testdata/reference-values-platform.json");
+        let mut document: serde_json::Value = serde_json::from_str(platform).unwrap();
+        let eval = |document: &serde_json::Value| {
+            rego_eval(
+                include_str!("arm-cca.rego"),
+                "data.arm_cca.allow",
+                &policy_data(&document.to_string()),
+                ear_claims,
+                &context(&[]),
+            )
+            .expect("successful eval")
+            .result
+            .to_string()
+        };
+
+        assert_eq!(eval(&document), "true");
+
+        // The platform is in the "secured" lifecycle state.
+        document["allowed-lifecycle-states"] = serde_json::json!(["non-psa-rot-debug"]);
+        assert_eq!(eval(&document), "false");
+        document["allowed-lifecycle-states"] = serde_json::json!(["secured"]);
+        assert_eq!(eval(&document), "true");
+
+        // The platform configuration is compared whatever its encoding.
+        document["platform-config-digests"] = serde_json::json!(["cfcfcfcf"]);
+        assert_eq!(eval(&document), "true");
+        document["platform-config-digests"] = serde_json::json!(["AAAAAA=="]);
+        assert_eq!(eval(&document), "false");
+    }
+
+    #[test]
+    fn rego_eval_key_and_challenge_input() {
+        let ear_claims = include_str!("../../../testdata/ear-claims-ok.json");
+        let reference_values = policy_data(include_str!("../../../testdata/rims-matching.json"));
+        let policy = r#"
+            package keys
+
+            default allow := false
+
+            allow if {
+                input.ear.eat_profile == "tag:github.com,2023:veraison/ear"
+                input.key.id == "skywalker"
+                "production" in input.key.tags
+                input.key.metadata.owner == "rebels"
+                input.challenge.id == 1234
+                input.challenge["created-at"] == "2024-11-06T10:20:34Z"
+                input.challenge["media-type"] == "application/eat-collection"
+            }
+        "#;
+
+        let results = rego_eval(
+            policy,
+            "data.keys.allow",
+            &reference_values,
+            ear_claims,
+            &context(&["production"]),
         )
         .expect("successful eval");
+        assert_eq!(results.result.to_string(), "true");
 
-        assert_eq!(results.to_string(), "false");
+        let results = rego_eval(
+            policy,
+            "data.keys.allow",
+            &reference_values,
+            ear_claims,
+            &context(&["staging"]),
+        )
+        .expect("successful eval");
+        assert_eq!(results.result.to_string(), "false");
+    }
+
+    #[test]
+    fn rego_eval_gathers_prints() {
+        let ear_claims = include_str!("../../../testdata/ear-claims-ok.json");
+        let reference_values = policy_data(include_str!("../../../testdata/rims-matching.json"));
+        let policy = r#"
+            package keys
+
+            default allow := false
+
+            allow if {
+                print("requested key:", input.key.id)
+                input.key.id == "skywalker"
+            }
+        "#;
+
+        let results = rego_eval(
+            policy,
+            "data.keys.allow",
+            &reference_values,
+            ear_claims,
+            &context(&[]),
+        )
+        .expect("successful eval");
+
+        assert_eq!(results.result.to_string(), "true");
+        assert_eq!(results.prints.len(), 1);
+        assert!(results.prints[0].ends_with("requested key: skywalker"));
     }
 
-    fn stringify_testdata_path(s: &str) -> String {
-        let mut test_data = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    #[test]
+    fn rego_eval_builtins() {
+        let ear_claims = include_str!("../../../testdata/ear-claims-ok.json");
+        let reference_values = policy_data(include_str!("../../../testdata/rims-matching.json"));
+        let policy = r#"
+            package keys
+
+            # The same digest in base64, hex and base64url without padding.
+            digests if {
+                keybroker.digest_equal("MRMUq3NiA1DPdYg0rlxl2ejC3H/r5ufZZUu+hk4wDUk=",
+                    "311314ab73620350cf758834ae5c65d9e8c2dc7febe6e7d9654bbe864e300d49")
+                keybroker.digest_equal("MRMUq3NiA1DPdYg0rlxl2ejC3H_r5ufZZUu-hk4wDUk",
+                    "311314AB73620350CF758834AE5C65D9E8C2DC7FEBE6E7D9654BBE864E300D49")
+                not keybroker.digest_equal("MRMUq3NiA1DPdYg0rlxl2ejC3H/r5ufZZUu+hk4wDUk=",
+                    "q3N/r5ufZZUu+iAg0rlxl2ejC3HMRMUhk4wDUk1DPdY=")
+            }
+
+            prefixes if {
+                keybroker.digest_has_prefix("MRMUq3NiA1DPdYg0rlxl2ejC3H/r5ufZZUu+hk4wDUk=",
+                    "MRMUq3NiA1DPdYg0", 32)
+                keybroker.digest_has_prefix(
+                    "311314ab73620350cf758834ae5c65d9e8c2dc7febe6e7d9654bbe864e300d49",
+                    "MRMUq3NiA1DPdYg0", 0)
+                not keybroker.digest_has_prefix("MRMUq3NiA1DPdYg0rlxl2ejC3H/r5ufZZUu+hk4wDUk=",
+                    "MRMUq3NiA1DPdYg1", 0)
+                not keybroker.digest_has_prefix("MRMUq3NiA1DPdYg0rlxl2ejC3H/r5ufZZUu+hk4wDUk=",
+                    "MRMUq3NiA1DPdYg0", 48)
+                # An empty prefix matches nothing, rather than every digest.
+                not keybroker.digest_has_prefix("MRMUq3NiA1DPdYg0rlxl2ejC3H/r5ufZZUu+hk4wDUk=",
+                    "", 0)
+            }
+
+            versions if {
+                keybroker.semver_gte("1.10.0", "1.9.3")
+                keybroker.semver_gte("v2.1", "2.1.0")
+                keybroker.semver_gte("1.2.0", "1.2.0-rc.1")
+                not keybroker.semver_gte("1.2.0-rc.1", "1.2")
+            }
+
+            allow if {
+                digests
+                prefixes
+                versions
+            }
+        "#;
 
-        test_data.push("../../testdata");
-        test_data.push(s);
+        let results = rego_eval(
+            policy,
+            "data.keys.allow",
+            &reference_values,
+            ear_claims,
+            &context(&[]),
+        )
+        .expect("successful eval");
+        assert_eq!(results.result.to_string(), "true");
+    }
+
+    #[test]
+    fn ear_digests_normalised() {
+        let mut ear: serde_json::Value =
+            serde_json::from_str(include_str!("../../../testdata/ear-claims-ok.json")).unwrap();
+        let claims = &mut ear["submods"]["CCA_REALM"]["ear.veraison.annotated-evidence"];
+        claims["cca-realm-initial-measurement"] =
+            "311314AB73620350CF758834AE5C65D9E8C2DC7FEBE6E7D9654BBE864E300D49".into();
+        claims["cca-realm-hash-algo-id"] = "sha-256".into();
+
+        normalise_digests(&mut ear);
+
+        let claims = &ear["submods"]["CCA_REALM"]["ear.veraison.annotated-evidence"];
+        assert_eq!(
+            claims["cca-realm-initial-measurement"],
+            "MRMUq3NiA1DPdYg0rlxl2ejC3H/r5ufZZUu+hk4wDUk="
+        );
+        assert_eq!(claims["cca-realm-hash-algo-id"], "sha-256");
+
+        // Claims-sets without annotated evidence are left alone.
+        let mut ear = serde_json::json!({ "submods": { "CCA_SSD_PLATFORM": "affirming" } });
+        normalise_digests(&mut ear);
+        assert_eq!(ear["submods"]["CCA_SSD_PLATFORM"], "affirming");
+    }
+
+    #[test]
+    fn versions() {
+        assert_eq!(parse_version("v1").unwrap(), semver::Version::new(1, 0, 0));
+        assert_eq!(parse_version("1.2").unwrap(), semver::Version::new(1, 2, 0));
+        assert_eq!(
+            parse_version("1.2-rc.1+build.5").unwrap(),
+            semver::Version::parse("1.2.0-rc.1+build.5").unwrap()
+        );
+        assert!(parse_version("1.2.3.4").is_err());
+        assert!(parse_version("latest").is_err());
+
+        let gte = |a: &str, b: &str| semver_gte(vec![Value::from(a), Value::from(b)]).unwrap();
+        assert_eq!(gte("1.10", "1.9.9"), Value::from(true));
+        assert_eq!(gte("1.0.0+build.2", "1.0.0+build.10"), Value::from(true));
+        assert_eq!(gte("0.9", "1.0"), Value::from(false));
+        assert!(semver_gte(vec![Value::from("1.0"), Value::Null]).is_err());
+    }
+
+    #[test]
+    fn prints_truncated() {
+        let prints = vec!["x".repeat(1000); 20];
+
+        let kept = truncate_prints(prints);
+
+        assert_eq!(kept.len(), 17);
+        assert_eq!(kept[16], "... 4 more print() lines truncated");
+    }
+
+    #[test]
+    fn prints_not_truncated() {
+        let prints = vec!["hello".to_string(), "world".to_string()];
 
-        test_data.into_os_string().into_string().unwrap()
+        assert_eq!(truncate_prints(prints.clone()), prints);
     }
 }
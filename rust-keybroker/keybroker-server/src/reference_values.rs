@@ -0,0 +1,1006 @@
+// Copyright 2024 Contributors to the Veraison project.
+// SPDX-License-Identifier: Apache-2.0
+
+//! This module handles the known-good reference values used by the appraisal policies.
+//!
+//! The reference values can either be read from a file, or given inline on the command line,
+//! which is simpler than relying on process substitution (`<(echo ...)`) since that does not work
+//! in all shells, nor in systemd unit files. An inline document is detected by an explicit
+//! `inline:` prefix, or by a leading `{` or `[`.
+//!
+//! The document is an object giving the known-good realm initial measurements, and optionally the
+//! platform configurations and lifecycle states that are allowed:
+//!
+//! ```json
+//! {
+//!   "realm-initial-measurements": [ "MRMUq3NiA1DPdYg0rlxl2ejC3H/r5ufZZUu+hk4wDUk=" ],
+//!   "platform-config-digests": [ "z8/Pzw==" ],
+//!   "allowed-lifecycle-states": [ "secured" ]
+//! }
+//! ```
+//!
+//! Besides digests, the realm initial measurements can be given as prefix rules, which match the
+//! measurements starting with some bytes, such as those of frequently rebuilt development images,
+//! and as named groups of digests and prefix rules:
+//!
+//! ```json
+//! {
+//!   "realm-initial-measurements": [
+//!     "MRMUq3NiA1DPdYg0rlxl2ejC3H/r5ufZZUu+hk4wDUk=",
+//!     { "prefix": "q3N/r5ufZZUu", "min-length": 32 },
+//!     { "group": "nightly-builds", "values": [ { "prefix": "UvZTSVUJ" } ] }
+//!   ]
+//! }
+//! ```
+//!
+//! The flat lists of realm initial measurements of the first versions, either as
+//! `{ "reference-values": [ ... ] }` or as a bare JSON array, are still accepted. The appraisal
+//! policies are given the realm initial measurements under both names.
+//!
+//! The realm initial measurements can be SHA-256, SHA-384 or SHA-512 digests in hex of either
+//! case, base64 or base64url: they are brought to padded standard base64 as they are loaded, as are
+//! the measurements of the attestation results before the policies see them.
+//!
+//! Whatever its source, the document is parsed and validated at startup, down to each value being a
+//! digest, so that a typo'd path or a malformed document stops the server rather
+//! than failing the first verification. It can then be reloaded
+//! while the server is running (on SIGHUP, or through the admin API), so that new known-good values
+//! can be added without discarding the state of the server. A document that fails to load on reload
+//! leaves the previous values in place. Individual values can also be appended through the admin
+//! API, and optionally persisted back to the reference values file.
+use crate::digest::{canonical_digest, decode_base64, DIGEST_SIZES};
+use crate::error::{Error, Result, VerificationErrorKind};
+use base64::engine::general_purpose::STANDARD;
+use base64::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+/// The name of the member holding the flat array of realm initial measurements in the JSON
+/// documents of the first versions.
+const REFERENCE_VALUES_MEMBER: &str = "reference-values";
+
+/// The platform lifecycle states that can be allowed, as named by the PSA attestation token
+/// specification.
+pub const LIFECYCLE_STATES: [&str; 7] = [
+    "unknown",
+    "assembly-and-test",
+    "psa-rot-provisioning",
+    "secured",
+    "non-psa-rot-debug",
+    "recoverable-psa-rot-debug",
+    "decommissioned",
+];
+
+/// A known-good realm initial measurement, or a rule matching a family of them.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum MeasurementEntry {
+    /// A digest, in padded standard base64 once loaded.
+    Digest(String),
+
+    /// The measurements starting with some bytes.
+    Prefix(PrefixRule),
+
+    /// A named group of digests and prefix rules, such as the builds of a release.
+    Group(MeasurementGroup),
+}
+
+/// The measurements starting with `prefix`, and at least `min_length` bytes long if it is given.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct PrefixRule {
+    /// The first bytes of the measurements, at least one, in padded standard base64 once loaded.
+    pub prefix: String,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_length: Option<usize>,
+}
+
+/// A named group of measurement entries. Groups can not be nested.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MeasurementGroup {
+    pub group: String,
+    pub values: Vec<MeasurementEntry>,
+}
+
+impl MeasurementEntry {
+    /// Validate the entry, and bring its digests and prefixes to padded standard base64.
+    pub(crate) fn normalise(&mut self, in_group: bool) -> Result<()> {
+        let invalid = |reason: String| {
+            Err(Error::Verification(
+                VerificationErrorKind::InvalidReferenceValues(reason),
+            ))
+        };
+        let largest_digest = DIGEST_SIZES[DIGEST_SIZES.len() - 1];
+
+        match self {
+            MeasurementEntry::Digest(digest) => *digest = canonical_reference_value(digest)?,
+            MeasurementEntry::Prefix(rule) => {
+                let prefix = match decode_base64(&rule.prefix) {
+                    Some(prefix) if !prefix.is_empty() && prefix.len() < largest_digest => prefix,
+                    _ => {
+                        return invalid(format!(
+                            "the prefix {} is not 1 to {} base64-encoded bytes",
+                            rule.prefix,
+                            largest_digest - 1
+                        ))
+                    }
+                };
+                if let Some(min_length) = rule.min_length {
+                    if !(prefix.len()..=largest_digest).contains(&min_length) {
+                        return invalid(format!(
+                            "the minimum length of the prefix {} must be between {} and \
+                             {largest_digest} bytes",
+                            rule.prefix,
+                            prefix.len()
+                        ));
+                    }
+                }
+                rule.prefix = STANDARD.encode(prefix);
+            }
+            MeasurementEntry::Group(group) => {
+                if in_group {
+                    return invalid(format!("the group {} is in another group", group.group));
+                }
+                if group.group.is_empty() {
+                    return invalid("a group has no name".to_string());
+                }
+                if group.values.is_empty() {
+                    return invalid(format!("the group {} is empty", group.group));
+                }
+                for value in &mut group.values {
+                    value.normalise(true)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The known-good reference values.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ReferenceValues {
+    /// The realm initial measurements, and the rules matching them.
+    pub realm_initial_measurements: Vec<MeasurementEntry>,
+
+    /// The base64-encoded platform configurations, if the platform configuration is pinned.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub platform_config_digests: Option<Vec<String>>,
+
+    /// The platform lifecycle states, if they are restricted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allowed_lifecycle_states: Option<Vec<String>>,
+}
+
+impl ReferenceValues {
+    /// The JSON document given as data to the appraisal policies, where the realm initial
+    /// measurements are also given as the "reference-values" of the first versions.
+    pub fn data(&self) -> String {
+        let mut data = serde_json::to_value(self).expect("Failed to serialise reference values.");
+        data[REFERENCE_VALUES_MEMBER] = serde_json::json!(self.realm_initial_measurements);
+        data.to_string()
+    }
+
+    /// Validate the reference values, and bring the realm initial measurements to the canonical
+    /// encoding of the digests.
+    fn normalise(&mut self) -> Result<()> {
+        let invalid = |reason: &str| {
+            Err(Error::Verification(
+                VerificationErrorKind::InvalidReferenceValues(reason.to_string()),
+            ))
+        };
+
+        if self.realm_initial_measurements.is_empty() {
+            return invalid("the array of realm initial measurements is empty");
+        }
+        for value in &mut self.realm_initial_measurements {
+            value.normalise(false)?;
+        }
+
+        if let Some(configs) = &self.platform_config_digests {
+            if configs.is_empty() {
+                return invalid("the array of platform configurations is empty");
+            }
+            for config in configs {
+                if STANDARD
+                    .decode(config)
+                    .is_ok_and(|config| !config.is_empty())
+                {
+                    continue;
+                }
+                return Err(Error::Verification(
+                    VerificationErrorKind::InvalidReferenceValues(format!(
+                        "the platform configuration {config} is not base64-encoded"
+                    )),
+                ));
+            }
+        }
+
+        if let Some(states) = &self.allowed_lifecycle_states {
+            if states.is_empty() {
+                return invalid("the array of lifecycle states is empty");
+            }
+            if let Some(state) = states
+                .iter()
+                .find(|state| !LIFECYCLE_STATES.contains(&state.as_str()))
+            {
+                return Err(Error::Verification(
+                    VerificationErrorKind::InvalidReferenceValues(format!(
+                        "unknown lifecycle state {state}, expected one of {}",
+                        LIFECYCLE_STATES.join(", ")
+                    )),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A reference values document, in any of its versions.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct ReferenceValuesDocument {
+    #[serde(default)]
+    reference_values: Option<Vec<MeasurementEntry>>,
+    #[serde(default)]
+    realm_initial_measurements: Option<Vec<MeasurementEntry>>,
+    #[serde(default)]
+    platform_config_digests: Option<Vec<String>>,
+    #[serde(default)]
+    allowed_lifecycle_states: Option<Vec<String>>,
+}
+
+/// Where the known-good reference values come from.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ReferenceValuesSource {
+    /// A JSON file.
+    File(PathBuf),
+
+    /// A literal JSON document.
+    Inline(String),
+}
+
+impl FromStr for ReferenceValuesSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if let Some(document) = s.strip_prefix("inline:") {
+            return Ok(ReferenceValuesSource::Inline(document.to_string()));
+        }
+
+        if s.trim_start().starts_with(['{', '[']) {
+            Ok(ReferenceValuesSource::Inline(s.to_string()))
+        } else {
+            Ok(ReferenceValuesSource::File(PathBuf::from(s)))
+        }
+    }
+}
+
+impl std::fmt::Display for ReferenceValuesSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReferenceValuesSource::File(path) => write!(f, "{}", path.display()),
+            ReferenceValuesSource::Inline(_) => write!(f, "the inline document"),
+        }
+    }
+}
+
+impl ReferenceValuesSource {
+    /// Read and validate the reference values.
+    ///
+    /// This is the validation the server runs at startup: anything checking the configuration
+    /// ahead of starting the server should use it too.
+    pub fn load(&self) -> Result<ReferenceValues> {
+        let document = match self {
+            ReferenceValuesSource::File(path) => {
+                std::fs::read_to_string(path).map_err(|error| {
+                    Error::Verification(VerificationErrorKind::InvalidReferenceValues(format!(
+                        "cannot read {}: {error}",
+                        path.display()
+                    )))
+                })?
+            }
+            ReferenceValuesSource::Inline(document) => document.clone(),
+        };
+
+        parse_reference_values(&document).map_err(|error| match error {
+            Error::Verification(VerificationErrorKind::InvalidReferenceValues(reason))
+                if matches!(self, ReferenceValuesSource::File(_)) =>
+            {
+                Error::Verification(VerificationErrorKind::InvalidReferenceValues(format!(
+                    "{self}: {reason}"
+                )))
+            }
+            error => error,
+        })
+    }
+}
+
+/// Parse and validate a reference values JSON document.
+///
+/// The document must be an object with a non-empty array of base64-encoded digests, prefix rules
+/// and groups as its "realm-initial-measurements" member, and optionally "platform-config-digests" and
+/// "allowed-lifecycle-states" arrays. A flat array of realm initial measurements, on its own or as
+/// the "reference-values" member, is also accepted.
+pub fn parse_reference_values(document: &str) -> Result<ReferenceValues> {
+    let invalid =
+        |reason: String| Error::Verification(VerificationErrorKind::InvalidReferenceValues(reason));
+
+    let document: serde_json::Value = serde_json::from_str(document)
+        .map_err(|error| invalid(format!("not a JSON document: {error}")))?;
+
+    let document = match document {
+        serde_json::Value::Array(_) => serde_json::json!({ REFERENCE_VALUES_MEMBER: document }),
+        document => document,
+    };
+    let document: ReferenceValuesDocument =
+        serde_json::from_value(document).map_err(|error| invalid(error.to_string()))?;
+
+    let realm_initial_measurements = match (
+        document.reference_values,
+        document.realm_initial_measurements,
+    ) {
+        (Some(values), None) | (None, Some(values)) => values,
+        (None, None) => {
+            return Err(invalid(
+                "no \"realm-initial-measurements\" array".to_string(),
+            ))
+        }
+        (Some(_), Some(_)) => {
+            return Err(invalid(
+                "both \"reference-values\" and \"realm-initial-measurements\" are given"
+                    .to_string(),
+            ))
+        }
+    };
+
+    let mut values = ReferenceValues {
+        realm_initial_measurements,
+        platform_config_digests: document.platform_config_digests,
+        allowed_lifecycle_states: document.allowed_lifecycle_states,
+    };
+    values.normalise()?;
+    Ok(values)
+}
+
+/// Bring a reference value to the canonical encoding of the digests, padded standard base64.
+fn canonical_reference_value(value: &str) -> Result<String> {
+    canonical_digest(value).map_err(|reason| {
+        Error::Verification(VerificationErrorKind::InvalidReferenceValues(reason))
+    })
+}
+
+/// A set of reference values to append to the active ones, as submitted to the admin API.
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ReferenceValuesUpdate {
+    /// The reference values, in any of the encodings of the digests.
+    pub reference_values: Vec<String>,
+}
+
+/// The outcome of a successful append.
+#[derive(Debug, PartialEq, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct AppendOutcome {
+    /// The number of values that were not already known.
+    pub added: usize,
+
+    /// The number of reference values after the append.
+    pub count: usize,
+
+    /// Whether the reference values were written back to their file.
+    pub persisted: bool,
+}
+
+/// The outcome of a successful reload.
+#[derive(Debug, PartialEq, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ReloadOutcome {
+    /// The number of reference values before the reload.
+    pub previous_count: usize,
+
+    /// The number of reference values after the reload.
+    pub count: usize,
+}
+
+/// The active reference values, along with the data document given to the appraisal policies.
+type ActiveReferenceValues = (ReferenceValues, Arc<String>);
+
+fn activate(values: ReferenceValues) -> ActiveReferenceValues {
+    let data = Arc::new(values.data());
+    (values, data)
+}
+
+/// Holds the current reference values, and the source they can be reloaded from.
+pub struct ReferenceValuesStore {
+    source: Option<ReferenceValuesSource>,
+    current: RwLock<Option<ActiveReferenceValues>>,
+}
+
+impl ReferenceValuesStore {
+    /// Load the reference values from their source, if any.
+    pub fn new(source: Option<ReferenceValuesSource>) -> Result<Self> {
+        let current = match &source {
+            Some(source) => Some(activate(source.load()?)),
+            None => None,
+        };
+
+        Ok(ReferenceValuesStore {
+            source,
+            current: RwLock::new(current),
+        })
+    }
+
+    /// Get the current reference values, as the data document of the appraisal policies.
+    #[cfg(any(feature = "remote-verifier", test))]
+    pub fn get(&self) -> Option<Arc<String>> {
+        self.current
+            .read()
+            .expect("Poisoned reference values lock.")
+            .as_ref()
+            .map(|(_, data)| data.clone())
+    }
+
+    /// The number of realm initial measurements currently known.
+    fn count(&self) -> usize {
+        self.current
+            .read()
+            .expect("Poisoned reference values lock.")
+            .as_ref()
+            .map_or(0, |(values, _)| values.realm_initial_measurements.len())
+    }
+
+    /// Reload the reference values from their source, and swap them in.
+    ///
+    /// On failure, the previous reference values are kept.
+    pub fn reload(&self) -> Result<ReloadOutcome> {
+        let Some(source) = &self.source else {
+            return Err(Error::Verification(
+                VerificationErrorKind::NoReferenceValues,
+            ));
+        };
+
+        let previous_count = self.count();
+
+        let values = match source.load() {
+            Ok(values) => values,
+            Err(error) => {
+                log::error!(
+                    "Failed to reload the reference values, keeping the {previous_count} previous ones: {error}"
+                );
+                return Err(error);
+            }
+        };
+        let count = values.realm_initial_measurements.len();
+
+        *self
+            .current
+            .write()
+            .expect("Poisoned reference values lock.") = Some(activate(values));
+
+        log::info!("Reloaded the reference values: {previous_count} before, {count} now.");
+        Ok(ReloadOutcome {
+            previous_count,
+            count,
+        })
+    }
+
+    /// Merge new values into the active reference values, skipping those that are already known.
+    ///
+    /// If `persist` is set and the reference values come from a file, the merged document is
+    /// written back to that file, so that the new values survive a restart. The values are only
+    /// swapped in once they have been persisted.
+    pub fn append(&self, update: &ReferenceValuesUpdate, persist: bool) -> Result<AppendOutcome> {
+        let update = update
+            .reference_values
+            .iter()
+            .map(|value| canonical_reference_value(value))
+            .collect::<Result<Vec<_>>>()?;
+
+        // Hold the write lock throughout, so that concurrent appends do not lose values.
+        let mut current = self
+            .current
+            .write()
+            .expect("Poisoned reference values lock.");
+
+        let mut values = current
+            .as_ref()
+            .map(|(values, _)| values.clone())
+            .unwrap_or_default();
+        let rims = &mut values.realm_initial_measurements;
+        let previous_count = rims.len();
+        for value in update {
+            let value = MeasurementEntry::Digest(value);
+            if !rims.contains(&value) {
+                rims.push(value);
+            }
+        }
+        let count = rims.len();
+        let added = count - previous_count;
+
+        if added == 0 {
+            return Ok(AppendOutcome {
+                added,
+                count,
+                persisted: false,
+            });
+        }
+
+        let persisted = match (&self.source, persist) {
+            (Some(ReferenceValuesSource::File(path)), true) => {
+                std::fs::write(path, serde_json::to_string_pretty(&values)?)?;
+                true
+            }
+            _ => false,
+        };
+
+        *current = Some(activate(values));
+
+        log::info!(
+            "Appended {added} reference values: {previous_count} before, {count} now{}.",
+            if persisted { " (persisted)" } else { "" }
+        );
+        Ok(AppendOutcome {
+            added,
+            count,
+            persisted,
+        })
+    }
+}
+
+/// Serialises the writes of the suggested reference values, so that the diagnostics of concurrent
+/// verifications merge their measurements rather than overwrite each other's.
+#[cfg(any(feature = "remote-verifier", test))]
+static SUGGESTED_REFERENCE_VALUES_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+/// Merge a realm initial measurement into the suggested reference values file at `path`, creating
+/// it if needed, so that it can be given as is to `--reference-values`.
+///
+/// Returns whether the measurement was not already in the file. The file is replaced atomically,
+/// so that it is never read half-written.
+#[cfg(any(feature = "remote-verifier", test))]
+pub fn suggest_reference_value(path: &std::path::Path, rim: &str) -> Result<bool> {
+    let rim = MeasurementEntry::Digest(canonical_reference_value(rim)?);
+
+    let _guard = SUGGESTED_REFERENCE_VALUES_LOCK
+        .lock()
+        .expect("Poisoned suggested reference values lock.");
+
+    let mut values = if path.exists() {
+        ReferenceValuesSource::File(path.to_path_buf()).load()?
+    } else {
+        ReferenceValues::default()
+    };
+
+    if values.realm_initial_measurements.contains(&rim) {
+        return Ok(false);
+    }
+    values.realm_initial_measurements.push(rim);
+
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    std::fs::write(&temporary, serde_json::to_string_pretty(&values)?)?;
+    std::fs::rename(&temporary, path)?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RIM: &str = "MRMUq3NiA1DPdYg0rlxl2ejC3H/r5ufZZUu+hk4wDUk=";
+
+    /// The realm initial measurements in a data document, as returned by
+    /// [`ReferenceValuesStore::get`].
+    fn reference_values_of(data: &str) -> Vec<String> {
+        let data: serde_json::Value = serde_json::from_str(data).unwrap();
+        assert_eq!(
+            data["realm-initial-measurements"],
+            data[REFERENCE_VALUES_MEMBER]
+        );
+        serde_json::from_value(data["realm-initial-measurements"].clone()).unwrap()
+    }
+
+    #[test]
+    fn source_detection() {
+        assert_eq!(
+            "reference-values.json".parse::<ReferenceValuesSource>(),
+            Ok(ReferenceValuesSource::File(PathBuf::from(
+                "reference-values.json"
+            )))
+        );
+        assert_eq!(
+            "/dev/fd/63".parse::<ReferenceValuesSource>(),
+            Ok(ReferenceValuesSource::File(PathBuf::from("/dev/fd/63")))
+        );
+        assert_eq!(
+            r#" { "reference-values": [] }"#.parse::<ReferenceValuesSource>(),
+            Ok(ReferenceValuesSource::Inline(
+                r#" { "reference-values": [] }"#.to_string()
+            ))
+        );
+        assert_eq!(
+            "inline:[]".parse::<ReferenceValuesSource>(),
+            Ok(ReferenceValuesSource::Inline("[]".to_string()))
+        );
+    }
+
+    #[test]
+    fn inline_object_and_array_are_equivalent() {
+        let object =
+            ReferenceValuesSource::Inline(format!(r#"{{ "reference-values": [ "{RIM}" ] }}"#))
+                .load()
+                .expect("valid reference values");
+        let array = ReferenceValuesSource::Inline(format!(r#"[ "{RIM}" ]"#))
+            .load()
+            .expect("valid reference values");
+
+        assert_eq!(object, array);
+        assert_eq!(
+            object,
+            ReferenceValuesSource::Inline(format!(
+                r#"{{ "realm-initial-measurements": [ "{RIM}" ] }}"#
+            ))
+            .load()
+            .expect("valid reference values")
+        );
+    }
+
+    #[test]
+    fn encodings_are_normalised() {
+        let values = parse_reference_values(&format!(
+            r#"[
+                "311314ab73620350cf758834ae5c65d9e8c2dc7febe6e7d9654bbe864e300d49",
+                "{}",
+                "{}"
+            ]"#,
+            "5A".repeat(48),
+            base64::engine::general_purpose::URL_SAFE_NO_PAD.encode([0xfb; 64])
+        ))
+        .expect("valid reference values");
+
+        assert_eq!(
+            values.realm_initial_measurements,
+            vec![
+                MeasurementEntry::Digest(RIM.to_string()),
+                MeasurementEntry::Digest(STANDARD.encode([0x5a; 48])),
+                MeasurementEntry::Digest(STANDARD.encode([0xfb; 64]))
+            ]
+        );
+
+        let error = parse_reference_values(
+            r#"[ "311314ab73620350cf758834ae5c65d9e8c2dc7febe6e7d9654bbe864E300D49" ]"#,
+        )
+        .expect_err("ambiguous reference value");
+        assert!(error.to_string().contains("ambiguous"), "{error}");
+    }
+
+    #[test]
+    fn platform_reference_values() {
+        let values = parse_reference_values(&format!(
+            r#"{{
+                "realm-initial-measurements": [ "{RIM}" ],
+                "platform-config-digests": [ "z8/Pzw==" ],
+                "allowed-lifecycle-states": [ "secured", "non-psa-rot-debug" ]
+            }}"#
+        ))
+        .expect("valid reference values");
+
+        assert_eq!(
+            values,
+            ReferenceValues {
+                realm_initial_measurements: vec![MeasurementEntry::Digest(RIM.to_string())],
+                platform_config_digests: Some(vec!["z8/Pzw==".to_string()]),
+                allowed_lifecycle_states: Some(vec![
+                    "secured".to_string(),
+                    "non-psa-rot-debug".to_string()
+                ]),
+            }
+        );
+
+        let data: serde_json::Value = serde_json::from_str(&values.data()).unwrap();
+        assert_eq!(
+            data,
+            serde_json::json!({
+                "realm-initial-measurements": [ RIM ],
+                "reference-values": [ RIM ],
+                "platform-config-digests": [ "z8/Pzw==" ],
+                "allowed-lifecycle-states": [ "secured", "non-psa-rot-debug" ],
+            })
+        );
+
+        // The members that are not given are left out of the data, rather than set to null.
+        let data = parse_reference_values(&format!(r#"[ "{RIM}" ]"#))
+            .unwrap()
+            .data();
+        assert!(!data.contains("platform-config-digests"));
+        assert!(!data.contains("allowed-lifecycle-states"));
+    }
+
+    #[test]
+    fn prefix_rules_and_groups() {
+        let values = parse_reference_values(&format!(
+            r#"{{ "realm-initial-measurements": [
+                "{RIM}",
+                {{ "prefix": "MRMUq3NiA1DPdYg0", "min-length": 32 }},
+                {{ "group": "nightly-builds", "values": [
+                    "5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a",
+                    {{ "prefix": "q3N_r5ufZZUu" }}
+                ] }}
+            ] }}"#
+        ))
+        .expect("valid reference values");
+
+        // The digests and prefixes are normalised, and the data keeps the structure.
+        let data: serde_json::Value = serde_json::from_str(&values.data()).unwrap();
+        assert_eq!(
+            data["realm-initial-measurements"],
+            serde_json::json!([
+                RIM,
+                { "prefix": "MRMUq3NiA1DPdYg0", "min-length": 32 },
+                { "group": "nightly-builds", "values": [
+                    STANDARD.encode([0x5a; 32]),
+                    { "prefix": "q3N/r5ufZZUu" }
+                ] }
+            ])
+        );
+
+        // The normalised document, as persisted, loads to the same values.
+        let persisted = serde_json::to_string(&values).unwrap();
+        assert_eq!(parse_reference_values(&persisted).unwrap(), values);
+
+        for entry in [
+            r#"{ "prefix": "" }"#,
+            r#"{ "prefix": "not base64!" }"#,
+            r#"{ "prefix": "MRMUq3NiA1DPdYg0", "min-length": 8 }"#,
+            r#"{ "prefix": "MRMUq3NiA1DPdYg0", "min-length": 65 }"#,
+            r#"{ "prefix": "MRMUq3NiA1DPdYg0", "max-length": 32 }"#,
+            r#"{ "group": "empty", "values": [] }"#,
+            r#"{ "group": "", "values": [ "MRMUq3NiA1DPdYg0rlxl2ejC3H/r5ufZZUu+hk4wDUk=" ] }"#,
+            r#"{ "group": "outer", "values": [ { "group": "inner", "values": [
+                "MRMUq3NiA1DPdYg0rlxl2ejC3H/r5ufZZUu+hk4wDUk=" ] } ] }"#,
+        ] {
+            assert!(
+                parse_reference_values(&format!("[ {entry} ]")).is_err(),
+                "{entry} should be rejected"
+            );
+        }
+    }
+
+    #[test]
+    fn file_and_inline_are_equivalent() {
+        let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        path.push("../../testdata/rims-matching.json");
+
+        let from_file = ReferenceValuesSource::File(path.clone())
+            .load()
+            .expect("valid reference values");
+        let inline = ReferenceValuesSource::Inline(std::fs::read_to_string(path).unwrap())
+            .load()
+            .expect("valid reference values");
+
+        assert_eq!(from_file, inline);
+    }
+
+    #[test]
+    fn invalid_documents() {
+        for document in [
+            "{",
+            "{}",
+            r#"{ "reference-values": [] }"#,
+            r#"{ "reference-values": [ 42 ] }"#,
+            r#"{ "reference-values": "MRMU" }"#,
+            r#"{ "reference-values": [ "MRMU" ] }"#,
+            r#"[ "not base64!" ]"#,
+            r#"{ "realm-initial-measurements": [] }"#,
+            r#"{ "platform-config-digests": [ "z8/Pzw==" ] }"#,
+            r#"{ "reference-values": [ "MRMUq3NiA1DPdYg0rlxl2ejC3H/r5ufZZUu+hk4wDUk=" ],
+                 "realm-initial-measurements": [ "MRMUq3NiA1DPdYg0rlxl2ejC3H/r5ufZZUu+hk4wDUk=" ] }"#,
+            r#"{ "realm-initial-measurements": [ "MRMUq3NiA1DPdYg0rlxl2ejC3H/r5ufZZUu+hk4wDUk=" ],
+                 "platform-config-digests": [] }"#,
+            r#"{ "realm-initial-measurements": [ "MRMUq3NiA1DPdYg0rlxl2ejC3H/r5ufZZUu+hk4wDUk=" ],
+                 "platform-config-digests": [ "not base64!" ] }"#,
+            r#"{ "realm-initial-measurements": [ "MRMUq3NiA1DPdYg0rlxl2ejC3H/r5ufZZUu+hk4wDUk=" ],
+                 "allowed-lifecycle-states": [] }"#,
+            r#"{ "realm-initial-measurements": [ "MRMUq3NiA1DPdYg0rlxl2ejC3H/r5ufZZUu+hk4wDUk=" ],
+                 "allowed-lifecycle-states": [ "debug" ] }"#,
+            r#"{ "realm-initial-measurements": [ "MRMUq3NiA1DPdYg0rlxl2ejC3H/r5ufZZUu+hk4wDUk=" ],
+                 "platform-config": [ "z8/Pzw==" ] }"#,
+        ] {
+            assert!(
+                parse_reference_values(document).is_err(),
+                "{document} should be rejected"
+            );
+        }
+    }
+
+    #[test]
+    fn errors_name_the_file() {
+        let path = PathBuf::from("/nonexistent/reference-values.json");
+        let error = ReferenceValuesSource::File(path)
+            .load()
+            .expect_err("missing file");
+        assert!(error
+            .to_string()
+            .contains("/nonexistent/reference-values.json"));
+    }
+
+    #[test]
+    fn reload_swaps_values_and_keeps_them_on_failure() {
+        let path = std::env::temp_dir().join(format!(
+            "keybroker-reference-values-{}.json",
+            std::process::id()
+        ));
+        std::fs::write(&path, format!(r#"[ "{RIM}" ]"#)).unwrap();
+
+        let store = ReferenceValuesStore::new(Some(ReferenceValuesSource::File(path.clone())))
+            .expect("valid reference values");
+        let initial = store.get().expect("reference values");
+
+        std::fs::write(
+            &path,
+            format!(r#"[ "{RIM}", "{}" ]"#, STANDARD.encode([0x5a; 48])),
+        )
+        .unwrap();
+        assert_eq!(
+            store.reload().expect("valid reference values"),
+            ReloadOutcome {
+                previous_count: 1,
+                count: 2
+            }
+        );
+        let reloaded = store.get().expect("reference values");
+        assert_ne!(initial, reloaded);
+
+        std::fs::write(&path, "[").unwrap();
+        assert!(store.reload().is_err());
+        assert_eq!(store.get(), Some(reloaded));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn reload_without_source() {
+        let store = ReferenceValuesStore::new(None).expect("no reference values");
+        assert!(store.get().is_none());
+        assert!(store.reload().is_err());
+    }
+
+    #[test]
+    fn append_deduplicates_and_persists() {
+        let path = std::env::temp_dir().join(format!(
+            "keybroker-reference-values-append-{}.json",
+            std::process::id()
+        ));
+        std::fs::write(
+            &path,
+            format!(
+                r#"{{ "realm-initial-measurements": [ "{RIM}" ],
+                      "allowed-lifecycle-states": [ "secured" ] }}"#
+            ),
+        )
+        .unwrap();
+
+        let store = ReferenceValuesStore::new(Some(ReferenceValuesSource::File(path.clone())))
+            .expect("valid reference values");
+        let new_rim = STANDARD.encode([0x5a; 48]);
+        let update = ReferenceValuesUpdate {
+            reference_values: vec![
+                RIM.to_string(),
+                new_rim.clone(),
+                // The same digest, in hex.
+                "5a".repeat(48),
+            ],
+        };
+
+        assert_eq!(
+            store.append(&update, true).expect("valid reference values"),
+            AppendOutcome {
+                added: 1,
+                count: 2,
+                persisted: true
+            }
+        );
+        assert_eq!(
+            reference_values_of(&store.get().unwrap()),
+            vec![RIM.to_string(), new_rim.clone()]
+        );
+
+        // The persisted file is reloaded to the same values, and keeps the platform ones.
+        assert_eq!(store.reload().expect("valid reference values").count, 2);
+        assert!(store
+            .get()
+            .unwrap()
+            .contains(r#""allowed-lifecycle-states":["secured"]"#));
+
+        assert_eq!(
+            store.append(&update, true).expect("valid reference values"),
+            AppendOutcome {
+                added: 0,
+                count: 2,
+                persisted: false
+            }
+        );
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn append_without_source() {
+        let store = ReferenceValuesStore::new(None).expect("no reference values");
+        let update = ReferenceValuesUpdate {
+            reference_values: vec![RIM.to_string()],
+        };
+
+        assert_eq!(
+            store.append(&update, true).expect("valid reference values"),
+            AppendOutcome {
+                added: 1,
+                count: 1,
+                persisted: false
+            }
+        );
+        assert_eq!(reference_values_of(&store.get().unwrap()), vec![RIM]);
+    }
+
+    #[test]
+    fn suggested_reference_values_merge() {
+        let path = std::env::temp_dir().join(format!(
+            "keybroker-suggested-reference-values-{}.json",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+
+        // Concurrent diagnostics for different measurements all end up in the file.
+        let rims: Vec<String> = (0..8u8).map(|byte| STANDARD.encode([byte; 32])).collect();
+        std::thread::scope(|scope| {
+            for rim in &rims {
+                let path = &path;
+                scope.spawn(move || {
+                    assert!(suggest_reference_value(path, rim).expect("valid measurement"));
+                });
+            }
+        });
+
+        // The same measurement, in hex.
+        assert!(!suggest_reference_value(&path, &"00".repeat(32)).expect("valid measurement"));
+        assert!(suggest_reference_value(&path, "not a digest").is_err());
+
+        // The file is a reference values document, as --reference-values reads it.
+        let values = ReferenceValuesSource::File(path.clone())
+            .load()
+            .expect("valid reference values");
+        assert_eq!(values.realm_initial_measurements.len(), rims.len());
+        for rim in rims {
+            assert!(values
+                .realm_initial_measurements
+                .contains(&MeasurementEntry::Digest(rim)));
+        }
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn append_rejects_implausible_digests() {
+        let store = ReferenceValuesStore::new(None).expect("no reference values");
+
+        for value in ["not base64!", "AAAA", &STANDARD.encode([0; 33])] {
+            let update = ReferenceValuesUpdate {
+                reference_values: vec![RIM.to_string(), value.to_string()],
+            };
+            assert!(
+                store.append(&update, false).is_err(),
+                "{value} should be rejected"
+            );
+        }
+
+        // Nothing was appended.
+        assert!(store.get().is_none());
+    }
+}
@@ -0,0 +1,249 @@
+// Copyright 2024 Contributors to the Veraison project.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Limits on how often a key can be released, whatever the evidence, for the keys whose entry in
+//! the key file has a `release-rate`:
+//!
+//! ```json
+//! "vault-unseal": { "value": "...", "release-rate": { "releases": 1, "window-secs": 60 } }
+//! ```
+//!
+//! Each key has a token bucket holding up to `releases` tokens, refilled at the rate of `releases`
+//! per `window-secs`. Releasing the key, once the evidence is appraised, takes a token. The
+//! buckets are kept apart from the key store and looked up by key identifier, so that they are
+//! not reset when the keys are loaded again.
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+/// The most releases of a key within a window.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ReleaseRate {
+    pub releases: u32,
+    pub window_secs: u64,
+}
+
+impl ReleaseRate {
+    /// The time it takes to get a token back.
+    fn refill_period(&self) -> Duration {
+        Duration::from_secs(self.window_secs) / self.releases
+    }
+}
+
+/// The token bucket of a key.
+#[derive(Debug)]
+struct Bucket {
+    rate: ReleaseRate,
+    tokens: f64,
+    updated: Instant,
+    released: u64,
+    throttled: u64,
+}
+
+impl Bucket {
+    /// The tokens in the bucket at `now`, capped to the capacity of `rate`.
+    fn tokens_at(&self, rate: &ReleaseRate, now: Instant) -> f64 {
+        let refilled = now.saturating_duration_since(self.updated).as_secs_f64()
+            / self.rate.refill_period().as_secs_f64();
+        (self.tokens + refilled).min(f64::from(rate.releases))
+    }
+
+    /// Add the tokens refilled since the last update, following any change to the rate.
+    fn refill(&mut self, rate: &ReleaseRate, now: Instant) {
+        self.tokens = self.tokens_at(rate, now);
+        self.rate = *rate;
+        self.updated = now;
+    }
+}
+
+/// The releases of a key with a release rate, as shown by the admin API.
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ReleaseStats {
+    pub releases: u32,
+    pub window_secs: u64,
+    /// The number of releases that can be made straight away.
+    pub available: u32,
+    /// The number of times the key was released.
+    pub released: u64,
+    /// The number of times the key was not released, as it was released too often.
+    pub throttled: u64,
+}
+
+/// The token buckets of the keys with a release rate.
+#[derive(Debug, Default)]
+pub struct ReleaseThrottle {
+    buckets: HashMap<String, Bucket>,
+}
+
+impl ReleaseThrottle {
+    pub fn new() -> ReleaseThrottle {
+        ReleaseThrottle::default()
+    }
+
+    /// Take a token to release a key, at `rate`. If there is none, returns how long to wait for
+    /// the next one.
+    pub fn try_release(
+        &mut self,
+        key_id: &str,
+        rate: &ReleaseRate,
+        now: Instant,
+    ) -> Result<(), Duration> {
+        let bucket = self
+            .buckets
+            .entry(key_id.to_owned())
+            .or_insert_with(|| Bucket {
+                rate: *rate,
+                tokens: f64::from(rate.releases),
+                updated: now,
+                released: 0,
+                throttled: 0,
+            });
+        bucket.refill(rate, now);
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            bucket.released += 1;
+            Ok(())
+        } else {
+            bucket.throttled += 1;
+            Err(rate.refill_period().mul_f64(1.0 - bucket.tokens))
+        }
+    }
+
+    /// The releases of the keys with the given rates.
+    pub fn stats<'a>(
+        &self,
+        rates: impl Iterator<Item = (&'a str, &'a ReleaseRate)>,
+        now: Instant,
+    ) -> BTreeMap<String, ReleaseStats> {
+        rates
+            .map(|(key_id, rate)| {
+                let (available, released, throttled) = match self.buckets.get(key_id) {
+                    Some(bucket) => (
+                        bucket.tokens_at(rate, now) as u32,
+                        bucket.released,
+                        bucket.throttled,
+                    ),
+                    None => (rate.releases, 0, 0),
+                };
+                let stats = ReleaseStats {
+                    releases: rate.releases,
+                    window_secs: rate.window_secs,
+                    available,
+                    released,
+                    throttled,
+                };
+                (key_id.to_owned(), stats)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TWICE_A_MINUTE: ReleaseRate = ReleaseRate {
+        releases: 2,
+        window_secs: 60,
+    };
+
+    #[test]
+    fn token_bucket() {
+        let mut throttle = ReleaseThrottle::new();
+        let start = Instant::now();
+
+        assert!(throttle
+            .try_release("vault-unseal", &TWICE_A_MINUTE, start)
+            .is_ok());
+        assert!(throttle
+            .try_release("vault-unseal", &TWICE_A_MINUTE, start)
+            .is_ok());
+        assert_eq!(
+            throttle.try_release("vault-unseal", &TWICE_A_MINUTE, start),
+            Err(Duration::from_secs(30))
+        );
+        assert!(matches!(
+            throttle.try_release("vault-unseal", &TWICE_A_MINUTE, start + Duration::from_secs(10)),
+            Err(retry_after) if retry_after.as_secs_f64().round() == 20.0
+        ));
+        // The other keys have buckets of their own.
+        assert!(throttle
+            .try_release("skywalker", &TWICE_A_MINUTE, start)
+            .is_ok());
+
+        // A token is back every 30 seconds.
+        assert!(throttle
+            .try_release(
+                "vault-unseal",
+                &TWICE_A_MINUTE,
+                start + Duration::from_secs(31)
+            )
+            .is_ok());
+        assert!(throttle
+            .try_release(
+                "vault-unseal",
+                &TWICE_A_MINUTE,
+                start + Duration::from_secs(32)
+            )
+            .is_err());
+
+        let stats = throttle.stats(
+            [
+                ("vault-unseal", &TWICE_A_MINUTE),
+                ("deathstar", &TWICE_A_MINUTE),
+            ]
+            .into_iter(),
+            start + Duration::from_secs(32),
+        );
+        assert_eq!(
+            stats["vault-unseal"],
+            ReleaseStats {
+                releases: 2,
+                window_secs: 60,
+                available: 0,
+                released: 3,
+                throttled: 3,
+            }
+        );
+        assert_eq!(stats["deathstar"].available, 2);
+        assert!(!stats.contains_key("skywalker"));
+    }
+
+    #[test]
+    fn rate_change() {
+        let mut throttle = ReleaseThrottle::new();
+        let start = Instant::now();
+        for _ in 0..2 {
+            assert!(throttle
+                .try_release("vault-unseal", &TWICE_A_MINUTE, start)
+                .is_ok());
+        }
+
+        // The bucket is not refilled by a change of rate, and the counts are kept.
+        let once_a_second = ReleaseRate {
+            releases: 1,
+            window_secs: 1,
+        };
+        assert_eq!(
+            throttle.try_release("vault-unseal", &once_a_second, start),
+            Err(Duration::from_secs(1))
+        );
+        assert!(throttle
+            .try_release(
+                "vault-unseal",
+                &once_a_second,
+                start + Duration::from_secs(1)
+            )
+            .is_ok());
+
+        let stats = throttle.stats(
+            [("vault-unseal", &once_a_second)].into_iter(),
+            start + Duration::from_secs(1),
+        );
+        assert_eq!(stats["vault-unseal"].released, 3);
+        assert_eq!(stats["vault-unseal"].throttled, 1);
+    }
+}
@@ -0,0 +1,14 @@
+package keybroker.submods
+
+# rules shared by the policies of all the evidence types, whatever the profile of their
+# attestation results
+
+# every submodule of the attestation result is affirming, there being at least one
+default all_affirming := false
+
+all_affirming if {
+    count(input.ear.submods) > 0
+    every submod in input.ear.submods {
+        submod["ear.status"] == "affirming"
+    }
+}
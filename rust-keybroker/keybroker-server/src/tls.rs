@@ -0,0 +1,215 @@
+// Copyright 2024 Contributors to the Veraison project.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Serving HTTPS, with a certificate that can be replaced without restarting the server.
+//!
+//! The certificate chain and its private key are read from PEM files. Rather than being fixed in
+//! the TLS configuration, they are handed out to each new TLS handshake by a resolver, which can
+//! swap them atomically when the files are renewed. The connections already established keep the
+//! certificate they were set up with.
+//!
+//! A reload that fails, because a file can't be read or parsed, or because the key does not match
+//! the certificate, leaves the previous certificate in place.
+//!
+//! For demonstrations, the server can instead present an ephemeral self-signed certificate,
+//! generated at startup, which the clients are then given to trust.
+use crate::error::{Error, Result};
+use arc_swap::ArcSwap;
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::ServerConfig;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Resolves the certificate of the server to the one most recently loaded from its files.
+#[derive(Debug)]
+pub struct CertificateResolver {
+    /// The certificate and key files, unless the certificate is a self-signed one.
+    files: Option<(PathBuf, PathBuf)>,
+    provider: Arc<CryptoProvider>,
+    current: ArcSwap<CertifiedKey>,
+}
+
+impl CertificateResolver {
+    /// Load the certificate chain and the private key from their PEM files.
+    pub fn load(cert_path: &Path, key_path: &Path) -> Result<CertificateResolver> {
+        let provider = Arc::new(ring::default_provider());
+        let certified_key = load_certified_key(cert_path, key_path, &provider)?;
+
+        Ok(CertificateResolver {
+            files: Some((cert_path.to_path_buf(), key_path.to_path_buf())),
+            provider,
+            current: ArcSwap::from_pointee(certified_key),
+        })
+    }
+
+    /// Generate a self-signed certificate for the given host names and IP addresses, returning
+    /// it in PEM along with the resolver presenting it.
+    pub fn self_signed(names: Vec<String>) -> Result<(CertificateResolver, String)> {
+        let provider = Arc::new(ring::default_provider());
+        let rcgen::CertifiedKey { cert, key_pair } = rcgen::generate_simple_self_signed(names)
+            .map_err(|error| {
+                Error::Tls(format!(
+                    "failed to generate a self-signed certificate: {error}"
+                ))
+            })?;
+        let key = PrivateKeyDer::Pkcs8(key_pair.serialize_der().into());
+        let certified_key = CertifiedKey::from_der(vec![cert.der().clone()], key, &provider)
+            .map_err(|error| Error::Tls(format!("unusable self-signed certificate: {error}")))?;
+
+        let resolver = CertificateResolver {
+            files: None,
+            provider,
+            current: ArcSwap::from_pointee(certified_key),
+        };
+        Ok((resolver, cert.pem()))
+    }
+
+    /// Load the certificate chain and the private key again, for the handshakes to come. On
+    /// failure, the previous ones are kept. A self-signed certificate is never replaced.
+    pub fn reload(&self) -> Result<()> {
+        let Some((cert_path, key_path)) = &self.files else {
+            return Ok(());
+        };
+
+        match load_certified_key(cert_path, key_path, &self.provider) {
+            Ok(certified_key) => {
+                self.current.store(Arc::new(certified_key));
+                log::info!("Reloaded the TLS certificate from {}.", cert_path.display());
+                Ok(())
+            }
+            Err(error) => {
+                log::error!(
+                    "Failed to reload the TLS certificate, the previous one is kept: {error}"
+                );
+                Err(error)
+            }
+        }
+    }
+
+    /// The TLS configuration of a server presenting the certificates of this resolver.
+    pub fn server_config(self: Arc<Self>) -> ServerConfig {
+        ServerConfig::builder_with_provider(self.provider.clone())
+            .with_safe_default_protocol_versions()
+            .expect("The default TLS protocol versions are not supported.")
+            .with_no_client_auth()
+            .with_cert_resolver(self)
+    }
+}
+
+impl ResolvesServerCert for CertificateResolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.load_full())
+    }
+}
+
+fn tls_error(path: &Path, detail: impl std::fmt::Display) -> Error {
+    Error::Tls(format!("{}: {detail}", path.display()))
+}
+
+/// Load a certificate chain and its private key, checking that they go together.
+fn load_certified_key(
+    cert_path: &Path,
+    key_path: &Path,
+    provider: &CryptoProvider,
+) -> Result<CertifiedKey> {
+    let cert_chain = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<std::result::Result<Vec<_>, _>>())
+        .map_err(|error| tls_error(cert_path, error))?;
+    if cert_chain.is_empty() {
+        return Err(tls_error(cert_path, "no certificate found"));
+    }
+
+    let key = PrivateKeyDer::from_pem_file(key_path).map_err(|error| tls_error(key_path, error))?;
+
+    CertifiedKey::from_der(cert_chain, key, provider).map_err(|error| {
+        tls_error(
+            key_path,
+            format!("not a usable key for {}: {error}", cert_path.display()),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn testdata_path(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../../testdata")
+            .join(name)
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("keybroker-tls-{}-{name}", std::process::id()))
+    }
+
+    fn presented(resolver: &CertificateResolver) -> CertificateDer<'static> {
+        resolver.current.load().cert[0].clone()
+    }
+
+    #[test]
+    fn reload() {
+        let cert_path = temp_path("reload-cert.pem");
+        let key_path = temp_path("reload-key.pem");
+        std::fs::copy(testdata_path("tls-cert.pem"), &cert_path).unwrap();
+        std::fs::copy(testdata_path("tls-key.pem"), &key_path).unwrap();
+
+        let resolver = CertificateResolver::load(&cert_path, &key_path).unwrap();
+        let original = presented(&resolver);
+
+        // A renewed certificate with the old key does not go.
+        std::fs::copy(testdata_path("tls-cert-renewed.pem"), &cert_path).unwrap();
+        assert!(matches!(resolver.reload(), Err(Error::Tls(_))));
+        assert_eq!(presented(&resolver), original);
+
+        // Neither does a file which is not PEM.
+        std::fs::write(&key_path, "not a key").unwrap();
+        assert!(matches!(resolver.reload(), Err(Error::Tls(_))));
+        assert_eq!(presented(&resolver), original);
+
+        std::fs::copy(testdata_path("tls-key-renewed.pem"), &key_path).unwrap();
+        resolver.reload().unwrap();
+        assert_ne!(presented(&resolver), original);
+
+        std::fs::remove_file(&cert_path).unwrap();
+        std::fs::remove_file(&key_path).unwrap();
+    }
+
+    #[test]
+    fn invalid_files() {
+        let cert_path = testdata_path("tls-cert.pem");
+        let key_path = testdata_path("tls-key.pem");
+
+        for (cert, key) in [
+            (&key_path, &key_path),
+            (&cert_path, &cert_path),
+            (&cert_path, &testdata_path("tls-key-renewed.pem")),
+            (&testdata_path("missing.pem"), &key_path),
+        ] {
+            assert!(
+                matches!(CertificateResolver::load(cert, key), Err(Error::Tls(_))),
+                "{} {}",
+                cert.display(),
+                key.display()
+            );
+        }
+    }
+
+    #[test]
+    fn self_signed() {
+        let names = vec!["keybroker.test".to_string(), "127.0.0.1".to_string()];
+        let (resolver, pem) = CertificateResolver::self_signed(names).unwrap();
+
+        // The certificate returned is the one presented.
+        let cert = CertificateDer::from_pem_slice(pem.as_bytes()).unwrap();
+        assert_eq!(presented(&resolver), cert);
+
+        // There is nothing to reload it from.
+        resolver.reload().unwrap();
+        assert_eq!(presented(&resolver), cert);
+    }
+}
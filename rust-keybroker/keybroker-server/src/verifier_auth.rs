@@ -0,0 +1,810 @@
+// Copyright 2024 Contributors to the Veraison project.
+// SPDX-License-Identifier: Apache-2.0
+
+//! This module handles the authentication of the keybroker to the Veraison verifier.
+//!
+//! Some Veraison deployments sit behind an authenticating proxy, or make use of the built-in
+//! authentication of the Veraison services. In both cases, the keybroker must present credentials
+//! on each of its calls to the verifier, in the form of an `Authorization` HTTP header.
+//!
+//! Two authentication methods are supported:
+//!
+//! - `bearer:<token-file>`: a static bearer token is read from a file at startup, and injected as is.
+//! - `oauth2:<client-id>,<secret-file>,<token-url>`: an access token is obtained from the token
+//!   endpoint with the OAuth2 client-credentials grant. The token is cached, and refreshed when
+//!   it expires or when the verifier rejects it, the rejected request being sent again once with
+//!   the fresh token.
+//!
+//! The `veraison_apiclient` crate does not provide a way to add headers to its requests, so when
+//! authentication is in use, the discovery exchanges are performed by this module directly. Nor
+//! does it tell the status of the responses, which tells whether the verifier failed, so that the
+//! evidence may be appraised again, or turned it down: the challenge-response exchanges are always
+//! performed by this module, with or without authentication.
+use crate::error::{Error, Result, VerificationErrorKind};
+use crate::verifier::{session_result, SessionPolling};
+use base64::engine::general_purpose::URL_SAFE;
+use base64::prelude::*;
+use reqwest::blocking::{Client, RequestBuilder, Response};
+use reqwest::{header, StatusCode};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+const DISCOVERY_MEDIA_TYPE: &str = "application/vnd.veraison.discovery+json";
+pub(crate) const SESSION_MEDIA_TYPE: &str =
+    "application/vnd.veraison.challenge-response-session+json";
+
+/// The tokens are refreshed a little before their announced expiry, so that they do not
+/// expire while a request is in flight.
+const TOKEN_EXPIRY_MARGIN: Duration = Duration::from_secs(30);
+
+/// The authentication method used to access the verifier, as specified on the command line.
+#[derive(Clone, Debug, PartialEq)]
+pub enum VerifierAuth {
+    /// A static bearer token, read from the given file.
+    Bearer(PathBuf),
+
+    /// The OAuth2 client-credentials grant.
+    OAuth2 {
+        client_id: String,
+        secret_file: PathBuf,
+        token_url: String,
+    },
+}
+
+impl FromStr for VerifierAuth {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("bearer", token_file)) if !token_file.is_empty() => {
+                Ok(VerifierAuth::Bearer(PathBuf::from(token_file)))
+            }
+            Some(("oauth2", params)) => match params.splitn(3, ',').collect::<Vec<_>>()[..] {
+                [client_id, secret_file, token_url]
+                    if !client_id.is_empty()
+                        && !secret_file.is_empty()
+                        && !token_url.is_empty() =>
+                {
+                    Ok(VerifierAuth::OAuth2 {
+                        client_id: client_id.to_string(),
+                        secret_file: PathBuf::from(secret_file),
+                        token_url: token_url.to_string(),
+                    })
+                }
+                _ => Err("expected oauth2:<client-id>,<secret-file>,<token-url>".to_string()),
+            },
+            _ => Err(
+                "expected bearer:<token-file> or oauth2:<client-id>,<secret-file>,<token-url>"
+                    .to_string(),
+            ),
+        }
+    }
+}
+
+/// The credentials, once loaded from their files.
+enum Credentials {
+    Bearer(String),
+    OAuth2 {
+        client_id: String,
+        client_secret: String,
+        token_url: String,
+    },
+}
+
+/// An OAuth2 access token, along with the time at which it must be refreshed.
+struct CachedToken {
+    access_token: String,
+    refresh_at: Instant,
+}
+
+#[derive(serde::Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: Option<u64>,
+}
+
+/// The members of the verification API description, as returned by the discovery API of the
+/// verifier, that the keybroker makes use of.
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct VerificationApi {
+    /// The key the attestation results are signed with, as a JWK or a JWK set.
+    pub ear_verification_key: serde_json::Value,
+
+    /// The media types of the evidence that the verifier supports.
+    pub media_types: Vec<String>,
+
+    /// The endpoints of the verification API, by name.
+    pub api_endpoints: HashMap<String, String>,
+}
+
+/// Provides the `Authorization` header value for the calls to the verifier, if authentication is in
+/// use, and performs these calls.
+pub struct VerifierAuthenticator {
+    credentials: Option<Credentials>,
+    token: Mutex<Option<CachedToken>>,
+    root_certificate: Option<reqwest::Certificate>,
+    /// Built on first use, as the blocking HTTP client can't be built from the async runtime of
+    /// the server, but only from the blocking tasks the evidence is appraised in.
+    client: OnceLock<Client>,
+}
+
+fn read_secret(path: &Path) -> Result<String> {
+    Ok(std::fs::read_to_string(path)?.trim().to_string())
+}
+
+impl VerifierAuthenticator {
+    /// Load the credentials, if any, and the root certificate to trust for the verifier.
+    pub fn new(auth: Option<&VerifierAuth>, root_certificate: &Option<PathBuf>) -> Result<Self> {
+        let credentials = match auth {
+            None => None,
+            Some(VerifierAuth::Bearer(token_file)) => {
+                Some(Credentials::Bearer(read_secret(token_file)?))
+            }
+            Some(VerifierAuth::OAuth2 {
+                client_id,
+                secret_file,
+                token_url,
+            }) => Some(Credentials::OAuth2 {
+                client_id: client_id.clone(),
+                client_secret: read_secret(secret_file)?,
+                token_url: token_url.clone(),
+            }),
+        };
+
+        let root_certificate = match root_certificate {
+            Some(root_certificate) => Some(reqwest::Certificate::from_pem(&std::fs::read(
+                root_certificate,
+            )?)?),
+            None => None,
+        };
+
+        Ok(VerifierAuthenticator {
+            credentials,
+            token: Mutex::new(None),
+            root_certificate,
+            client: OnceLock::new(),
+        })
+    }
+
+    fn client(&self) -> Result<&Client> {
+        if let Some(client) = self.client.get() {
+            return Ok(client);
+        }
+        let mut client = Client::builder();
+        if let Some(root_certificate) = &self.root_certificate {
+            client = client.add_root_certificate(root_certificate.clone());
+        }
+        let client = client.build()?;
+        Ok(self.client.get_or_init(|| client))
+    }
+
+    /// Whether the calls to the verifier are authenticated.
+    pub fn is_authenticated(&self) -> bool {
+        self.credentials.is_some()
+    }
+
+    /// Get the `Authorization` header value, if authentication is in use, fetching a new OAuth2
+    /// access token if needed.
+    fn authorization(&self) -> Result<Option<String>> {
+        let (client_id, client_secret, token_url) = match &self.credentials {
+            None => return Ok(None),
+            Some(Credentials::Bearer(token)) => return Ok(Some(format!("Bearer {token}"))),
+            Some(Credentials::OAuth2 {
+                client_id,
+                client_secret,
+                token_url,
+            }) => (client_id, client_secret, token_url),
+        };
+
+        let mut cached = self.token.lock().expect("Poisoned token lock.");
+        if let Some(token) = cached.as_ref() {
+            if Instant::now() < token.refresh_at {
+                return Ok(Some(format!("Bearer {}", token.access_token)));
+            }
+        }
+
+        log::debug!("Requesting a verifier access token from {token_url}");
+        let response = self
+            .client()?
+            .post(token_url)
+            .form(&[
+                ("grant_type", "client_credentials"),
+                ("client_id", client_id),
+                ("client_secret", client_secret),
+            ])
+            .send()?;
+
+        if !response.status().is_success() {
+            log::error!(
+                "The token endpoint {token_url} refused to issue an access token: {}",
+                response.status()
+            );
+            return Err(Error::Verification(
+                VerificationErrorKind::VerifierCredentialsRejected,
+            ));
+        }
+
+        let token: TokenResponse = response.json()?;
+        let lifetime = Duration::from_secs(token.expires_in.unwrap_or(300));
+        let authorization = format!("Bearer {}", token.access_token);
+
+        *cached = Some(CachedToken {
+            access_token: token.access_token,
+            refresh_at: Instant::now() + lifetime.saturating_sub(TOKEN_EXPIRY_MARGIN),
+        });
+
+        Ok(Some(authorization))
+    }
+
+    /// Drop the cached access token, so that the next call gets a fresh one.
+    fn drop_token(&self) {
+        *self.token.lock().expect("Poisoned token lock.") = None;
+    }
+
+    /// Send a request to the verifier, authenticated if authentication is in use.
+    fn send_authenticated(&self, mut request: RequestBuilder) -> Result<Response> {
+        if let Some(authorization) = self.authorization()? {
+            request = request.header(header::AUTHORIZATION, authorization);
+        }
+        Ok(request.send()?)
+    }
+
+    /// Send a request to the verifier, authenticated if authentication is in use.
+    ///
+    /// If the verifier rejects an OAuth2 access token, which may have been revoked before it
+    /// expired, the request is sent again once with a fresh token. If it rejects our credentials
+    /// still, the cached access token is dropped (so that the next call gets a fresh one) and a
+    /// distinct error is returned.
+    fn send(&self, request: RequestBuilder) -> Result<Response> {
+        let retry = match self.credentials {
+            Some(Credentials::OAuth2 { .. }) => request.try_clone(),
+            _ => None,
+        };
+        let mut response = self.send_authenticated(request)?;
+        if let (StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN, Some(retry)) =
+            (response.status(), retry)
+        {
+            log::debug!(
+                "The verifier rejected the access token ({}), requesting a fresh one.",
+                response.status()
+            );
+            self.drop_token();
+            response = self.send_authenticated(retry)?;
+        }
+
+        match response.status() {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+                self.drop_token();
+                Err(Error::Verification(
+                    VerificationErrorKind::VerifierCredentialsRejected,
+                ))
+            }
+            status if !status.is_success() => Err(Error::Verification(
+                VerificationErrorKind::VerifierStatus(status, response.url().to_string()),
+            )),
+            _ => Ok(response),
+        }
+    }
+
+    /// Query the discovery API of the verifier located at `base_url`, for its verification API
+    /// description.
+    pub fn discover(&self, base_url: &str) -> Result<VerificationApi> {
+        let url = format!("{base_url}/.well-known/veraison/verification");
+        let request = self
+            .client()?
+            .get(url)
+            .header(header::ACCEPT, DISCOVERY_MEDIA_TYPE);
+
+        let description = self.send(request)?.bytes()?;
+        serde_json::from_slice(&description).map_err(|error| {
+            Error::Verification(VerificationErrorKind::VerifierResponse(format!(
+                "malformed verification API description: {error}"
+            )))
+        })
+    }
+
+    /// Run a complete challenge-response session against the verifier, returning the EAR.
+    ///
+    /// If the verifier processes the evidence asynchronously, the session is polled until it
+    /// completes, or until the deadline.
+    pub fn challenge_response(
+        &self,
+        new_session_url: &str,
+        nonce: &[u8],
+        evidence: &[u8],
+        media_type: &str,
+        polling: &SessionPolling,
+        deadline: Instant,
+    ) -> Result<String> {
+        let mut url = reqwest::Url::parse(new_session_url).map_err(|e| {
+            Error::Verification(VerificationErrorKind::VerifierResponse(e.to_string()))
+        })?;
+        url.query_pairs_mut()
+            .append_pair("nonce", &URL_SAFE.encode(nonce));
+
+        let response = self.send(
+            self.client()?
+                .post(url.clone())
+                .header(header::ACCEPT, SESSION_MEDIA_TYPE),
+        )?;
+
+        let session_url = response
+            .headers()
+            .get(header::LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .and_then(|location| url.join(location).ok())
+            .ok_or(Error::Verification(
+                VerificationErrorKind::VerifierResponse(
+                    "no session location returned by the verifier".to_string(),
+                ),
+            ))?;
+
+        let session: serde_json::Value = self
+            .send(
+                self.client()?
+                    .post(session_url.clone())
+                    .header(header::ACCEPT, SESSION_MEDIA_TYPE)
+                    .header(header::CONTENT_TYPE, media_type)
+                    .body(evidence.to_vec()),
+            )?
+            .json()?;
+
+        let result = session_result(session_url.as_str(), session, polling, deadline, || {
+            Ok(self
+                .send(
+                    self.client()?
+                        .get(session_url.clone())
+                        .header(header::ACCEPT, SESSION_MEDIA_TYPE),
+                )?
+                .json()?)
+        });
+
+        // The session is no longer needed once we have the result.
+        if let Err(error) = self.send(self.client()?.delete(session_url)) {
+            log::warn!("Failed to delete the verifier session: {error}");
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::rt::task;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use wiremock::matchers::{body_string_contains, header, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    const DISCOVERY_PATH: &str = "/.well-known/veraison/verification";
+    const TOKEN_PATH: &str = "/token";
+    const NEW_SESSION_PATH: &str = "/challenge-response/v1/newSession";
+    const SESSION_PATH: &str = "/challenge-response/v1/session/1";
+    const MEDIA_TYPE: &str = "application/eat-collection";
+
+    const POLLING: SessionPolling = SessionPolling {
+        interval: Duration::from_millis(10),
+        deadline: Duration::from_secs(5),
+    };
+
+    fn processing() -> ResponseTemplate {
+        ResponseTemplate::new(200).set_body_json(serde_json::json!({ "status": "processing" }))
+    }
+
+    /// A verifier which opens a session, and answers the submission of the evidence to it with
+    /// `submission`.
+    async fn mock_verifier(submission: ResponseTemplate) -> MockServer {
+        let verifier = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path(NEW_SESSION_PATH))
+            .respond_with(
+                ResponseTemplate::new(201)
+                    .insert_header("location", SESSION_PATH)
+                    .set_body_json(serde_json::json!({ "status": "waiting" })),
+            )
+            .mount(&verifier)
+            .await;
+        Mock::given(method("POST"))
+            .and(path(SESSION_PATH))
+            .and(header("content-type", MEDIA_TYPE))
+            .respond_with(submission)
+            .mount(&verifier)
+            .await;
+        Mock::given(method("DELETE"))
+            .and(path(SESSION_PATH))
+            .respond_with(ResponseTemplate::new(204))
+            .mount(&verifier)
+            .await;
+        verifier
+    }
+
+    /// Run a challenge-response session with the verifier, over HTTP.
+    async fn challenge_response(verifier: &MockServer) -> Result<String> {
+        let new_session_url = format!("{}{NEW_SESSION_PATH}", verifier.uri());
+        task::spawn_blocking(move || {
+            VerifierAuthenticator::new(None, &None)?.challenge_response(
+                &new_session_url,
+                b"nonce",
+                b"evidence",
+                MEDIA_TYPE,
+                &POLLING,
+                Instant::now() + POLLING.deadline,
+            )
+        })
+        .await
+        .unwrap()
+    }
+
+    /// The number of requests of the given method the verifier received for the session.
+    async fn session_requests(verifier: &MockServer, method: &str) -> usize {
+        verifier
+            .received_requests()
+            .await
+            .unwrap()
+            .iter()
+            .filter(|request| {
+                request.method.as_str() == method && request.url.path() == SESSION_PATH
+            })
+            .count()
+    }
+
+    #[actix_web::test]
+    async fn session_polled_over_http() {
+        let verifier = mock_verifier(processing()).await;
+        Mock::given(method("GET"))
+            .and(path(SESSION_PATH))
+            .respond_with(processing())
+            .up_to_n_times(2)
+            .mount(&verifier)
+            .await;
+        Mock::given(method("GET"))
+            .and(path(SESSION_PATH))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(serde_json::json!({ "status": "complete", "result": "ear" })),
+            )
+            .mount(&verifier)
+            .await;
+
+        assert_eq!(challenge_response(&verifier).await.unwrap(), "ear");
+        assert_eq!(session_requests(&verifier, "GET").await, 3);
+        assert_eq!(session_requests(&verifier, "DELETE").await, 1);
+    }
+
+    #[actix_web::test]
+    async fn session_error_statuses() {
+        // The verifier turns the evidence down.
+        let verifier = mock_verifier(ResponseTemplate::new(400)).await;
+        assert!(matches!(
+            challenge_response(&verifier).await,
+            Err(Error::Verification(VerificationErrorKind::VerifierStatus(
+                StatusCode::BAD_REQUEST,
+                _
+            )))
+        ));
+
+        // The verifier fails while the session is processing.
+        let verifier = mock_verifier(processing()).await;
+        Mock::given(method("GET"))
+            .and(path(SESSION_PATH))
+            .respond_with(ResponseTemplate::new(503))
+            .mount(&verifier)
+            .await;
+        assert!(matches!(
+            challenge_response(&verifier).await,
+            Err(Error::Verification(VerificationErrorKind::VerifierStatus(
+                StatusCode::SERVICE_UNAVAILABLE,
+                _
+            )))
+        ));
+        assert_eq!(session_requests(&verifier, "DELETE").await, 1);
+    }
+
+    #[actix_web::test]
+    async fn malformed_sessions() {
+        // Not JSON.
+        let verifier =
+            mock_verifier(ResponseTemplate::new(200).set_body_string("not a session")).await;
+        assert!(matches!(
+            challenge_response(&verifier).await,
+            Err(Error::Http(_))
+        ));
+
+        // Not JSON once polled.
+        let verifier = mock_verifier(processing()).await;
+        Mock::given(method("GET"))
+            .and(path(SESSION_PATH))
+            .respond_with(ResponseTemplate::new(200).set_body_string("{"))
+            .mount(&verifier)
+            .await;
+        assert!(matches!(
+            challenge_response(&verifier).await,
+            Err(Error::Http(_))
+        ));
+
+        // A complete session without result.
+        let verifier = mock_verifier(
+            ResponseTemplate::new(200).set_body_json(serde_json::json!({ "status": "complete" })),
+        )
+        .await;
+        assert!(matches!(
+            challenge_response(&verifier).await,
+            Err(Error::Verification(VerificationErrorKind::SessionFailed(_)))
+        ));
+
+        // No session location.
+        let verifier = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path(NEW_SESSION_PATH))
+            .respond_with(ResponseTemplate::new(201))
+            .mount(&verifier)
+            .await;
+        assert!(matches!(
+            challenge_response(&verifier).await,
+            Err(Error::Verification(
+                VerificationErrorKind::VerifierResponse(_)
+            ))
+        ));
+    }
+
+    /// The verification API description of a verifier.
+    fn verification_api() -> ResponseTemplate {
+        ResponseTemplate::new(200).set_body_json(serde_json::json!({
+            "ear-verification-key": { "kty": "EC", "crv": "P-256", "x": "AQAB", "y": "AQAB" },
+            "media-types": [MEDIA_TYPE],
+            "version": "mock",
+            "api-endpoints": { "newChallengeResponseSession": NEW_SESSION_PATH },
+        }))
+    }
+
+    /// An OAuth2 access token, as issued by the token endpoint.
+    fn access_token(token: &str) -> ResponseTemplate {
+        ResponseTemplate::new(200).set_body_json(serde_json::json!({
+            "access_token": token,
+            "token_type": "Bearer",
+            "expires_in": 3600,
+        }))
+    }
+
+    /// OAuth2 credentials, with the token endpoint of the verifier.
+    fn oauth2(verifier: &MockServer) -> impl FnOnce(PathBuf) -> VerifierAuth + Send + 'static {
+        let token_url = format!("{}{TOKEN_PATH}", verifier.uri());
+        move |secret_file| VerifierAuth::OAuth2 {
+            client_id: "keybroker".to_string(),
+            secret_file,
+            token_url,
+        }
+    }
+
+    /// Query the discovery API of the verifier `count` times with the same authenticator, whose
+    /// credentials `auth` makes from a file holding the secret 's3cret'.
+    async fn discover(
+        verifier: &MockServer,
+        count: usize,
+        auth: impl FnOnce(PathBuf) -> VerifierAuth + Send + 'static,
+    ) -> Result<VerificationApi> {
+        static SECRET_FILES: AtomicUsize = AtomicUsize::new(0);
+        let secret_file = std::env::temp_dir().join(format!(
+            "keybroker-verifier-auth-{}-{}",
+            std::process::id(),
+            SECRET_FILES.fetch_add(1, Ordering::Relaxed)
+        ));
+        let base_url = verifier.uri();
+        task::spawn_blocking(move || {
+            std::fs::write(&secret_file, "s3cret\n").unwrap();
+            let authenticator = VerifierAuthenticator::new(Some(&auth(secret_file.clone())), &None);
+            std::fs::remove_file(&secret_file).unwrap();
+            let authenticator = authenticator?;
+            for _ in 1..count {
+                authenticator.discover(&base_url)?;
+            }
+            authenticator.discover(&base_url)
+        })
+        .await
+        .unwrap()
+    }
+
+    /// The number of requests the server received for the given path.
+    async fn requests_to(server: &MockServer, request_path: &str) -> usize {
+        server
+            .received_requests()
+            .await
+            .unwrap()
+            .iter()
+            .filter(|request| request.url.path() == request_path)
+            .count()
+    }
+
+    #[actix_web::test]
+    async fn oauth2_token_cached() {
+        let verifier = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path(TOKEN_PATH))
+            .and(body_string_contains("grant_type=client_credentials"))
+            .and(body_string_contains("client_id=keybroker"))
+            .and(body_string_contains("client_secret=s3cret"))
+            .respond_with(access_token("token-1"))
+            .mount(&verifier)
+            .await;
+        Mock::given(method("GET"))
+            .and(path(DISCOVERY_PATH))
+            .and(header("authorization", "Bearer token-1"))
+            .respond_with(verification_api())
+            .mount(&verifier)
+            .await;
+
+        let verification_api = discover(&verifier, 3, oauth2(&verifier)).await.unwrap();
+        assert_eq!(verification_api.media_types, [MEDIA_TYPE]);
+        assert_eq!(
+            verification_api.api_endpoints["newChallengeResponseSession"],
+            NEW_SESSION_PATH
+        );
+        assert_eq!(verification_api.ear_verification_key["crv"], "P-256");
+        assert_eq!(requests_to(&verifier, TOKEN_PATH).await, 1);
+        assert_eq!(requests_to(&verifier, DISCOVERY_PATH).await, 3);
+    }
+
+    #[actix_web::test]
+    async fn oauth2_token_refreshed_on_rejection() {
+        // The first token is revoked before it expires.
+        let verifier = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path(TOKEN_PATH))
+            .respond_with(access_token("revoked"))
+            .up_to_n_times(1)
+            .mount(&verifier)
+            .await;
+        Mock::given(method("POST"))
+            .and(path(TOKEN_PATH))
+            .respond_with(access_token("token-2"))
+            .mount(&verifier)
+            .await;
+        Mock::given(method("GET"))
+            .and(path(DISCOVERY_PATH))
+            .and(header("authorization", "Bearer token-2"))
+            .respond_with(verification_api())
+            .mount(&verifier)
+            .await;
+        Mock::given(method("GET"))
+            .and(path(DISCOVERY_PATH))
+            .respond_with(ResponseTemplate::new(401))
+            .with_priority(10)
+            .mount(&verifier)
+            .await;
+
+        discover(&verifier, 1, oauth2(&verifier)).await.unwrap();
+        assert_eq!(requests_to(&verifier, TOKEN_PATH).await, 2);
+        assert_eq!(requests_to(&verifier, DISCOVERY_PATH).await, 2);
+    }
+
+    #[actix_web::test]
+    async fn oauth2_credentials_rejected() {
+        // The fresh token is rejected too: the request is only sent again once.
+        let verifier = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path(TOKEN_PATH))
+            .respond_with(access_token("token-1"))
+            .mount(&verifier)
+            .await;
+        Mock::given(method("GET"))
+            .and(path(DISCOVERY_PATH))
+            .respond_with(ResponseTemplate::new(403))
+            .mount(&verifier)
+            .await;
+        assert!(matches!(
+            discover(&verifier, 1, oauth2(&verifier)).await,
+            Err(Error::Verification(
+                VerificationErrorKind::VerifierCredentialsRejected
+            ))
+        ));
+        assert_eq!(requests_to(&verifier, TOKEN_PATH).await, 2);
+        assert_eq!(requests_to(&verifier, DISCOVERY_PATH).await, 2);
+
+        // The token endpoint refuses to issue a token.
+        let verifier = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path(TOKEN_PATH))
+            .respond_with(ResponseTemplate::new(401))
+            .mount(&verifier)
+            .await;
+        assert!(matches!(
+            discover(&verifier, 1, oauth2(&verifier)).await,
+            Err(Error::Verification(
+                VerificationErrorKind::VerifierCredentialsRejected
+            ))
+        ));
+        assert_eq!(requests_to(&verifier, DISCOVERY_PATH).await, 0);
+    }
+
+    #[actix_web::test]
+    async fn bearer_token() {
+        let verifier = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path(DISCOVERY_PATH))
+            .and(header("authorization", "Bearer s3cret"))
+            .respond_with(verification_api())
+            .mount(&verifier)
+            .await;
+        discover(&verifier, 1, VerifierAuth::Bearer).await.unwrap();
+
+        // A static token is not sent again once rejected.
+        let verifier = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path(DISCOVERY_PATH))
+            .respond_with(ResponseTemplate::new(401))
+            .mount(&verifier)
+            .await;
+        assert!(matches!(
+            discover(&verifier, 1, VerifierAuth::Bearer).await,
+            Err(Error::Verification(
+                VerificationErrorKind::VerifierCredentialsRejected
+            ))
+        ));
+        assert_eq!(requests_to(&verifier, DISCOVERY_PATH).await, 1);
+    }
+
+    #[actix_web::test]
+    async fn malformed_verification_api() {
+        for description in [
+            ResponseTemplate::new(200).set_body_string("not JSON"),
+            // No EAR verification key.
+            ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "media-types": [MEDIA_TYPE],
+                "api-endpoints": { "newChallengeResponseSession": NEW_SESSION_PATH },
+            })),
+            // Media types which are not strings.
+            ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "ear-verification-key": {},
+                "media-types": [42],
+                "api-endpoints": { "newChallengeResponseSession": NEW_SESSION_PATH },
+            })),
+        ] {
+            let verifier = MockServer::start().await;
+            Mock::given(method("GET"))
+                .and(path(DISCOVERY_PATH))
+                .respond_with(description)
+                .mount(&verifier)
+                .await;
+            assert!(matches!(
+                discover(&verifier, 1, VerifierAuth::Bearer).await,
+                Err(Error::Verification(
+                    VerificationErrorKind::VerifierResponse(_)
+                ))
+            ));
+        }
+    }
+
+    #[test]
+    fn parse_bearer() {
+        assert_eq!(
+            "bearer:/run/secrets/token".parse::<VerifierAuth>(),
+            Ok(VerifierAuth::Bearer(PathBuf::from("/run/secrets/token")))
+        );
+    }
+
+    #[test]
+    fn parse_oauth2() {
+        assert_eq!(
+            "oauth2:keybroker,/run/secrets/secret,https://auth.example/token?realm=a,b"
+                .parse::<VerifierAuth>(),
+            Ok(VerifierAuth::OAuth2 {
+                client_id: "keybroker".to_string(),
+                secret_file: PathBuf::from("/run/secrets/secret"),
+                token_url: "https://auth.example/token?realm=a,b".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn parse_invalid() {
+        assert!("bearer:".parse::<VerifierAuth>().is_err());
+        assert!("basic:user".parse::<VerifierAuth>().is_err());
+        assert!("oauth2:keybroker,/run/secrets/secret"
+            .parse::<VerifierAuth>()
+            .is_err());
+    }
+}